@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::restore::under_prefix;
+use crate::{unsupported, SyncError, SyncRequest, SyncTransport};
+
+const BLOCK: u64 = 512;
+pub(crate) const MANIFEST_ENTRY: &str = "manifest";
+pub(crate) const MANIFEST_HEADER: &str = "# rust-client archive manifest v1";
+pub(crate) const CHUNK_PREFIX: &str = "chunks/";
+
+/// Local file and empty archive signatures, so a zip gets a clear error instead of a
+/// tar checksum mismatch.
+const ZIP_MAGIC: [&[u8]; 2] = [b"PK\x03\x04", b"PK\x05\x06"];
+
+/// A restore archive on disk: one tar stream, possibly split across several part files,
+/// holding a `manifest` entry with the sync request of every file and a `chunks/<id>` entry
+/// for each chunk those requests name. Chunks keep the form they were uploaded in, so
+/// encrypted content stays encrypted inside the archive.
+///
+/// It serves records and chunks like a server would, so a [`crate::Restorer`] over it
+/// extracts with the same verification, collision policies and metadata as a live restore.
+pub struct ArchiveSource {
+    parts: Parts,
+    records: BTreeMap<String, SyncRequest>,
+    /// Offset in the joined stream and length of each chunk.
+    chunks: HashMap<String, (u64, u64)>,
+}
+
+impl ArchiveSource {
+    /// Indexes the parts, given in order. Only the manifest is read into memory.
+    pub fn open<P: AsRef<Path>>(parts: &[P]) -> Result<Self, SyncError> {
+        let mut parts = Parts::open(parts)?;
+        if parts.len() >= 4 && ZIP_MAGIC.contains(&parts.read_at(0, 4)?.as_slice()) {
+            return Err(SyncError::Protocol(
+                "archive is a zip file; only tar archives can be restored".to_string(),
+            ));
+        }
+        let mut records = BTreeMap::new();
+        let mut chunks = HashMap::new();
+        let mut manifest_seen = false;
+
+        let mut offset = 0;
+        let mut long_name = None;
+        while let Some(header) = parts.header_at(offset)? {
+            let data = offset + BLOCK;
+            offset = data + header.size.div_ceil(BLOCK) * BLOCK;
+            if offset > parts.len() {
+                return Err(SyncError::Protocol(
+                    "archive ends in the middle of an entry".to_string(),
+                ));
+            }
+            let name = long_name.take().unwrap_or(header.name);
+            match header.kind {
+                // PAX and GNU long names replace the name of the entry that follows.
+                b'x' => long_name = pax_path(&parts.read_at(data, header.size)?),
+                b'L' => {
+                    let name = parts.read_at(data, header.size)?;
+                    long_name = Some(
+                        String::from_utf8_lossy(&name)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    );
+                }
+                b'0' | 0 if name == MANIFEST_ENTRY => {
+                    let text =
+                        String::from_utf8(parts.read_at(data, header.size)?).map_err(|_| {
+                            SyncError::Protocol("archive manifest is not UTF-8".to_string())
+                        })?;
+                    for record in decode_manifest(&text)? {
+                        records.insert(record.path.clone(), record);
+                    }
+                    manifest_seen = true;
+                }
+                b'0' | 0 => {
+                    if let Some(id) = name.strip_prefix(CHUNK_PREFIX) {
+                        chunks.insert(id.to_string(), (data, header.size));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !manifest_seen {
+            return Err(SyncError::Protocol("archive has no manifest".to_string()));
+        }
+        Ok(Self {
+            parts,
+            records,
+            chunks,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl SyncTransport for ArchiveSource {
+    fn health_check(&mut self) -> Result<bool, SyncError> {
+        Ok(true)
+    }
+
+    fn sync_file(&mut self, _req: &SyncRequest) -> Result<(), SyncError> {
+        Err(unsupported("syncing into an archive"))
+    }
+
+    fn list_files(&mut self, prefix: &str) -> Result<Vec<String>, SyncError> {
+        Ok(self
+            .records
+            .keys()
+            .filter(|path| under_prefix(path, prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn fetch_file(&mut self, path: &str) -> Result<SyncRequest, SyncError> {
+        self.records
+            .get(path)
+            .cloned()
+            .ok_or_else(|| SyncError::Protocol(format!("archive has no record for {path}")))
+    }
+
+    fn download_chunk(&mut self, chunk_id: &str) -> Result<Vec<u8>, SyncError> {
+        let (offset, length) = *self
+            .chunks
+            .get(chunk_id)
+            .ok_or_else(|| SyncError::Protocol(format!("archive is missing chunk {chunk_id}")))?;
+        self.parts.read_at(offset, length)
+    }
+}
+
+/// The manifest entry: a header line, then one sync request body per file separated by
+/// blank lines. The server writes it; the client only reads it.
+fn decode_manifest(text: &str) -> Result<Vec<SyncRequest>, SyncError> {
+    let body = text
+        .strip_prefix(MANIFEST_HEADER)
+        .ok_or_else(|| SyncError::Protocol("archive manifest has an unknown format".to_string()))?;
+    body.split("\n\n")
+        .map(str::trim)
+        .filter(|block| !block.is_empty())
+        .map(SyncRequest::decode_body)
+        .collect()
+}
+
+struct Header {
+    name: String,
+    size: u64,
+    kind: u8,
+}
+
+/// Parses a ustar header block; `None` for the zero block that ends the archive.
+fn parse_header(block: &[u8]) -> Result<Option<Header>, SyncError> {
+    if block.iter().all(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    let invalid = |what: &str| SyncError::Protocol(format!("invalid tar header: {what}"));
+    let checksum: u64 = block
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| {
+            if (148..156).contains(&index) {
+                32
+            } else {
+                u64::from(*byte)
+            }
+        })
+        .sum();
+    if octal(&block[148..156]) != Some(checksum) {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let text = |field: &[u8]| {
+        let end = field
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(field.len());
+        String::from_utf8_lossy(&field[..end]).to_string()
+    };
+    let mut name = text(&block[..100]);
+    if &block[257..262] == b"ustar" {
+        let prefix = text(&block[345..500]);
+        if !prefix.is_empty() {
+            name = format!("{prefix}/{name}");
+        }
+    }
+    // Sizes past 8 GiB use GNU base-256: high bit set, then a big-endian number.
+    let size = if block[124] & 0x80 != 0 {
+        block[125..136]
+            .iter()
+            .fold(0_u64, |size, byte| (size << 8) | u64::from(*byte))
+    } else {
+        octal(&block[124..136]).ok_or_else(|| invalid("bad size"))?
+    };
+    Ok(Some(Header {
+        name,
+        size,
+        kind: block[156],
+    }))
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|ch: char| ch == '\0' || ch == ' ');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// The `path` record of a PAX extended header, if it has one.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+    // Each record is `<length> <key>=<value>\n`.
+    text.lines()
+        .filter_map(|record| record.split_once(' ').map(|(_, pair)| pair))
+        .find_map(|pair| pair.strip_prefix("path=").map(str::to_string))
+}
+
+/// Part files read as one stream.
+struct Parts {
+    files: Vec<(File, u64)>,
+}
+
+impl Parts {
+    fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, SyncError> {
+        let files = paths
+            .iter()
+            .map(|path| {
+                let file = File::open(path.as_ref()).map_err(SyncError::Io)?;
+                let length = file.metadata().map_err(SyncError::Io)?.len();
+                Ok((file, length))
+            })
+            .collect::<Result<_, SyncError>>()?;
+        Ok(Self { files })
+    }
+
+    fn len(&self) -> u64 {
+        self.files.iter().map(|(_, length)| length).sum()
+    }
+
+    fn header_at(&mut self, offset: u64) -> Result<Option<Header>, SyncError> {
+        // A stream without its end-of-archive blocks still ends cleanly at a boundary.
+        if offset + BLOCK > self.len() {
+            return Ok(None);
+        }
+        parse_header(&self.read_at(offset, BLOCK)?)
+    }
+
+    /// Reads `length` bytes at `offset` in the joined stream, crossing parts as needed.
+    fn read_at(&mut self, mut offset: u64, length: u64) -> Result<Vec<u8>, SyncError> {
+        let mut buffer = vec![0; length as usize];
+        let mut filled = 0;
+        for (file, part_length) in &mut self.files {
+            if filled == buffer.len() {
+                break;
+            }
+            if offset >= *part_length {
+                offset -= *part_length;
+                continue;
+            }
+            let take = ((*part_length - offset) as usize).min(buffer.len() - filled);
+            file.seek(SeekFrom::Start(offset)).map_err(SyncError::Io)?;
+            file.read_exact(&mut buffer[filled..filled + take])
+                .map_err(SyncError::Io)?;
+            filled += take;
+            offset = 0;
+        }
+        if filled < buffer.len() {
+            return Err(SyncError::Protocol(
+                "archive ends in the middle of an entry".to_string(),
+            ));
+        }
+        Ok(buffer)
+    }
+}
+
+/// Sorted part files in `directory`, as written by [`crate::BulkRestore::download_parts`].
+pub fn archive_parts<P: AsRef<Path>>(directory: P) -> Result<Vec<PathBuf>, SyncError> {
+    let mut parts = std::fs::read_dir(directory.as_ref())
+        .map_err(SyncError::Io)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("part-") && name.ends_with(".tar"))
+        })
+        .collect::<Vec<_>>();
+    parts.sort();
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::{encode_manifest, tar_archive, tar_entry, temp_dir};
+
+    fn manifest(paths: &[&str]) -> Vec<u8> {
+        let records = paths
+            .iter()
+            .map(|path| SyncRequest {
+                path: path.to_string(),
+                hash: format!("hash-of-{path}"),
+                ..SyncRequest::default()
+            })
+            .collect::<Vec<_>>();
+        encode_manifest(&records).into_bytes()
+    }
+
+    /// Writes `stream` as parts of `part_size` bytes and returns their paths in order.
+    fn write_parts(label: &str, stream: &[u8], part_size: usize) -> Vec<PathBuf> {
+        let dir = temp_dir(label);
+        stream
+            .chunks(part_size)
+            .enumerate()
+            .map(|(index, bytes)| {
+                let path = dir.join(format!("part-{index:05}.tar"));
+                fs::write(&path, bytes).expect("part should be written");
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_headers_and_chunks_that_continue_into_the_next_part() {
+        let chunk = b"0123456789".repeat(150);
+        let stream = tar_archive(&[
+            (
+                MANIFEST_ENTRY.to_string(),
+                manifest(&["docs/a.txt", "docs/b.txt"]),
+            ),
+            (format!("{CHUNK_PREFIX}c1"), chunk.clone()),
+            (format!("{CHUNK_PREFIX}c2"), b"tail".to_vec()),
+        ]);
+        // 700-byte parts split the first data block, the second chunk's header and the
+        // first chunk's body across part boundaries.
+        let parts = write_parts("archive-split", &stream, 700);
+        assert!(parts.len() > 3);
+
+        let mut archive = ArchiveSource::open(&parts).expect("archive should open");
+        assert_eq!(archive.len(), 2);
+        assert_eq!(
+            archive.list_files("docs").expect("files should list"),
+            ["docs/a.txt", "docs/b.txt"]
+        );
+        assert_eq!(
+            archive.fetch_file("docs/b.txt").expect("record").hash,
+            "hash-of-docs/b.txt"
+        );
+        assert_eq!(archive.download_chunk("c1").expect("c1"), chunk);
+        assert_eq!(archive.download_chunk("c2").expect("c2"), b"tail");
+        assert!(archive.download_chunk("c3").is_err());
+    }
+
+    #[test]
+    fn takes_entry_names_from_pax_and_gnu_long_name_headers() {
+        let pax_id = "p".repeat(150);
+        let gnu_id = "g".repeat(180);
+        let mut stream = Vec::new();
+        tar_entry(&mut stream, MANIFEST_ENTRY, b'0', &manifest(&["a.txt"]));
+        let record = format!("path={CHUNK_PREFIX}{pax_id}\n");
+        // The length prefix counts itself (three digits here), the space and the record.
+        let pax = format!("{} {record}", record.len() + 4);
+        assert_eq!(
+            pax.split_once(' ').map(|(length, _)| length),
+            Some(&*pax.len().to_string())
+        );
+        tar_entry(&mut stream, "PaxHeaders/0", b'x', pax.as_bytes());
+        tar_entry(
+            &mut stream,
+            &format!("{CHUNK_PREFIX}{pax_id}"),
+            b'0',
+            b"pax body",
+        );
+        let long_name = format!("{CHUNK_PREFIX}{gnu_id}\0");
+        tar_entry(&mut stream, "././@LongLink", b'L', long_name.as_bytes());
+        tar_entry(
+            &mut stream,
+            &format!("{CHUNK_PREFIX}{gnu_id}"),
+            b'0',
+            b"gnu body",
+        );
+        stream.resize(stream.len() + 1024, 0);
+        let parts = write_parts("archive-long-names", &stream, stream.len());
+
+        let mut archive = ArchiveSource::open(&parts).expect("archive should open");
+        assert_eq!(archive.download_chunk(&pax_id).expect("pax"), b"pax body");
+        assert_eq!(archive.download_chunk(&gnu_id).expect("gnu"), b"gnu body");
+        // The truncated header names are not indexed on their own.
+        assert!(archive.download_chunk(&pax_id[..93]).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_truncated_and_zip_archives() {
+        let stream = tar_archive(&[
+            (MANIFEST_ENTRY.to_string(), manifest(&["a.txt"])),
+            (format!("{CHUNK_PREFIX}c1"), vec![7; 2000]),
+        ]);
+        let open = |label: &str, stream: &[u8]| {
+            let parts = write_parts(label, stream, 1000);
+            ArchiveSource::open(&parts)
+                .err()
+                .expect("archive should be rejected")
+                .to_string()
+        };
+
+        let mut corrupt = stream.clone();
+        corrupt[1024] ^= 0x01;
+        assert!(open("archive-checksum", &corrupt).contains("checksum mismatch"));
+
+        assert!(open("archive-truncated", &stream[..2048]).contains("middle of an entry"));
+
+        let mut zip = b"PK\x03\x04".to_vec();
+        zip.extend_from_slice(&[0; 600]);
+        assert!(open("archive-zip", &zip).contains("zip file"));
+
+        let parts = write_parts("archive-no-manifest", &tar_archive(&[]), 1024);
+        let error = ArchiveSource::open(&parts).err().expect("no manifest");
+        assert!(error.to_string().contains("no manifest"));
+    }
+}
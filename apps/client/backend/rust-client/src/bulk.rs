@@ -0,0 +1,487 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::metadata::to_hex;
+use crate::{percent_encode, SyncClient, SyncError, SyncTransport};
+
+/// Asks the server to pack everything at or below `prefix`, as of `snapshot` or the latest
+/// state, into an archive split into parts of at most `part_size` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreJobRequest {
+    pub prefix: String,
+    pub snapshot: Option<String>,
+    pub part_size: u64,
+}
+
+impl RestoreJobRequest {
+    /// The `POST /v1/restore-jobs` body.
+    pub fn encode_body(&self) -> String {
+        let mut body = format!("prefix={}\n", self.prefix);
+        if let Some(snapshot) = &self.snapshot {
+            body.push_str(&format!("snapshot={snapshot}\n"));
+        }
+        body.push_str(&format!("part_size={}\n", self.part_size));
+        body
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobState {
+    Pending,
+    Building,
+    Ready,
+    Failed(String),
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Building => "building",
+            JobState::Ready => "ready",
+            JobState::Failed(_) => "failed",
+        }
+    }
+}
+
+/// One piece of a finished archive. Joined in index order the parts form one tar stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivePart {
+    pub index: usize,
+    pub size: u64,
+    /// Hex SHA-256 of the part's bytes.
+    pub sha256: String,
+}
+
+impl ArchivePart {
+    /// Where [`BulkRestore::download_parts`] stores this part.
+    pub fn file_name(&self) -> String {
+        format!("part-{:04}.tar", self.index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreJob {
+    pub id: String,
+    pub state: JobState,
+    /// Filled in once the job is ready.
+    pub parts: Vec<ArchivePart>,
+}
+
+impl RestoreJob {
+    /// Parses a `GET /v1/restore-jobs/<id>` body: `state=`, `error=` when failed, and one
+    /// `part=<index>:<size>:<sha256>` line per part.
+    pub fn decode(id: &str, body: &str) -> Result<Self, SyncError> {
+        let invalid =
+            |line: &str| SyncError::Protocol(format!("unexpected restore job line: {line}"));
+        let mut state = None;
+        let mut error = String::new();
+        let mut parts = Vec::new();
+        for line in body.lines().filter(|line| !line.is_empty()) {
+            match line.split_once('=') {
+                Some(("state", value)) => state = Some(value.to_string()),
+                Some(("error", value)) => error = value.to_string(),
+                Some(("part", value)) => {
+                    let mut fields = value.split(':');
+                    let (Some(index), Some(size), Some(sha256), None) =
+                        (fields.next(), fields.next(), fields.next(), fields.next())
+                    else {
+                        return Err(invalid(line));
+                    };
+                    parts.push(ArchivePart {
+                        index: index.parse().map_err(|_| invalid(line))?,
+                        size: size.parse().map_err(|_| invalid(line))?,
+                        sha256: sha256.to_string(),
+                    });
+                }
+                // Unknown keys are ignored so servers can add progress fields.
+                Some(_) => {}
+                None => return Err(invalid(line)),
+            }
+        }
+        let state = match state.as_deref() {
+            Some("pending") => JobState::Pending,
+            Some("building") => JobState::Building,
+            Some("ready") => JobState::Ready,
+            Some("failed") => JobState::Failed(error),
+            _ => {
+                return Err(SyncError::Protocol(format!(
+                    "restore job {id} has no valid state"
+                )))
+            }
+        };
+        parts.sort_by_key(|part| part.index);
+        Ok(Self {
+            id: id.to_string(),
+            state,
+            parts,
+        })
+    }
+}
+
+impl SyncClient {
+    pub fn create_restore_job(&self, request: &RestoreJobRequest) -> Result<String, SyncError> {
+        let response = self.send("POST", "/v1/restore-jobs", &request.encode_body())?;
+        if !(200..=202).contains(&response.status) {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+        response
+            .body
+            .lines()
+            .find_map(|line| line.strip_prefix("job="))
+            .filter(|id| !id.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| SyncError::Protocol("restore job response has no job id".to_string()))
+    }
+
+    pub fn restore_job(&self, id: &str) -> Result<RestoreJob, SyncError> {
+        let response = self.send(
+            "GET",
+            &format!("/v1/restore-jobs/{}", percent_encode(id)),
+            "",
+        )?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+        RestoreJob::decode(id, &response.body)
+    }
+
+    /// Streams one archive part into `out`.
+    pub fn download_archive_part(
+        &self,
+        id: &str,
+        index: usize,
+        out: &mut dyn Write,
+    ) -> Result<u64, SyncError> {
+        self.download_to(
+            &format!("/v1/restore-jobs/{}/parts/{index}", percent_encode(id)),
+            out,
+        )
+    }
+}
+
+/// What [`BulkRestore::download_parts`] fetched: every part path in order, and how many
+/// were already on disk from an earlier attempt.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartDownload {
+    pub parts: Vec<PathBuf>,
+    pub reused: usize,
+    pub downloaded_bytes: u64,
+}
+
+/// Requests a server-built restore archive, waits for it, and downloads its parts with
+/// each one checked against its SHA-256. Extract the parts with [`crate::ArchiveSource`].
+pub struct BulkRestore<T: SyncTransport> {
+    transport: T,
+    poll_interval: Duration,
+    timeout: Option<Duration>,
+}
+
+impl<T: SyncTransport> BulkRestore<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            poll_interval: Duration::from_secs(5),
+            timeout: None,
+        }
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Gives up waiting for a job after `timeout`. Without it, [`BulkRestore::wait`] polls
+    /// until the job is ready or fails.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Starts a job and returns its id.
+    pub fn start(&mut self, request: &RestoreJobRequest) -> Result<String, SyncError> {
+        self.transport.create_restore_job(request)
+    }
+
+    /// Polls the job until it is ready, passing each status to `on_poll`.
+    pub fn wait(
+        &mut self,
+        id: &str,
+        mut on_poll: impl FnMut(&RestoreJob),
+    ) -> Result<RestoreJob, SyncError> {
+        let started = Instant::now();
+        loop {
+            let job = self.transport.restore_job(id)?;
+            on_poll(&job);
+            match &job.state {
+                JobState::Ready => return Ok(job),
+                JobState::Failed(reason) => {
+                    return Err(SyncError::Protocol(format!(
+                        "restore job {id} failed: {reason}"
+                    )))
+                }
+                JobState::Pending | JobState::Building => {}
+            }
+            if self
+                .timeout
+                .is_some_and(|timeout| started.elapsed() >= timeout)
+            {
+                return Err(SyncError::Protocol(format!(
+                    "restore job {id} was not ready after {}s",
+                    started.elapsed().as_secs()
+                )));
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Downloads every part of a ready job into `directory`. Parts already there with the
+    /// right size and hash are kept, so an interrupted download picks up where it stopped;
+    /// a part that fails its hash is deleted and reported.
+    pub fn download_parts(
+        &mut self,
+        job: &RestoreJob,
+        directory: &Path,
+    ) -> Result<PartDownload, SyncError> {
+        if job.state != JobState::Ready {
+            return Err(SyncError::Protocol(format!(
+                "restore job {} is not ready",
+                job.id
+            )));
+        }
+        fs::create_dir_all(directory).map_err(SyncError::Io)?;
+
+        let mut download = PartDownload::default();
+        for part in &job.parts {
+            let path = directory.join(part.file_name());
+            if part_matches(&path, part)? {
+                download.reused += 1;
+                download.parts.push(path);
+                continue;
+            }
+
+            let partial = directory.join(format!("{}.partial", part.file_name()));
+            let mut out = HashingWriter {
+                file: File::create(&partial).map_err(SyncError::Io)?,
+                hasher: Sha256::new(),
+            };
+            let written = self
+                .transport
+                .download_archive_part(&job.id, part.index, &mut out)?;
+            out.file.sync_all().map_err(SyncError::Io)?;
+            if written != part.size || to_hex(&out.hasher.finalize()) != part.sha256 {
+                let _ = fs::remove_file(&partial);
+                return Err(SyncError::Protocol(format!(
+                    "archive part {} of job {} does not match its recorded size and hash",
+                    part.index, job.id
+                )));
+            }
+            fs::rename(&partial, &path).map_err(SyncError::Io)?;
+            download.downloaded_bytes += written;
+            download.parts.push(path);
+        }
+        Ok(download)
+    }
+}
+
+fn part_matches(path: &Path, part: &ArchivePart) -> Result<bool, SyncError> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() == part.size => {}
+        Ok(_) => return Ok(false),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(SyncError::Io(err)),
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path).map_err(SyncError::Io)?, &mut hasher).map_err(SyncError::Io)?;
+    Ok(to_hex(&hasher.finalize()) == part.sha256)
+}
+
+/// Writes to a file while hashing what passes through.
+struct HashingWriter {
+    file: File,
+    hasher: Sha256,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve_scripted, temp_dir, MemoryRemote, MockResponse};
+    use crate::{ArchiveSource, ContentOptions, MasterKey, Restorer, SyncManager};
+
+    #[test]
+    fn restores_a_tree_from_verified_size_split_archive_parts() {
+        let root = temp_dir("bulk-restore");
+        fs::create_dir_all(root.join("docs/deep")).expect("dirs should be created");
+        fs::write(root.join("docs/a.txt"), "alpha").expect("write a");
+        fs::write(root.join("docs/deep/b.csv"), b"member,hours\n".repeat(500)).expect("write b");
+        fs::write(root.join("other.txt"), "not restored").expect("write other");
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(root.join("docs/a.txt"))
+            .and_then(|file| file.set_modified(old))
+            .expect("mtime should be set");
+
+        let master = MasterKey::generate().expect("master key should generate");
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone()).with_content_upload(ContentOptions {
+            chunk_size: 1000,
+            encryption: Some(master.clone()),
+            compression: None,
+        });
+        manager
+            .queue_directory(&root)
+            .expect("directory should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        fs::remove_dir_all(root.join("docs")).expect("docs should be removed");
+        remote.state().job_polls = 2;
+
+        let prefix = root.join("docs").display().to_string();
+        let request = RestoreJobRequest {
+            prefix: prefix.clone(),
+            snapshot: None,
+            part_size: 2048,
+        };
+        let mut bulk = BulkRestore::new(remote.clone()).with_poll_interval(Duration::ZERO);
+        let id = bulk.start(&request).expect("job should start");
+        let mut states = Vec::new();
+        let job = bulk
+            .wait(&id, |job| states.push(job.state.as_str()))
+            .expect("job should become ready");
+        assert_eq!(states, ["building", "building", "ready"]);
+        assert!(job.parts.len() > 2, "the archive should span several parts");
+        assert!(job.parts.iter().all(|part| part.size <= 2048));
+
+        let parts_dir = root.join("parts");
+        let download = bulk
+            .download_parts(&job, &parts_dir)
+            .expect("parts should download");
+        assert_eq!(
+            (download.parts.len(), download.reused),
+            (job.parts.len(), 0)
+        );
+        assert_eq!(
+            download.parts,
+            crate::archive_parts(&parts_dir).expect("parts should list")
+        );
+
+        let archive = ArchiveSource::open(&download.parts).expect("archive should open");
+        assert_eq!(archive.len(), 2);
+        let report = Restorer::new(archive)
+            .with_master_key(master)
+            .restore(&prefix)
+            .expect("archive should extract");
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(report.restored, 2);
+        assert_eq!(
+            fs::read_to_string(root.join("docs/a.txt")).expect("a"),
+            "alpha"
+        );
+        assert_eq!(
+            fs::read(root.join("docs/deep/b.csv")).expect("b"),
+            b"member,hours\n".repeat(500)
+        );
+        assert_eq!(
+            fs::metadata(root.join("docs/a.txt"))
+                .and_then(|m| m.modified())
+                .expect("mtime"),
+            old
+        );
+
+        // A second download keeps every verified part; a corrupted one is rejected.
+        let served = remote.state().part_downloads;
+        let again = bulk
+            .download_parts(&job, &parts_dir)
+            .expect("parts should be reused");
+        assert_eq!((again.reused, again.downloaded_bytes), (job.parts.len(), 0));
+        assert_eq!(remote.state().part_downloads, served);
+
+        fs::remove_file(&download.parts[1]).expect("part should be removed");
+        remote.state().corrupt_part = Some(1);
+        let err = bulk
+            .download_parts(&job, &parts_dir)
+            .expect_err("corrupt part should fail");
+        assert!(err.to_string().contains("archive part 1"), "{err}");
+        let names = fs::read_dir(&parts_dir)
+            .expect("parts dir should list")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert!(
+            names
+                .iter()
+                .all(|name| name != "part-0001.tar" && !name.ends_with(".partial")),
+            "{names:?}"
+        );
+    }
+
+    #[test]
+    fn http_client_starts_polls_and_streams_restore_jobs() {
+        let (base_url, handle) = serve_scripted(4, |request| match request.path.as_str() {
+            "/v1/restore-jobs" => MockResponse::text(202, "job=j%1\n"),
+            "/v1/restore-jobs/j%251" => MockResponse::text(
+                200,
+                "state=ready\nprogress=100\npart=1:3:bbb\npart=0:5:aaa\n",
+            ),
+            "/v1/restore-jobs/j%251/parts/0" => MockResponse::text(200, "hello"),
+            _ => MockResponse::text(404, "no such part"),
+        });
+        let client = SyncClient::new(&base_url).expect("client should build");
+        let request = RestoreJobRequest {
+            prefix: "/home/a/docs".to_string(),
+            snapshot: Some("0123abcd".to_string()),
+            part_size: 4_000_000_000,
+        };
+
+        let id = client
+            .create_restore_job(&request)
+            .expect("job should start");
+        assert_eq!(id, "j%1");
+        let job = client.restore_job(&id).expect("status should parse");
+        assert_eq!(job.state, JobState::Ready);
+        assert_eq!(
+            job.parts
+                .iter()
+                .map(|part| (part.index, part.size))
+                .collect::<Vec<_>>(),
+            [(0, 5), (1, 3)]
+        );
+        let mut body = Vec::new();
+        assert_eq!(
+            client
+                .download_archive_part(&id, 0, &mut body)
+                .expect("part should stream"),
+            5
+        );
+        assert_eq!(body, b"hello");
+        let missing = client.download_archive_part(&id, 7, &mut Vec::new());
+        assert!(matches!(missing, Err(SyncError::Server(404, _))));
+
+        let seen = handle.join().expect("mock server should finish");
+        assert_eq!(
+            seen[0].body_text(),
+            "prefix=/home/a/docs\nsnapshot=0123abcd\npart_size=4000000000\n"
+        );
+
+        let failed = RestoreJob::decode("j", "state=failed\nerror=disk full\n").expect("decode");
+        assert_eq!(failed.state, JobState::Failed("disk full".to_string()));
+        assert!(RestoreJob::decode("j", "part=0:1:aa\n").is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::queue_store::{blocks, write_atomically};
+use crate::{percent_encode, SyncClient, SyncError, SyncTransport};
+
+const CURSOR_HEADER: &str = "# rust-client change cursor v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteChangeKind {
+    /// A new record for the path: created or modified on some device.
+    Updated,
+    Deleted,
+}
+
+impl RemoteChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RemoteChangeKind::Updated => "updated",
+            RemoteChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// One entry of the server's change feed, in the order the server applied it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteChange {
+    pub kind: RemoteChangeKind,
+    /// The server path, encrypted when the root uses path encryption.
+    pub path: String,
+    /// The recorded hash (or keyed tag) for updates; `None` for deletions.
+    pub hash: Option<String>,
+    /// The device that made the change, when the server knows it.
+    pub device: Option<String>,
+    /// When the server applied the change.
+    pub time: SystemTime,
+}
+
+impl RemoteChange {
+    /// Parses `<kind>:<time>:<device>:<hash>:<path>`; the path comes last because it may
+    /// contain colons. Returns `None` for kinds this client does not know yet.
+    fn decode(value: &str) -> Result<Option<Self>, SyncError> {
+        let invalid = || SyncError::Protocol(format!("invalid change record: {value}"));
+        let mut fields = value.splitn(5, ':');
+        let (Some(kind), Some(time), Some(device), Some(hash), Some(path)) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(invalid());
+        };
+        let kind = match kind {
+            "updated" => RemoteChangeKind::Updated,
+            "deleted" => RemoteChangeKind::Deleted,
+            _ => return Ok(None),
+        };
+        if path.is_empty() || (kind == RemoteChangeKind::Updated && hash.is_empty()) {
+            return Err(invalid());
+        }
+        let optional = |field: &str| (!field.is_empty()).then(|| field.to_string());
+        Ok(Some(Self {
+            kind,
+            path: path.to_string(),
+            hash: optional(hash),
+            device: optional(device),
+            time: UNIX_EPOCH + Duration::from_secs(time.parse().map_err(|_| invalid())?),
+        }))
+    }
+}
+
+impl fmt::Display for RemoteChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<7}  {}", self.kind.as_str(), self.path)?;
+        if let Some(device) = &self.device {
+            write!(f, " (from {device})")?;
+        }
+        Ok(())
+    }
+}
+
+/// One page of `GET /v1/changes`. `cursor` resumes after the last change on the page and
+/// is returned even when the page is empty; `more` says whether another page is ready now.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangePage {
+    pub changes: Vec<RemoteChange>,
+    pub cursor: String,
+    pub more: bool,
+}
+
+impl SyncClient {
+    /// Fetches the changes after `cursor`, or from the start of the feed without one.
+    pub fn changes(&self, cursor: Option<&str>) -> Result<ChangePage, SyncError> {
+        let mut path = "/v1/changes".to_string();
+        if let Some(cursor) = cursor {
+            path.push_str(&format!("?cursor={}", percent_encode(cursor)));
+        }
+        let response = self.send("GET", &path, "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+
+        let mut page = ChangePage::default();
+        let mut cursor = None;
+        for line in response.body.lines().filter(|line| !line.is_empty()) {
+            match line.split_once('=') {
+                Some(("change", value)) => page.changes.extend(RemoteChange::decode(value)?),
+                Some(("cursor", value)) => cursor = Some(value.to_string()),
+                Some(("more", value)) => page.more = value == "true",
+                _ => {
+                    return Err(SyncError::Protocol(format!(
+                        "unexpected change feed line: {line}"
+                    )))
+                }
+            }
+        }
+        page.cursor = cursor
+            .ok_or_else(|| SyncError::Protocol("change feed page has no cursor".to_string()))?;
+        Ok(page)
+    }
+}
+
+/// Follows the server's change feed from a persisted cursor.
+///
+/// [`ChangeFeed::events`] yields every change since the last run. The cursor advances, and
+/// is written to the cursor file, only once all changes of a page have been taken, so a
+/// crash or an early stop delivers those changes again: consumers see each change at least
+/// once and should handle repeats.
+pub struct ChangeFeed<T: SyncTransport> {
+    transport: T,
+    cursor: Option<String>,
+    cursor_path: Option<PathBuf>,
+}
+
+impl<T: SyncTransport> ChangeFeed<T> {
+    /// Starts from the beginning of the feed and keeps the cursor in memory only.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            cursor: None,
+            cursor_path: None,
+        }
+    }
+
+    /// Resumes from the cursor stored at `path`, if any, and stores it there as it advances.
+    pub fn with_cursor_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, SyncError> {
+        let path = path.as_ref();
+        for block in blocks(path, CURSOR_HEADER, "change cursor")? {
+            if let Some(cursor) = block.strip_prefix("cursor=") {
+                self.cursor = Some(cursor.to_string());
+            }
+        }
+        self.cursor_path = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Starts after `cursor` instead of the beginning of the feed.
+    pub fn with_cursor(mut self, cursor: &str) -> Self {
+        self.cursor = Some(cursor.to_string());
+        self
+    }
+
+    /// The position after the last fully consumed page; `None` before the first one.
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_deref()
+    }
+
+    /// The changes since the cursor, fetched a page at a time as the iterator is drained.
+    /// An error ends the stream and leaves the cursor where it was.
+    pub fn events(&mut self) -> ChangeStream<'_, T> {
+        ChangeStream {
+            feed: self,
+            buffered: VecDeque::new(),
+            next_cursor: None,
+            more: true,
+        }
+    }
+
+    fn advance(&mut self, cursor: String) -> Result<(), SyncError> {
+        if self.cursor.as_deref() == Some(cursor.as_str()) {
+            return Ok(());
+        }
+        if let Some(path) = &self.cursor_path {
+            write_atomically(path, &format!("{CURSOR_HEADER}\n\ncursor={cursor}\n"))?;
+        }
+        self.cursor = Some(cursor);
+        Ok(())
+    }
+}
+
+/// Iterator over [`ChangeFeed::events`].
+pub struct ChangeStream<'a, T: SyncTransport> {
+    feed: &'a mut ChangeFeed<T>,
+    buffered: VecDeque<RemoteChange>,
+    /// The cursor after the buffered page, committed once the page is drained.
+    next_cursor: Option<String>,
+    more: bool,
+}
+
+impl<T: SyncTransport> Iterator for ChangeStream<'_, T> {
+    type Item = Result<RemoteChange, SyncError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.buffered.pop_front() {
+                return Some(Ok(change));
+            }
+            if let Some(cursor) = self.next_cursor.take() {
+                if let Err(err) = self.feed.advance(cursor) {
+                    self.more = false;
+                    return Some(Err(err));
+                }
+            }
+            if !self.more {
+                return None;
+            }
+            match self.feed.transport.changes(self.feed.cursor.as_deref()) {
+                Ok(page) => {
+                    self.buffered.extend(page.changes);
+                    self.next_cursor = Some(page.cursor);
+                    self.more = page.more;
+                }
+                Err(err) => {
+                    self.more = false;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::{serve_scripted, temp_dir, MemoryRemote, MockResponse};
+
+    fn record(path: &str, hash: &str) -> crate::SyncRequest {
+        crate::SyncRequest {
+            path: path.to_string(),
+            hash: hash.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn http_client_pages_through_the_change_feed() {
+        let (base_url, handle) = serve_scripted(2, |request| match request.path.as_str() {
+            "/v1/changes" => MockResponse::text(
+                200,
+                "change=updated:1700000000:laptop:abc:/home/a/x:y.txt\n\
+                 change=renamed:1700000001::abc:/home/a/z\n\
+                 cursor=c 1\nmore=true\n",
+            ),
+            "/v1/changes?cursor=c%201" => MockResponse::text(
+                200,
+                "change=deleted:1700000002:::/home/a/x:y.txt\ncursor=c2\nmore=false\n",
+            ),
+            _ => MockResponse::text(404, "unknown"),
+        });
+        let client = SyncClient::new(&base_url).expect("client should build");
+
+        let first = client.changes(None).expect("first page should parse");
+        assert_eq!(
+            first.changes,
+            [RemoteChange {
+                kind: RemoteChangeKind::Updated,
+                path: "/home/a/x:y.txt".to_string(),
+                hash: Some("abc".to_string()),
+                device: Some("laptop".to_string()),
+                time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            }]
+        );
+        assert_eq!((first.cursor.as_str(), first.more), ("c 1", true));
+
+        let second = client
+            .changes(Some(&first.cursor))
+            .expect("second page should parse");
+        assert_eq!(second.changes[0].kind, RemoteChangeKind::Deleted);
+        assert_eq!(
+            (
+                second.changes[0].hash.as_ref(),
+                second.changes[0].device.as_ref()
+            ),
+            (None, None)
+        );
+        assert_eq!((second.cursor.as_str(), second.more), ("c2", false));
+        handle.join().expect("server thread should finish");
+    }
+
+    #[test]
+    fn rejects_pages_without_a_cursor() {
+        let (base_url, handle) = serve_scripted(1, |_| {
+            MockResponse::text(200, "change=updated:1:::abc:/a\n")
+        });
+        let client = SyncClient::new(&base_url).expect("client should build");
+
+        let err = client
+            .changes(None)
+            .expect_err("a page without a cursor is invalid");
+        assert!(matches!(err, SyncError::Protocol(_)), "{err:?}");
+        handle.join().expect("server thread should finish");
+    }
+
+    #[test]
+    fn commits_the_cursor_only_after_a_page_is_consumed() {
+        let dir = temp_dir("change-feed");
+        let cursor_path = dir.join("change-cursor");
+        let mut remote = MemoryRemote::default();
+        remote.state().change_page = 2;
+        for (path, hash) in [("/a", "1"), ("/b", "2"), ("/c", "3")] {
+            remote
+                .sync_file(&record(path, hash))
+                .expect("record should sync");
+        }
+
+        let mut feed = ChangeFeed::new(remote.clone())
+            .with_cursor_file(&cursor_path)
+            .expect("missing cursor file is fine");
+        let mut events = feed.events();
+        let first = events.next().expect("first change").expect("first change");
+        assert_eq!(first.path, "/a");
+        drop(events);
+        assert_eq!(feed.cursor(), None, "a partly read page is delivered again");
+
+        let paths = feed
+            .events()
+            .map(|change| change.expect("change should arrive").path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+        assert_eq!(feed.cursor(), Some("3"));
+
+        remote
+            .sync_file(&record("/a", "4"))
+            .expect("record should sync");
+        let mut resumed = ChangeFeed::new(remote)
+            .with_cursor_file(&cursor_path)
+            .expect("cursor file should load");
+        assert_eq!(resumed.cursor(), Some("3"));
+        let later = resumed
+            .events()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("feed should read");
+        assert_eq!(later.len(), 1);
+        assert_eq!(later[0].hash.as_deref(), Some("4"));
+        assert!(fs::read_to_string(&cursor_path)
+            .expect("cursor file should exist")
+            .contains("cursor=4"));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,923 @@
+//! Command-line parsing. One static command table drives parsing, `--help` output and the
+//! generated shell completions, so the three cannot drift apart.
+
+pub const BIN: &str = "rust-client";
+
+pub struct Command {
+    pub name: &'static str,
+    pub about: &'static str,
+    /// Positional arguments as shown in the usage line, e.g. `<PATH>...`.
+    pub args: &'static str,
+    /// Fixed values for the positional arguments, offered by completions.
+    pub choices: &'static [&'static str],
+    pub options: &'static [Opt],
+    pub subcommands: &'static [Command],
+}
+
+pub struct Opt {
+    pub name: &'static str,
+    pub short: Option<char>,
+    /// Placeholder for the option's value; `None` for switches.
+    pub value: Option<&'static str>,
+    pub about: &'static str,
+}
+
+const fn command(name: &'static str, about: &'static str) -> Command {
+    Command {
+        name,
+        about,
+        args: "",
+        choices: &[],
+        options: &[],
+        subcommands: &[],
+    }
+}
+
+const fn opt(name: &'static str, value: &'static str, about: &'static str) -> Opt {
+    Opt {
+        name,
+        short: None,
+        value: Some(value),
+        about,
+    }
+}
+
+const fn switch(name: &'static str, short: Option<char>, about: &'static str) -> Opt {
+    Opt {
+        name,
+        short,
+        value: None,
+        about,
+    }
+}
+
+/// Accepted by every command, before or after the command name.
+pub const GLOBAL_OPTIONS: &[Opt] = &[
+    opt(
+        "config",
+        "PATH",
+        "Config file (default: ~/.config/rust-client/config.toml)",
+    ),
+    opt(
+        "server",
+        "URL",
+        "Sync server URL, overriding the config file",
+    ),
+    opt(
+        "profile",
+        "NAME",
+        "Use a named profile's config and state (default: RUST_CLIENT_PROFILE)",
+    ),
+    switch("json", None, "Print machine-readable JSON"),
+    switch("verbose", Some('v'), "Log debug messages"),
+    switch("help", Some('h'), "Print help"),
+    switch("version", Some('V'), "Print version"),
+];
+
+const DAEMON_OPTIONS: &[Opt] = &[
+    opt(
+        "state-dir",
+        "PATH",
+        "Directory for the queue, caches and lock file",
+    ),
+    opt(
+        "root",
+        "PATH",
+        "Sync root, replacing the configured roots (repeatable)",
+    ),
+    opt("log-level", "LEVEL", "error, warn, info or debug"),
+    opt(
+        "bandwidth-limit",
+        "RATE",
+        "Upload limit such as 1MB/s, or unlimited",
+    ),
+    switch("all-profiles", None, "Run every profile side by side"),
+];
+
+pub const ROOT: Command = Command {
+    about: "Client for the cooperative sync server",
+    subcommands: &[
+        command(
+            "health",
+            "Check that the sync server is reachable and healthy",
+        ),
+        Command {
+            args: "<PATH>...",
+            options: &[
+                switch(
+                    "dry-run",
+                    None,
+                    "List what would be sent without contacting the server",
+                ),
+                opt(
+                    "retries",
+                    "N",
+                    "Extra flush rounds for failed requests (default 3)",
+                ),
+                switch(
+                    "paranoid",
+                    None,
+                    "Rehash every file instead of trusting the hash cache",
+                ),
+                opt(
+                    "hash-workers",
+                    "N",
+                    "Threads hashing directories (default: half the cores)",
+                ),
+                opt(
+                    "hash-rate",
+                    "RATE",
+                    "Combined hashing read rate such as 50MB/s, or unlimited",
+                ),
+            ],
+            ..command(
+                "sync",
+                "Hash files and directories and send them to the server",
+            )
+        },
+        command("queue", "List requests waiting in the daemon's queue"),
+        command(
+            "flush",
+            "Send queued requests now, or ask the running daemon to",
+        ),
+        command("status", "Show configuration, queue depth and daemon state"),
+        command("pause", "Pause uploads in the running daemon"),
+        command("resume", "Resume uploads in the running daemon"),
+        command(
+            "dead-letters",
+            "List requests that failed too often to be retried",
+        ),
+        Command {
+            args: "<PATH>...",
+            options: &[
+                opt(
+                    "store",
+                    "PATH",
+                    "Key store to unlock when content is encrypted",
+                ),
+                opt(
+                    "at",
+                    "SNAPSHOT",
+                    "Restore as of a snapshot id, id prefix or UTC time",
+                ),
+                opt(
+                    "to",
+                    "DIR",
+                    "Restore into DIR instead of the original location",
+                ),
+                opt(
+                    "on-conflict",
+                    "POLICY",
+                    "skip, overwrite (default), overwrite-if-older or keep-both",
+                ),
+                switch(
+                    "dry-run",
+                    None,
+                    "List what would change without writing anything",
+                ),
+                switch(
+                    "bulk",
+                    None,
+                    "Have the server pack an archive and download it in parts",
+                ),
+                opt(
+                    "part-size",
+                    "SIZE",
+                    "Largest archive part for --bulk, e.g. 4GB (default from config)",
+                ),
+                opt(
+                    "parts-dir",
+                    "DIR",
+                    "Keep --bulk archive parts in DIR, e.g. removable media",
+                ),
+                switch(
+                    "keep-parts",
+                    None,
+                    "Keep --bulk archive parts after extracting them",
+                ),
+                opt(
+                    "from-parts",
+                    "DIR",
+                    "Extract from archive parts in DIR without the server",
+                ),
+            ],
+            ..command(
+                "restore",
+                "Download files or whole directories from the server",
+            )
+        },
+        Command {
+            args: "[ROOT]...",
+            options: &[
+                opt(
+                    "store",
+                    "PATH",
+                    "Key store to unlock when content is encrypted",
+                ),
+                opt(
+                    "sample",
+                    "N",
+                    "Also download N random in-sync files to check their content",
+                ),
+            ],
+            ..command(
+                "verify",
+                "Compare local roots with what the server holds (default: configured roots)",
+            )
+        },
+        Command {
+            options: &[opt(
+                "since",
+                "CURSOR",
+                "Start after this cursor instead of the beginning of the feed",
+            )],
+            ..command(
+                "changes",
+                "List changes the server recorded, from any device",
+            )
+        },
+        Command {
+            subcommands: &[
+                command("list", "List the snapshots the server holds, oldest first"),
+                Command {
+                    args: "<FROM> <TO>",
+                    ..command(
+                        "diff",
+                        "Show paths added, removed or changed between two snapshots",
+                    )
+                },
+            ],
+            ..command(
+                "snapshots",
+                "Inspect point-in-time snapshots of the synced tree",
+            )
+        },
+        Command {
+            subcommands: &[
+                command("show", "Print the effective configuration"),
+                command("check", "Validate the config file"),
+                command("path", "Print the config file location"),
+            ],
+            ..command("config", "Inspect the configuration")
+        },
+        Command {
+            options: DAEMON_OPTIONS,
+            ..command(
+                "daemon",
+                "Watch the configured roots and sync changes until stopped",
+            )
+        },
+        Command {
+            subcommands: &[
+                command(
+                    "list",
+                    "List profiles with their servers and daemons; * marks the one in use",
+                ),
+                Command {
+                    args: "<NAME>",
+                    options: &[opt(
+                        "root",
+                        "PATH",
+                        "Sync root for the profile (repeatable)",
+                    )],
+                    ..command("add", "Create a profile for the server given with --server")
+                },
+            ],
+            ..command("profiles", "Manage named profiles, one per account")
+        },
+        Command {
+            subcommands: &[
+                Command {
+                    options: &[opt(
+                        "name",
+                        "NAME",
+                        "Name shown in device lists (default: the host name)",
+                    )],
+                    ..command(
+                        "register",
+                        "Create this device's id and key pair and register it with the server",
+                    )
+                },
+                command("list", "List registered devices; * marks this one"),
+                Command {
+                    args: "<ID> <NAME>",
+                    ..command("rename", "Rename a registered device")
+                },
+                Command {
+                    args: "<ID>",
+                    ..command(
+                        "revoke",
+                        "Revoke a device so the server refuses its requests",
+                    )
+                },
+            ],
+            ..command(
+                "devices",
+                "Register this device and manage registered devices",
+            )
+        },
+        Command {
+            subcommands: &[
+                Command {
+                    options: &[opt("store", "PATH", "Key store file to create")],
+                    ..command("init", "Create a key store and print its recovery key")
+                },
+                Command {
+                    options: &[
+                        opt("store", "PATH", "Key store file"),
+                        opt("recovery-key", "KEY", "Printed recovery key"),
+                    ],
+                    ..command("recover", "Set a new passphrase using the recovery key")
+                },
+                Command {
+                    options: &[opt("store", "PATH", "Key store file")],
+                    ..command(
+                        "rotate",
+                        "Rotate the master key and re-wrap server data keys",
+                    )
+                },
+                Command {
+                    options: &[opt("device-key", "PATH", "Device key file to create")],
+                    ..command(
+                        "device-keygen",
+                        "Create this device's key pair and print its public key",
+                    )
+                },
+                Command {
+                    options: &[
+                        opt("store", "PATH", "Key store file"),
+                        opt("device", "ID", "Device to share with"),
+                        opt(
+                            "public-key",
+                            "HEX",
+                            "The device's public key (default: from its registration)",
+                        ),
+                    ],
+                    ..command("share", "Send the master key to another device")
+                },
+                Command {
+                    options: &[
+                        opt(
+                            "device",
+                            "ID",
+                            "This device's id (default: the registered identity)",
+                        ),
+                        opt(
+                            "device-key",
+                            "PATH",
+                            "This device's key file (default: the registered identity)",
+                        ),
+                        opt("store", "PATH", "Key store file to create"),
+                    ],
+                    ..command("accept", "Receive the master key shared with this device")
+                },
+            ],
+            ..command("keys", "Manage encryption keys")
+        },
+        Command {
+            args: "<SHELL>",
+            choices: &["bash", "zsh", "fish"],
+            ..command(
+                "completions",
+                "Print a completion script for bash, zsh or fish",
+            )
+        },
+    ],
+    ..command(BIN, "")
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    Run(Invocation),
+    Help(String),
+    Version,
+}
+
+/// A parsed command line: the command path and its arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Invocation {
+    pub command: Vec<&'static str>,
+    pub positionals: Vec<String>,
+    /// Option values in command-line order; repeated options keep every value.
+    pub values: Vec<(&'static str, String)>,
+    pub switches: Vec<&'static str>,
+}
+
+impl Invocation {
+    /// The command path joined with spaces, e.g. `keys init`.
+    pub fn name(&self) -> String {
+        self.command.join(" ")
+    }
+
+    /// Last value given for `name`.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn required(&self, name: &str) -> Result<&str, String> {
+        self.value(name).ok_or_else(|| {
+            let metavar = find_command(&self.command)
+                .options
+                .iter()
+                .chain(GLOBAL_OPTIONS)
+                .find(|option| option.name == name)
+                .and_then(|option| option.value)
+                .unwrap_or("VALUE");
+            format!("`{BIN} {}` requires --{name} <{metavar}>", self.name())
+        })
+    }
+
+    pub fn switch(&self, name: &str) -> bool {
+        self.switches.contains(&name)
+    }
+}
+
+/// Parses the arguments after the program name. Options may appear anywhere after the
+/// command they belong to; global options may appear anywhere.
+pub fn parse(args: &[String]) -> Result<Parsed, String> {
+    let mut current = &ROOT;
+    let mut invocation = Invocation::default();
+    let mut help = false;
+    let mut tokens = args.iter();
+
+    while let Some(token) = tokens.next() {
+        if token == "--" {
+            invocation.positionals.extend(tokens.by_ref().cloned());
+            break;
+        }
+        let option = if let Some(long) = token.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let option = lookup(current, |option| option.name == name)
+                .ok_or_else(|| unknown(&format!("option --{name}"), &invocation))?;
+            Some((option, inline))
+        } else if let Some(short) = token.strip_prefix('-').filter(|short| !short.is_empty()) {
+            let mut chars = short.chars();
+            let (Some(short), None) = (chars.next(), chars.next()) else {
+                // Bundles like `-vh` are not supported; never mistake them for a path.
+                return Err(unknown(&format!("option {token}"), &invocation));
+            };
+            let option = lookup(current, |option| option.short == Some(short))
+                .ok_or_else(|| unknown(&format!("option {token}"), &invocation))?;
+            Some((option, None))
+        } else {
+            None
+        };
+
+        match option {
+            Some((option, inline)) => match option.value {
+                Some(metavar) => {
+                    let missing = || format!("--{} requires a value <{metavar}>", option.name);
+                    let value = match inline {
+                        Some(value) => value,
+                        // `--server --json` is a forgotten value, not a server named `--json`;
+                        // such values can still be given as `--server=--json`.
+                        None => tokens
+                            .next()
+                            .filter(|value| !value.starts_with("--"))
+                            .cloned()
+                            .ok_or_else(missing)?,
+                    };
+                    invocation.values.push((option.name, value));
+                }
+                None if inline.is_some() => {
+                    return Err(format!("--{} does not take a value", option.name));
+                }
+                None if option.name == "help" => help = true,
+                None if option.name == "version" => return Ok(Parsed::Version),
+                None => invocation.switches.push(option.name),
+            },
+            None if !current.subcommands.is_empty() => {
+                current = current
+                    .subcommands
+                    .iter()
+                    .find(|command| command.name == token)
+                    .ok_or_else(|| unknown(&format!("command `{token}`"), &invocation))?;
+                invocation.command.push(current.name);
+            }
+            None => invocation.positionals.push(token.clone()),
+        }
+    }
+
+    if help || (invocation.command.is_empty() && args.is_empty()) {
+        return Ok(Parsed::Help(help_text(&invocation.command)));
+    }
+    if !current.subcommands.is_empty() {
+        return Err(format!(
+            "`{}` needs a command; run `{} --help` to list them",
+            full_name(&invocation.command),
+            full_name(&invocation.command)
+        ));
+    }
+    let (min, max) = arity(current.args);
+    if let Some(extra) = max.and_then(|max| invocation.positionals.get(max)) {
+        return Err(format!(
+            "unexpected argument `{extra}` for `{}`",
+            full_name(&invocation.command)
+        ));
+    }
+    if invocation.positionals.len() < min {
+        return Err(format!(
+            "`{}` needs {}",
+            full_name(&invocation.command),
+            current.args
+        ));
+    }
+    Ok(Parsed::Run(invocation))
+}
+
+/// Fewest and most positional arguments a usage string such as `<FROM> <TO>` or
+/// `[ROOT]...` allows; `None` when a trailing `...` makes it unbounded.
+fn arity(args: &str) -> (usize, Option<usize>) {
+    let words = args.split_whitespace().collect::<Vec<_>>();
+    let min = words.iter().filter(|word| word.starts_with('<')).count();
+    let unbounded = words.last().is_some_and(|word| word.ends_with("..."));
+    (min, (!unbounded).then_some(words.len()))
+}
+
+/// Whether `--json` was given, judged from the raw arguments so that errors from parsing
+/// the command line itself can be reported as JSON too.
+pub fn wants_json(args: &[String]) -> bool {
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--json")
+}
+
+fn lookup(command: &'static Command, matches: impl Fn(&Opt) -> bool) -> Option<&'static Opt> {
+    command
+        .options
+        .iter()
+        .chain(GLOBAL_OPTIONS)
+        .find(|option| matches(option))
+}
+
+fn unknown(what: &str, invocation: &Invocation) -> String {
+    let name = full_name(&invocation.command);
+    format!("unknown {what} for `{name}`; run `{name} --help` for usage")
+}
+
+fn full_name(command: &[&str]) -> String {
+    std::iter::once(BIN)
+        .chain(command.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn find_command(path: &[&str]) -> &'static Command {
+    path.iter().fold(&ROOT, |command, name| {
+        command
+            .subcommands
+            .iter()
+            .find(|sub| sub.name == *name)
+            .unwrap_or(command)
+    })
+}
+
+/// Help for the command at `path` (empty for the top level).
+pub fn help_text(path: &[&str]) -> String {
+    let command = find_command(path);
+    let mut text = String::new();
+    if !command.about.is_empty() {
+        text.push_str(&format!("{}\n\n", command.about));
+    }
+    let mut usage = full_name(path);
+    if !command.subcommands.is_empty() {
+        usage.push_str(" <COMMAND>");
+    }
+    if !command.options.is_empty() || path.is_empty() {
+        usage.push_str(" [OPTIONS]");
+    }
+    if !command.args.is_empty() {
+        usage.push_str(&format!(" {}", command.args));
+    }
+    text.push_str(&format!("Usage: {usage}\n"));
+
+    if !command.subcommands.is_empty() {
+        text.push_str("\nCommands:\n");
+        let rows = command
+            .subcommands
+            .iter()
+            .map(|sub| (sub.name.to_string(), sub.about))
+            .collect::<Vec<_>>();
+        push_rows(&mut text, &rows);
+    }
+    if !command.options.is_empty() {
+        text.push_str("\nOptions:\n");
+        push_rows(&mut text, &option_rows(command.options));
+    }
+    text.push_str("\nGlobal options:\n");
+    push_rows(&mut text, &option_rows(GLOBAL_OPTIONS));
+    text
+}
+
+fn option_rows(options: &[Opt]) -> Vec<(String, &'static str)> {
+    options
+        .iter()
+        .map(|option| {
+            let short = option
+                .short
+                .map_or("    ".to_string(), |short| format!("-{short}, "));
+            let value = option
+                .value
+                .map_or(String::new(), |value| format!(" <{value}>"));
+            (format!("{short}--{}{value}", option.name), option.about)
+        })
+        .collect()
+}
+
+fn push_rows(text: &mut String, rows: &[(String, &str)]) {
+    let width = rows.iter().map(|(left, _)| left.len()).max().unwrap_or(0);
+    for (left, about) in rows {
+        text.push_str(&format!("  {left:width$}  {about}\n"));
+    }
+}
+
+/// Every command with its path, parents before children.
+fn command_paths() -> Vec<(Vec<&'static str>, &'static Command)> {
+    fn visit(
+        path: Vec<&'static str>,
+        command: &'static Command,
+        found: &mut Vec<(Vec<&'static str>, &'static Command)>,
+    ) {
+        found.push((path.clone(), command));
+        for sub in command.subcommands {
+            let mut child = path.clone();
+            child.push(sub.name);
+            visit(child, sub, found);
+        }
+    }
+    let mut found = Vec::new();
+    visit(Vec::new(), &ROOT, &mut found);
+    found
+}
+
+/// Words offered after the command at `path`: subcommands, fixed values and options.
+fn completion_words(command: &Command) -> Vec<String> {
+    command
+        .subcommands
+        .iter()
+        .map(|sub| sub.name.to_string())
+        .chain(command.choices.iter().map(|choice| choice.to_string()))
+        .chain(
+            command
+                .options
+                .iter()
+                .chain(GLOBAL_OPTIONS)
+                .map(|option| format!("--{}", option.name)),
+        )
+        .collect()
+}
+
+/// Completion script for `shell` (`bash`, `zsh` or `fish`).
+pub fn completions(shell: &str) -> Result<String, String> {
+    let commands = command_paths();
+    match shell {
+        "bash" | "zsh" => {
+            // Both shells walk the words typed so far to find the current command, then
+            // offer that command's words.
+            let transitions = commands
+                .iter()
+                .filter_map(|(path, _)| {
+                    let (last, parent) = path.split_last()?;
+                    Some(format!("{}/{last}", parent.join(" ")))
+                })
+                .collect::<Vec<_>>()
+                .join("|");
+            let mut cases = String::new();
+            for (path, command) in &commands {
+                let words = completion_words(command).join(" ");
+                if shell == "bash" {
+                    cases.push_str(&format!(
+                        "        \"{}\") opts=\"{words}\" ;;\n",
+                        path.join(" ")
+                    ));
+                } else {
+                    cases.push_str(&format!(
+                        "        \"{}\") opts=({words}) ;;\n",
+                        path.join(" ")
+                    ));
+                }
+            }
+            Ok(if shell == "bash" {
+                format!(
+                    "_rust_client() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" cmd=\"\" word opts=\"\"\n    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do\n        case \"$cmd/$word\" in\n            {transitions}) cmd=\"${{cmd:+$cmd }}$word\" ;;\n        esac\n    done\n    case \"$cmd\" in\n{cases}    esac\n    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n}}\ncomplete -F _rust_client {BIN}\n"
+                )
+            } else {
+                format!(
+                    "#compdef {BIN}\n\n_rust_client() {{\n    local cmd=\"\" word\n    local -a opts\n    for word in \"${{(@)words[2,CURRENT-1]}}\"; do\n        case \"$cmd/$word\" in\n            {transitions}) cmd=\"${{cmd:+$cmd }}$word\" ;;\n        esac\n    done\n    case \"$cmd\" in\n{cases}    esac\n    compadd -a opts\n}}\n\n_rust_client \"$@\"\n"
+                )
+            })
+        }
+        "fish" => {
+            let mut script = format!("complete -c {BIN} -f\n");
+            for option in GLOBAL_OPTIONS {
+                script.push_str(&fish_option(None, option));
+            }
+            for (path, command) in &commands {
+                let condition = fish_condition(path, command);
+                for sub in command.subcommands {
+                    script.push_str(&format!(
+                        "complete -c {BIN} -n '{condition}' -a {} -d '{}'\n",
+                        sub.name,
+                        fish_escape(sub.about)
+                    ));
+                }
+                if !command.choices.is_empty() {
+                    script.push_str(&format!(
+                        "complete -c {BIN} -n '{condition}' -a '{}'\n",
+                        command.choices.join(" ")
+                    ));
+                }
+                for option in command.options {
+                    script.push_str(&fish_option(Some(&condition), option));
+                }
+            }
+            Ok(script)
+        }
+        _ => Err(format!(
+            "unsupported shell `{shell}`; expected bash, zsh or fish"
+        )),
+    }
+}
+
+fn fish_condition(path: &[&str], command: &Command) -> String {
+    let mut parts = path
+        .iter()
+        .map(|name| format!("__fish_seen_subcommand_from {name}"))
+        .collect::<Vec<_>>();
+    if path.is_empty() {
+        parts.push("__fish_use_subcommand".to_string());
+    } else if !command.subcommands.is_empty() {
+        let names = command
+            .subcommands
+            .iter()
+            .map(|sub| sub.name)
+            .collect::<Vec<_>>();
+        parts.push(format!(
+            "not __fish_seen_subcommand_from {}",
+            names.join(" ")
+        ));
+    }
+    parts.join("; and ")
+}
+
+fn fish_option(condition: Option<&str>, option: &Opt) -> String {
+    let mut line = format!("complete -c {BIN}");
+    if let Some(condition) = condition {
+        line.push_str(&format!(" -n '{condition}'"));
+    }
+    line.push_str(&format!(" -l {}", option.name));
+    if let Some(short) = option.short {
+        line.push_str(&format!(" -s {short}"));
+    }
+    if option.value.is_some() {
+        line.push_str(" -r");
+    }
+    line.push_str(&format!(" -d '{}'\n", fish_escape(option.about)));
+    line
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(args: &[&str]) -> Result<Parsed, String> {
+        parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    fn invocation(args: &[&str]) -> Invocation {
+        match run(args).expect("arguments should parse") {
+            Parsed::Run(invocation) => invocation,
+            other => panic!("expected a command, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_options_in_any_order_and_globals_anywhere() {
+        let expected = invocation(&[
+            "sync",
+            "--server",
+            "http://h:1",
+            "--retries",
+            "5",
+            "a.txt",
+            "--dry-run",
+        ]);
+        let reordered = invocation(&[
+            "--server",
+            "http://h:1",
+            "sync",
+            "--dry-run",
+            "a.txt",
+            "--retries=5",
+        ]);
+        assert_eq!(reordered.command, vec!["sync"]);
+        assert_eq!(reordered.positionals, vec!["a.txt"]);
+        assert!(reordered.switch("dry-run"));
+        for name in ["server", "retries"] {
+            assert_eq!(expected.value(name), reordered.value(name), "--{name}");
+        }
+
+        let keys = invocation(&["keys", "-v", "init", "--store", "/tmp/k", "--json"]);
+        assert_eq!(keys.name(), "keys init");
+        assert!(keys.switch("verbose") && keys.switch("json"));
+        assert_eq!(keys.required("store"), Ok("/tmp/k"));
+        assert_eq!(
+            invocation(&["keys", "rotate"]).required("store"),
+            Err("`rust-client keys rotate` requires --store <PATH>".to_string())
+        );
+
+        let daemon = invocation(&["daemon", "--root", "/a", "--root", "/b"]);
+        assert_eq!(
+            daemon.values,
+            vec![("root", "/a".to_string()), ("root", "/b".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_input_and_answers_help_and_version() {
+        assert!(run(&["sync", "--store", "x"])
+            .unwrap_err()
+            .contains("unknown option --store for `rust-client sync`"));
+        assert!(run(&["frobnicate"])
+            .unwrap_err()
+            .contains("unknown command `frobnicate`"));
+        assert!(run(&["keys"]).unwrap_err().contains("needs a command"));
+        assert!(run(&["health", "extra"])
+            .unwrap_err()
+            .contains("unexpected argument `extra`"));
+        assert!(run(&["sync", "--retries"])
+            .unwrap_err()
+            .contains("requires a value"));
+        assert!(run(&["sync", "--dry-run=yes"])
+            .unwrap_err()
+            .contains("does not take a value"));
+        assert_eq!(run(&["health", "--version"]), Ok(Parsed::Version));
+        assert!(run(&["sync", "-vx", "a"])
+            .unwrap_err()
+            .contains("unknown option -vx"));
+        assert!(run(&["sync", "a", "--retries", "--json"])
+            .unwrap_err()
+            .contains("--retries requires a value"));
+        assert_eq!(
+            invocation(&["sync", "--server=--odd", "a"]).value("server"),
+            Some("--odd")
+        );
+        assert!(run(&["sync"]).unwrap_err().contains("needs <PATH>..."));
+        assert!(run(&["snapshots", "diff", "a"])
+            .unwrap_err()
+            .contains("needs <FROM> <TO>"));
+        assert!(run(&["snapshots", "diff", "a", "b", "c"])
+            .unwrap_err()
+            .contains("unexpected argument `c`"));
+        assert_eq!(invocation(&["sync", "--", "-dash"]).positionals, ["-dash"]);
+        let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert!(wants_json(&args(&["frobnicate", "--json"])));
+        assert!(!wants_json(&args(&["sync", "--", "--json"])));
+
+        let Ok(Parsed::Help(help)) = run(&["keys", "share", "--help"]) else {
+            panic!("expected help");
+        };
+        assert!(help.starts_with(
+            "Send the master key to another device\n\nUsage: rust-client keys share [OPTIONS]\n"
+        ));
+        assert!(help.contains("--public-key <HEX>") && help.contains("-v, --verbose"));
+        assert!(matches!(run(&[]), Ok(Parsed::Help(text)) if text.contains("completions")));
+    }
+
+    #[test]
+    fn completions_cover_every_command_and_option() {
+        for shell in ["bash", "zsh", "fish"] {
+            let script = completions(shell).expect("shell should be supported");
+            for (path, command) in command_paths() {
+                if let Some(name) = path.last() {
+                    assert!(script.contains(name), "{shell} misses command {name}");
+                }
+                for option in command.options {
+                    assert!(
+                        script.contains(option.name),
+                        "{shell} misses --{}",
+                        option.name
+                    );
+                }
+            }
+        }
+        assert!(completions("bash")
+            .expect("bash")
+            .contains("/keys|keys/init|"));
+        assert!(completions("fish")
+            .expect("fish")
+            .contains("-n '__fish_seen_subcommand_from keys; and not __fish_seen_subcommand_from init recover"));
+        assert!(completions("tcsh").is_err());
+    }
+}
@@ -0,0 +1,1382 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{
+    parse_rate, BandwidthSchedule, CompressionOptions, ContentOptions, HashBudget, IgnoreRules,
+    KeyStore, LogLevel, MasterKey, PathCipher, ScheduleRule, SelectiveSync, SyncClient, SyncError,
+    MAX_CHUNK_SIZE,
+};
+
+/// Environment variable holding the key store passphrase when no passphrase file is set.
+pub const PASSPHRASE_ENV: &str = "RUST_CLIENT_PASSPHRASE";
+
+/// Client settings, read from a TOML file and layered with environment and command-line
+/// overrides. See the README for the full schema; a minimal file is:
+///
+/// ```toml
+/// server = "http://127.0.0.1:8080"
+/// roots = ["/home/me/Documents"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub server: String,
+    /// Sent as a bearer token with every request, so each profile signs in as its own account.
+    pub token: Option<String>,
+    pub roots: Vec<PathBuf>,
+    /// Applied to every root, relative to that root.
+    pub ignore: IgnoreRules,
+    /// Queue, hash cache, watcher state and the lock file live here.
+    pub state_dir: PathBuf,
+    pub log_level: LogLevel,
+    pub flush_interval: Duration,
+    pub scan_interval: Duration,
+    /// Also download other devices' changes, keeping both copies on conflict. Off by default.
+    pub bidirectional: bool,
+    /// Subtrees this device keeps off its disk, and subtrees inside them it keeps after all.
+    pub selective: SelectiveSync,
+    pub retry: RetryPolicy,
+    pub bandwidth: BandwidthSchedule,
+    pub content: ContentSettings,
+    pub encryption: EncryptionSettings,
+    pub hashing: HashingSettings,
+    pub restore: RestoreSettings,
+}
+
+/// Whether file content is uploaded alongside hashes. Without it the server only learns
+/// hashes and metadata, and nothing can be restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentSettings {
+    pub upload: bool,
+    pub chunk_size: usize,
+    pub compress: bool,
+}
+
+impl Default for ContentSettings {
+    fn default() -> Self {
+        Self {
+            upload: false,
+            chunk_size: ContentOptions::default().chunk_size,
+            compress: false,
+        }
+    }
+}
+
+impl ContentSettings {
+    /// Manager options for these settings, or `None` when content upload is off. Chunks are
+    /// encrypted under `master` when one is given.
+    pub fn options(&self, master: Option<&MasterKey>) -> Option<ContentOptions> {
+        self.upload.then(|| ContentOptions {
+            chunk_size: self.chunk_size,
+            encryption: master.cloned(),
+            compression: self.compress.then(CompressionOptions::default),
+        })
+    }
+}
+
+/// Client-side encryption of uploaded content and of paths and metadata under the master
+/// key in a key store (see `rust-client keys init`). Off by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncryptionSettings {
+    pub content: bool,
+    /// Paths are encrypted per root and metadata, xattrs included, is sealed.
+    pub paths: bool,
+    /// Defaults to `keystore` in the state directory.
+    pub key_store: Option<PathBuf>,
+    /// First line is the passphrase. Without it the passphrase comes from
+    /// `RUST_CLIENT_PASSPHRASE`, or a prompt where one is possible.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+impl EncryptionSettings {
+    pub fn enabled(&self) -> bool {
+        self.content || self.paths
+    }
+}
+
+/// How scans hash files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashingSettings {
+    /// Rehash every file on every scan instead of trusting the hash cache, for
+    /// filesystems whose timestamps cannot be relied on.
+    pub paranoid: bool,
+    /// Hashing threads for directory scans; `None` keeps [`HashBudget`]'s default.
+    pub workers: Option<usize>,
+    /// Combined read rate of those threads in bytes/sec; `None` is unlimited.
+    pub read_rate: Option<u64>,
+}
+
+/// Hashing thread counts accepted in `[hashing] workers` and `--hash-workers`.
+const HASH_WORKERS: std::ops::RangeInclusive<i64> = 1..=256;
+
+impl HashingSettings {
+    /// Applies `--hash-workers` or `--hash-rate`, replacing the file's value.
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), SyncError> {
+        match setting {
+            "hash-workers" => {
+                self.workers = Some(match value.parse::<i64>() {
+                    Ok(workers) if HASH_WORKERS.contains(&workers) => workers as usize,
+                    _ => {
+                        return Err(SyncError::InvalidConfig(format!(
+                            "expected 1 to 256 workers, got `{value}`"
+                        )))
+                    }
+                })
+            }
+            "hash-rate" => self.read_rate = parse_rate(value)?,
+            _ => {
+                return Err(SyncError::InvalidConfig(format!(
+                    "unknown setting `{setting}`"
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// The budget directory scans hash under.
+    pub fn budget(&self) -> HashBudget {
+        let mut budget = HashBudget::default();
+        if let Some(workers) = self.workers {
+            budget.workers = workers;
+            budget.channel_capacity = workers * 4;
+        }
+        budget.max_read_bytes_per_sec = self.read_rate;
+        budget
+    }
+}
+
+/// How bulk restores are packaged. Parts default to just under 4GB so each fits on a
+/// FAT32 stick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreSettings {
+    pub part_size: u64,
+}
+
+impl Default for RestoreSettings {
+    fn default() -> Self {
+        Self {
+            part_size: 4_000_000_000,
+        }
+    }
+}
+
+/// Archive part sizes accepted in `[restore] part_size`.
+pub(crate) const PART_SIZES: std::ops::RangeInclusive<u64> = 1 << 20..=1 << 40;
+
+/// Chunk sizes accepted in `[content] chunk_size`.
+const CHUNK_SIZES: std::ops::RangeInclusive<u64> = 4096..=MAX_CHUNK_SIZE as u64;
+
+/// Delay between flushes while the server keeps failing: starts at `initial_backoff`
+/// and doubles up to `max_backoff`. A request that fails `max_attempts` sends in a row
+/// becomes a dead letter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(300),
+            max_attempts: 10,
+        }
+    }
+}
+
+/// Settings that replace the file's values. Environment variables are layered over the
+/// file and command-line flags over both.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigOverrides {
+    pub server: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub roots: Option<Vec<PathBuf>>,
+    pub log_level: Option<LogLevel>,
+    pub bandwidth_limit: Option<Option<u64>>,
+}
+
+const ENV_OVERRIDES: [(&str, &str); 5] = [
+    ("RUST_CLIENT_SERVER", "server"),
+    ("RUST_CLIENT_STATE_DIR", "state-dir"),
+    ("RUST_CLIENT_ROOTS", "roots"),
+    ("RUST_CLIENT_LOG_LEVEL", "log-level"),
+    ("RUST_CLIENT_BANDWIDTH_LIMIT", "bandwidth-limit"),
+];
+
+impl ConfigOverrides {
+    /// Reads `RUST_CLIENT_SERVER`, `RUST_CLIENT_STATE_DIR`, `RUST_CLIENT_ROOTS`
+    /// (`PATH`-style list), `RUST_CLIENT_LOG_LEVEL` and `RUST_CLIENT_BANDWIDTH_LIMIT`.
+    pub fn from_env() -> Result<Self, SyncError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, SyncError> {
+        let mut overrides = Self::default();
+        for (variable, setting) in ENV_OVERRIDES {
+            let Some(value) = lookup(variable) else {
+                continue;
+            };
+            if setting == "roots" {
+                overrides.roots = Some(std::env::split_paths(&value).collect());
+                continue;
+            }
+            overrides
+                .set(setting, &value)
+                .map_err(|err| SyncError::InvalidConfig(format!("{variable}: {}", message(err))))?;
+        }
+        Ok(overrides)
+    }
+
+    /// Applies one override by its flag name (`server`, `state-dir`, `root`, `log-level`,
+    /// `bandwidth-limit`). `root` may be given several times and replaces the file's roots.
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), SyncError> {
+        match setting {
+            "server" => {
+                validate_server(value)?;
+                self.server = Some(value.to_string());
+            }
+            "state-dir" => self.state_dir = Some(PathBuf::from(value)),
+            "root" => self
+                .roots
+                .get_or_insert_with(Vec::new)
+                .push(PathBuf::from(value)),
+            "log-level" => self.log_level = Some(LogLevel::parse(value)?),
+            "bandwidth-limit" => self.bandwidth_limit = Some(parse_rate(value)?),
+            _ => {
+                return Err(SyncError::InvalidConfig(format!(
+                    "unknown setting `{setting}`"
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns these overrides with `lower` filling in whatever they leave unset.
+    pub fn layered_over(self, lower: ConfigOverrides) -> Self {
+        Self {
+            server: self.server.or(lower.server),
+            state_dir: self.state_dir.or(lower.state_dir),
+            roots: self.roots.or(lower.roots),
+            log_level: self.log_level.or(lower.log_level),
+            bandwidth_limit: self.bandwidth_limit.or(lower.bandwidth_limit),
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        Self::load_with(path, &ConfigOverrides::default())
+    }
+
+    /// Reads the file at `path` and applies `overrides`. Errors name the file and line.
+    pub fn load_with<P: AsRef<Path>>(
+        path: P,
+        overrides: &ConfigOverrides,
+    ) -> Result<Self, SyncError> {
+        Self::load_in(path.as_ref(), overrides, None)
+    }
+
+    /// Like [`Config::load_with`], with `state_dir` as the state directory when neither the
+    /// file nor `overrides` name one.
+    pub(crate) fn load_in(
+        path: &Path,
+        overrides: &ConfigOverrides,
+        state_dir: Option<&Path>,
+    ) -> Result<Self, SyncError> {
+        let text = fs::read_to_string(path).map_err(|err| {
+            SyncError::InvalidConfig(format!("cannot read {}: {err}", path.display()))
+        })?;
+        Self::parse_in(&text, overrides, state_dir).map_err(|err| {
+            SyncError::InvalidConfig(format!("{}: {}", path.display(), message(err)))
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SyncError> {
+        Self::parse_with(text, &ConfigOverrides::default())
+    }
+
+    pub fn parse_with(text: &str, overrides: &ConfigOverrides) -> Result<Self, SyncError> {
+        Self::parse_in(text, overrides, None)
+    }
+
+    pub(crate) fn parse_in(
+        text: &str,
+        overrides: &ConfigOverrides,
+        default_state: Option<&Path>,
+    ) -> Result<Self, SyncError> {
+        let document = parse_document(text)?;
+        let mut server = None;
+        let mut token = None;
+        let mut roots = Vec::new();
+        let mut ignore = IgnoreRules::default();
+        let mut state_dir = None;
+        let mut log_level = LogLevel::default();
+        let mut flush_interval = Duration::from_secs(30);
+        let mut scan_interval = Duration::from_secs(10);
+        let mut bidirectional_line = None;
+        let mut included: Vec<(PathBuf, usize)> = Vec::new();
+        let mut excluded: Vec<(PathBuf, usize)> = Vec::new();
+        let mut retry = RetryPolicy::default();
+        let mut max_backoff_line = None;
+        let mut bandwidth = BandwidthSchedule::default();
+        let mut content = ContentSettings::default();
+        let mut encryption = EncryptionSettings::default();
+        let mut encryption_line = None;
+        let mut hashing = HashingSettings::default();
+        let mut restore = RestoreSettings::default();
+
+        for entry in document {
+            let line = entry.line;
+            let at =
+                |err: SyncError| SyncError::InvalidConfig(format!("line {line}: {}", message(err)));
+            let name = entry.name();
+            match (entry.table.as_str(), entry.key.as_str()) {
+                ("", "server") => {
+                    let value = entry.value.into_string(&name).map_err(at)?;
+                    validate_server(&value).map_err(at)?;
+                    server = Some(value);
+                }
+                ("", "token") => {
+                    let value = entry.value.into_string(&name).map_err(at)?;
+                    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_graphic()) {
+                        return Err(at(SyncError::InvalidConfig(
+                            "`token` must be printable ASCII without spaces".to_string(),
+                        )));
+                    }
+                    token = Some(value);
+                }
+                ("", "roots") => {
+                    roots = Vec::new();
+                    for root in entry.value.into_strings(&name).map_err(at)? {
+                        let root = PathBuf::from(root);
+                        if !root.is_absolute() {
+                            return Err(at(SyncError::InvalidConfig(format!(
+                                "root `{}` must be an absolute path",
+                                root.display()
+                            ))));
+                        }
+                        roots.push(root);
+                    }
+                }
+                ("", "ignore") => {
+                    ignore = IgnoreRules::new(&entry.value.into_strings(&name).map_err(at)?)
+                        .map_err(at)?
+                }
+                ("", "state_dir") => {
+                    state_dir = Some(PathBuf::from(entry.value.into_string(&name).map_err(at)?))
+                }
+                ("", "log_level") => {
+                    log_level =
+                        LogLevel::parse(&entry.value.into_string(&name).map_err(at)?).map_err(at)?
+                }
+                ("sync", "flush_interval_secs") => {
+                    flush_interval = entry.value.into_secs(&name).map_err(at)?
+                }
+                ("sync", "scan_interval_secs") => {
+                    scan_interval = entry.value.into_secs(&name).map_err(at)?
+                }
+                ("sync", "bidirectional") => {
+                    if entry.value.into_bool(&name).map_err(at)? {
+                        bidirectional_line = Some(line);
+                    }
+                }
+                ("selective_sync", kind @ ("include" | "exclude")) => {
+                    let mut paths = Vec::new();
+                    for path in entry.value.into_strings(&name).map_err(at)? {
+                        let path = PathBuf::from(path);
+                        if !path.is_absolute() {
+                            return Err(at(SyncError::InvalidConfig(format!(
+                                "`{name}` path `{}` must be absolute",
+                                path.display()
+                            ))));
+                        }
+                        paths.push((path, line));
+                    }
+                    if kind == "include" {
+                        included = paths;
+                    } else {
+                        excluded = paths;
+                    }
+                }
+                ("retry", "initial_backoff_secs") => {
+                    retry.initial_backoff = entry.value.into_secs(&name).map_err(at)?
+                }
+                ("retry", "max_backoff_secs") => {
+                    retry.max_backoff = entry.value.into_secs(&name).map_err(at)?;
+                    max_backoff_line = Some(line);
+                }
+                ("retry", "max_attempts") => {
+                    retry.max_attempts = match entry.value {
+                        Value::Integer(attempts)
+                            if (1..=i64::from(u32::MAX)).contains(&attempts) =>
+                        {
+                            attempts as u32
+                        }
+                        other => return Err(at(expected(&name, "a positive integer", &other))),
+                    }
+                }
+                ("bandwidth", "limit") => {
+                    bandwidth.default_limit = match entry.value {
+                        Value::Integer(bytes) if bytes > 0 => Some(bytes as u64),
+                        value => parse_rate(&value.into_string(&name).map_err(at)?).map_err(at)?,
+                    }
+                }
+                ("bandwidth", "schedule") => {
+                    bandwidth.rules = entry
+                        .value
+                        .into_strings(&name)
+                        .map_err(at)?
+                        .iter()
+                        .map(|rule| ScheduleRule::parse(rule))
+                        .collect::<Result<_, _>>()
+                        .map_err(at)?
+                }
+                ("content", "upload") => {
+                    content.upload = entry.value.into_bool(&name).map_err(at)?
+                }
+                ("content", "compress") => {
+                    content.compress = entry.value.into_bool(&name).map_err(at)?
+                }
+                ("content", "chunk_size") => {
+                    let size = match entry.value {
+                        Value::Integer(bytes) if bytes > 0 => Some(bytes as u64),
+                        value => parse_rate(&value.into_string(&name).map_err(at)?).map_err(at)?,
+                    };
+                    content.chunk_size = match size {
+                        Some(size) if CHUNK_SIZES.contains(&size) => size as usize,
+                        _ => {
+                            return Err(at(SyncError::InvalidConfig(format!(
+                                "`{name}` must be between 4KiB and 64MiB"
+                            ))))
+                        }
+                    }
+                }
+                ("encryption", "content") => {
+                    encryption.content = entry.value.into_bool(&name).map_err(at)?;
+                    encryption_line = Some(line);
+                }
+                ("encryption", "paths") => {
+                    encryption.paths = entry.value.into_bool(&name).map_err(at)?
+                }
+                ("encryption", key @ ("key_store" | "passphrase_file")) => {
+                    let path = PathBuf::from(entry.value.into_string(&name).map_err(at)?);
+                    if !path.is_absolute() {
+                        return Err(at(SyncError::InvalidConfig(format!(
+                            "`{name}` path `{}` must be absolute",
+                            path.display()
+                        ))));
+                    }
+                    if key == "key_store" {
+                        encryption.key_store = Some(path);
+                    } else {
+                        encryption.passphrase_file = Some(path);
+                    }
+                }
+                ("hashing", "paranoid") => {
+                    hashing.paranoid = entry.value.into_bool(&name).map_err(at)?
+                }
+                ("hashing", "workers") => {
+                    hashing.workers = match entry.value {
+                        Value::Integer(workers) if HASH_WORKERS.contains(&workers) => {
+                            Some(workers as usize)
+                        }
+                        _ => {
+                            return Err(at(SyncError::InvalidConfig(format!(
+                                "`{name}` must be between 1 and 256"
+                            ))))
+                        }
+                    }
+                }
+                ("hashing", "read_rate") => {
+                    hashing.read_rate = match entry.value {
+                        Value::Integer(bytes) if bytes > 0 => Some(bytes as u64),
+                        value => parse_rate(&value.into_string(&name).map_err(at)?).map_err(at)?,
+                    }
+                }
+                ("restore", "part_size") => {
+                    let size = match entry.value {
+                        Value::Integer(bytes) if bytes > 0 => Some(bytes as u64),
+                        value => parse_rate(&value.into_string(&name).map_err(at)?).map_err(at)?,
+                    };
+                    restore.part_size = match size {
+                        Some(size) if PART_SIZES.contains(&size) => size,
+                        _ => {
+                            return Err(at(SyncError::InvalidConfig(format!(
+                                "`{name}` must be between 1MiB and 1024GiB"
+                            ))))
+                        }
+                    }
+                }
+                _ => {
+                    return Err(at(SyncError::InvalidConfig(format!(
+                        "unknown setting `{name}`"
+                    ))))
+                }
+            }
+        }
+
+        if retry.initial_backoff > retry.max_backoff {
+            let message =
+                "`retry.max_backoff_secs` must not be less than `retry.initial_backoff_secs`";
+            return Err(SyncError::InvalidConfig(match max_backoff_line {
+                Some(line) => format!("line {line}: {message}"),
+                None => message.to_string(),
+            }));
+        }
+
+        if let (Some(line), false) = (bidirectional_line, content.upload) {
+            return Err(SyncError::InvalidConfig(format!(
+                "line {line}: `sync.bidirectional` needs `content.upload = true` to download other devices' files"
+            )));
+        }
+
+        if let (Some(line), true, false) = (encryption_line, encryption.content, content.upload) {
+            return Err(SyncError::InvalidConfig(format!(
+                "line {line}: `encryption.content` needs `content.upload = true`"
+            )));
+        }
+
+        let mut selective = SelectiveSync::new();
+        for (path, line) in &excluded {
+            if included.iter().any(|(other, _)| other == path) {
+                return Err(SyncError::InvalidConfig(format!(
+                    "line {line}: `{}` is both included and excluded in [selective_sync]",
+                    path.display()
+                )));
+            }
+            if !content.upload {
+                return Err(SyncError::InvalidConfig(format!(
+                    "line {line}: `selective_sync.exclude` needs `content.upload = true` so excluded files can come back"
+                )));
+            }
+            selective = selective.exclude(path);
+        }
+        for (path, _) in &included {
+            selective = selective.include(path);
+        }
+
+        if let Some(limit) = overrides.bandwidth_limit {
+            bandwidth = BandwidthSchedule::fixed(limit);
+        }
+        Ok(Self {
+            server: overrides.server.clone().or(server).ok_or_else(|| {
+                SyncError::InvalidConfig(
+                    "`server` is required (set it in the file, RUST_CLIENT_SERVER or --server)"
+                        .to_string(),
+                )
+            })?,
+            token,
+            roots: overrides.roots.clone().unwrap_or(roots),
+            ignore,
+            state_dir: match overrides.state_dir.clone().or(state_dir) {
+                Some(dir) => dir,
+                None => match default_state {
+                    Some(dir) => dir.to_path_buf(),
+                    None => default_state_dir()?,
+                },
+            },
+            log_level: overrides.log_level.unwrap_or(log_level),
+            flush_interval,
+            scan_interval,
+            bidirectional: bidirectional_line.is_some(),
+            selective,
+            retry,
+            bandwidth,
+            content,
+            encryption,
+            hashing,
+            restore,
+        })
+    }
+
+    /// Where the key store lives: `encryption.key_store`, or `keystore` in the state directory.
+    pub fn key_store_path(&self) -> PathBuf {
+        self.encryption
+            .key_store
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("keystore"))
+    }
+
+    /// One cipher per root when `encryption.paths` is on, with opaque root ids derived from
+    /// the master key; empty otherwise.
+    pub fn path_ciphers(&self, master: Option<&MasterKey>) -> Vec<PathCipher> {
+        match master {
+            Some(master) if self.encryption.paths => self
+                .roots
+                .iter()
+                .map(|root| {
+                    PathCipher::for_root(master, root, &PathCipher::root_id_for(master, root))
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Unlocks the master key when encryption is on. The passphrase is the first line of
+    /// `encryption.passphrase_file`, else `RUST_CLIENT_PASSPHRASE`, else whatever `prompt`
+    /// returns.
+    pub fn master_key(
+        &self,
+        prompt: impl FnOnce() -> Result<String, SyncError>,
+    ) -> Result<Option<MasterKey>, SyncError> {
+        if !self.encryption.enabled() {
+            return Ok(None);
+        }
+        let passphrase = match &self.encryption.passphrase_file {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|err| {
+                    SyncError::InvalidConfig(format!("cannot read {}: {err}", path.display()))
+                })?
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            None => match std::env::var(PASSPHRASE_ENV) {
+                Ok(passphrase) => passphrase,
+                Err(_) => prompt()?,
+            },
+        };
+        if passphrase.is_empty() {
+            return Err(SyncError::InvalidConfig(
+                "the key store passphrase must not be empty".to_string(),
+            ));
+        }
+        let key_store = self.key_store_path();
+        let store = KeyStore::load(&key_store).map_err(|err| match err {
+            SyncError::Io(err) => SyncError::InvalidConfig(format!(
+                "cannot read key store {}: {err} (create it with `rust-client keys init`)",
+                key_store.display()
+            )),
+            other => other,
+        })?;
+        store.unlock(&passphrase).map(Some)
+    }
+}
+
+fn validate_server(value: &str) -> Result<(), SyncError> {
+    SyncClient::new(value).map(|_| ()).map_err(|_| {
+        SyncError::InvalidConfig(format!(
+            "server `{value}` should look like http://host:port"
+        ))
+    })
+}
+
+/// The message inside an `InvalidConfig` error, so context can be prefixed without
+/// repeating "invalid configuration:".
+fn message(err: SyncError) -> String {
+    match err {
+        SyncError::InvalidConfig(message) => message,
+        other => other.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+
+    fn into_string(self, name: &str) -> Result<String, SyncError> {
+        match self {
+            Value::String(value) => Ok(value),
+            other => Err(expected(name, "a string", &other)),
+        }
+    }
+
+    fn into_strings(self, name: &str) -> Result<Vec<String>, SyncError> {
+        match self {
+            Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(value) => Ok(value),
+                    other => Err(expected(name, "an array of strings", &other)),
+                })
+                .collect(),
+            other => Err(expected(name, "an array of strings", &other)),
+        }
+    }
+
+    fn into_bool(self, name: &str) -> Result<bool, SyncError> {
+        match self {
+            Value::Boolean(value) => Ok(value),
+            other => Err(expected(name, "a boolean", &other)),
+        }
+    }
+
+    fn into_secs(self, name: &str) -> Result<Duration, SyncError> {
+        match self {
+            Value::Integer(secs) if secs > 0 => Ok(Duration::from_secs(secs as u64)),
+            other => Err(expected(name, "a positive integer", &other)),
+        }
+    }
+}
+
+fn expected(name: &str, wanted: &str, found: &Value) -> SyncError {
+    let found = match found {
+        Value::Integer(value) => format!("{value}"),
+        other => other.type_name().to_string(),
+    };
+    SyncError::InvalidConfig(format!("`{name}` must be {wanted}, found {found}"))
+}
+
+struct Entry {
+    table: String,
+    key: String,
+    value: Value,
+    line: usize,
+}
+
+impl Entry {
+    fn name(&self) -> String {
+        if self.table.is_empty() {
+            self.key.clone()
+        } else {
+            format!("{}.{}", self.table, self.key)
+        }
+    }
+}
+
+const TABLES: [&str; 8] = [
+    "sync",
+    "selective_sync",
+    "retry",
+    "bandwidth",
+    "content",
+    "encryption",
+    "hashing",
+    "restore",
+];
+
+/// Parses the TOML subset the config uses: `[table]` headers, bare keys, basic and
+/// literal strings, integers, booleans, and arrays that may span lines. Other TOML
+/// (multi-line strings, inline tables, dotted or quoted keys, floats, dates) is rejected
+/// by name rather than misread.
+fn parse_document(text: &str) -> Result<Vec<Entry>, SyncError> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        line: 1,
+    };
+    let mut entries = Vec::new();
+    let mut first_seen: HashMap<String, usize> = HashMap::new();
+    let mut table = String::new();
+
+    loop {
+        parser.skip_blank_lines();
+        let Some(next) = parser.peek() else {
+            break;
+        };
+        let line = parser.line;
+        if next == '[' {
+            parser.position += 1;
+            if parser.peek() == Some('[') {
+                return Err(parser.error("arrays of tables (`[[...]]`) are not supported"));
+            }
+            let name = parser
+                .take_while(|ch| ch != ']' && ch != '\n')
+                .trim()
+                .to_string();
+            if parser.peek() != Some(']') {
+                return Err(parser.error("table header is missing `]`"));
+            }
+            parser.position += 1;
+            parser.expect_line_end()?;
+            if !TABLES.contains(&name.as_str()) {
+                return Err(SyncError::InvalidConfig(format!(
+                    "line {line}: unknown table `[{name}]`; expected one of [sync], [selective_sync], [retry], [bandwidth], [content], [encryption], [hashing], [restore]"
+                )));
+            }
+            if let Some(first) = first_seen.insert(format!("[{name}]"), line) {
+                return Err(SyncError::InvalidConfig(format!(
+                    "line {line}: table `[{name}]` is defined twice (first on line {first})"
+                )));
+            }
+            table = name;
+            continue;
+        }
+
+        let key = parser.take_while(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-');
+        if key.is_empty() {
+            if next == '"' || next == '\'' {
+                return Err(parser.error("quoted keys are not supported; use a bare name"));
+            }
+            return Err(parser.error(&format!("expected a setting name, found `{next}`")));
+        }
+        parser.skip_spaces();
+        if parser.peek() == Some('.') {
+            return Err(parser.error(&format!(
+                "dotted keys are not supported; put `{key}` settings under a [{key}] header"
+            )));
+        }
+        if parser.peek() != Some('=') {
+            return Err(parser.error(&format!("expected `=` after `{key}`")));
+        }
+        parser.position += 1;
+        parser.skip_spaces();
+        let value = parser.parse_value()?;
+        parser.expect_line_end()?;
+
+        let entry = Entry {
+            table: table.clone(),
+            key,
+            value,
+            line,
+        };
+        if let Some(first) = first_seen.insert(entry.name(), line) {
+            return Err(SyncError::InvalidConfig(format!(
+                "line {line}: `{}` is set twice (first on line {first})",
+                entry.name()
+            )));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.position += 1;
+        if ch == '\n' {
+            self.line += 1;
+        }
+        Some(ch)
+    }
+
+    fn error(&self, message: &str) -> SyncError {
+        SyncError::InvalidConfig(format!("line {}: {message}", self.line))
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(ch) = self.peek().filter(|&ch| keep(ch)) {
+            taken.push(ch);
+            self.bump();
+        }
+        taken
+    }
+
+    fn skip_spaces(&mut self) {
+        self.take_while(|ch| ch == ' ' || ch == '\t');
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            self.take_while(|ch| ch != '\n');
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.take_while(char::is_whitespace);
+            if self.peek() != Some('#') {
+                return;
+            }
+            self.skip_comment();
+        }
+    }
+
+    fn expect_line_end(&mut self) -> Result<(), SyncError> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some('\r') if self.chars.get(self.position + 1) == Some(&'\n') => {
+                self.position += 1;
+                self.bump();
+                Ok(())
+            }
+            Some(ch) => Err(self.error(&format!("unexpected `{ch}` after value"))),
+        }
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(offset, ch)| self.chars.get(self.position + offset) == Some(&ch))
+    }
+
+    fn parse_value(&mut self) -> Result<Value, SyncError> {
+        if self.starts_with("\"\"\"") || self.starts_with("'''") {
+            return Err(self.error("multi-line strings are not supported"));
+        }
+        match self.peek() {
+            Some('"') => self.parse_basic_string().map(Value::String),
+            Some('\'') => {
+                self.bump();
+                let value = self.take_while(|ch| ch != '\'' && ch != '\n');
+                if self.bump() != Some('\'') {
+                    return Err(self.error("unterminated string"));
+                }
+                Ok(Value::String(value))
+            }
+            Some('[') => self.parse_array(),
+            Some('{') => Err(self.error("inline tables are not supported; use a [table] header")),
+            Some(ch) if ch.is_ascii_digit() || ch == '-' || ch == '+' => {
+                let digits =
+                    self.take_while(|ch| ch.is_ascii_alphanumeric() || "+-_.:".contains(ch));
+                let unsigned = digits.trim_start_matches(['+', '-']);
+                if unsigned.contains([':', '-']) {
+                    return Err(self.error(&format!(
+                        "`{digits}` looks like a date or time; those are not supported, quote it"
+                    )));
+                }
+                if unsigned.contains('.')
+                    || (!unsigned.starts_with("0x") && unsigned.contains(['e', 'E']))
+                    || ["inf", "nan"].contains(&unsigned)
+                {
+                    return Err(self.error(&format!(
+                        "`{digits}` is a float; only whole numbers are supported"
+                    )));
+                }
+                digits
+                    .replace('_', "")
+                    .parse::<i64>()
+                    .map(Value::Integer)
+                    .map_err(|_| self.error(&format!("`{digits}` is not an integer")))
+            }
+            Some(ch) if ch.is_ascii_alphabetic() => {
+                let word = self.take_while(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    "inf" | "nan" => Err(self.error(&format!(
+                        "`{word}` is a float; only whole numbers are supported"
+                    ))),
+                    _ => Err(self.error(&format!("`{word}` is not a value; strings need quotes"))),
+                }
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, SyncError> {
+        self.bump();
+        let mut value = String::new();
+        loop {
+            let ch = match self.peek() {
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(ch) => ch,
+            };
+            self.bump();
+            match ch {
+                '"' => return Ok(value),
+                '\\' => match self.bump() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(kind @ ('u' | 'U')) => {
+                        let len = if kind == 'u' { 4 } else { 8 };
+                        let hex = self.take_while(|ch| ch.is_ascii_hexdigit());
+                        let ch = Some(&hex)
+                            .filter(|hex| hex.len() >= len)
+                            .and_then(|hex| u32::from_str_radix(&hex[..len], 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| {
+                                self.error(&format!("invalid escape `\\{kind}{hex}`"))
+                            })?;
+                        value.push(ch);
+                        value.push_str(&hex[len..]);
+                    }
+                    other => {
+                        return Err(
+                            self.error(&format!("unsupported escape `\\{}`", other.unwrap_or(' ')))
+                        )
+                    }
+                },
+                ch => value.push(ch),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, SyncError> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_blank_lines();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                Some(ch) => {
+                    return Err(self.error(&format!("expected `,` or `]` in array, found `{ch}`")))
+                }
+                None => return Err(self.error("array is missing `]`")),
+            }
+        }
+    }
+}
+
+fn home_dir() -> Result<PathBuf, SyncError> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| SyncError::InvalidConfig("HOME is not set; pass explicit paths".to_string()))
+}
+
+/// `$XDG_CONFIG_HOME/rust-client/config.toml`, falling back to `~/.config`.
+pub fn default_config_path() -> Result<PathBuf, SyncError> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => home_dir()?.join(".config"),
+    };
+    Ok(base.join("rust-client").join("config.toml"))
+}
+
+/// `$XDG_STATE_HOME/rust-client`, falling back to `~/.local/state`.
+pub fn default_state_dir() -> Result<PathBuf, SyncError> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => home_dir()?.join(".local").join("state"),
+    };
+    Ok(base.join("rust-client"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: &str = r#"
+# Provisioned by fleet tooling
+server = "http://127.0.0.1:8080"
+token = "family-7f3a"
+state_dir = '/var/lib/rust-client'
+log_level = "debug"
+roots = [
+    "/srv/docs",
+    "/srv/photos \"2024\"",   # quoted name
+]
+ignore = ["*.tmp", "node_modules/"]
+
+[sync]
+flush_interval_secs = 5
+scan_interval_secs = 2
+bidirectional = true
+
+[selective_sync]
+exclude = ["/srv/photos \"2024\"/raw"]
+include = ["/srv/photos \"2024\"/raw/best"]
+
+[retry]
+initial_backoff_secs = 10
+max_backoff_secs = 1_200
+max_attempts = 5
+
+[bandwidth]
+limit = "1MB/s"
+schedule = ["01:00-06:00 unlimited"]
+
+[content]
+upload = true
+chunk_size = "4MiB"
+compress = true
+
+[encryption]
+content = true
+paths = true
+key_store = "/var/lib/rust-client/keys"
+passphrase_file = "/etc/rust-client/passphrase"
+
+[hashing]
+paranoid = true
+workers = 2
+read_rate = "20MB/s"
+
+[restore]
+part_size = "25GB"
+"#;
+
+    #[test]
+    fn parses_the_documented_schema() {
+        let config = Config::parse(FULL).expect("config should parse");
+
+        assert_eq!(config.server, "http://127.0.0.1:8080");
+        assert_eq!(config.token.as_deref(), Some("family-7f3a"));
+        assert_eq!(config.state_dir, PathBuf::from("/var/lib/rust-client"));
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(
+            config.roots,
+            vec![
+                PathBuf::from("/srv/docs"),
+                PathBuf::from("/srv/photos \"2024\"")
+            ]
+        );
+        assert!(config.ignore.is_ignored(Path::new("a/b.tmp"), false));
+        assert_eq!(config.flush_interval, Duration::from_secs(5));
+        assert_eq!(config.scan_interval, Duration::from_secs(2));
+        assert!(config.bidirectional);
+        assert!(!config
+            .selective
+            .includes(Path::new("/srv/photos \"2024\"/raw/a.raw")));
+        assert!(config
+            .selective
+            .includes(Path::new("/srv/photos \"2024\"/raw/best/b.raw")));
+        assert_eq!(config.retry.max_backoff, Duration::from_secs(1200));
+        assert_eq!(config.retry.max_attempts, 5);
+        assert_eq!(config.bandwidth.default_limit, Some(1_000_000));
+        assert_eq!(config.bandwidth.limit_at(3 * 60), None);
+        let content = config
+            .content
+            .options(None)
+            .expect("content upload should be on");
+        assert_eq!(content.chunk_size, 4 * 1024 * 1024);
+        assert!(content.compression.is_some() && content.encryption.is_none());
+        assert!(config.encryption.enabled() && config.encryption.paths);
+        assert_eq!(
+            config.key_store_path(),
+            PathBuf::from("/var/lib/rust-client/keys")
+        );
+        assert_eq!(
+            config.encryption.passphrase_file,
+            Some(PathBuf::from("/etc/rust-client/passphrase"))
+        );
+        assert!(config.hashing.paranoid);
+        let budget = config.hashing.budget();
+        assert_eq!(budget.workers, 2);
+        assert_eq!(budget.max_read_bytes_per_sec, Some(20_000_000));
+        assert_eq!(config.restore.part_size, 25_000_000_000);
+    }
+
+    #[test]
+    fn parses_settings_and_fills_defaults() {
+        let config = Config::parse("server = \"http://127.0.0.1:8080\"\nstate_dir = \"/tmp/s\"\n")
+            .expect("config should parse");
+        assert!(config.roots.is_empty());
+        assert_eq!(config.token, None);
+        assert_eq!(config.log_level, LogLevel::Info);
+        assert_eq!(config.flush_interval, Duration::from_secs(30));
+        assert_eq!(config.scan_interval, Duration::from_secs(10));
+        assert!(!config.bidirectional);
+        assert!(config.selective.is_empty());
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert_eq!(config.bandwidth, BandwidthSchedule::default());
+        assert!(config.content.options(None).is_none());
+        assert_eq!(config.encryption, EncryptionSettings::default());
+        assert_eq!(config.key_store_path(), PathBuf::from("/tmp/s/keystore"));
+        assert_eq!(config.hashing, HashingSettings::default());
+        assert_eq!(config.restore, RestoreSettings::default());
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_setting() {
+        let cases = [
+            ("server = \"http://x\"\n\n[sync]\nflush_interval_secs = \"soon\"\n", "line 4: `sync.flush_interval_secs` must be a positive integer, found a string"),
+            ("server = \"http://x\"\nroot = [\"/a\"]\n", "line 2: unknown setting `root`"),
+            ("server = \"ftp://x\"\n", "line 1: server `ftp://x` should look like http://host:port"),
+            ("server = \"http://x\"\ntoken = \"two words\"\n", "line 2: `token` must be printable ASCII without spaces"),
+            ("server = \"http://x\"\nroots = [\n  \"/a\",\n  \"relative\",\n]\n", "line 2: root `relative` must be an absolute path"),
+            ("server = \"http://x\"\n[retry]\nmax_attempts = 0\n", "line 3: `retry.max_attempts` must be a positive integer, found 0"),
+            ("server = \"http://x\"\n[bandwidth]\nlimit = \"fast\"\n", "line 3: invalid rate `fast`"),
+            ("server = \"http://x\"\n[network]\n", "line 2: unknown table `[network]`"),
+            ("server = \"http://x\"\n[content]\nupload = \"yes\"\n", "line 3: `content.upload` must be a boolean, found a string"),
+            ("server = \"http://x\"\n[content]\nchunk_size = 100\n", "line 3: `content.chunk_size` must be between 4KiB and 64MiB"),
+            ("server = \"http://x\"\n[hashing]\nparanoid = 1\n", "line 3: `hashing.paranoid` must be a boolean, found 1"),
+            ("server = \"http://x\"\n[hashing]\nworkers = 0\n", "line 3: `hashing.workers` must be between 1 and 256"),
+            ("server = \"http://x\"\n[hashing]\nread_rate = \"quick\"\n", "line 3: invalid rate `quick`"),
+            ("server = \"http://x\"\n[restore]\npart_size = \"10KB\"\n", "line 3: `restore.part_size` must be between 1MiB and 1024GiB"),
+            ("server = \"http://x\"\n[sync]\nbidirectional = true\n", "line 3: `sync.bidirectional` needs `content.upload = true`"),
+            ("server = \"http://x\"\n[selective_sync]\nexclude = [\"/a\"]\n", "line 3: `selective_sync.exclude` needs `content.upload = true`"),
+            ("server = \"http://x\"\n[selective_sync]\ninclude = [\"a\"]\n", "line 3: `selective_sync.include` path `a` must be absolute"),
+            ("server = \"http://x\"\n[encryption]\ncontent = true\n", "line 3: `encryption.content` needs `content.upload = true`"),
+            ("server = \"http://x\"\n[encryption]\nkey_store = \"keys\"\n", "line 3: `encryption.key_store` path `keys` must be absolute"),
+            ("server = \"http://x\"\n[content]\nupload = true\n[selective_sync]\nexclude = [\"/a\"]\ninclude = [\"/a\"]\n", "line 5: `/a` is both included and excluded"),
+            ("server = \"http://x\"\nlog_level = \"loud\"\n", "line 2: log level `loud`"),
+            ("server = \"http://x\"\nserver = \"http://y\"\n", "line 2: `server` is set twice (first on line 1)"),
+            ("server = http://x\n", "line 1: `http` is not a value; strings need quotes"),
+            ("server = \"http://x\nroots = []\n", "line 1: unterminated string"),
+            ("server = \"http://x\"\n[retry]\ninitial_backoff_secs = 60\nmax_backoff_secs = 30\n", "line 4: `retry.max_backoff_secs` must not be less"),
+            ("server = \"\"\"http://x\"\"\"\n", "line 1: multi-line strings are not supported"),
+            ("server = \'\'\'http://x\'\'\'\n", "line 1: multi-line strings are not supported"),
+            ("server = \"http://x\\q\"\n", "line 1: unsupported escape `\\q`"),
+            ("server = \"http://x\\u12\"\n", "line 1: invalid escape `\\u12`"),
+            ("server = \"http://x\"\nretry = { max_attempts = 3 }\n", "line 2: inline tables are not supported"),
+            ("server = \"http://x\"\nretry.max_attempts = 3\n", "line 2: dotted keys are not supported; put `retry` settings under a [retry] header"),
+            ("\"server\" = \"http://x\"\n", "line 1: quoted keys are not supported"),
+            ("server = \"http://x\"\n[sync]\nflush_interval_secs = 1.5\n", "line 3: `1.5` is a float"),
+            ("server = \"http://x\"\n[sync]\nflush_interval_secs = 1e3\n", "line 3: `1e3` is a float"),
+            ("server = \"http://x\"\n[sync]\nflush_interval_secs = inf\n", "line 3: `inf` is a float"),
+            ("server = \"http://x\"\n[sync]\nflush_interval_secs = 2024-01-01\n", "line 3: `2024-01-01` looks like a date"),
+            ("server = \"http://x\"\n[sync]\nflush_interval_secs = 07:30:00\n", "line 3: `07:30:00` looks like a date"),
+        ];
+        for (text, expected) in cases {
+            let error = Config::parse(text).expect_err(expected).to_string();
+            assert!(
+                error.contains(expected),
+                "expected `{expected}` in `{error}`"
+            );
+        }
+        assert!(Config::parse("roots = []\n").is_err());
+    }
+
+    #[test]
+    fn decodes_every_basic_string_escape() {
+        let entries =
+            parse_document("token = \"\\u0041\\U0001F600\\u00e9f\\b\\f\\r\\t\\\"\\\\\"\n")
+                .expect("escapes should parse");
+        assert_eq!(
+            entries[0].value,
+            Value::String("A\u{1F600}\u{e9}f\u{8}\u{c}\r\t\"\\".to_string())
+        );
+    }
+
+    #[test]
+    fn unlocks_the_master_key_from_the_configured_key_store_and_passphrase_file() {
+        let dir = crate::test_support::temp_dir("config-encryption");
+        let (store, master, _) =
+            KeyStore::create("open sesame", crate::crypto::test_kdf_params()).expect("store");
+        store.save(dir.join("keys")).expect("store should save");
+        fs::write(dir.join("passphrase"), "open sesame\n").expect("write passphrase");
+        let text = |passphrase_file: &str| {
+            format!(
+                "server = \"http://x\"\nstate_dir = \"{}\"\n[content]\nupload = true\n\
+                 [encryption]\ncontent = true\npassphrase_file = \"{}\"\n",
+                dir.display(),
+                dir.join(passphrase_file).display()
+            )
+        };
+        let no_prompt = || -> Result<String, SyncError> { panic!("the file has the passphrase") };
+
+        let mut config = Config::parse(&text("passphrase")).expect("config should parse");
+        assert!(
+            config.master_key(no_prompt).is_err(),
+            "no store at the default path"
+        );
+        config.encryption.key_store = Some(dir.join("keys"));
+        let unlocked = config
+            .master_key(no_prompt)
+            .expect("store should unlock")
+            .expect("encryption is on");
+        assert_eq!(unlocked.as_bytes(), master.as_bytes());
+        let options = config
+            .content
+            .options(Some(&unlocked))
+            .expect("content upload is on");
+        assert!(options.encryption.is_some());
+        assert!(config.path_ciphers(Some(&unlocked)).is_empty());
+        config.roots = vec![PathBuf::from("/a"), PathBuf::from("/b")];
+        config.encryption.paths = true;
+        let ciphers = config.path_ciphers(Some(&unlocked));
+        assert_eq!(ciphers.len(), 2);
+        assert_ne!(ciphers[0].root_id(), ciphers[1].root_id());
+        assert!(config.path_ciphers(None).is_empty());
+
+        fs::write(dir.join("wrong"), "guess\n").expect("write passphrase");
+        let mut wrong = Config::parse(&text("wrong")).expect("config should parse");
+        wrong.encryption.key_store = Some(dir.join("keys"));
+        assert!(wrong.master_key(no_prompt).is_err());
+
+        let off = Config::parse("server = \"http://x\"\n").expect("config should parse");
+        assert!(off
+            .master_key(no_prompt)
+            .expect("nothing to unlock")
+            .is_none());
+    }
+
+    #[test]
+    fn environment_overrides_the_file_and_flags_override_the_environment() {
+        let environment = ConfigOverrides::from_vars(|name| match name {
+            "RUST_CLIENT_SERVER" => Some("http://env:1".to_string()),
+            "RUST_CLIENT_LOG_LEVEL" => Some("warn".to_string()),
+            "RUST_CLIENT_ROOTS" => Some("/env/a:/env/b".to_string()),
+            _ => None,
+        })
+        .expect("environment should parse");
+        let mut flags = ConfigOverrides::default();
+        flags
+            .set("server", "http://flag:2")
+            .expect("flag should parse");
+        flags
+            .set("bandwidth-limit", "unlimited")
+            .expect("flag should parse");
+
+        let config = Config::parse_with(FULL, &flags.layered_over(environment))
+            .expect("config should parse");
+
+        assert_eq!(config.server, "http://flag:2");
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(
+            config.roots,
+            vec![PathBuf::from("/env/a"), PathBuf::from("/env/b")]
+        );
+        assert_eq!(config.bandwidth, BandwidthSchedule::fixed(None));
+
+        let bad = ConfigOverrides::from_vars(|name| {
+            (name == "RUST_CLIENT_LOG_LEVEL").then(|| "chatty".to_string())
+        });
+        assert!(bad
+            .expect_err("bad level")
+            .to_string()
+            .contains("RUST_CLIENT_LOG_LEVEL"));
+    }
+
+    #[test]
+    fn hashing_flags_replace_the_file_budget() {
+        let config =
+            Config::parse("server = \"http://x\"\n[hashing]\nworkers = 8\nread_rate = 1000000\n")
+                .expect("config should parse");
+        let mut hashing = config.hashing;
+        assert_eq!(hashing.budget().workers, 8);
+        assert_eq!(hashing.budget().max_read_bytes_per_sec, Some(1_000_000));
+
+        hashing
+            .set("hash-workers", "3")
+            .expect("workers should parse");
+        hashing
+            .set("hash-rate", "unlimited")
+            .expect("rate should parse");
+        let budget = hashing.budget();
+        assert_eq!((budget.workers, budget.channel_capacity), (3, 12));
+        assert_eq!(budget.max_read_bytes_per_sec, None);
+
+        for (setting, value) in [
+            ("hash-workers", "0"),
+            ("hash-workers", "many"),
+            ("hash-rate", "fast"),
+        ] {
+            assert!(hashing.set(setting, value).is_err(), "--{setting} {value}");
+        }
+        assert_eq!(
+            HashingSettings::default().budget(),
+            HashBudget::default(),
+            "unset values keep the defaults"
+        );
+    }
+}
@@ -0,0 +1,679 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::crypto::{random_bytes, ChunkPosition, DataKey, MasterKey, FILE_NONCE_LEN};
+use crate::metadata::{from_hex, to_hex};
+use crate::{SparseLayout, SyncError, SyncTransport};
+
+/// One uploaded piece of a file, matching the `ChunkRecord` in the design doc.
+/// `offset` and `length` describe the plaintext range; `id` addresses the uploaded bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRecord {
+    pub id: String,
+    pub offset: u64,
+    pub length: u64,
+    pub codec: Codec,
+}
+
+/// How a chunk's bytes were compressed before encryption and upload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Codec {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Codec::None),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Per-chunk zstd compression. A chunk is uploaded compressed only when that saves at
+/// least `min_savings_percent`; after `give_up_after` incompressible chunks in a row the
+/// rest of the file is only probed every `reprobe_interval` chunks, so media and archives
+/// cost little CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionOptions {
+    pub level: i32,
+    pub min_savings_percent: u8,
+    pub give_up_after: u32,
+    pub reprobe_interval: u32,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            min_savings_percent: 5,
+            give_up_after: 4,
+            reprobe_interval: 16,
+        }
+    }
+}
+
+/// Everything a restoring device needs, besides the master key, to decrypt a file's chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionHeader {
+    pub wrapped_key: Vec<u8>,
+    pub file_nonce: [u8; FILE_NONCE_LEN],
+}
+
+/// Largest chunk, in plaintext bytes, that is uploaded or accepted back. A recorded length
+/// sizes the buffer a chunk decompresses into, and nothing authenticates it when content
+/// is not encrypted, so larger claims are refused before any allocation.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// How file content is prepared for upload. Without content options the manager only
+/// sends hashes and metadata, as the v0 protocol does.
+#[derive(Debug, Clone)]
+pub struct ContentOptions {
+    /// Plaintext bytes per chunk, capped at [`MAX_CHUNK_SIZE`].
+    pub chunk_size: usize,
+    /// When set, every chunk is encrypted on this device before it is uploaded.
+    pub encryption: Option<MasterKey>,
+    /// When set, chunks are compressed before encryption if that makes them smaller.
+    pub compression: Option<CompressionOptions>,
+}
+
+impl Default for ContentOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024 * 1024,
+            encryption: None,
+            compression: None,
+        }
+    }
+}
+
+/// Result of uploading a file's content. `uploaded_bytes` counts chunk bodies as sent,
+/// after compression and encryption.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentManifest {
+    pub chunks: Vec<ChunkRecord>,
+    pub encryption: Option<EncryptionHeader>,
+    pub uploaded_bytes: u64,
+}
+
+impl ChunkRecord {
+    /// `chunk=<id>:<offset>:<length>`, with `:<codec>` appended for compressed chunks.
+    pub(crate) fn encode_line(&self, out: &mut String) {
+        out.push_str(&format!(
+            "chunk={}:{}:{}",
+            self.id, self.offset, self.length
+        ));
+        if self.codec != Codec::None {
+            out.push(':');
+            out.push_str(self.codec.as_str());
+        }
+        out.push('\n');
+    }
+
+    pub(crate) fn decode(value: &str) -> Result<Self, SyncError> {
+        let invalid = || SyncError::Protocol(format!("invalid chunk record: {value}"));
+        let mut parts = value.split(':');
+        let record = Self {
+            id: parts.next().ok_or_else(invalid)?.to_string(),
+            offset: parts
+                .next()
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())?,
+            length: parts
+                .next()
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())?,
+            codec: match parts.next() {
+                Some(codec) => Codec::parse(codec).ok_or_else(invalid)?,
+                None => Codec::None,
+            },
+        };
+        if parts.next().is_some() || record.id.is_empty() {
+            return Err(invalid());
+        }
+        Ok(record)
+    }
+}
+
+impl EncryptionHeader {
+    pub(crate) fn encode_line(&self, out: &mut String) {
+        out.push_str(&format!(
+            "encryption=xchacha20poly1305:{}:{}\n",
+            to_hex(&self.wrapped_key),
+            to_hex(&self.file_nonce)
+        ));
+    }
+
+    pub(crate) fn decode(value: &str) -> Result<Self, SyncError> {
+        let invalid = || SyncError::Protocol(format!("invalid encryption header: {value}"));
+        let mut parts = value.split(':');
+        if parts.next() != Some("xchacha20poly1305") {
+            return Err(invalid());
+        }
+        Ok(Self {
+            wrapped_key: from_hex(parts.next().ok_or_else(invalid)?).ok_or_else(invalid)?,
+            file_nonce: from_hex(parts.next().ok_or_else(invalid)?)
+                .and_then(|nonce| nonce.try_into().ok())
+                .ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// Reads a file chunk by chunk, compresses and encrypts each chunk when configured, and
+/// uploads it.
+/// Sparse files contribute only their data extents. Content is streamed, so memory use
+/// stays at one chunk regardless of file size.
+pub(crate) fn upload_content<T: SyncTransport + ?Sized>(
+    transport: &mut T,
+    file_path: &Path,
+    sparse: Option<&SparseLayout>,
+    options: &ContentOptions,
+) -> Result<ContentManifest, SyncError> {
+    let mut file = File::open(file_path).map_err(SyncError::Io)?;
+    let chunk_size = options.chunk_size.clamp(1, MAX_CHUNK_SIZE);
+    let spans = chunk_spans(&file, sparse, chunk_size)?;
+
+    let sealing = match &options.encryption {
+        Some(master) => {
+            let data_key = DataKey::generate()?;
+            let header = EncryptionHeader {
+                wrapped_key: master.wrap_data_key(&data_key)?,
+                file_nonce: random_bytes()?,
+            };
+            Some((data_key, header))
+        }
+        None => None,
+    };
+
+    let mut compressor = options.compression.map(AdaptiveCompressor::new);
+    let mut manifest = ContentManifest::default();
+    let mut plaintext = Vec::with_capacity(chunk_size);
+    for (index, &(offset, length)) in spans.iter().enumerate() {
+        plaintext.clear();
+        file.seek(SeekFrom::Start(offset)).map_err(SyncError::Io)?;
+        (&mut file)
+            .take(length)
+            .read_to_end(&mut plaintext)
+            .map_err(SyncError::Io)?;
+        if plaintext.len() as u64 != length {
+            return Err(SyncError::FileUnstable(file_path.display().to_string()));
+        }
+
+        let (codec, packed) = match compressor.as_mut() {
+            Some(compressor) => compressor.compress(&plaintext)?,
+            None => (Codec::None, None),
+        };
+        let payload = packed.as_deref().unwrap_or(&plaintext);
+        let body = match &sealing {
+            Some((data_key, header)) => data_key.encrypt_chunk(
+                &header.file_nonce,
+                &ChunkPosition {
+                    index: index as u64,
+                    last: index + 1 == spans.len(),
+                    offset,
+                    length,
+                    codec,
+                },
+                payload,
+            )?,
+            None => payload.to_vec(),
+        };
+        let id = to_hex(&Sha256::digest(&body));
+        transport.upload_chunk(&id, &body)?;
+        manifest.uploaded_bytes += body.len() as u64;
+        manifest.chunks.push(ChunkRecord {
+            id,
+            offset,
+            length,
+            codec,
+        });
+    }
+
+    manifest.encryption = sealing.map(|(_, header)| header);
+    Ok(manifest)
+}
+
+/// Tracks how compressible a file has been so far and skips work on data that is not.
+struct AdaptiveCompressor {
+    options: CompressionOptions,
+    misses: u32,
+}
+
+impl AdaptiveCompressor {
+    fn new(options: CompressionOptions) -> Self {
+        Self { options, misses: 0 }
+    }
+
+    /// Returns the compressed chunk, or `None` when it should be sent as is.
+    fn compress(&mut self, plaintext: &[u8]) -> Result<(Codec, Option<Vec<u8>>), SyncError> {
+        if plaintext.is_empty() {
+            return Ok((Codec::None, None));
+        }
+        if self.misses >= self.options.give_up_after {
+            // Past the limit, `misses` keeps counting skipped chunks to space out probes.
+            self.misses += 1;
+            let skipped = self.misses - self.options.give_up_after;
+            if !skipped.is_multiple_of(self.options.reprobe_interval.max(1)) {
+                return Ok((Codec::None, None));
+            }
+        }
+
+        let compressed = zstd::bulk::compress(plaintext, self.options.level)
+            .map_err(|err| SyncError::Protocol(format!("zstd compression failed: {err}")))?;
+        let budget = plaintext.len() as u64
+            * (100 - u64::from(self.options.min_savings_percent.min(100)))
+            / 100;
+        if compressed.len() as u64 <= budget {
+            self.misses = 0;
+            Ok((Codec::Zstd, Some(compressed)))
+        } else {
+            if self.misses < self.options.give_up_after {
+                self.misses += 1;
+            }
+            Ok((Codec::None, None))
+        }
+    }
+}
+
+/// Splits the readable ranges of a file into chunk-sized spans. Encrypted uploads need at
+/// least one (possibly empty) chunk so an empty file still carries an authenticated end.
+fn chunk_spans(
+    file: &File,
+    sparse: Option<&SparseLayout>,
+    chunk_size: usize,
+) -> Result<Vec<(u64, u64)>, SyncError> {
+    let ranges = match sparse {
+        Some(layout) => layout
+            .extents
+            .iter()
+            .map(|extent| (extent.offset, extent.length))
+            .collect::<Vec<_>>(),
+        None => vec![(0, file.metadata().map_err(SyncError::Io)?.len())],
+    };
+
+    let mut spans = Vec::new();
+    for (start, length) in ranges {
+        let mut offset = start;
+        while offset < start + length {
+            let piece = (start + length - offset).min(chunk_size as u64);
+            spans.push((offset, piece));
+            offset += piece;
+        }
+    }
+    if spans.is_empty() {
+        spans.push((0, 0));
+    }
+    Ok(spans)
+}
+
+/// Turns downloaded chunk bodies back into plaintext for one file.
+pub struct ContentDecoder {
+    sealing: Option<(DataKey, [u8; FILE_NONCE_LEN])>,
+    chunks: Vec<ChunkRecord>,
+}
+
+impl ContentDecoder {
+    pub fn new(manifest: &ContentManifest, master: Option<&MasterKey>) -> Result<Self, SyncError> {
+        let sealing = match (&manifest.encryption, master) {
+            (Some(header), Some(master)) => Some((
+                master.unwrap_data_key(&header.wrapped_key)?,
+                header.file_nonce,
+            )),
+            (Some(_), None) => {
+                return Err(SyncError::Crypto(
+                    "content is encrypted but no master key is loaded".to_string(),
+                ))
+            }
+            (None, _) => None,
+        };
+        Ok(Self {
+            sealing,
+            chunks: manifest.chunks.clone(),
+        })
+    }
+
+    pub fn decode(&self, index: usize, body: Vec<u8>) -> Result<Vec<u8>, SyncError> {
+        let chunk = self
+            .chunks
+            .get(index)
+            .ok_or_else(|| SyncError::Protocol(format!("chunk {index} is not in the manifest")))?;
+        let (codec, length) = (chunk.codec, chunk.length);
+        if length > MAX_CHUNK_SIZE as u64 {
+            return Err(SyncError::Protocol(format!(
+                "chunk {index} claims {length} bytes, more than the {MAX_CHUNK_SIZE} allowed"
+            )));
+        }
+        let payload = match &self.sealing {
+            Some((data_key, file_nonce)) => data_key.decrypt_chunk(
+                file_nonce,
+                &ChunkPosition {
+                    index: index as u64,
+                    last: index + 1 == self.chunks.len(),
+                    offset: chunk.offset,
+                    length,
+                    codec,
+                },
+                &body,
+            )?,
+            None => body,
+        };
+        match codec {
+            Codec::None => Ok(payload),
+            Codec::Zstd => {
+                let plaintext =
+                    zstd::bulk::decompress(&payload, length as usize).map_err(|err| {
+                        SyncError::Protocol(format!("chunk {index} failed to decompress: {err}"))
+                    })?;
+                if plaintext.len() as u64 != length {
+                    return Err(SyncError::Protocol(format!(
+                        "chunk {index} decompressed to {} bytes, expected {length}",
+                        plaintext.len()
+                    )));
+                }
+                Ok(plaintext)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use crate::{Extent, SyncRequest};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[derive(Default)]
+    struct ChunkStore {
+        chunks: HashMap<String, Vec<u8>>,
+    }
+
+    impl SyncTransport for ChunkStore {
+        fn health_check(&mut self) -> Result<bool, SyncError> {
+            Ok(true)
+        }
+
+        fn sync_file(&mut self, _req: &SyncRequest) -> Result<(), SyncError> {
+            Ok(())
+        }
+
+        fn upload_chunk(&mut self, chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+            self.chunks.insert(chunk_id.to_string(), body.to_vec());
+            Ok(())
+        }
+    }
+
+    fn temp_file(label: &str, content: &[u8]) -> std::path::PathBuf {
+        let path = temp_dir(label).join("payload.bin");
+        fs::write(&path, content).expect("payload should be written");
+        path
+    }
+
+    fn reassemble(
+        store: &ChunkStore,
+        manifest: &ContentManifest,
+        master: Option<&MasterKey>,
+    ) -> Vec<u8> {
+        let decoder = ContentDecoder::new(manifest, master).expect("decoder should build");
+        let mut content = Vec::new();
+        for (index, record) in manifest.chunks.iter().enumerate() {
+            let body = store.chunks[&record.id].clone();
+            content.extend(decoder.decode(index, body).expect("chunk should decode"));
+        }
+        content
+    }
+
+    #[test]
+    fn uploads_plain_chunks_that_reassemble_to_the_file() {
+        let content = (0..10_000_u32)
+            .flat_map(|n| n.to_le_bytes())
+            .collect::<Vec<_>>();
+        let path = temp_file("content-plain", &content);
+        let mut store = ChunkStore::default();
+        let options = ContentOptions {
+            chunk_size: 4096,
+            ..ContentOptions::default()
+        };
+
+        let manifest =
+            upload_content(&mut store, &path, None, &options).expect("upload should succeed");
+
+        assert_eq!(manifest.chunks.len(), 10);
+        assert_eq!(manifest.encryption, None);
+        assert_eq!(reassemble(&store, &manifest, None), content);
+    }
+
+    #[test]
+    fn encrypted_chunks_never_contain_plaintext_and_decrypt_with_the_master_key() {
+        let content = b"cooperative member ledger: alice owes bob 3 hours".repeat(100);
+        let path = temp_file("content-encrypted", &content);
+        let master = MasterKey::generate().expect("master key should generate");
+        let mut store = ChunkStore::default();
+        let options = ContentOptions {
+            chunk_size: 1000,
+            encryption: Some(master.clone()),
+            ..ContentOptions::default()
+        };
+
+        let manifest =
+            upload_content(&mut store, &path, None, &options).expect("upload should succeed");
+
+        assert!(manifest.encryption.is_some());
+        for body in store.chunks.values() {
+            assert!(!body.windows(6).any(|window| window == b"ledger"));
+        }
+        assert_eq!(reassemble(&store, &manifest, Some(&master)), content);
+        assert!(matches!(
+            ContentDecoder::new(&manifest, None),
+            Err(SyncError::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn empty_encrypted_file_still_uploads_an_authenticated_final_chunk() {
+        let path = temp_file("content-empty", b"");
+        let master = MasterKey::generate().expect("master key should generate");
+        let mut store = ChunkStore::default();
+        let options = ContentOptions {
+            encryption: Some(master.clone()),
+            ..ContentOptions::default()
+        };
+
+        let manifest =
+            upload_content(&mut store, &path, None, &options).expect("upload should succeed");
+
+        assert_eq!(manifest.chunks.len(), 1);
+        assert!(reassemble(&store, &manifest, Some(&master)).is_empty());
+    }
+
+    #[test]
+    fn sparse_files_upload_only_their_extents() {
+        let mut content = vec![0_u8; 64 * 1024];
+        content[8192..12288].fill(1);
+        let path = temp_file("content-sparse", &content);
+        let layout = SparseLayout {
+            size: content.len() as u64,
+            extents: vec![Extent {
+                offset: 8192,
+                length: 4096,
+            }],
+        };
+        let mut store = ChunkStore::default();
+
+        let manifest = upload_content(&mut store, &path, Some(&layout), &ContentOptions::default())
+            .expect("upload should succeed");
+
+        assert_eq!(
+            manifest
+                .chunks
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.length))
+                .collect::<Vec<_>>(),
+            vec![(8192, 4096)]
+        );
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compresses_text_chunks_and_skips_incompressible_ones() {
+        let mut content = b"member,hours,balance\nalice,3,-3\nbob,0,3\n".repeat(200);
+        let text_len = content.len() as u64;
+        content.extend(noise(8192));
+        let path = temp_file("content-compressed", &content);
+        let master = MasterKey::generate().expect("master key should generate");
+        let mut store = ChunkStore::default();
+        let options = ContentOptions {
+            chunk_size: 4096,
+            encryption: Some(master.clone()),
+            compression: Some(CompressionOptions::default()),
+        };
+
+        let manifest =
+            upload_content(&mut store, &path, None, &options).expect("upload should succeed");
+
+        let codecs = manifest
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.offset < text_len, chunk.codec))
+            .collect::<Vec<_>>();
+        assert!(codecs
+            .iter()
+            .any(|&(text, codec)| text && codec == Codec::Zstd));
+        assert!(codecs
+            .iter()
+            .filter(|(text, _)| !text)
+            .all(|&(_, codec)| codec == Codec::None));
+        assert!(manifest.uploaded_bytes < content.len() as u64);
+        assert_eq!(reassemble(&store, &manifest, Some(&master)), content);
+    }
+
+    #[test]
+    fn stops_trying_on_incompressible_files_but_reprobes() {
+        let mut compressor = AdaptiveCompressor::new(CompressionOptions {
+            give_up_after: 2,
+            reprobe_interval: 3,
+            ..CompressionOptions::default()
+        });
+        let random = noise(4096);
+        let text = b"abcabcabc".repeat(400);
+
+        for _ in 0..2 {
+            assert_eq!(
+                compressor.compress(&random).expect("compress").0,
+                Codec::None
+            );
+        }
+        // Given up: the next two chunks are not even tried, so text goes out raw.
+        assert_eq!(compressor.compress(&text).expect("compress").1, None);
+        assert_eq!(compressor.compress(&text).expect("compress").1, None);
+        // Third chunk after giving up is a probe, and success resumes compression.
+        assert_eq!(compressor.compress(&text).expect("compress").0, Codec::Zstd);
+        assert_eq!(compressor.compress(&text).expect("compress").0, Codec::Zstd);
+    }
+
+    #[test]
+    fn uncompressed_chunk_lines_keep_the_original_format() {
+        let record = ChunkRecord {
+            id: "cd".repeat(32),
+            offset: 0,
+            length: 10,
+            codec: Codec::None,
+        };
+        let mut line = String::new();
+        record.encode_line(&mut line);
+        assert_eq!(line, format!("chunk={}:0:10\n", "cd".repeat(32)));
+        assert!(ChunkRecord::decode(&format!("{}:0:10:lz4", "cd".repeat(32))).is_err());
+    }
+
+    #[test]
+    fn manifest_lines_round_trip() {
+        let record = ChunkRecord {
+            id: "ab".repeat(32),
+            offset: 1 << 20,
+            length: 4096,
+            codec: Codec::Zstd,
+        };
+        let header = EncryptionHeader {
+            wrapped_key: vec![1, 2, 3],
+            file_nonce: [4; FILE_NONCE_LEN],
+        };
+        let mut lines = String::new();
+        record.encode_line(&mut lines);
+        header.encode_line(&mut lines);
+
+        let mut decoded_lines = lines.lines();
+        let chunk = decoded_lines
+            .next()
+            .and_then(|line| line.strip_prefix("chunk="));
+        let encryption = decoded_lines
+            .next()
+            .and_then(|line| line.strip_prefix("encryption="));
+        assert_eq!(
+            ChunkRecord::decode(chunk.expect("chunk line")).expect("chunk decodes"),
+            record
+        );
+        assert_eq!(
+            EncryptionHeader::decode(encryption.expect("encryption line")).expect("header decodes"),
+            header
+        );
+    }
+
+    #[test]
+    fn refuses_chunk_lengths_beyond_the_limit_before_decompressing() {
+        let body = zstd::bulk::compress(b"small", 0).expect("body should compress");
+        let manifest = ContentManifest {
+            chunks: vec![ChunkRecord {
+                id: "forged".to_string(),
+                offset: 0,
+                length: u64::MAX / 2,
+                codec: Codec::Zstd,
+            }],
+            ..ContentManifest::default()
+        };
+        let decoder = ContentDecoder::new(&manifest, None).expect("decoder should build");
+        let error = decoder
+            .decode(0, body.clone())
+            .expect_err("a forged length should be refused");
+        assert!(error.to_string().contains("more than the"), "{error}");
+
+        let manifest = ContentManifest {
+            chunks: vec![ChunkRecord {
+                length: 5,
+                ..manifest.chunks[0].clone()
+            }],
+            ..ContentManifest::default()
+        };
+        let decoder = ContentDecoder::new(&manifest, None).expect("decoder should build");
+        assert_eq!(
+            decoder.decode(0, body).expect("chunk should decode"),
+            b"small"
+        );
+    }
+}
@@ -0,0 +1,493 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::logging::{self, LogLevel};
+use crate::{DeadLetter, Json, SyncError};
+
+/// Version carried in every request and response; bumped on incompatible changes.
+pub const PROTOCOL_VERSION: i64 = 1;
+
+/// Socket file name inside the daemon's state directory.
+pub const CONTROL_SOCKET: &str = "control.sock";
+
+/// Requests longer than this are rejected so a client cannot make the daemon buffer
+/// unbounded input.
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+/// Connections idle for this long are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connections served at once; further clients get an error and are closed so they
+/// cannot make the daemon spawn unbounded threads.
+const MAX_CLIENTS: usize = 16;
+
+/// What the daemon last published about its queue and transfers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DaemonStatus {
+    pub paused: bool,
+    pub queued: usize,
+    pub deferred: usize,
+    /// Paths being sent right now.
+    pub in_flight: Vec<String>,
+    pub last_success: Option<SystemTime>,
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+/// State shared between the daemon loop and control socket connections. The daemon
+/// publishes its status here and polls the pause and sync-now requests.
+#[derive(Debug, Clone, Default)]
+pub struct ControlState {
+    status: Arc<Mutex<DaemonStatus>>,
+    sync_now: Arc<AtomicBool>,
+}
+
+impl ControlState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self) -> DaemonStatus {
+        self.status.lock().expect("control state lock").clone()
+    }
+
+    pub(crate) fn update(&self, change: impl FnOnce(&mut DaemonStatus)) {
+        change(&mut self.status.lock().expect("control state lock"));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status().paused
+    }
+
+    /// Pausing stops flushing after the request in flight; scans keep queueing changes.
+    pub fn set_paused(&self, paused: bool) {
+        self.update(|status| status.paused = paused);
+    }
+
+    pub fn request_sync_now(&self) {
+        self.sync_now.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once per sync-now request.
+    pub fn take_sync_now(&self) -> bool {
+        self.sync_now.swap(false, Ordering::SeqCst)
+    }
+
+    /// Answers one protocol request, e.g. `{"version":1,"command":"status"}`.
+    pub fn handle(&self, request: &Json) -> Json {
+        let version = request.get("version").and_then(Json::as_i64);
+        if version != Some(PROTOCOL_VERSION) {
+            return error_response(&format!(
+                "unsupported protocol version {}; this daemon speaks version {PROTOCOL_VERSION}",
+                version.map_or("(missing)".to_string(), |version| version.to_string())
+            ));
+        }
+        match request.get("command").and_then(Json::as_str) {
+            Some("status") => {
+                let status = self.status();
+                ok_response([
+                    ("paused", Json::from(status.paused)),
+                    ("queued", Json::from(status.queued)),
+                    ("deferred", Json::from(status.deferred)),
+                    ("in_flight", Json::from(status.in_flight)),
+                    (
+                        "last_success",
+                        Json::from(status.last_success.map(unix_seconds)),
+                    ),
+                    ("dead_letters", Json::from(status.dead_letters.len())),
+                ])
+            }
+            Some(command @ ("pause" | "resume")) => {
+                let paused = command == "pause";
+                self.set_paused(paused);
+                logging::log(
+                    "control",
+                    LogLevel::Info,
+                    &format!("{command}d by control socket"),
+                );
+                ok_response([("paused", Json::from(paused))])
+            }
+            Some("sync_now") if self.is_paused() => {
+                error_response("syncing is paused; resume first")
+            }
+            Some("sync_now") => {
+                self.request_sync_now();
+                ok_response([])
+            }
+            Some("dead_letters") => {
+                let dead_letters = self
+                    .status()
+                    .dead_letters
+                    .iter()
+                    .map(|dead| {
+                        Json::object([
+                            ("path", Json::from(dead.request.path.as_str())),
+                            ("hash", Json::from(dead.request.hash.as_str())),
+                            ("attempts", Json::from(u64::from(dead.attempts))),
+                            ("error", Json::from(dead.error.as_str())),
+                        ])
+                    })
+                    .collect();
+                ok_response([("dead_letters", Json::Array(dead_letters))])
+            }
+            Some(other) => error_response(&format!("unknown command `{other}`")),
+            None => error_response("request has no `command`"),
+        }
+    }
+}
+
+fn ok_response<const N: usize>(fields: [(&str, Json); N]) -> Json {
+    Json::object(
+        [
+            ("version", Json::Int(PROTOCOL_VERSION)),
+            ("ok", Json::Bool(true)),
+        ]
+        .into_iter()
+        .chain(fields),
+    )
+}
+
+fn error_response(message: &str) -> Json {
+    Json::object([
+        ("version", Json::Int(PROTOCOL_VERSION)),
+        ("ok", Json::Bool(false)),
+        ("error", Json::from(message)),
+    ])
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Unix domain socket serving [`ControlState::handle`]: one JSON request per line, one
+/// JSON response per line. The socket is private to the user (mode 0600) and removed
+/// when the server is dropped.
+#[derive(Debug)]
+pub struct ControlServer {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    /// Binds `path`, replacing a socket left behind by a crashed daemon. Callers must hold
+    /// the daemon lock so a live daemon's socket is never replaced.
+    ///
+    /// The socket is bound inside a fresh owner-only directory and renamed into place once
+    /// it is mode 0600, so no other user can connect in between.
+    pub fn bind<P: AsRef<Path>>(path: P, state: ControlState) -> Result<Self, SyncError> {
+        let path = path.as_ref().to_path_buf();
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SyncError::Io(err)),
+        }
+        let listener = bind_private(&path).map_err(SyncError::Io)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let clients = Arc::new(AtomicUsize::new(0));
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(mut stream) = stream else {
+                        continue;
+                    };
+                    if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                        clients.fetch_sub(1, Ordering::SeqCst);
+                        let _ = writeln!(stream, "{}", error_response("too many clients"));
+                        continue;
+                    }
+                    let state = state.clone();
+                    let clients = Arc::clone(&clients);
+                    thread::spawn(move || {
+                        if let Err(err) = serve_connection(stream, &state) {
+                            logging::log(
+                                "control",
+                                LogLevel::Debug,
+                                &format!("connection closed: {err}"),
+                            );
+                        }
+                        clients.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            }
+        });
+        Ok(Self {
+            path,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the stop flag.
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds a listener at `path` without the socket ever being reachable by other users:
+/// binding happens in a 0700 directory next to `path`, and the socket is only renamed
+/// to `path` after being restricted to mode 0600.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let staging = parent.join(format!(
+        ".control-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(CONTROL_SOCKET);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+#[cfg(unix)]
+fn serve_connection(stream: UnixStream, state: &ControlState) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader
+            .by_ref()
+            .take(MAX_REQUEST_BYTES)
+            .read_line(&mut line)?
+            == 0
+        {
+            return Ok(());
+        }
+        if line.len() as u64 >= MAX_REQUEST_BYTES && !line.ends_with('\n') {
+            writeln!(writer, "{}", error_response("request too long"))?;
+            return Ok(());
+        }
+        let response = match Json::parse(line.trim()) {
+            Ok(request) => state.handle(&request),
+            Err(err) => error_response(&err.to_string()),
+        };
+        writeln!(writer, "{response}")?;
+    }
+}
+
+/// Client side of the control socket, used by the CLI.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+#[cfg(unix)]
+impl ControlClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let writer = UnixStream::connect(path.as_ref()).map_err(SyncError::Connection)?;
+        writer
+            .set_read_timeout(Some(IDLE_TIMEOUT))
+            .map_err(SyncError::Io)?;
+        let reader = BufReader::new(writer.try_clone().map_err(SyncError::Io)?);
+        Ok(Self { reader, writer })
+    }
+
+    /// Sends `command` and returns the response. A response with `"ok": false` becomes a
+    /// protocol error carrying the daemon's message.
+    pub fn request(&mut self, command: &str) -> Result<Json, SyncError> {
+        let request = Json::object([
+            ("version", Json::Int(PROTOCOL_VERSION)),
+            ("command", Json::from(command)),
+        ]);
+        writeln!(self.writer, "{request}").map_err(SyncError::Connection)?;
+        let mut line = String::new();
+        self.reader
+            .read_line(&mut line)
+            .map_err(SyncError::Connection)?;
+        let response = Json::parse(line.trim())?;
+        if response.get("version").and_then(Json::as_i64) != Some(PROTOCOL_VERSION) {
+            return Err(SyncError::Protocol(
+                "daemon speaks another control protocol version".to_string(),
+            ));
+        }
+        if response.get("ok").and_then(Json::as_bool) != Some(true) {
+            let message = response
+                .get("error")
+                .and_then(Json::as_str)
+                .unwrap_or("request failed");
+            return Err(SyncError::Protocol(message.to_string()));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use crate::SyncRequest;
+
+    fn request(state: &ControlState, text: &str) -> Json {
+        state.handle(&Json::parse(text).expect("request should parse"))
+    }
+
+    #[test]
+    fn answers_status_pause_resume_and_dead_letter_requests() {
+        let state = ControlState::new();
+        state.update(|status| {
+            status.queued = 3;
+            status.in_flight = vec!["a.txt".to_string()];
+            status.last_success = Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+            status.dead_letters = vec![DeadLetter {
+                request: SyncRequest {
+                    path: "b.txt".to_string(),
+                    hash: "bb".to_string(),
+                    ..SyncRequest::default()
+                },
+                attempts: 10,
+                error: "server error 500".to_string(),
+            }];
+        });
+
+        assert_eq!(
+            request(&state, r#"{"version":1,"command":"status"}"#).to_string(),
+            r#"{"version":1,"ok":true,"paused":false,"queued":3,"deferred":0,"in_flight":["a.txt"],"last_success":1700000000,"dead_letters":1}"#
+        );
+        assert_eq!(
+            request(&state, r#"{"version":1,"command":"dead_letters"}"#).get("dead_letters"),
+            Some(&Json::Array(vec![Json::object([
+                ("path", Json::from("b.txt")),
+                ("hash", Json::from("bb")),
+                ("attempts", Json::Int(10)),
+                ("error", Json::from("server error 500")),
+            ])]))
+        );
+
+        request(&state, r#"{"version":1,"command":"pause"}"#);
+        assert!(state.is_paused());
+        let refused = request(&state, r#"{"version":1,"command":"sync_now"}"#);
+        assert_eq!(refused.get("ok"), Some(&Json::Bool(false)));
+        request(&state, r#"{"version":1,"command":"resume"}"#);
+        request(&state, r#"{"version":1,"command":"sync_now"}"#);
+        assert!(state.take_sync_now());
+        assert!(!state.take_sync_now());
+
+        for bad in [
+            r#"{"version":2,"command":"status"}"#,
+            r#"{"command":"status"}"#,
+            r#"{"version":1,"command":"explode"}"#,
+        ] {
+            assert_eq!(
+                request(&state, bad).get("ok"),
+                Some(&Json::Bool(false)),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn serves_requests_over_a_private_socket_and_removes_it_on_drop() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_dir("control").join(CONTROL_SOCKET);
+        fs::write(&path, "left over from a crash").expect("stale file should be written");
+        let state = ControlState::new();
+        let server = ControlServer::bind(&path, state.clone()).expect("socket should bind");
+        let mode = fs::metadata(&path)
+            .expect("socket should exist")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = ControlClient::connect(server.path()).expect("client should connect");
+        client.request("pause").expect("pause should succeed");
+        assert!(state.is_paused());
+        let status = client.request("status").expect("status should succeed");
+        assert_eq!(status.get("paused"), Some(&Json::Bool(true)));
+        let error = client
+            .request("sync_now")
+            .expect_err("sync_now should be refused while paused");
+        assert!(error.to_string().contains("paused"));
+
+        let mut raw = UnixStream::connect(&path).expect("raw client should connect");
+        raw.write_all(b"not json\n")
+            .expect("request should be sent");
+        let mut reply = String::new();
+        BufReader::new(&raw)
+            .read_line(&mut reply)
+            .expect("reply should arrive");
+        assert!(reply.contains(r#""ok":false"#));
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refuses_clients_beyond_the_cap_until_one_disconnects() {
+        let dir = temp_dir("control-cap");
+        let path = dir.join(CONTROL_SOCKET);
+        let server = ControlServer::bind(&path, ControlState::new()).expect("socket should bind");
+        assert_eq!(
+            fs::read_dir(&dir).expect("dir should list").count(),
+            1,
+            "the staging directory should be removed after binding"
+        );
+
+        let mut held: Vec<ControlClient> = (0..MAX_CLIENTS)
+            .map(|_| ControlClient::connect(server.path()).expect("client should connect"))
+            .collect();
+        held[0]
+            .request("status")
+            .expect("held clients should be served");
+
+        let refused = UnixStream::connect(&path).expect("socket should accept");
+        let mut reply = String::new();
+        BufReader::new(&refused)
+            .read_line(&mut reply)
+            .expect("refusal should arrive");
+        assert!(reply.contains("too many clients"));
+
+        held.pop();
+        let deadline = SystemTime::now() + Duration::from_secs(5);
+        loop {
+            let mut client = ControlClient::connect(server.path()).expect("client should connect");
+            match client.request("status") {
+                Ok(_) => break,
+                Err(err) => {
+                    assert!(SystemTime::now() < deadline, "slot was never freed: {err}");
+                    thread::sleep(Duration::from_millis(20));
+                }
+            }
+        }
+    }
+}
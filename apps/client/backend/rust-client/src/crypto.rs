@@ -0,0 +1,474 @@
+use std::fmt;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::metadata::{from_hex, to_hex};
+use crate::{Codec, SyncError};
+
+pub const KEY_LEN: usize = 32;
+pub const FILE_NONCE_LEN: usize = 16;
+const SEAL_NONCE_LEN: usize = 24;
+const WRAP_AAD: &[u8] = b"rust-client data key v1";
+const CHUNK_AAD: &[u8] = b"rust-client chunk v2";
+const CONTENT_TAG_INFO: &[u8] = b"rust-client content tag v1";
+const NAMING_INFO: &[u8] = b"rust-client naming key v1";
+
+/// Root secret of a user's encrypted data. It never leaves the device unwrapped; it only
+/// wraps the per-file [`DataKey`]s that actually encrypt content.
+///
+/// Alongside it travels a naming key that content tags and encrypted paths derive from.
+/// Rotation replaces the wrapping key but keeps the naming key, so names and tags already
+/// on the server stay valid.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct MasterKey {
+    bytes: [u8; KEY_LEN],
+    naming: [u8; KEY_LEN],
+}
+
+/// Random key that encrypts a single file's chunks. Uploaded only in wrapped form.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct DataKey {
+    bytes: [u8; KEY_LEN],
+}
+
+/// Where a chunk belongs in its file. All of it is authenticated with the chunk, so a
+/// ciphertext cannot be replayed at another index or offset, with another length, or under
+/// another codec, and a file cannot be silently truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkPosition {
+    pub index: u64,
+    pub last: bool,
+    pub offset: u64,
+    pub length: u64,
+    pub codec: Codec,
+}
+
+/// Argon2id settings and salt used to turn a passphrase into a [`MasterKey`]. Not secret;
+/// store it next to whatever needs to re-derive the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KdfParams {
+    pub salt: [u8; 16],
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl KdfParams {
+    /// Fresh salt with the OWASP-recommended Argon2id cost (64 MiB, 3 passes).
+    pub fn generate() -> Result<Self, SyncError> {
+        Ok(Self {
+            salt: random_bytes()?,
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "argon2id:m={},t={},p={}:{}",
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            to_hex(&self.salt)
+        )
+    }
+
+    pub fn decode(value: &str) -> Result<Self, SyncError> {
+        let invalid = || SyncError::Crypto(format!("invalid kdf parameters: {value}"));
+        let mut parts = value.split(':');
+        if parts.next() != Some("argon2id") {
+            return Err(invalid());
+        }
+        let mut params = Self {
+            salt: [0; 16],
+            memory_kib: 0,
+            iterations: 0,
+            parallelism: 0,
+        };
+        for field in parts.next().ok_or_else(invalid)?.split(',') {
+            let (name, number) = field.split_once('=').ok_or_else(invalid)?;
+            let number = number.parse::<u32>().map_err(|_| invalid())?;
+            match name {
+                "m" => params.memory_kib = number,
+                "t" => params.iterations = number,
+                "p" => params.parallelism = number,
+                _ => return Err(invalid()),
+            }
+        }
+        params.salt = from_hex(parts.next().ok_or_else(invalid)?)
+            .and_then(|salt| salt.try_into().ok())
+            .ok_or_else(invalid)?;
+        Ok(params)
+    }
+}
+
+impl MasterKey {
+    pub fn generate() -> Result<Self, SyncError> {
+        Ok(Self::from_bytes(random_bytes()?))
+    }
+
+    /// A key whose naming key is derived from `bytes`, as for a freshly generated key.
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self {
+            bytes,
+            naming: hkdf_subkey(&bytes, NAMING_INFO),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.bytes
+    }
+
+    pub(crate) fn naming_key(&self) -> &[u8; KEY_LEN] {
+        &self.naming
+    }
+
+    pub(crate) fn with_naming_key(mut self, naming: [u8; KEY_LEN]) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    /// A new random wrapping key with the same naming key, for key rotation.
+    pub fn rotated(&self) -> Result<Self, SyncError> {
+        Ok(Self::generate()?.with_naming_key(self.naming))
+    }
+
+    /// Derives the key with Argon2id, which is deliberately slow and memory-hard so a stolen
+    /// params record cannot be brute-forced cheaply.
+    pub fn derive_from_passphrase(passphrase: &str, params: &KdfParams) -> Result<Self, SyncError> {
+        let argon_params = Params::new(
+            params.memory_kib,
+            params.iterations,
+            params.parallelism,
+            Some(KEY_LEN),
+        )
+        .map_err(|err| SyncError::Crypto(format!("invalid kdf parameters: {err}")))?;
+        let mut bytes = [0_u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+            .hash_password_into(passphrase.as_bytes(), &params.salt, &mut bytes)
+            .map_err(|err| SyncError::Crypto(format!("key derivation failed: {err}")))?;
+        let key = Self::from_bytes(bytes);
+        bytes.zeroize();
+        Ok(key)
+    }
+
+    pub fn wrap_data_key(&self, key: &DataKey) -> Result<Vec<u8>, SyncError> {
+        seal(&self.bytes, WRAP_AAD, &key.bytes)
+    }
+
+    pub fn unwrap_data_key(&self, wrapped: &[u8]) -> Result<DataKey, SyncError> {
+        let mut plain = open(&self.bytes, WRAP_AAD, wrapped)?;
+        let bytes = plain
+            .as_slice()
+            .try_into()
+            .map_err(|_| SyncError::Crypto("wrapped data key has the wrong length".to_string()));
+        plain.zeroize();
+        Ok(DataKey { bytes: bytes? })
+    }
+
+    /// Keyed digest of a plaintext content hash. Sent instead of the raw hash so the server
+    /// can still spot unchanged files without being able to confirm guesses about content.
+    /// The tag key is an HKDF subkey of the naming key, so tags survive key rotation and the
+    /// wrapping key is only ever used to wrap.
+    pub fn content_tag(&self, plaintext_hash: &str) -> String {
+        let mut tag_key = hkdf_subkey(&self.naming, CONTENT_TAG_INFO);
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&tag_key)
+            .expect("HMAC accepts keys of any length");
+        tag_key.zeroize();
+        mac.update(plaintext_hash.as_bytes());
+        to_hex(&mac.finalize().into_bytes())
+    }
+}
+
+impl DataKey {
+    pub fn generate() -> Result<Self, SyncError> {
+        Ok(Self {
+            bytes: random_bytes()?,
+        })
+    }
+
+    /// Encrypts one chunk with XChaCha20-Poly1305. The nonce is the file nonce followed by
+    /// the chunk index, and the chunk's whole [`ChunkPosition`] is authenticated, so chunks
+    /// cannot be reordered, swapped between files, relabelled, or silently truncated.
+    pub fn encrypt_chunk(
+        &self,
+        file_nonce: &[u8; FILE_NONCE_LEN],
+        position: &ChunkPosition,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, SyncError> {
+        let cipher = XChaCha20Poly1305::new((&self.bytes).into());
+        cipher
+            .encrypt(
+                &chunk_nonce(file_nonce, position.index),
+                Payload {
+                    msg: plaintext,
+                    aad: &chunk_aad(position),
+                },
+            )
+            .map_err(|_| SyncError::Crypto("chunk encryption failed".to_string()))
+    }
+
+    pub fn decrypt_chunk(
+        &self,
+        file_nonce: &[u8; FILE_NONCE_LEN],
+        position: &ChunkPosition,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, SyncError> {
+        let cipher = XChaCha20Poly1305::new((&self.bytes).into());
+        cipher
+            .decrypt(
+                &chunk_nonce(file_nonce, position.index),
+                Payload {
+                    msg: ciphertext,
+                    aad: &chunk_aad(position),
+                },
+            )
+            .map_err(|_| {
+                SyncError::Crypto(format!("chunk {} failed authentication", position.index))
+            })
+    }
+}
+
+fn chunk_nonce(file_nonce: &[u8; FILE_NONCE_LEN], index: u64) -> XNonce {
+    let mut nonce = [0_u8; SEAL_NONCE_LEN];
+    nonce[..FILE_NONCE_LEN].copy_from_slice(file_nonce);
+    nonce[FILE_NONCE_LEN..].copy_from_slice(&index.to_be_bytes());
+    nonce.into()
+}
+
+fn chunk_aad(position: &ChunkPosition) -> Vec<u8> {
+    let mut aad = CHUNK_AAD.to_vec();
+    aad.extend_from_slice(&position.index.to_be_bytes());
+    aad.push(u8::from(position.last));
+    aad.extend_from_slice(&position.offset.to_be_bytes());
+    aad.extend_from_slice(&position.length.to_be_bytes());
+    aad.extend_from_slice(position.codec.as_str().as_bytes());
+    aad
+}
+
+fn hkdf_subkey(input: &[u8; KEY_LEN], info: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0_u8; KEY_LEN];
+    Hkdf::<Sha256>::new(None, input)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Encrypts `plaintext` under `key` with a random nonce, returning `nonce || ciphertext`.
+pub(crate) fn seal(
+    key: &[u8; KEY_LEN],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, SyncError> {
+    let nonce = random_bytes::<SEAL_NONCE_LEN>()?;
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let ciphertext = cipher
+        .encrypt(
+            &nonce.into(),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| SyncError::Crypto("encryption failed".to_string()))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub(crate) fn open(key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, SyncError> {
+    if sealed.len() < SEAL_NONCE_LEN {
+        return Err(SyncError::Crypto("sealed value is truncated".to_string()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(SEAL_NONCE_LEN);
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| SyncError::Crypto("wrong key or corrupted data".to_string()))
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N], SyncError> {
+    let mut bytes = [0_u8; N];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| SyncError::Crypto(format!("system randomness unavailable: {err}")))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+pub(crate) fn test_kdf_params() -> KdfParams {
+    // Cheap settings keep the test suite fast; production uses `KdfParams::generate`.
+    KdfParams {
+        salt: [9; 16],
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_derivation_is_deterministic_per_salt() {
+        let params = test_kdf_params();
+        let first = MasterKey::derive_from_passphrase("correct horse", &params)
+            .expect("derivation should succeed");
+        let second = MasterKey::derive_from_passphrase("correct horse", &params)
+            .expect("derivation should succeed");
+        let other_salt = MasterKey::derive_from_passphrase(
+            "correct horse",
+            &KdfParams {
+                salt: [1; 16],
+                ..params.clone()
+            },
+        )
+        .expect("derivation should succeed");
+
+        assert_eq!(first.as_bytes(), second.as_bytes());
+        assert_ne!(first.as_bytes(), other_salt.as_bytes());
+        assert_eq!(
+            KdfParams::decode(&params.encode()).expect("params should round trip"),
+            params
+        );
+    }
+
+    #[test]
+    fn data_keys_only_unwrap_under_the_master_key_that_wrapped_them() {
+        let master = MasterKey::generate().expect("master key should generate");
+        let other = MasterKey::generate().expect("master key should generate");
+        let data_key = DataKey::generate().expect("data key should generate");
+
+        let wrapped = master
+            .wrap_data_key(&data_key)
+            .expect("wrap should succeed");
+        let unwrapped = master
+            .unwrap_data_key(&wrapped)
+            .expect("unwrap should succeed");
+        assert_eq!(unwrapped.bytes, data_key.bytes);
+
+        assert!(matches!(
+            other.unwrap_data_key(&wrapped),
+            Err(SyncError::Crypto(_))
+        ));
+    }
+
+    fn position(index: u64, last: bool, offset: u64, length: u64) -> ChunkPosition {
+        ChunkPosition {
+            index,
+            last,
+            offset,
+            length,
+            codec: Codec::None,
+        }
+    }
+
+    #[test]
+    fn chunks_reject_tampering_reordering_and_truncation() {
+        let key = DataKey::generate().expect("data key should generate");
+        let nonce = [3_u8; FILE_NONCE_LEN];
+        let first_at = position(0, false, 0, 10);
+        let second_at = position(1, true, 10, 11);
+        let first = key
+            .encrypt_chunk(&nonce, &first_at, b"first half")
+            .expect("chunk 0 should encrypt");
+        let second = key
+            .encrypt_chunk(&nonce, &second_at, b"second half")
+            .expect("chunk 1 should encrypt");
+
+        assert_eq!(
+            key.decrypt_chunk(&nonce, &first_at, &first)
+                .expect("chunk 0 should decrypt"),
+            b"first half"
+        );
+
+        let mut tampered = second.clone();
+        tampered[0] ^= 1;
+        assert!(key.decrypt_chunk(&nonce, &second_at, &tampered).is_err());
+        assert!(key.decrypt_chunk(&nonce, &first_at, &second).is_err());
+        // Dropping the real final chunk leaves chunk 0 posing as last, which must fail.
+        assert!(key
+            .decrypt_chunk(&nonce, &position(0, true, 0, 10), &first)
+            .is_err());
+    }
+
+    #[test]
+    fn chunks_are_bound_to_their_offset_length_and_codec() {
+        let key = DataKey::generate().expect("data key should generate");
+        let nonce = [4_u8; FILE_NONCE_LEN];
+        let at = position(2, false, 8192, 4096);
+        let sealed = key
+            .encrypt_chunk(&nonce, &at, b"payload")
+            .expect("chunk should encrypt");
+
+        assert!(key.decrypt_chunk(&nonce, &at, &sealed).is_ok());
+        for moved in [
+            ChunkPosition { offset: 0, ..at },
+            ChunkPosition { length: 7, ..at },
+            ChunkPosition {
+                codec: Codec::Zstd,
+                ..at
+            },
+        ] {
+            assert!(key.decrypt_chunk(&nonce, &moved, &sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn content_tags_hide_the_plain_hash_but_stay_stable() {
+        let master = MasterKey::from_bytes([5; KEY_LEN]);
+        let tag = master.content_tag("00ff00ff00ff00ff");
+        assert_ne!(tag, "00ff00ff00ff00ff");
+        let mut raw_key_mac = <Hmac<Sha256> as Mac>::new_from_slice(master.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        raw_key_mac.update(b"00ff00ff00ff00ff");
+        assert_ne!(
+            tag,
+            to_hex(&raw_key_mac.finalize().into_bytes()),
+            "keyed by a derived subkey, not the master key"
+        );
+        assert_eq!(tag, master.content_tag("00ff00ff00ff00ff"));
+        assert_ne!(
+            tag,
+            MasterKey::from_bytes([6; KEY_LEN]).content_tag("00ff00ff00ff00ff")
+        );
+    }
+
+    #[test]
+    fn rotated_keys_wrap_differently_but_keep_tags() {
+        let master = MasterKey::generate().expect("master key should generate");
+        let rotated = master.rotated().expect("key should rotate");
+        assert_ne!(rotated.as_bytes(), master.as_bytes());
+        assert_eq!(rotated.content_tag("abc"), master.content_tag("abc"));
+
+        let wrapped = master
+            .wrap_data_key(&DataKey::generate().expect("data key should generate"))
+            .expect("data key should wrap");
+        assert!(rotated.unwrap_data_key(&wrapped).is_err());
+    }
+}
@@ -0,0 +1,1682 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use crate::logging::{self, LogLevel};
+use crate::metadata::to_hex;
+use crate::{
+    is_unsupported, load_dead_letters, load_isolated, load_queue, restore_included, set_log_level,
+    BandwidthLimiter, ChangeFeed, Config, ConfigOverrides, ControlServer, ControlState,
+    DeviceIdentity, HashCache, HttpTransport, MasterKey, PathIndex, Profile, Reconciler,
+    RemoteChange, RemoteChangeKind, Restorer, RootWatcher, SelectiveSync, SyncError, SyncManager,
+    SyncTransport, SyncedIndex, ThrottledTransport, CONTROL_SOCKET, PASSPHRASE_ENV,
+};
+
+/// How often the run loop wakes up to check for signals between scans and flushes.
+const POLL_TICK: Duration = Duration::from_millis(200);
+
+static OS_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+/// Counts SIGHUPs, so every daemon in the process sees each one.
+static OS_RELOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Shutdown and reload requests for the daemon loop. Requests come from OS signals once
+/// [`DaemonSignals::install`] has run, or from [`DaemonSignals::request_shutdown`] and
+/// [`DaemonSignals::request_reload`] (tests, the control socket).
+#[derive(Debug, Clone, Default)]
+pub struct DaemonSignals {
+    shutdown: Arc<AtomicBool>,
+    reloads: Arc<AtomicU64>,
+    /// Reload requests already taken through this handle and its clones.
+    reloads_taken: Arc<AtomicU64>,
+    os: bool,
+}
+
+impl DaemonSignals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes SIGTERM and SIGINT to shutdown and SIGHUP to reload.
+    #[cfg(unix)]
+    pub fn install() -> Result<Self, SyncError> {
+        extern "C" fn on_signal(signal: libc::c_int) {
+            if signal == libc::SIGHUP {
+                OS_RELOADS.fetch_add(1, Ordering::SeqCst);
+            } else {
+                OS_SHUTDOWN.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            // SAFETY: the handler only stores to atomics, which is async-signal-safe.
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(SyncError::Io(std::io::Error::last_os_error()));
+            }
+        }
+        let signals = Self {
+            os: true,
+            ..Self::default()
+        };
+        signals
+            .reloads_taken
+            .store(signals.reload_requests(), Ordering::SeqCst);
+        Ok(signals)
+    }
+
+    #[cfg(not(unix))]
+    pub fn install() -> Result<Self, SyncError> {
+        Ok(Self::default())
+    }
+
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    pub fn request_reload(&self) {
+        self.reloads.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A handle that shares shutdown and reload requests but takes reloads on its own, so
+    /// each of several daemons reloads once per request.
+    pub fn subscribe(&self) -> Self {
+        Self {
+            reloads_taken: Arc::new(AtomicU64::new(self.reload_requests())),
+            ..self.clone()
+        }
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst) || (self.os && OS_SHUTDOWN.load(Ordering::SeqCst))
+    }
+
+    /// Returns true once per handle when reloads were requested since the last call.
+    pub fn take_reload(&self) -> bool {
+        let requested = self.reload_requests();
+        self.reloads_taken.swap(requested, Ordering::SeqCst) != requested
+    }
+
+    fn reload_requests(&self) -> u64 {
+        let os = if self.os {
+            OS_RELOADS.load(Ordering::SeqCst)
+        } else {
+            0
+        };
+        self.reloads.load(Ordering::SeqCst) + os
+    }
+}
+
+/// Exclusive lock held for the daemon's lifetime so only one instance runs per state
+/// directory; a second one beside the config file allows one per user and profile. Locks
+/// are released when the process exits, even if it crashes.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+    _file: File,
+}
+
+impl LockFile {
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(SyncError::Io)?;
+        }
+        let mut file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(SyncError::Io)?;
+        lock_exclusive(&file).map_err(|err| {
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                SyncError::InvalidConfig(format!(
+                    "another rust-client daemon holds {}",
+                    path.display()
+                ))
+            } else {
+                SyncError::Io(err)
+            }
+        })?;
+        file.set_len(0).map_err(SyncError::Io)?;
+        writeln!(file, "{}", std::process::id()).map_err(SyncError::Io)?;
+        Ok(Self { path, _file: file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Pid of the process holding the lock at `path`, without taking it; `None` when free.
+    pub fn holder<P: AsRef<Path>>(path: P) -> Option<u32> {
+        let file = File::open(path.as_ref()).ok()?;
+        match lock_exclusive(&file) {
+            Ok(()) => None,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                let pid = fs::read_to_string(path.as_ref()).ok()?;
+                Some(pid.trim().parse().unwrap_or(0))
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor stays open for the duration of the call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+    }
+    Err(err)
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Exponential delay between flushes while the server keeps failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: None,
+        }
+    }
+
+    /// Records a failed round and returns the delay before the next one.
+    pub fn failure(&mut self) -> Duration {
+        let next = match self.current {
+            Some(delay) => (delay * 2).min(self.max),
+            None => self.base.min(self.max),
+        };
+        self.current = Some(next);
+        next
+    }
+
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+/// Builds the transport for a config; called at start and on every reload. The limiter is
+/// shared with the daemon so reloads can change limits without reconnecting.
+type Connect<T> = Box<dyn Fn(&Config, &BandwidthLimiter) -> Result<T, SyncError> + Send>;
+
+/// Long-running sync loop: watches the configured roots, queues what changes, and flushes
+/// on an interval. The queue and watcher state are persisted in the state directory so a
+/// restart picks up where the previous run stopped.
+pub struct Daemon<T: SyncTransport> {
+    config_path: Option<PathBuf>,
+    /// Set when started for a profile, whose default state directory reloads keep.
+    profile: Option<Profile>,
+    overrides: ConfigOverrides,
+    config: Config,
+    connect: Connect<T>,
+    limiter: BandwidthLimiter,
+    /// Unlocked when the config turns encryption on.
+    master: Option<MasterKey>,
+    manager: SyncManager<T>,
+    /// The server's change feed; `None` once the server turned out not to offer one.
+    changes: Option<ChangeFeed<T>>,
+    /// Spaces out reads of a change feed that keeps failing.
+    feed_backoff: Backoff,
+    next_feed_poll: Instant,
+    /// Applies remote changes locally when bidirectional sync is on.
+    reconciler: Option<Reconciler<T>>,
+    /// Server paths whose last reconciliation failed, retried on the next poll.
+    unreconciled: BTreeSet<String>,
+    /// Set when excluded subtrees may still hold local files.
+    cleanup_pending: bool,
+    watchers: Vec<RootWatcher>,
+    backoff: Backoff,
+    control: ControlState,
+    /// Declared before `lock` so the socket is removed before the lock is released.
+    _control_server: Option<ControlServer>,
+    lock: LockFile,
+    /// Held when started from a config file, so a second daemon for the same user and
+    /// profile is refused even with another state directory.
+    _user_lock: Option<LockFile>,
+}
+
+impl Daemon<ThrottledTransport<HttpTransport>> {
+    /// Loads the config file with `overrides` on top and prepares a daemon that talks HTTP
+    /// to the configured server. Reloads apply the same overrides. Every request carries the
+    /// device id from the state directory, created on first start.
+    pub fn start<P: AsRef<Path>>(
+        config_path: P,
+        overrides: ConfigOverrides,
+    ) -> Result<Self, SyncError> {
+        let user_lock = LockFile::acquire(user_lock_path(config_path.as_ref()))?;
+        let config = Config::load_with(config_path.as_ref(), &overrides)?;
+        let mut daemon = Self::over_http(config)?;
+        daemon._user_lock = Some(user_lock);
+        daemon.config_path = Some(config_path.as_ref().to_path_buf());
+        daemon.overrides = overrides;
+        Ok(daemon)
+    }
+
+    /// Like [`Daemon::start`] with the profile's config file and state directory.
+    pub fn start_profile(profile: &Profile, overrides: ConfigOverrides) -> Result<Self, SyncError> {
+        let user_lock = LockFile::acquire(user_lock_path(profile.config_path()))?;
+        let config = profile.load(&overrides)?;
+        let mut daemon = Self::over_http(config)?;
+        daemon._user_lock = Some(user_lock);
+        daemon.config_path = Some(profile.config_path().to_path_buf());
+        daemon.profile = Some(profile.clone());
+        daemon.overrides = overrides;
+        Ok(daemon)
+    }
+
+    fn over_http(config: Config) -> Result<Self, SyncError> {
+        let connect = |config: &Config, limiter: &BandwidthLimiter| {
+            let device = DeviceIdentity::load_or_create(&config.state_dir)?;
+            let mut transport = HttpTransport::new(&config.server)?.with_device(device.id());
+            if let Some(token) = &config.token {
+                transport = transport.with_token(token);
+            }
+            Ok(ThrottledTransport::new(transport, limiter.clone()))
+        };
+        Self::with_transport(config, Box::new(connect))
+    }
+}
+
+/// Lock beside the config file, e.g. `~/.config/rust-client/config.lock` or
+/// `profiles/<name>.lock`. The config directory belongs to one user and each profile has its
+/// own file, so this allows one daemon per user and profile whatever `state_dir` says.
+fn user_lock_path(config_path: &Path) -> PathBuf {
+    config_path.with_extension("lock")
+}
+
+/// Runs a daemon per profile, each on its own thread with its own state directory, lock,
+/// control socket and log label, until a shutdown is requested. All profiles start before
+/// any runs; one that fails while running does not stop the others.
+pub fn run_profiles(
+    profiles: &[Profile],
+    overrides: ConfigOverrides,
+    signals: &DaemonSignals,
+) -> Result<(), SyncError> {
+    load_isolated(profiles, &overrides)?;
+    let mut daemons = Vec::new();
+    for profile in profiles {
+        logging::set_thread_profile(Some(profile.name()));
+        let started = Daemon::start_profile(profile, overrides.clone());
+        if let Err(err) = &started {
+            log(LogLevel::Error, &format!("cannot start: {err}"));
+        }
+        logging::set_thread_profile(None);
+        daemons.push(started?);
+    }
+
+    let results = thread::scope(|scope| {
+        let handles = daemons
+            .into_iter()
+            .zip(profiles)
+            .map(|(mut daemon, profile)| {
+                let signals = signals.subscribe();
+                scope.spawn(move || {
+                    logging::set_thread_profile(Some(profile.name()));
+                    set_log_level(daemon.config.log_level);
+                    let result = daemon.run(&signals);
+                    if let Err(err) = &result {
+                        log(LogLevel::Error, &format!("stopped with an error: {err}"));
+                    }
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>()
+    });
+    results.into_iter().collect()
+}
+
+impl<T: SyncTransport> Daemon<T> {
+    pub fn with_transport(config: Config, connect: Connect<T>) -> Result<Self, SyncError> {
+        fs::create_dir_all(&config.state_dir).map_err(SyncError::Io)?;
+        let lock = LockFile::acquire(config.state_dir.join("daemon.lock"))?;
+        set_log_level(config.log_level);
+        let limiter = BandwidthLimiter::new(config.bandwidth.clone());
+        let master = unlock(&config)?;
+        let manager = build_manager(&config, &connect, &limiter, master.as_ref())?;
+        let changes = build_change_feed(&config, &connect, &limiter)?;
+        let reconciler = build_reconciler(&config, &connect, &limiter, master.clone())?;
+        let watchers = build_watchers(&config)?;
+        let control = ControlState::new();
+        #[cfg(unix)]
+        let control_server = Some(ControlServer::bind(
+            config.state_dir.join(CONTROL_SOCKET),
+            control.clone(),
+        )?);
+        #[cfg(not(unix))]
+        let control_server = None;
+        let mut daemon = Self {
+            config_path: None,
+            profile: None,
+            overrides: ConfigOverrides::default(),
+            backoff: Backoff::new(config.retry.initial_backoff, config.retry.max_backoff),
+            feed_backoff: Backoff::new(config.retry.initial_backoff, config.retry.max_backoff),
+            next_feed_poll: Instant::now(),
+            config,
+            connect,
+            limiter,
+            master,
+            manager,
+            changes: Some(changes),
+            reconciler,
+            unreconciled: BTreeSet::new(),
+            cleanup_pending: false,
+            watchers,
+            control,
+            _control_server: control_server,
+            lock,
+            _user_lock: None,
+        };
+        daemon.apply_selective_sync();
+        daemon.publish();
+        Ok(daemon)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn manager(&self) -> &SyncManager<T> {
+        &self.manager
+    }
+
+    /// Status and requests shared with the control socket.
+    pub fn control(&self) -> &ControlState {
+        &self.control
+    }
+
+    /// Shared upload limiter; changes apply to the next request.
+    pub fn limiter(&self) -> &BandwidthLimiter {
+        &self.limiter
+    }
+
+    pub fn lock_file(&self) -> &Path {
+        self.lock.path()
+    }
+
+    /// Runs until a shutdown is requested, then finishes the request in flight and
+    /// persists the queue before returning.
+    pub fn run(&mut self, signals: &DaemonSignals) -> Result<(), SyncError> {
+        log(
+            LogLevel::Info,
+            &format!(
+                "started for {} root(s), server {}",
+                self.watchers.len(),
+                self.config.server
+            ),
+        );
+        let mut next_scan = Instant::now();
+        let mut next_flush = Instant::now();
+
+        while !signals.shutdown_requested() {
+            if signals.take_reload() {
+                self.reload();
+                next_scan = Instant::now();
+            }
+            if self.control.take_sync_now() {
+                self.backoff.reset();
+                next_scan = Instant::now();
+                next_flush = Instant::now();
+            }
+
+            if Instant::now() >= next_scan {
+                self.scan_roots();
+                next_scan = Instant::now() + self.config.scan_interval;
+            }
+            if Instant::now() >= next_flush {
+                let delay = self.flush(signals);
+                next_flush = Instant::now() + delay;
+            }
+
+            let until_next = next_scan
+                .min(next_flush)
+                .saturating_duration_since(Instant::now());
+            thread::sleep(until_next.min(POLL_TICK));
+        }
+
+        self.persist()?;
+        log(
+            LogLevel::Info,
+            &format!(
+                "stopped with {} request(s) queued",
+                self.manager.pending_count()
+            ),
+        );
+        Ok(())
+    }
+
+    /// Re-reads the config file. An invalid file is reported and the old config kept.
+    pub fn reload(&mut self) {
+        let Some(config_path) = &self.config_path else {
+            return;
+        };
+        let loaded = match &self.profile {
+            Some(profile) => profile.load(&self.overrides),
+            None => Config::load_with(config_path, &self.overrides),
+        };
+        let config = match loaded {
+            Ok(config) => config,
+            Err(err) => {
+                log(LogLevel::Error, &format!("keeping previous config: {err}"));
+                return;
+            }
+        };
+        if config.state_dir != self.config.state_dir {
+            log(
+                LogLevel::Warn,
+                "state_dir changes take effect after a restart",
+            );
+        }
+        let config = Config {
+            state_dir: self.config.state_dir.clone(),
+            ..config
+        };
+
+        if let Err(err) = self.apply(config) {
+            log(
+                LogLevel::Error,
+                &format!("reload failed, keeping previous config: {err}"),
+            );
+            return;
+        }
+        log(LogLevel::Info, "config reloaded");
+    }
+
+    fn apply(&mut self, config: Config) -> Result<(), SyncError> {
+        self.persist()?;
+        let master = unlock(&config)?;
+        let manager = build_manager(&config, &self.connect, &self.limiter, master.as_ref())?;
+        let changes = build_change_feed(&config, &self.connect, &self.limiter)?;
+        let reconciler = build_reconciler(&config, &self.connect, &self.limiter, master.clone())?;
+        let watchers = build_watchers(&config)?;
+        self.master = master;
+        self.manager = manager;
+        self.changes = Some(changes);
+        self.reconciler = reconciler;
+        self.watchers = watchers;
+        self.backoff = Backoff::new(config.retry.initial_backoff, config.retry.max_backoff);
+        self.feed_backoff = Backoff::new(config.retry.initial_backoff, config.retry.max_backoff);
+        self.next_feed_poll = Instant::now();
+        self.publish();
+        self.limiter.set_schedule(config.bandwidth.clone());
+        set_log_level(config.log_level);
+        self.config = config;
+        self.apply_selective_sync();
+        Ok(())
+    }
+
+    /// Downloads what the selective sync rules include again since they were last applied,
+    /// then records them as applied and schedules cleanup of excluded subtrees. A failed
+    /// download leaves the old rules recorded, so it is retried on the next start or reload.
+    fn apply_selective_sync(&mut self) {
+        let applied_path = self.config.state_dir.join("selective-sync");
+        let previous = SelectiveSync::load(&applied_path).unwrap_or_else(|err| {
+            log(
+                LogLevel::Warn,
+                &format!("ignoring previously applied selective sync rules: {err}"),
+            );
+            SelectiveSync::new()
+        });
+        self.cleanup_pending = self.config.selective.excluded_paths().next().is_some();
+        if previous == self.config.selective {
+            return;
+        }
+        let manager = &mut self.manager;
+        let restored = (self.connect)(&self.config, &self.limiter).and_then(|transport| {
+            let mut restorer = Restorer::new(transport);
+            if let Some(master) = &self.master {
+                restorer = restorer.with_master_key(master.clone());
+            }
+            restore_included(&mut restorer, manager, &previous)
+        });
+        match restored {
+            Ok(report) if report.failed.is_empty() => {
+                if report.restored > 0 {
+                    log(
+                        LogLevel::Info,
+                        &format!(
+                            "downloaded {} file(s) selective sync includes again",
+                            report.restored
+                        ),
+                    );
+                }
+                if let Err(err) = self.config.selective.save(&applied_path) {
+                    log(
+                        LogLevel::Error,
+                        &format!("cannot record selective sync rules: {err}"),
+                    );
+                }
+            }
+            Ok(report) => {
+                for (server_path, err) in report.failed {
+                    log(
+                        LogLevel::Warn,
+                        &format!("cannot download included {server_path}: {err}"),
+                    );
+                }
+            }
+            Err(err) => log(
+                LogLevel::Warn,
+                &format!("cannot download newly included paths: {err}"),
+            ),
+        }
+    }
+
+    fn scan_roots(&mut self) {
+        for watcher in &mut self.watchers {
+            let changes = match watcher.scan() {
+                Ok(changes) => changes,
+                Err(err) => {
+                    log(
+                        LogLevel::Warn,
+                        &format!("cannot scan {}: {err}", watcher.root().display()),
+                    );
+                    continue;
+                }
+            };
+            for file_path in &changes.removed {
+                self.manager.forget(file_path);
+            }
+            for file_path in changes.changed {
+                if !self.manager.selective_sync().includes(&file_path) {
+                    // Reported again next scan, so the file is picked up if it is included later.
+                    watcher.forget(&file_path);
+                    continue;
+                }
+                match self.manager.queue_file(&file_path) {
+                    Ok(_) | Err(SyncError::FileUnstable(_)) => {}
+                    Err(err) => {
+                        log(
+                            LogLevel::Warn,
+                            &format!("cannot queue {}: {err}", file_path.display()),
+                        );
+                        watcher.forget(&file_path);
+                    }
+                }
+            }
+        }
+        if let Err(err) = self.persist() {
+            log(LogLevel::Error, &format!("cannot persist state: {err}"));
+        }
+        self.publish();
+    }
+
+    /// Flushes once and returns the delay until the next flush. Once everything is sent,
+    /// commits a snapshot if the synced tree changed and cleans up excluded subtrees.
+    fn flush(&mut self, signals: &DaemonSignals) -> Duration {
+        if self.control.is_paused() {
+            return self.config.flush_interval;
+        }
+        self.poll_changes();
+        if self.manager.pending_count() == 0 && self.manager.deferred_paths().is_empty() {
+            self.commit_snapshot();
+            self.clean_excluded();
+            return self.config.flush_interval;
+        }
+
+        let control = self.control.clone();
+        let report = self.manager.flush_while(|request| {
+            if signals.shutdown_requested() || control.is_paused() {
+                return false;
+            }
+            control.update(|status| status.in_flight = vec![request.path.clone()]);
+            true
+        });
+        self.control.update(|status| {
+            status.in_flight.clear();
+            if report.succeeded > 0 {
+                status.last_success = Some(SystemTime::now());
+            }
+        });
+        self.publish();
+        if let Err(err) = self.save_queues() {
+            log(LogLevel::Error, &format!("cannot persist queue: {err}"));
+        }
+        if report.failed > 0 && report.succeeded == 0 {
+            let delay = self.backoff.failure();
+            log(
+                LogLevel::Warn,
+                &format!(
+                    "flush failed for {} request(s); retrying in {}s",
+                    report.failed,
+                    delay.as_secs_f32()
+                ),
+            );
+            delay
+        } else {
+            self.backoff.reset();
+            if report.succeeded > 0 || report.failed > 0 {
+                log(
+                    LogLevel::Debug,
+                    &format!(
+                        "flushed {} request(s), {} failed, {} remaining",
+                        report.succeeded, report.failed, report.remaining
+                    ),
+                );
+            }
+            self.commit_snapshot();
+            self.clean_excluded();
+            self.config.flush_interval
+        }
+    }
+
+    /// Reads the change feed. With bidirectional sync the changed paths are reconciled;
+    /// otherwise synced paths that another device changed or deleted on the server are
+    /// reported. Only the last change per path counts, so this client's own uploads of a
+    /// file it has since changed again are not reported. A feed that fails is read again
+    /// after a backoff; only a server without one stops the polling.
+    fn poll_changes(&mut self) {
+        let Some(feed) = &mut self.changes else {
+            return;
+        };
+        let mut latest = BTreeMap::new();
+        if Instant::now() >= self.next_feed_poll {
+            let mut failed = false;
+            for change in feed.events() {
+                match change {
+                    Ok(change) => {
+                        latest.insert(change.path.clone(), change);
+                    }
+                    Err(err) if is_unsupported(&err) => {
+                        log(
+                            LogLevel::Info,
+                            "the server has no change feed; not watching for remote changes",
+                        );
+                        self.changes = None;
+                        return;
+                    }
+                    Err(err) => {
+                        let delay = self.feed_backoff.failure();
+                        self.next_feed_poll = Instant::now() + delay;
+                        log(
+                            LogLevel::Warn,
+                            &format!(
+                                "cannot read the change feed: {err}; retrying in {}s",
+                                delay.as_secs_f32()
+                            ),
+                        );
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if !failed {
+                self.feed_backoff.reset();
+            }
+        }
+        if let Some(reconciler) = &mut self.reconciler {
+            let mut paths = std::mem::take(&mut self.unreconciled);
+            paths.extend(latest.into_keys());
+            let report = reconciler.reconcile(&mut self.manager, paths.iter().map(String::as_str));
+            for local_path in &report.downloaded {
+                log(
+                    LogLevel::Info,
+                    &format!("downloaded {}", local_path.display()),
+                );
+            }
+            for local_path in &report.deleted {
+                log(
+                    LogLevel::Info,
+                    &format!("deleted {} (deleted on the server)", local_path.display()),
+                );
+            }
+            for (local_path, copy) in &report.conflicts {
+                log(
+                    LogLevel::Warn,
+                    &format!(
+                    "{} was changed here and on another device; kept this device's version as {}",
+                    local_path.display(),
+                    copy.display()
+                ),
+                );
+            }
+            for (server_path, err) in report.failed {
+                log(
+                    LogLevel::Warn,
+                    &format!("cannot reconcile {server_path}: {err}"),
+                );
+                self.unreconciled.insert(server_path);
+            }
+            self.publish();
+            return;
+        }
+        let Some(synced) = self.manager.synced_index() else {
+            return;
+        };
+        for change in latest.values().filter(|change| is_foreign(change, synced)) {
+            log(
+                LogLevel::Warn,
+                &format!(
+                    "{} was {} on the server from another device",
+                    change.path,
+                    match change.kind {
+                        RemoteChangeKind::Updated => "changed",
+                        RemoteChangeKind::Deleted => "deleted",
+                    },
+                ),
+            );
+        }
+    }
+
+    fn commit_snapshot(&mut self) {
+        match self.manager.commit_snapshot() {
+            Ok(Some(snapshot)) => log(
+                LogLevel::Info,
+                &format!(
+                    "committed snapshot {} ({} files)",
+                    snapshot.id, snapshot.files
+                ),
+            ),
+            Ok(None) => {}
+            Err(err) => log(LogLevel::Warn, &format!("cannot commit snapshot: {err}")),
+        }
+    }
+
+    /// Removes local copies under excluded subtrees once nothing is left to send.
+    fn clean_excluded(&mut self) {
+        if !self.cleanup_pending || self.manager.pending_count() > 0 {
+            return;
+        }
+        self.cleanup_pending = false;
+        match self.manager.clean_excluded() {
+            Ok(cleanup) => {
+                if !cleanup.removed.is_empty() {
+                    log(
+                        LogLevel::Info,
+                        &format!(
+                            "removed {} excluded file(s) from this device",
+                            cleanup.removed.len()
+                        ),
+                    );
+                }
+                for file_path in &cleanup.kept {
+                    log(
+                        LogLevel::Warn,
+                        &format!(
+                            "kept excluded {}: the server does not hold its latest content",
+                            file_path.display()
+                        ),
+                    );
+                }
+            }
+            Err(err) => log(
+                LogLevel::Warn,
+                &format!("cannot clean up excluded paths: {err}"),
+            ),
+        }
+    }
+
+    /// Copies queue depth and dead letters into the state the control socket reports.
+    fn publish(&self) {
+        let queued = self.manager.pending_count();
+        let deferred = self.manager.deferred_paths().len();
+        let dead_letters = self.manager.dead_letters().to_vec();
+        self.control.update(|status| {
+            status.queued = queued;
+            status.deferred = deferred;
+            status.dead_letters = dead_letters;
+        });
+    }
+
+    fn save_queues(&self) -> Result<(), SyncError> {
+        self.manager.save_queue(self.queue_path())?;
+        self.manager
+            .save_dead_letters(self.config.state_dir.join("dead-letters"))?;
+        self.manager.save_synced_index()
+    }
+
+    fn persist(&mut self) -> Result<(), SyncError> {
+        self.save_queues()?;
+        self.manager.save_hash_cache()?;
+        for watcher in &self.watchers {
+            watcher.save_except(self.manager.deferred_paths())?;
+        }
+        Ok(())
+    }
+
+    fn queue_path(&self) -> PathBuf {
+        self.config.state_dir.join("queue")
+    }
+}
+
+/// The master key for an encrypted config. The daemon cannot prompt, so the passphrase has
+/// to come from `encryption.passphrase_file` or the environment.
+fn unlock(config: &Config) -> Result<Option<MasterKey>, SyncError> {
+    config.master_key(|| {
+        Err(SyncError::InvalidConfig(format!(
+            "encryption is on; set `encryption.passphrase_file` or {PASSPHRASE_ENV} for the daemon"
+        )))
+    })
+}
+
+fn build_manager<T: SyncTransport>(
+    config: &Config,
+    connect: &Connect<T>,
+    limiter: &BandwidthLimiter,
+    master: Option<&MasterKey>,
+) -> Result<SyncManager<T>, SyncError> {
+    let queue = load_queue(config.state_dir.join("queue"))?;
+    let dead_letters = load_dead_letters(config.state_dir.join("dead-letters"))?;
+    let mut hash_cache = HashCache::open(config.state_dir.join("hash-cache"))?;
+    hash_cache.set_paranoid(config.hashing.paranoid);
+    let synced = SyncedIndex::open(config.state_dir.join("synced-index"))?;
+    let mut manager = SyncManager::from_snapshot(connect(config, limiter)?, queue)
+        .with_hash_cache(hash_cache)
+        .with_hash_budget(config.hashing.budget())
+        .with_snapshots(synced)
+        .with_max_attempts(config.retry.max_attempts)
+        .with_dead_letters(dead_letters)
+        .with_selective_sync(config.selective.clone());
+    if config.bidirectional {
+        manager = manager.with_versions(DeviceIdentity::load_or_create(&config.state_dir)?.id());
+    }
+    let ciphers = config.path_ciphers(master);
+    if !ciphers.is_empty() {
+        let index = PathIndex::open(config.state_dir.join("path-index"))?;
+        for cipher in ciphers {
+            manager = manager.with_path_encryption(cipher, index.clone());
+        }
+    }
+    Ok(match config.content.options(master) {
+        Some(options) => manager.with_content_upload(options),
+        None => manager,
+    })
+}
+
+fn build_change_feed<T: SyncTransport>(
+    config: &Config,
+    connect: &Connect<T>,
+    limiter: &BandwidthLimiter,
+) -> Result<ChangeFeed<T>, SyncError> {
+    ChangeFeed::new(connect(config, limiter)?)
+        .with_cursor_file(config.state_dir.join("change-cursor"))
+}
+
+fn build_reconciler<T: SyncTransport>(
+    config: &Config,
+    connect: &Connect<T>,
+    limiter: &BandwidthLimiter,
+    master: Option<MasterKey>,
+) -> Result<Option<Reconciler<T>>, SyncError> {
+    if !config.bidirectional {
+        return Ok(None);
+    }
+    let device = DeviceIdentity::load_or_create(&config.state_dir)?;
+    let mut reconciler = Reconciler::new(connect(config, limiter)?, device.id(), &config.roots);
+    for cipher in config.path_ciphers(master.as_ref()) {
+        reconciler = reconciler.with_path_encryption(cipher);
+    }
+    Ok(Some(match master {
+        Some(master) => reconciler.with_master_key(master),
+        None => reconciler,
+    }))
+}
+
+/// Whether a remote change disagrees with what this client last synced for the path.
+fn is_foreign(change: &RemoteChange, synced: &SyncedIndex) -> bool {
+    let Some(record) = synced.get(&change.path) else {
+        return false;
+    };
+    match change.kind {
+        RemoteChangeKind::Updated => change.hash.as_deref() != Some(record.hash.as_str()),
+        RemoteChangeKind::Deleted => true,
+    }
+}
+
+fn build_watchers(config: &Config) -> Result<Vec<RootWatcher>, SyncError> {
+    config
+        .roots
+        .iter()
+        .map(|root| {
+            let digest = Sha256::digest(root.to_string_lossy().as_bytes());
+            let state_file = config
+                .state_dir
+                .join(format!("watch-{}.state", to_hex(&digest[..8])));
+            RootWatcher::new(root)
+                .with_ignore(config.ignore.clone())
+                .with_state_file(state_file)
+        })
+        .collect()
+}
+
+fn log(level: LogLevel, message: &str) {
+    logging::log("daemon", level, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{foreign_edit, temp_dir, MemoryRemote};
+    use crate::{
+        BandwidthSchedule, ChangePage, ContentDecoder, ContentManifest, ContentSettings,
+        IgnoreRules, KeyStore, RetryPolicy, Snapshot, StabilityPolicy, SyncRequest, VersionVector,
+    };
+    use std::sync::Mutex;
+    use std::time::UNIX_EPOCH;
+
+    #[derive(Clone, Default)]
+    struct SharedServer {
+        received: Arc<Mutex<Vec<String>>>,
+        failing: Arc<AtomicBool>,
+        /// File count of each committed snapshot.
+        snapshots: Arc<Mutex<Vec<usize>>>,
+        /// The change feed, when the server offers one; the cursor is an index into it.
+        feed: Option<Arc<Mutex<Vec<RemoteChange>>>>,
+        /// Makes the feed answer with a page that does not parse.
+        feed_broken: Arc<AtomicBool>,
+    }
+
+    impl SharedServer {
+        fn received(&self) -> Vec<String> {
+            self.received.lock().expect("server lock").clone()
+        }
+
+        fn snapshots(&self) -> Vec<usize> {
+            self.snapshots.lock().expect("server lock").clone()
+        }
+    }
+
+    impl SyncTransport for SharedServer {
+        fn health_check(&mut self) -> Result<bool, SyncError> {
+            Ok(true)
+        }
+
+        fn sync_file(&mut self, req: &SyncRequest) -> Result<(), SyncError> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(SyncError::Server(503, "unavailable".to_string()));
+            }
+            self.received
+                .lock()
+                .expect("server lock")
+                .push(req.path.clone());
+            if let Some(feed) = &self.feed {
+                feed.lock().expect("server lock").push(RemoteChange {
+                    kind: RemoteChangeKind::Updated,
+                    path: req.path.clone(),
+                    hash: Some(req.hash.clone()),
+                    device: None,
+                    time: UNIX_EPOCH,
+                });
+            }
+            Ok(())
+        }
+
+        fn changes(&mut self, cursor: Option<&str>) -> Result<ChangePage, SyncError> {
+            let Some(feed) = &self.feed else {
+                return Err(SyncError::Server(404, "not found".to_string()));
+            };
+            if self.feed_broken.load(Ordering::SeqCst) {
+                return Err(SyncError::Protocol("malformed change page".to_string()));
+            }
+            let feed = feed.lock().expect("server lock");
+            let start = cursor.map_or(0, |cursor| cursor.parse().expect("cursor is an index"));
+            Ok(ChangePage {
+                changes: feed[start..].to_vec(),
+                cursor: feed.len().to_string(),
+                more: false,
+            })
+        }
+
+        fn put_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), SyncError> {
+            self.snapshots
+                .lock()
+                .expect("server lock")
+                .push(snapshot.files.len());
+            Ok(())
+        }
+    }
+
+    fn config_for(dir: &Path, roots: &[PathBuf]) -> Config {
+        Config {
+            server: "http://127.0.0.1:1".to_string(),
+            token: None,
+            roots: roots.to_vec(),
+            ignore: IgnoreRules::default(),
+            state_dir: dir.join("state"),
+            log_level: LogLevel::Info,
+            flush_interval: Duration::from_millis(20),
+            scan_interval: Duration::from_millis(20),
+            bidirectional: false,
+            selective: SelectiveSync::new(),
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(20),
+                max_backoff: Duration::from_millis(80),
+                max_attempts: 10,
+            },
+            bandwidth: BandwidthSchedule::default(),
+            content: ContentSettings::default(),
+            encryption: Default::default(),
+            hashing: Default::default(),
+            restore: Default::default(),
+        }
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not reached in time");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn daemon(config: Config, server: &SharedServer) -> Daemon<SharedServer> {
+        let server = server.clone();
+        Daemon::with_transport(
+            config,
+            Box::new(move |_: &Config, _: &BandwidthLimiter| Ok(server.clone())),
+        )
+        .expect("daemon should start")
+    }
+
+    #[test]
+    fn syncs_new_files_until_shut_down() {
+        let dir = temp_dir("daemon-sync");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("first.txt"), "one").expect("file should be written");
+        let server = SharedServer::default();
+        let signals = DaemonSignals::new();
+        let mut daemon = daemon(config_for(&dir, std::slice::from_ref(&root)), &server);
+
+        thread::scope(|scope| {
+            scope.spawn(|| daemon.run(&signals).expect("daemon should run"));
+            wait_until(|| server.received().len() == 1);
+            fs::write(root.join("second.txt"), "two").expect("file should be written");
+            wait_until(|| server.received().len() == 2);
+            wait_until(|| server.snapshots().last() == Some(&2));
+            fs::remove_file(root.join("first.txt")).expect("file should be removed");
+            wait_until(|| server.snapshots().last() == Some(&1));
+            signals.request_shutdown();
+        });
+
+        let received = server.received();
+        assert!(received[0].ends_with("first.txt"));
+        assert!(received[1].ends_with("second.txt"));
+        // An unchanged tree is never committed twice in a row.
+        assert!(server.snapshots().windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn follows_the_change_feed_and_drops_it_when_the_server_has_none() {
+        let dir = temp_dir("daemon-changes");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("shared.txt"), "one").expect("file should be written");
+        let config = config_for(&dir, std::slice::from_ref(&root));
+        let server = SharedServer {
+            feed: Some(Arc::default()),
+            ..SharedServer::default()
+        };
+        let cursor_file = config.state_dir.join("change-cursor");
+
+        let signals = DaemonSignals::new();
+        let mut first = daemon(config.clone(), &server);
+        thread::scope(|scope| {
+            scope.spawn(|| first.run(&signals).expect("daemon should run"));
+            wait_until(|| {
+                fs::read_to_string(&cursor_file).is_ok_and(|text| text.contains("cursor=1"))
+            });
+            signals.request_shutdown();
+        });
+        assert!(first.changes.is_some());
+        drop(first);
+
+        let mut second = daemon(config, &SharedServer::default());
+        second.flush(&DaemonSignals::new());
+        assert!(
+            second.changes.is_none(),
+            "a server without a feed is not polled again"
+        );
+    }
+
+    #[test]
+    fn keeps_polling_a_failing_change_feed_after_a_backoff() {
+        let dir = temp_dir("daemon-feed-backoff");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        let config = config_for(&dir, std::slice::from_ref(&root));
+        let server = SharedServer {
+            feed: Some(Arc::default()),
+            ..SharedServer::default()
+        };
+        let cursor_file = config.state_dir.join("change-cursor");
+        let mut daemon = daemon(config, &server);
+
+        server.feed_broken.store(true, Ordering::SeqCst);
+        daemon.flush(&DaemonSignals::new());
+        assert!(
+            daemon.changes.is_some(),
+            "a malformed page does not turn the feed off"
+        );
+        assert!(daemon.next_feed_poll > Instant::now());
+
+        server.feed_broken.store(false, Ordering::SeqCst);
+        fs::write(root.join("late.txt"), "late").expect("file should be written");
+        daemon.scan_roots();
+        daemon.flush(&DaemonSignals::new());
+        assert!(
+            !cursor_file.exists(),
+            "the feed is not read again before the backoff ends"
+        );
+
+        thread::sleep(
+            daemon
+                .next_feed_poll
+                .saturating_duration_since(Instant::now()),
+        );
+        daemon.flush(&DaemonSignals::new());
+        assert!(fs::read_to_string(&cursor_file).is_ok_and(|text| text.contains("cursor=1")));
+        assert_eq!(
+            daemon.feed_backoff.failure(),
+            Duration::from_millis(20),
+            "a successful read resets the backoff"
+        );
+    }
+
+    #[test]
+    fn bidirectional_mode_downloads_remote_edits_without_sending_them_back() {
+        let dir = temp_dir("daemon-bidirectional");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("a.txt"), "laptop").expect("file should be written");
+        let config = Config {
+            bidirectional: true,
+            content: ContentSettings {
+                upload: true,
+                ..ContentSettings::default()
+            },
+            ..config_for(&dir, std::slice::from_ref(&root))
+        };
+        let remote = MemoryRemote::default();
+        let server = remote.clone();
+        let signals = DaemonSignals::new();
+        let mut daemon = Daemon::with_transport(
+            config.clone(),
+            Box::new(move |_: &Config, _: &BandwidthLimiter| Ok(server.clone())),
+        )
+        .expect("daemon should start");
+        let device =
+            fs::read_to_string(config.state_dir.join("device-id")).expect("device id should exist");
+        let a = root.join("a.txt").to_string_lossy().to_string();
+        let version = |path: &str| {
+            remote
+                .state()
+                .files
+                .get(path)
+                .and_then(|record| record.version.as_ref().map(ToString::to_string))
+        };
+
+        thread::scope(|scope| {
+            scope.spawn(|| daemon.run(&signals).expect("daemon should run"));
+            wait_until(|| version(&a).is_some());
+            assert_eq!(version(&a), Some(format!("{}:1", device.trim())));
+            foreign_edit(
+                &remote,
+                &root.join("a.txt"),
+                "desk",
+                &format!("desk:1,{}:1", device.trim()),
+            );
+            foreign_edit(&remote, &root.join("new.txt"), "from the desk", "desk:1");
+            wait_until(|| fs::read_to_string(root.join("a.txt")).is_ok_and(|text| text == "desk"));
+            wait_until(|| root.join("new.txt").exists());
+            // Give later scans and flushes time to (wrongly) send the downloads back.
+            thread::sleep(Duration::from_millis(200));
+            signals.request_shutdown();
+        });
+
+        let desk = VersionVector::decode(&format!("desk:1,{}:1", device.trim()))
+            .expect("version should parse");
+        assert_eq!(version(&a), Some(desk.to_string()));
+    }
+
+    #[test]
+    fn encrypts_uploaded_content_when_the_config_turns_encryption_on() {
+        let dir = temp_dir("daemon-encrypted");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("diary.txt"), "dear diary").expect("file should be written");
+        let (store, master, _) =
+            KeyStore::create("open sesame", crate::crypto::test_kdf_params()).expect("store");
+        store.save(dir.join("keys")).expect("store should save");
+        fs::write(dir.join("passphrase"), "open sesame\n").expect("passphrase file");
+        let mut config = Config {
+            content: ContentSettings {
+                upload: true,
+                ..ContentSettings::default()
+            },
+            ..config_for(&dir, std::slice::from_ref(&root))
+        };
+        config.encryption.content = true;
+        config.encryption.key_store = Some(dir.join("keys"));
+
+        let remote = MemoryRemote::default();
+        let server = remote.clone();
+        let connect = move |_: &Config, _: &BandwidthLimiter| Ok(server.clone());
+        assert!(
+            Daemon::with_transport(config.clone(), Box::new(connect.clone())).is_err(),
+            "no passphrase to unlock the key store with"
+        );
+        config.encryption.passphrase_file = Some(dir.join("passphrase"));
+        let mut daemon =
+            Daemon::with_transport(config, Box::new(connect)).expect("daemon should start");
+        let signals = DaemonSignals::new();
+        let path = root.join("diary.txt").to_string_lossy().to_string();
+
+        thread::scope(|scope| {
+            scope.spawn(|| daemon.run(&signals).expect("daemon should run"));
+            wait_until(|| remote.state().files.contains_key(&path));
+            signals.request_shutdown();
+        });
+
+        let state = remote.state();
+        let record = &state.files[&path];
+        assert!(record.encryption.is_some());
+        assert!(state
+            .chunks
+            .values()
+            .all(|body| !body.windows(5).any(|window| window == b"diary")));
+        let manifest = ContentManifest {
+            chunks: record.chunks.clone(),
+            encryption: record.encryption.clone(),
+            uploaded_bytes: 0,
+        };
+        let decoder = ContentDecoder::new(&manifest, Some(&master)).expect("decoder");
+        let body = state.chunks[&record.chunks[0].id].clone();
+        assert_eq!(decoder.decode(0, body).expect("decrypts"), b"dear diary");
+    }
+
+    #[test]
+    fn selective_sync_clears_excluded_folders_without_deleting_them_and_brings_them_back() {
+        let dir = temp_dir("daemon-selective");
+        let root = dir.join("root");
+        let media = root.join("media");
+        fs::create_dir_all(&media).expect("media should be created");
+        fs::write(root.join("notes.txt"), "notes").expect("file should be written");
+        fs::write(media.join("a.jpg"), "photo").expect("file should be written");
+        let config = Config {
+            content: ContentSettings {
+                upload: true,
+                ..ContentSettings::default()
+            },
+            ..config_for(&dir, std::slice::from_ref(&root))
+        };
+        let remote = MemoryRemote::default();
+        let start = |config: Config| {
+            let remote = remote.clone();
+            Daemon::with_transport(
+                config,
+                Box::new(move |_: &Config, _: &BandwidthLimiter| Ok(remote.clone())),
+            )
+            .expect("daemon should start")
+        };
+        let signals = DaemonSignals::new();
+        let photo = media.join("a.jpg").to_string_lossy().to_string();
+
+        let mut first = start(config.clone());
+        first.scan_roots();
+        first.flush(&signals);
+        assert_eq!(remote.state().files.len(), 2);
+        drop(first);
+
+        let mut second = start(Config {
+            selective: SelectiveSync::new().exclude(&media),
+            ..config.clone()
+        });
+        second.flush(&signals);
+        assert!(!media.exists(), "the excluded folder is cleaned up");
+        second.scan_roots();
+        second.flush(&signals);
+        let synced = second.manager().synced_index().expect("snapshots are on");
+        assert!(
+            synced.get(&photo).is_some(),
+            "cleaning up is not a deletion"
+        );
+        drop(second);
+
+        let _third = start(config);
+        assert_eq!(
+            fs::read_to_string(media.join("a.jpg")).expect("photo should be back"),
+            "photo"
+        );
+    }
+
+    #[test]
+    fn only_changes_that_disagree_with_the_synced_index_are_foreign() {
+        let mut synced = SyncedIndex::in_memory();
+        synced.record(SyncRequest {
+            path: "/root/a.txt".to_string(),
+            hash: "mine".to_string(),
+            ..Default::default()
+        });
+        let change = |kind, path: &str, hash: Option<&str>| RemoteChange {
+            kind,
+            path: path.to_string(),
+            hash: hash.map(str::to_string),
+            device: None,
+            time: UNIX_EPOCH,
+        };
+
+        assert!(!is_foreign(
+            &change(RemoteChangeKind::Updated, "/root/a.txt", Some("mine")),
+            &synced
+        ));
+        assert!(is_foreign(
+            &change(RemoteChangeKind::Updated, "/root/a.txt", Some("theirs")),
+            &synced
+        ));
+        assert!(is_foreign(
+            &change(RemoteChangeKind::Deleted, "/root/a.txt", None),
+            &synced
+        ));
+        assert!(!is_foreign(
+            &change(RemoteChangeKind::Updated, "/root/new.txt", Some("x")),
+            &synced
+        ));
+    }
+
+    #[test]
+    fn persists_unsent_requests_on_shutdown_and_sends_them_after_restart() {
+        let dir = temp_dir("daemon-restart");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("pending.txt"), "waiting").expect("file should be written");
+        let config = config_for(&dir, std::slice::from_ref(&root));
+        let server = SharedServer::default();
+        server.failing.store(true, Ordering::SeqCst);
+
+        let signals = DaemonSignals::new();
+        let mut first = daemon(config.clone(), &server);
+        thread::scope(|scope| {
+            scope.spawn(|| first.run(&signals).expect("daemon should run"));
+            wait_until(|| {
+                load_queue(config.state_dir.join("queue"))
+                    .map(|q| q.len())
+                    .unwrap_or(0)
+                    == 1
+            });
+            signals.request_shutdown();
+        });
+        drop(first);
+
+        server.failing.store(false, Ordering::SeqCst);
+        let signals = DaemonSignals::new();
+        let mut second = daemon(config.clone(), &server);
+        assert_eq!(second.manager().pending_count(), 1);
+        thread::scope(|scope| {
+            scope.spawn(|| second.run(&signals).expect("daemon should run"));
+            wait_until(|| server.received().len() == 1);
+            // Give the watcher a few scans: the file is unchanged, so it must not be re-sent.
+            thread::sleep(Duration::from_millis(100));
+            signals.request_shutdown();
+        });
+
+        assert_eq!(server.received().len(), 1);
+        assert!(load_queue(config.state_dir.join("queue"))
+            .expect("queue loads")
+            .is_empty());
+    }
+
+    #[test]
+    fn files_deferred_at_shutdown_are_picked_up_after_restart() {
+        let dir = temp_dir("daemon-deferred");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("download.part"), "partial").expect("file should be written");
+        let config = config_for(&dir, std::slice::from_ref(&root));
+        let server = SharedServer::default();
+
+        let mut first = daemon(config.clone(), &server);
+        first.manager = build_manager(&first.config, &first.connect, &first.limiter, None)
+            .expect("manager should build")
+            .with_stability_policy(StabilityPolicy {
+                quiet_period: Duration::from_secs(3600),
+                ..StabilityPolicy::default()
+            });
+        first.scan_roots();
+        assert_eq!(first.manager().deferred_paths().len(), 1);
+        assert_eq!(first.manager().pending_count(), 0);
+        drop(first);
+
+        let mut second = daemon(config, &server);
+        second.scan_roots();
+        assert_eq!(second.manager().pending_count(), 1);
+    }
+
+    #[test]
+    fn refuses_a_second_daemon_for_the_same_config_even_with_another_state_dir() {
+        let dir = temp_dir("daemon-user-lock");
+        let config_path = dir.join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "server = \"http://127.0.0.1:1\"\nroots = [\"{}\"]\nstate_dir = \"{}\"\n",
+                dir.join("root").display(),
+                dir.join("state").display()
+            ),
+        )
+        .expect("config should be written");
+
+        let first = Daemon::start(&config_path, ConfigOverrides::default())
+            .expect("first daemon should start");
+        let elsewhere = ConfigOverrides {
+            state_dir: Some(dir.join("other-state")),
+            ..ConfigOverrides::default()
+        };
+        let second = Daemon::start(&config_path, elsewhere.clone());
+        assert!(
+            matches!(&second, Err(SyncError::InvalidConfig(message)) if message.contains("config.lock")),
+            "{:?}",
+            second.err()
+        );
+        drop(first);
+        Daemon::start(&config_path, elsewhere).expect("lock should be free again");
+    }
+
+    #[test]
+    fn reload_picks_up_new_roots_and_ignores_broken_config() {
+        let dir = temp_dir("daemon-reload");
+        let (first_root, second_root) = (dir.join("a"), dir.join("b"));
+        fs::create_dir_all(&first_root).expect("root should be created");
+        fs::create_dir_all(&second_root).expect("root should be created");
+        fs::write(second_root.join("late.txt"), "late").expect("file should be written");
+
+        let config_path = dir.join("config.toml");
+        let write_config = |roots: &str| {
+            fs::write(
+                &config_path,
+                format!(
+                    "server = \"http://127.0.0.1:1\"\nroots = [{roots}]\nstate_dir = \"{}\"\n\n[sync]\nscan_interval_secs = 1\n",
+                    dir.join("state").display()
+                ),
+            )
+            .expect("config should be written")
+        };
+        write_config(&format!("\"{}\"", first_root.display()));
+
+        let server = SharedServer::default();
+        let mut daemon = daemon(Config::load(&config_path).expect("config loads"), &server);
+        daemon.config_path = Some(config_path.clone());
+
+        fs::write(&config_path, "server = oops\n").expect("config should be written");
+        daemon.reload();
+        assert_eq!(daemon.config().roots, vec![first_root.clone()]);
+
+        write_config(&format!(
+            "\"{}\", \"{}\"",
+            first_root.display(),
+            second_root.display()
+        ));
+        daemon.reload();
+        assert_eq!(daemon.config().roots.len(), 2);
+        daemon.scan_roots();
+        assert_eq!(daemon.manager().pending_count(), 1);
+    }
+
+    #[test]
+    fn pause_holds_back_flushes_until_resumed_over_the_control_socket() {
+        let dir = temp_dir("daemon-pause");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).expect("root should be created");
+        fs::write(root.join("held.txt"), "held").expect("file should be written");
+        let config = config_for(&dir, std::slice::from_ref(&root));
+        let server = SharedServer::default();
+        let signals = DaemonSignals::new();
+        let mut daemon = daemon(config.clone(), &server);
+        let control = daemon.control().clone();
+        let mut client = crate::ControlClient::connect(config.state_dir.join(CONTROL_SOCKET))
+            .expect("control socket should accept connections");
+        client.request("pause").expect("pause should succeed");
+
+        thread::scope(|scope| {
+            scope.spawn(|| daemon.run(&signals).expect("daemon should run"));
+            wait_until(|| control.status().queued == 1);
+            thread::sleep(Duration::from_millis(100));
+            assert!(server.received().is_empty());
+
+            client.request("resume").expect("resume should succeed");
+            client.request("sync_now").expect("sync_now should succeed");
+            wait_until(|| server.received().len() == 1);
+            wait_until(|| control.status().last_success.is_some());
+            let status = client.request("status").expect("status should succeed");
+            assert_eq!(status.get("queued"), Some(&crate::Json::Int(0)));
+            signals.request_shutdown();
+        });
+    }
+
+    #[test]
+    fn every_subscribed_handle_takes_each_reload_once() {
+        let signals = DaemonSignals::new();
+        let (first, second) = (signals.subscribe(), signals.subscribe());
+        signals.request_reload();
+        assert!(first.take_reload() && !first.take_reload());
+        assert!(second.take_reload());
+        assert!(
+            !signals.subscribe().take_reload(),
+            "only requests made after subscribing"
+        );
+    }
+
+    #[test]
+    fn runs_profiles_side_by_side_with_separate_queues_locks_and_identities() {
+        let dir = temp_dir("daemon-profiles");
+        let (config_dir, state_dir) = (dir.join("config"), dir.join("state"));
+        let mut profiles = Vec::new();
+        for name in ["alice", "bob"] {
+            let root = dir.join(name);
+            fs::create_dir_all(&root).expect("root should be created");
+            fs::write(root.join(format!("{name}.txt")), name).expect("file should be written");
+            let profile = Profile::in_dirs(name, &config_dir, &state_dir).expect("profile");
+            // Nothing listens on port 1, so every upload stays queued.
+            profile
+                .create("http://127.0.0.1:1", &[root])
+                .expect("profile should be created");
+            profiles.push(profile);
+        }
+        let profile_state = |name: &str| state_dir.join("profiles").join(name);
+
+        let signals = DaemonSignals::new();
+        let observer = {
+            let signals = signals.clone();
+            let states = [profile_state("alice"), profile_state("bob")];
+            // Never panics, so the daemons are always told to stop.
+            thread::spawn(move || {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                let queued = |state: &PathBuf| {
+                    load_queue(state.join("queue"))
+                        .map(|q| q.len())
+                        .unwrap_or(0)
+                };
+                while Instant::now() < deadline && !states.iter().all(|state| queued(state) == 1) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                let locked = states.iter().all(|state| {
+                    LockFile::holder(state.join("daemon.lock")) == Some(std::process::id())
+                });
+                signals.request_shutdown();
+                locked
+            })
+        };
+        run_profiles(&profiles, ConfigOverrides::default(), &signals).expect("profiles should run");
+        assert!(
+            observer.join().expect("observer"),
+            "each profile holds its own lock"
+        );
+
+        let mut devices = BTreeSet::new();
+        for name in ["alice", "bob"] {
+            let queue = load_queue(profile_state(name).join("queue")).expect("queue loads");
+            let expected = dir.join(name).join(format!("{name}.txt"));
+            let queued = queue
+                .iter()
+                .map(|request| PathBuf::from(&request.path))
+                .collect::<Vec<_>>();
+            assert_eq!(queued, [expected]);
+            let identity = DeviceIdentity::load(profile_state(name)).expect("identity loads");
+            devices.insert(
+                identity
+                    .expect("each profile has an identity")
+                    .id()
+                    .to_string(),
+            );
+        }
+        assert_eq!(devices.len(), 2);
+    }
+
+    #[test]
+    fn second_instance_cannot_take_the_lock() {
+        let dir = temp_dir("daemon-lock");
+        let held = LockFile::acquire(dir.join("daemon.lock")).expect("first lock should succeed");
+        assert!(matches!(
+            LockFile::acquire(dir.join("daemon.lock")),
+            Err(SyncError::InvalidConfig(_))
+        ));
+        assert_eq!(
+            LockFile::holder(dir.join("daemon.lock")),
+            Some(std::process::id())
+        );
+        drop(held);
+        assert_eq!(LockFile::holder(dir.join("daemon.lock")), None);
+        LockFile::acquire(dir.join("daemon.lock")).expect("lock should be free again");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(30), Duration::from_secs(100));
+        assert_eq!(backoff.failure(), Duration::from_secs(30));
+        assert_eq!(backoff.failure(), Duration::from_secs(60));
+        assert_eq!(backoff.failure(), Duration::from_secs(100));
+        backoff.reset();
+        assert_eq!(backoff.failure(), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,359 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::control::unix_seconds;
+use crate::crypto::random_bytes;
+use crate::metadata::to_hex;
+use crate::queue_store::write_atomically;
+use crate::{percent_encode, DeviceKeyPair, SyncClient, SyncError};
+
+/// Files in the state directory holding this device's id and secret key.
+const ID_FILE: &str = "device-id";
+const KEY_FILE: &str = "device.key";
+
+/// What this client can do, registered so the server can tell device generations apart.
+pub const CAPABILITIES: [&str; 6] = [
+    "content",
+    "encryption",
+    "snapshots",
+    "change-feed",
+    "versions",
+    "restore-jobs",
+];
+
+/// This installation's identity: a random id sent with every request and an X25519 key
+/// pair that master key envelopes are sealed to. Both live in the state directory.
+#[derive(Debug)]
+pub struct DeviceIdentity {
+    id: String,
+    key: DeviceKeyPair,
+}
+
+impl DeviceIdentity {
+    /// The identity in `state_dir`, or `None` before one was created.
+    pub fn load<P: AsRef<Path>>(state_dir: P) -> Result<Option<Self>, SyncError> {
+        let state_dir = state_dir.as_ref();
+        match fs::read_to_string(state_dir.join(ID_FILE)) {
+            Ok(id) if !id.trim().is_empty() => Self::with_key(state_dir, id.trim()).map(Some),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(SyncError::Io(err)),
+        }
+    }
+
+    /// Loads the identity in `state_dir`, creating it on first use. An id created before
+    /// devices had keys is kept and gains a key pair.
+    pub fn load_or_create<P: AsRef<Path>>(state_dir: P) -> Result<Self, SyncError> {
+        let state_dir = state_dir.as_ref();
+        if let Some(identity) = Self::load(state_dir)? {
+            return Ok(identity);
+        }
+        fs::create_dir_all(state_dir).map_err(SyncError::Io)?;
+        let id = to_hex(&random_bytes::<8>()?);
+        let identity = Self::with_key(state_dir, &id)?;
+        write_atomically(&state_dir.join(ID_FILE), &format!("{id}\n"))?;
+        Ok(identity)
+    }
+
+    fn with_key(state_dir: &Path, id: &str) -> Result<Self, SyncError> {
+        let key_path = state_dir.join(KEY_FILE);
+        let key = if key_path.exists() {
+            DeviceKeyPair::load(&key_path)?
+        } else {
+            let key = DeviceKeyPair::generate()?;
+            key.save(&key_path)?;
+            key
+        };
+        Ok(Self {
+            id: id.to_string(),
+            key,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn key(&self) -> &DeviceKeyPair {
+        &self.key
+    }
+
+    pub fn into_key(self) -> DeviceKeyPair {
+        self.key
+    }
+
+    /// The record that registers this device under `name`.
+    pub fn record(&self, name: &str) -> DeviceRecord {
+        DeviceRecord {
+            id: self.id.clone(),
+            name: name.to_string(),
+            platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            capabilities: CAPABILITIES
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+            public_key: self.key.public_key_hex(),
+            last_seen: None,
+            revoked: false,
+        }
+    }
+}
+
+/// A device as the server knows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRecord {
+    pub id: String,
+    pub name: String,
+    /// `<os>-<arch>`, e.g. `linux-x86_64`.
+    pub platform: String,
+    pub capabilities: Vec<String>,
+    /// Hex X25519 public key that master key envelopes for the device are sealed to.
+    pub public_key: String,
+    /// When the server last received a request from the device; set by the server.
+    pub last_seen: Option<SystemTime>,
+    /// Revoked devices stay listed, but the server refuses their requests.
+    pub revoked: bool,
+}
+
+impl DeviceRecord {
+    pub fn encode_body(&self) -> String {
+        let mut body = format!(
+            "id={}\nname={}\nplatform={}\n",
+            self.id, self.name, self.platform
+        );
+        for capability in &self.capabilities {
+            body.push_str(&format!("capability={capability}\n"));
+        }
+        body.push_str(&format!("public_key={}\n", self.public_key));
+        if let Some(last_seen) = self.last_seen {
+            body.push_str(&format!("last_seen={}\n", unix_seconds(last_seen)));
+        }
+        if self.revoked {
+            body.push_str("revoked=true\n");
+        }
+        body
+    }
+
+    /// Parses one record. Unknown lines are skipped so servers can add fields.
+    pub fn decode_body(body: &str) -> Result<Self, SyncError> {
+        let invalid =
+            |line: &str| SyncError::Protocol(format!("invalid device record line: {line}"));
+        let mut record = Self {
+            id: String::new(),
+            name: String::new(),
+            platform: String::new(),
+            capabilities: Vec::new(),
+            public_key: String::new(),
+            last_seen: None,
+            revoked: false,
+        };
+        for line in body.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid(line))?;
+            match key {
+                "id" => record.id = value.to_string(),
+                "name" => record.name = value.to_string(),
+                "platform" => record.platform = value.to_string(),
+                "capability" => record.capabilities.push(value.to_string()),
+                "public_key" => record.public_key = value.to_string(),
+                "last_seen" => {
+                    let seconds = value.parse().map_err(|_| invalid(line))?;
+                    record.last_seen = Some(UNIX_EPOCH + Duration::from_secs(seconds));
+                }
+                "revoked" => record.revoked = value == "true",
+                _ => {}
+            }
+        }
+        if record.id.is_empty() {
+            return Err(SyncError::Protocol(
+                "device record without an id".to_string(),
+            ));
+        }
+        Ok(record)
+    }
+}
+
+impl fmt::Display for DeviceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}  {}  ({})", self.id, self.name, self.platform)?;
+        if self.revoked {
+            write!(f, "  revoked")?;
+        }
+        Ok(())
+    }
+}
+
+/// Device names are single-line labels.
+fn validate_name(name: &str) -> Result<(), SyncError> {
+    if name.trim().is_empty() || name.chars().any(char::is_control) {
+        return Err(SyncError::InvalidConfig(format!(
+            "device name `{}` must be a non-empty single line",
+            name.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+/// This machine's host name, the default device name.
+pub fn host_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0_u8; 256];
+        // SAFETY: the buffer is valid for its whole length and gethostname NUL-terminates
+        // within it on success.
+        if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } == 0 {
+            let end = buffer
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(buffer.len());
+            let name = String::from_utf8_lossy(&buffer[..end]).trim().to_string();
+            if !name.is_empty() {
+                return name;
+            }
+        }
+    }
+    "device".to_string()
+}
+
+impl SyncClient {
+    /// Registers a device, or updates its platform, capabilities and name.
+    pub fn register_device(&self, record: &DeviceRecord) -> Result<(), SyncError> {
+        validate_name(&record.name)?;
+        let path = format!("/v1/devices/{}", percent_encode(&record.id));
+        let response = self.send("PUT", &path, &record.encode_body())?;
+        if response.status == 200 || response.status == 201 || response.status == 204 {
+            return Ok(());
+        }
+        Err(SyncError::Server(response.status, response.body))
+    }
+
+    /// Every registered device, revoked ones included.
+    pub fn list_devices(&self) -> Result<Vec<DeviceRecord>, SyncError> {
+        let response = self.send("GET", "/v1/devices", "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+        response
+            .body
+            .split("\n\n")
+            .filter(|block| !block.trim().is_empty())
+            .map(DeviceRecord::decode_body)
+            .collect()
+    }
+
+    pub fn rename_device(&self, id: &str, name: &str) -> Result<(), SyncError> {
+        validate_name(name)?;
+        let path = format!("/v1/devices/{}/name", percent_encode(id));
+        let response = self.send("POST", &path, &format!("name={name}\n"))?;
+        if response.status == 200 || response.status == 204 {
+            return Ok(());
+        }
+        Err(SyncError::Server(response.status, response.body))
+    }
+
+    /// Revokes a device: the server keeps its record but refuses its requests from now on.
+    pub fn revoke_device(&self, id: &str) -> Result<(), SyncError> {
+        let path = format!("/v1/devices/{}", percent_encode(id));
+        let response = self.send("DELETE", &path, "")?;
+        if response.status == 200 || response.status == 204 {
+            return Ok(());
+        }
+        Err(SyncError::Server(response.status, response.body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve_scripted, temp_dir, MockResponse};
+
+    #[test]
+    fn identity_is_created_once_and_keeps_an_id_from_before_device_keys() {
+        let dir = temp_dir("device-identity");
+        assert!(DeviceIdentity::load(&dir)
+            .expect("no identity yet")
+            .is_none());
+
+        let created = DeviceIdentity::load_or_create(&dir).expect("identity should be created");
+        let loaded = DeviceIdentity::load_or_create(&dir).expect("identity should load");
+        assert_eq!(created.id().len(), 16);
+        assert_eq!(loaded.id(), created.id());
+        assert_eq!(loaded.key().public_key(), created.key().public_key());
+
+        let older = temp_dir("device-identity-older");
+        fs::write(older.join(ID_FILE), "cafe0123\n").expect("write id");
+        let upgraded = DeviceIdentity::load(&older)
+            .expect("identity should load")
+            .expect("an id exists");
+        assert_eq!(upgraded.id(), "cafe0123");
+        assert!(older.join(KEY_FILE).exists(), "a key pair is added");
+
+        let record = upgraded.record("laptop");
+        assert_eq!(record.public_key, upgraded.key().public_key_hex());
+        assert!(record
+            .capabilities
+            .iter()
+            .any(|capability| capability == "versions"));
+    }
+
+    #[test]
+    fn http_client_registers_lists_renames_and_revokes_devices_with_its_id() {
+        let identity = DeviceIdentity::load_or_create(temp_dir("device-http")).expect("identity");
+        let id = identity.id().to_string();
+        let listing = format!(
+            "id={id}\nname=laptop\nplatform=linux-x86_64\ncapability=content\npublic_key=ab\nlast_seen=1700000000\n\n\
+             id=old\nname=desk\nplatform=macos-aarch64\npublic_key=cd\nrevoked=true\nfuture=field\n"
+        );
+        let (base_url, handle) = serve_scripted(4, move |request| match request.method.as_str() {
+            "GET" => MockResponse::text(200, &listing),
+            "DELETE" if request.path == "/v1/devices/gone" => {
+                MockResponse::text(404, "no such device")
+            }
+            _ => MockResponse::text(204, ""),
+        });
+        let client = SyncClient::new(&base_url).expect("client").with_device(&id);
+
+        client
+            .register_device(&identity.record("laptop"))
+            .expect("register");
+        let devices = client.list_devices().expect("list");
+        client.rename_device("old", "old desk").expect("rename");
+        assert!(matches!(
+            client.revoke_device("gone"),
+            Err(SyncError::Server(404, _))
+        ));
+        assert!(
+            client.rename_device("old", "two\nlines").is_err(),
+            "checked before sending"
+        );
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, id);
+        assert_eq!(
+            devices[0].last_seen,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert!(!devices[0].revoked && devices[1].revoked);
+        assert_eq!(
+            devices[1].to_string(),
+            "old  desk  (macos-aarch64)  revoked"
+        );
+
+        let seen = handle.join().expect("mock server");
+        assert_eq!(seen[0].method, "PUT");
+        assert_eq!(seen[0].path, format!("/v1/devices/{id}"));
+        assert_eq!(
+            DeviceRecord::decode_body(&seen[0].body_text()).expect("record"),
+            identity.record("laptop")
+        );
+        assert_eq!(
+            (seen[2].method.as_str(), seen[2].path.as_str()),
+            ("POST", "/v1/devices/old/name")
+        );
+        assert_eq!(seen[2].body_text(), "name=old desk\n");
+        assert!(seen
+            .iter()
+            .all(|request| request.header("X-Device-Id") == Some(id.as_str())));
+    }
+}
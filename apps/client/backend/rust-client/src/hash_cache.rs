@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::queue_store::write_atomically;
+use crate::stability::system_time_ns;
+use crate::{FileStamp, SparseLayout, SyncError};
+
+/// v2 holds SHA-256 digests. Older caches held digests from std's `DefaultHasher`, which
+/// may change between Rust releases, so they are discarded rather than trusted.
+const HEADER: &str = "# rust-client hash cache v2";
+
+/// Persistent map from path to the digest computed for a given [`FileStamp`], so repeated
+/// scans only rehash files whose size, mtime, ctime or inode moved.
+///
+/// Like git's index, an entry is only trusted when the file's mtime is older than the
+/// moment hashing started by more than the filesystem timestamp granularity. Otherwise a
+/// write landing in the same timestamp tick as the hash would leave the stamp unchanged and
+/// the stale digest would be reused forever.
+#[derive(Debug, Clone)]
+pub struct HashCache {
+    path: Option<PathBuf>,
+    entries: HashMap<String, CacheEntry>,
+    paranoid: bool,
+    racy_window: Duration,
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheEntry {
+    stamp: FileStamp,
+    hashed_at_ns: i64,
+    hash: String,
+    sparse: Option<SparseLayout>,
+}
+
+/// A digest reused from the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedHash {
+    pub hash: String,
+    pub sparse: Option<SparseLayout>,
+}
+
+impl HashCache {
+    /// A cache that lives only as long as the process.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            entries: HashMap::new(),
+            paranoid: false,
+            racy_window: Duration::from_secs(2),
+            dirty: false,
+        }
+    }
+
+    /// Loads the cache stored at `path`, starting empty if the file does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let path = path.as_ref().to_path_buf();
+        let mut cache = Self {
+            path: Some(path.clone()),
+            ..Self::in_memory()
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(err) => return Err(SyncError::Io(err)),
+        };
+        if contents.lines().next() != Some(HEADER) {
+            cache.dirty = true;
+            return Ok(cache);
+        }
+
+        for (index, line) in contents.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (file_path, entry) = parse_line(line).ok_or_else(|| {
+                SyncError::Protocol(format!(
+                    "corrupt hash cache {} at line {}",
+                    path.display(),
+                    index + 1
+                ))
+            })?;
+            cache.entries.insert(file_path, entry);
+        }
+
+        Ok(cache)
+    }
+
+    /// In paranoid mode every lookup misses, forcing a full rehash, while fresh results
+    /// are still recorded so the cache is correct again once paranoid mode is turned off.
+    pub fn set_paranoid(&mut self, paranoid: bool) {
+        self.paranoid = paranoid;
+    }
+
+    pub fn is_paranoid(&self) -> bool {
+        self.paranoid
+    }
+
+    /// Widens or narrows the racy-timestamp guard. Defaults to 2 s, the coarsest common
+    /// granularity (FAT).
+    pub fn set_racy_window(&mut self, window: Duration) {
+        self.racy_window = window;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn lookup(&self, file_path: &str, stamp: &FileStamp) -> Option<CachedHash> {
+        if self.paranoid {
+            return None;
+        }
+        let entry = self.entries.get(file_path)?;
+        if entry.stamp != *stamp || self.is_racy(entry) {
+            return None;
+        }
+        Some(CachedHash {
+            hash: entry.hash.clone(),
+            sparse: entry.sparse.clone(),
+        })
+    }
+
+    /// Records a digest. `hashed_at` must be taken before the file was read.
+    pub fn record(
+        &mut self,
+        file_path: &str,
+        stamp: FileStamp,
+        hashed_at: SystemTime,
+        hash: &str,
+        sparse: Option<SparseLayout>,
+    ) {
+        self.entries.insert(
+            file_path.to_string(),
+            CacheEntry {
+                stamp,
+                hashed_at_ns: system_time_ns(hashed_at),
+                hash: hash.to_string(),
+                sparse,
+            },
+        );
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, file_path: &str) {
+        self.dirty |= self.entries.remove(file_path).is_some();
+    }
+
+    /// Writes the cache back to disk through a temp file and rename. In-memory caches and
+    /// caches without changes are left alone.
+    pub fn save(&mut self) -> Result<(), SyncError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut paths = self.entries.keys().collect::<Vec<_>>();
+        paths.sort();
+
+        let mut contents = format!("{HEADER}\n");
+        for file_path in paths {
+            contents.push_str(&format_line(file_path, &self.entries[file_path]));
+            contents.push('\n');
+        }
+
+        write_atomically(path, &contents)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn is_racy(&self, entry: &CacheEntry) -> bool {
+        // Content writes always move mtime to the current time, so an mtime safely older than
+        // the hash means any later write must change it. ctime only needs to match exactly.
+        let trusted_before = entry.hashed_at_ns as i128 - self.racy_window.as_nanos() as i128;
+        entry.stamp.mtime_ns as i128 >= trusted_before
+    }
+}
+
+fn format_line(file_path: &str, entry: &CacheEntry) -> String {
+    let sparse = entry
+        .sparse
+        .as_ref()
+        .map(|layout| {
+            let mut lines = String::new();
+            layout.encode_lines(&mut lines);
+            lines.replace('\n', ";")
+        })
+        .unwrap_or_default();
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        escape(file_path),
+        entry.stamp.size,
+        entry.stamp.mtime_ns,
+        entry.stamp.ctime_ns,
+        entry.stamp.inode,
+        entry.hashed_at_ns,
+        entry.hash,
+        sparse
+    )
+}
+
+fn parse_line(line: &str) -> Option<(String, CacheEntry)> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let [path, size, mtime, ctime, inode, hashed_at, hash, sparse] = fields.as_slice() else {
+        return None;
+    };
+
+    let sparse = if sparse.is_empty() {
+        None
+    } else {
+        let mut size = None;
+        let mut extents = None;
+        for field in sparse.split(';').filter(|field| !field.is_empty()) {
+            match field.split_once('=')? {
+                ("size", value) => size = Some(value),
+                ("extents", value) => extents = Some(value),
+                _ => return None,
+            }
+        }
+        Some(SparseLayout::decode(size?, extents?).ok()?)
+    };
+
+    Some((
+        unescape(path)?,
+        CacheEntry {
+            stamp: FileStamp {
+                size: size.parse().ok()?,
+                mtime_ns: mtime.parse().ok()?,
+                ctime_ns: ctime.parse().ok()?,
+                inode: inode.parse().ok()?,
+            },
+            hashed_at_ns: hashed_at.parse().ok()?,
+            hash: hash.to_string(),
+            sparse,
+        },
+    ))
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\t', "%09")
+        .replace('\n', "%0A")
+        .replace('\r', "%0D")
+}
+
+pub(crate) fn unescape(value: &str) -> Option<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            out.push(ch);
+            continue;
+        }
+        let code = [chars.next()?, chars.next()?].iter().collect::<String>();
+        out.push(u8::from_str_radix(&code, 16).ok()? as char);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use crate::Extent;
+
+    const HOUR_NS: i64 = 3_600_000_000_000;
+
+    fn old_stamp() -> FileStamp {
+        let now = system_time_ns(SystemTime::now());
+        FileStamp {
+            size: 42,
+            mtime_ns: now - HOUR_NS,
+            ctime_ns: now - HOUR_NS,
+            inode: 7,
+        }
+    }
+
+    #[test]
+    fn reuses_digest_only_while_the_stamp_matches() {
+        let mut cache = HashCache::in_memory();
+        let stamp = old_stamp();
+        cache.record("media/film.mkv", stamp, SystemTime::now(), "abc123", None);
+
+        let hit = cache
+            .lookup("media/film.mkv", &stamp)
+            .expect("unchanged file should hit");
+        assert_eq!(hit.hash, "abc123");
+
+        let touched = FileStamp {
+            mtime_ns: stamp.mtime_ns + 1,
+            ..stamp
+        };
+        assert_eq!(cache.lookup("media/film.mkv", &touched), None);
+        assert_eq!(cache.lookup("media/other.mkv", &stamp), None);
+    }
+
+    #[test]
+    fn distrusts_entries_modified_within_the_racy_window() {
+        let mut cache = HashCache::in_memory();
+        let now = SystemTime::now();
+        let fresh = FileStamp {
+            mtime_ns: system_time_ns(now),
+            ctime_ns: system_time_ns(now),
+            ..old_stamp()
+        };
+        cache.record("logs/app.log", fresh, now, "abc123", None);
+
+        assert_eq!(cache.lookup("logs/app.log", &fresh), None);
+    }
+
+    #[test]
+    fn paranoid_mode_forces_rehash() {
+        let mut cache = HashCache::in_memory();
+        let stamp = old_stamp();
+        cache.record("a.txt", stamp, SystemTime::now(), "abc123", None);
+        cache.set_paranoid(true);
+
+        assert_eq!(cache.lookup("a.txt", &stamp), None);
+        cache.set_paranoid(false);
+        assert!(cache.lookup("a.txt", &stamp).is_some());
+    }
+
+    #[test]
+    fn persists_entries_across_reopen() {
+        let dir = temp_dir("hash-cache");
+        let cache_path = dir.join("hash-cache.tsv");
+        let stamp = old_stamp();
+        let sparse = SparseLayout {
+            size: 1 << 20,
+            extents: vec![Extent {
+                offset: 4096,
+                length: 4096,
+            }],
+        };
+
+        let mut cache = HashCache::open(&cache_path).expect("missing cache file should open empty");
+        assert!(cache.is_empty());
+        cache.record("odd\tname%.txt", stamp, SystemTime::now(), "abc123", None);
+        cache.record(
+            "disk.img",
+            stamp,
+            SystemTime::now(),
+            "def456",
+            Some(sparse.clone()),
+        );
+        cache.save().expect("cache should save");
+
+        let reopened = HashCache::open(&cache_path).expect("saved cache should load");
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(
+            reopened
+                .lookup("odd\tname%.txt", &stamp)
+                .expect("escaped path should round trip")
+                .hash,
+            "abc123"
+        );
+        assert_eq!(
+            reopened
+                .lookup("disk.img", &stamp)
+                .expect("sparse entry should round trip")
+                .sparse,
+            Some(sparse)
+        );
+    }
+
+    #[test]
+    fn discards_caches_written_with_the_old_hasher() {
+        let dir = temp_dir("hash-cache-v1");
+        let cache_path = dir.join("hash-cache.tsv");
+        let stamp = old_stamp();
+        fs::write(
+            &cache_path,
+            format!(
+                "# rust-client hash cache v1\na.txt\t{}\t{}\t{}\t{}\t0\t0123456789abcdef\t\n",
+                stamp.size, stamp.mtime_ns, stamp.ctime_ns, stamp.inode
+            ),
+        )
+        .expect("old cache should be written");
+
+        let mut cache = HashCache::open(&cache_path).expect("old cache should open");
+        assert!(cache.is_empty());
+        cache.save().expect("cache should save");
+        let saved = fs::read_to_string(&cache_path).expect("cache should be rewritten");
+        assert_eq!(saved, format!("{HEADER}\n"));
+    }
+}
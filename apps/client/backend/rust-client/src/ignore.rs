@@ -0,0 +1,158 @@
+use std::path::Path;
+
+use crate::SyncError;
+
+/// gitignore-style patterns matched against paths relative to a sync root.
+///
+/// - A pattern without `/` matches a file or directory name at any depth (`*.tmp`).
+/// - A pattern containing `/` matches from the root (`build/out`, `/cache`).
+/// - `*` and `?` stay within one path segment; `**` crosses segments.
+/// - A trailing `/` matches directories only; everything under an ignored directory is ignored.
+/// - A leading `!` re-includes paths an earlier pattern ignored. The last match wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    source: String,
+    glob: String,
+    negated: bool,
+    directory_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreRules {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, SyncError> {
+        patterns
+            .iter()
+            .map(|pattern| Pattern::parse(pattern.as_ref()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|patterns| Self { patterns })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.patterns.iter().map(|pattern| pattern.source.as_str())
+    }
+
+    /// Adds `other`'s patterns after this set's, so they take precedence.
+    pub fn extend(&mut self, other: &IgnoreRules) {
+        self.patterns.extend(other.patterns.iter().cloned());
+    }
+
+    /// `relative` is relative to the sync root; `is_dir` tells directory-only patterns apart.
+    pub fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+        let path = relative.to_string_lossy().replace('\\', "/");
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if pattern.negated == ignored && pattern.matches(&path, is_dir) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+}
+
+impl Pattern {
+    fn parse(source: &str) -> Result<Self, SyncError> {
+        let trimmed = source.trim();
+        let (negated, rest) = match trimmed.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed),
+        };
+        let (directory_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let anchored = rest.contains('/');
+        let glob = rest.trim_start_matches('/').to_string();
+        if glob.is_empty() {
+            return Err(SyncError::InvalidConfig(format!(
+                "ignore pattern `{source}` matches nothing"
+            )));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            glob,
+            negated,
+            directory_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.directory_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(self.glob.as_bytes(), path.as_bytes())
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(self.glob.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=text.len()).any(|skip| {
+                (skip == 0 || text[skip - 1] == b'/' || rest.is_empty())
+                    && glob_match(rest, &text[skip..])
+            })
+        }
+        [b'*', rest @ ..] => {
+            let segment_end = text
+                .iter()
+                .position(|&byte| byte == b'/')
+                .unwrap_or(text.len());
+            (0..=segment_end).any(|skip| glob_match(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(text, [first, tail @ ..] if *first != b'/' && glob_match(rest, tail))
+        }
+        [expected, rest @ ..] => {
+            matches!(text, [first, tail @ ..] if first == expected && glob_match(rest, tail))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored(Path::new(path), false)
+    }
+
+    #[test]
+    fn name_patterns_match_at_any_depth_and_anchored_ones_from_the_root() {
+        let rules = IgnoreRules::new(&["*.tmp", "/build/out", "docs/**/*.bak", "cache/"])
+            .expect("patterns should parse");
+
+        assert!(ignored(&rules, "a.tmp"));
+        assert!(ignored(&rules, "deep/nested/b.tmp"));
+        assert!(!ignored(&rules, "a.tmpl"));
+        assert!(ignored(&rules, "build/out"));
+        assert!(!ignored(&rules, "src/build/out"));
+        assert!(ignored(&rules, "docs/old.bak"));
+        assert!(ignored(&rules, "docs/x/y/old.bak"));
+        assert!(!ignored(&rules, "cache"));
+        assert!(rules.is_ignored(Path::new("sub/cache"), true));
+    }
+
+    #[test]
+    fn negation_reincludes_and_the_last_match_wins() {
+        let rules = IgnoreRules::new(&["*.log", "!keep.log"]).expect("patterns should parse");
+        assert!(ignored(&rules, "app.log"));
+        assert!(!ignored(&rules, "logs/keep.log"));
+        assert!(IgnoreRules::new(&["!/"]).is_err());
+    }
+}
@@ -0,0 +1,840 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::crypto::{self, random_bytes, KEY_LEN};
+use crate::metadata::{from_hex, to_hex};
+use crate::{percent_encode, KdfParams, MasterKey, SyncClient, SyncError};
+
+const STORE_HEADER: &str = "# rust-client keystore v1";
+const ENVELOPE_INFO: &[u8] = b"rust-client device envelope v1";
+
+/// High-entropy secret shown once at setup. It unlocks the key store when the passphrase
+/// is forgotten, so it has to be printed or written down, never synced.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RecoveryKey {
+    bytes: [u8; KEY_LEN],
+}
+
+impl Drop for RecoveryKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl fmt::Debug for RecoveryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecoveryKey(..)")
+    }
+}
+
+impl RecoveryKey {
+    pub fn generate() -> Result<Self, SyncError> {
+        Ok(Self {
+            bytes: random_bytes()?,
+        })
+    }
+
+    /// Groups of four base32 characters with a two-byte checksum at the end, e.g.
+    /// `ab3d-...-xy7q`. Ambiguous characters are avoided by the base32 alphabet itself.
+    pub fn to_printable(&self) -> String {
+        let mut payload = self.bytes.to_vec();
+        payload.extend_from_slice(&Sha256::digest(self.bytes)[..2]);
+        let encoded = crate::path_cipher::base32_encode(&payload);
+        encoded
+            .as_bytes()
+            .chunks(4)
+            .map(|group| String::from_utf8_lossy(group).to_string())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Parses a printed key, ignoring case, spaces and dashes.
+    pub fn parse(printed: &str) -> Result<Self, SyncError> {
+        let invalid = || SyncError::Crypto("recovery key is not valid".to_string());
+        let normalized = printed
+            .chars()
+            .filter(|ch| !ch.is_whitespace() && *ch != '-')
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let payload = crate::path_cipher::base32_decode(&normalized).ok_or_else(invalid)?;
+        if payload.len() != KEY_LEN + 2 {
+            return Err(invalid());
+        }
+        let (key, checksum) = payload.split_at(KEY_LEN);
+        if Sha256::digest(key)[..2] != *checksum {
+            return Err(SyncError::Crypto(
+                "recovery key checksum does not match; check for typos".to_string(),
+            ));
+        }
+        Ok(Self {
+            bytes: key.try_into().map_err(|_| invalid())?,
+        })
+    }
+
+    fn kek(&self) -> [u8; KEY_LEN] {
+        derive_key(&self.bytes, b"rust-client recovery kek v1")
+    }
+}
+
+/// On-disk record of the master key, wrapped separately under the passphrase and under
+/// the recovery key, with its naming key sealed under the master key itself. While a
+/// rotation is in progress it also keeps the previous master key (sealed under the new
+/// one) so data keys not yet re-wrapped can still be opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStore {
+    generation: u32,
+    kdf: KdfParams,
+    passphrase_wrapped: Vec<u8>,
+    recovery_wrapped: Vec<u8>,
+    recovery_kek_wrapped: Vec<u8>,
+    /// Absent in stores written before naming keys were kept separately; their naming key
+    /// is the one derived from the master key.
+    naming_wrapped: Option<Vec<u8>>,
+    previous_wrapped: Option<Vec<u8>>,
+}
+
+impl KeyStore {
+    /// Creates a store around a fresh master key and returns the recovery key to show the user.
+    pub fn create(
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<(Self, MasterKey, RecoveryKey), SyncError> {
+        let master = MasterKey::generate()?;
+        let recovery = RecoveryKey::generate()?;
+        let store = Self::wrap(1, &master, passphrase, kdf, recovery.kek())?;
+        Ok((store, master, recovery))
+    }
+
+    /// Builds a store for a master key received from another device, with a new recovery key.
+    pub fn import(
+        master: &MasterKey,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<(Self, RecoveryKey), SyncError> {
+        let recovery = RecoveryKey::generate()?;
+        let store = Self::wrap(1, master, passphrase, kdf, recovery.kek())?;
+        Ok((store, recovery))
+    }
+
+    fn wrap(
+        generation: u32,
+        master: &MasterKey,
+        passphrase: &str,
+        kdf: KdfParams,
+        recovery_kek: [u8; KEY_LEN],
+    ) -> Result<Self, SyncError> {
+        let passphrase_kek = MasterKey::derive_from_passphrase(passphrase, &kdf)?;
+        Ok(Self {
+            generation,
+            passphrase_wrapped: crypto::seal(
+                passphrase_kek.as_bytes(),
+                &aad("passphrase", generation),
+                master.as_bytes(),
+            )?,
+            recovery_wrapped: crypto::seal(
+                &recovery_kek,
+                &aad("recovery", generation),
+                master.as_bytes(),
+            )?,
+            recovery_kek_wrapped: crypto::seal(
+                master.as_bytes(),
+                &aad("recovery-kek", generation),
+                &recovery_kek,
+            )?,
+            naming_wrapped: Some(crypto::seal(
+                master.as_bytes(),
+                &aad("naming", generation),
+                master.naming_key(),
+            )?),
+            previous_wrapped: None,
+            kdf,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(SyncError::Io)?;
+        let corrupt =
+            |what: &str| SyncError::Crypto(format!("key store {} has {what}", path.display()));
+        let hex = |value: &str| from_hex(value).ok_or_else(|| corrupt("invalid hex"));
+
+        let mut generation = None;
+        let mut kdf = None;
+        let mut passphrase_wrapped = None;
+        let mut recovery_wrapped = None;
+        let mut recovery_kek_wrapped = None;
+        let mut naming_wrapped = None;
+        let mut previous_wrapped = None;
+        for line in contents
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| corrupt("a malformed line"))?;
+            match key {
+                "generation" => {
+                    generation = Some(value.parse().map_err(|_| corrupt("a bad generation"))?)
+                }
+                "kdf" => kdf = Some(KdfParams::decode(value)?),
+                "passphrase" => passphrase_wrapped = Some(hex(value)?),
+                "recovery" => recovery_wrapped = Some(hex(value)?),
+                "recovery_kek" => recovery_kek_wrapped = Some(hex(value)?),
+                "naming" => naming_wrapped = Some(hex(value)?),
+                "previous" => previous_wrapped = Some(hex(value)?),
+                _ => return Err(corrupt(&format!("an unknown field {key}"))),
+            }
+        }
+
+        Ok(Self {
+            generation: generation.ok_or_else(|| corrupt("no generation"))?,
+            kdf: kdf.ok_or_else(|| corrupt("no kdf parameters"))?,
+            passphrase_wrapped: passphrase_wrapped.ok_or_else(|| corrupt("no passphrase slot"))?,
+            recovery_wrapped: recovery_wrapped.ok_or_else(|| corrupt("no recovery slot"))?,
+            recovery_kek_wrapped: recovery_kek_wrapped.ok_or_else(|| corrupt("no recovery kek"))?,
+            naming_wrapped,
+            previous_wrapped,
+        })
+    }
+
+    /// Writes the store with owner-only permissions through a temp file and rename.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SyncError> {
+        let path = path.as_ref();
+        let mut contents = format!(
+            "{STORE_HEADER}\ngeneration={}\nkdf={}\npassphrase={}\nrecovery={}\nrecovery_kek={}\n",
+            self.generation,
+            self.kdf.encode(),
+            to_hex(&self.passphrase_wrapped),
+            to_hex(&self.recovery_wrapped),
+            to_hex(&self.recovery_kek_wrapped),
+        );
+        if let Some(naming) = &self.naming_wrapped {
+            contents.push_str(&format!("naming={}\n", to_hex(naming)));
+        }
+        if let Some(previous) = &self.previous_wrapped {
+            contents.push_str(&format!("previous={}\n", to_hex(previous)));
+        }
+        write_private(path, contents.as_bytes()).map_err(SyncError::Io)
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn rotation_in_progress(&self) -> bool {
+        self.previous_wrapped.is_some()
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<MasterKey, SyncError> {
+        let kek = MasterKey::derive_from_passphrase(passphrase, &self.kdf)?;
+        let master = open_master(
+            kek.as_bytes(),
+            &aad("passphrase", self.generation),
+            &self.passphrase_wrapped,
+        )
+        .map_err(|_| SyncError::Crypto("wrong passphrase".to_string()))?;
+        self.with_naming_key(master)
+    }
+
+    pub fn unlock_with_recovery(&self, recovery: &RecoveryKey) -> Result<MasterKey, SyncError> {
+        let master = open_master(
+            &recovery.kek(),
+            &aad("recovery", self.generation),
+            &self.recovery_wrapped,
+        )
+        .map_err(|_| SyncError::Crypto("recovery key does not match this key store".to_string()))?;
+        self.with_naming_key(master)
+    }
+
+    fn with_naming_key(&self, master: MasterKey) -> Result<MasterKey, SyncError> {
+        let Some(sealed) = &self.naming_wrapped else {
+            return Ok(master);
+        };
+        let mut plain = crypto::open(master.as_bytes(), &aad("naming", self.generation), sealed)?;
+        let naming: Result<[u8; KEY_LEN], _> = plain.as_slice().try_into();
+        plain.zeroize();
+        let mut naming = naming
+            .map_err(|_| SyncError::Crypto("sealed naming key has the wrong length".to_string()))?;
+        let master = master.with_naming_key(naming);
+        naming.zeroize();
+        Ok(master)
+    }
+
+    /// Re-wraps the master key under a new passphrase, e.g. after recovering with the
+    /// recovery key. Data is untouched.
+    pub fn set_passphrase(
+        &mut self,
+        master: &MasterKey,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<(), SyncError> {
+        let kek = MasterKey::derive_from_passphrase(passphrase, &kdf)?;
+        self.passphrase_wrapped = crypto::seal(
+            kek.as_bytes(),
+            &aad("passphrase", self.generation),
+            master.as_bytes(),
+        )?;
+        self.kdf = kdf;
+        Ok(())
+    }
+
+    /// Replaces the master key. The recovery key stays valid, the naming key is carried over
+    /// so encrypted paths and content tags do not change, and the old master key is kept
+    /// sealed under the new one until [`KeyStore::finish_rotation`], so data keys can be
+    /// re-wrapped at leisure (and the re-wrap resumed after an interruption).
+    pub fn begin_rotation(
+        &mut self,
+        current: &MasterKey,
+        passphrase: &str,
+    ) -> Result<MasterKey, SyncError> {
+        if self.rotation_in_progress() {
+            return Err(SyncError::Crypto(
+                "a key rotation is already in progress; resume it first".to_string(),
+            ));
+        }
+        let mut recovery_kek: [u8; KEY_LEN] = crypto::open(
+            current.as_bytes(),
+            &aad("recovery-kek", self.generation),
+            &self.recovery_kek_wrapped,
+        )?
+        .try_into()
+        .map_err(|_| SyncError::Crypto("recovery kek has the wrong length".to_string()))?;
+
+        let next = current.rotated()?;
+        let mut rotated = Self::wrap(
+            self.generation + 1,
+            &next,
+            passphrase,
+            self.kdf.clone(),
+            recovery_kek,
+        )?;
+        recovery_kek.zeroize();
+        rotated.previous_wrapped = Some(crypto::seal(
+            next.as_bytes(),
+            &aad("previous", rotated.generation),
+            current.as_bytes(),
+        )?);
+        *self = rotated;
+        Ok(next)
+    }
+
+    pub fn previous_key(&self, current: &MasterKey) -> Result<Option<MasterKey>, SyncError> {
+        match &self.previous_wrapped {
+            Some(sealed) => Ok(Some(
+                open_master(
+                    current.as_bytes(),
+                    &aad("previous", self.generation),
+                    sealed,
+                )?
+                .with_naming_key(*current.naming_key()),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub fn finish_rotation(&mut self) {
+        self.previous_wrapped = None;
+    }
+}
+
+/// Moves a wrapped data key from one master key to another. Keys already under `new`
+/// come back unchanged, which makes an interrupted rotation safe to repeat.
+pub fn rewrap_data_key(
+    old: &MasterKey,
+    new: &MasterKey,
+    wrapped: &[u8],
+) -> Result<Option<Vec<u8>>, SyncError> {
+    if new.unwrap_data_key(wrapped).is_ok() {
+        return Ok(None);
+    }
+    let data_key = old.unwrap_data_key(wrapped)?;
+    Ok(Some(new.wrap_data_key(&data_key)?))
+}
+
+/// Long-lived X25519 identity of one device. Other devices seal the master key to its
+/// public key so it can join without the passphrase ever crossing the network.
+pub struct DeviceKeyPair {
+    secret: StaticSecret,
+}
+
+impl fmt::Debug for DeviceKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceKeyPair")
+            .field("public", &to_hex(&self.public_key()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeviceKeyPair {
+    pub fn generate() -> Result<Self, SyncError> {
+        Ok(Self {
+            secret: StaticSecret::from(random_bytes::<KEY_LEN>()?),
+        })
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// Parses a public key as printed by `keys device-keygen` (64 hex characters).
+    pub fn parse_public_key(hex: &str) -> Result<[u8; KEY_LEN], SyncError> {
+        from_hex(hex.trim())
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                SyncError::Crypto("device public key must be 64 hex characters".to_string())
+            })
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        to_hex(&self.public_key())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let contents = fs::read_to_string(path.as_ref()).map_err(SyncError::Io)?;
+        let mut bytes: [u8; KEY_LEN] = from_hex(contents.trim())
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| SyncError::Crypto("device key file is corrupt".to_string()))?;
+        let secret = StaticSecret::from(bytes);
+        bytes.zeroize();
+        Ok(Self { secret })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SyncError> {
+        let mut hex = to_hex(self.secret.as_bytes());
+        hex.push('\n');
+        let result = write_private(path.as_ref(), hex.as_bytes()).map_err(SyncError::Io);
+        hex.zeroize();
+        result
+    }
+
+    /// Opens an envelope produced by [`seal_for_device`] for this device.
+    pub fn open_envelope(&self, envelope: &[u8]) -> Result<MasterKey, SyncError> {
+        if envelope.len() < KEY_LEN {
+            return Err(SyncError::Crypto("key envelope is truncated".to_string()));
+        }
+        let (ephemeral, sealed) = envelope.split_at(KEY_LEN);
+        let ephemeral: [u8; KEY_LEN] = ephemeral.try_into().expect("split at key length");
+        let shared = self.secret.diffie_hellman(&PublicKey::from(ephemeral));
+        let key = envelope_key(shared.as_bytes(), &ephemeral, &self.public_key());
+        let mut plain = crypto::open(&key, ENVELOPE_INFO, sealed).map_err(|_| {
+            SyncError::Crypto("key envelope was not sealed for this device".to_string())
+        })?;
+        let master = match plain.len() {
+            // Envelopes from before naming keys were sent along carry the master key only.
+            KEY_LEN => open_master_bytes(&plain),
+            len if len == 2 * KEY_LEN => {
+                let mut naming: [u8; KEY_LEN] =
+                    plain[KEY_LEN..].try_into().expect("split at key length");
+                let master = open_master_bytes(&plain[..KEY_LEN])
+                    .map(|master| master.with_naming_key(naming));
+                naming.zeroize();
+                master
+            }
+            _ => Err(SyncError::Crypto(
+                "sealed master key has the wrong length".to_string(),
+            )),
+        };
+        plain.zeroize();
+        master
+    }
+}
+
+/// Seals the master key and its naming key to another device's public key (ephemeral
+/// X25519, HKDF-SHA256, XChaCha20-Poly1305). Only the holder of the matching device secret
+/// can open it.
+pub fn seal_for_device(
+    master: &MasterKey,
+    device_public_key: &[u8; KEY_LEN],
+) -> Result<Vec<u8>, SyncError> {
+    let ephemeral = StaticSecret::from(random_bytes::<KEY_LEN>()?);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*device_public_key));
+    let key = envelope_key(shared.as_bytes(), &ephemeral_public, device_public_key);
+    let mut keys = master.as_bytes().to_vec();
+    keys.extend_from_slice(master.naming_key());
+    let sealed = crypto::seal(&key, ENVELOPE_INFO, &keys);
+    keys.zeroize();
+    let mut envelope = ephemeral_public.to_vec();
+    envelope.extend(sealed?);
+    Ok(envelope)
+}
+
+/// One page of the data keys the server holds, as returned by `GET /v1/keys/wrapped`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WrappedKeyPage {
+    pub keys: Vec<Vec<u8>>,
+    pub next_cursor: Option<String>,
+}
+
+impl SyncClient {
+    pub fn list_wrapped_keys(&self, cursor: Option<&str>) -> Result<WrappedKeyPage, SyncError> {
+        let path = match cursor {
+            Some(cursor) => format!("/v1/keys/wrapped?cursor={}", percent_encode(cursor)),
+            None => "/v1/keys/wrapped".to_string(),
+        };
+        let response = self.send("GET", &path, "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+
+        let mut page = WrappedKeyPage::default();
+        for line in response.body.lines().filter(|line| !line.is_empty()) {
+            match line.split_once('=') {
+                Some(("key", hex)) => {
+                    page.keys.push(from_hex(hex).ok_or_else(|| {
+                        SyncError::Protocol(format!("invalid wrapped key: {hex}"))
+                    })?)
+                }
+                Some(("next", cursor)) if !cursor.is_empty() => {
+                    page.next_cursor = Some(cursor.to_string())
+                }
+                Some(("next", _)) => {}
+                _ => {
+                    return Err(SyncError::Protocol(format!(
+                        "unexpected key listing line: {line}"
+                    )))
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    /// Asks the server to replace each old wrapped data key with its new wrapping.
+    pub fn rewrap_keys(&self, replacements: &[(Vec<u8>, Vec<u8>)]) -> Result<(), SyncError> {
+        let body = replacements
+            .iter()
+            .map(|(old, new)| format!("rewrap={}:{}\n", to_hex(old), to_hex(new)))
+            .collect::<String>();
+        let response = self.send("POST", "/v1/keys/rewrap", &body)?;
+        if response.status == 200 || response.status == 204 {
+            return Ok(());
+        }
+        Err(SyncError::Server(response.status, response.body))
+    }
+
+    /// Rotates the master key: the store gets a new key, then every data key on the server
+    /// is re-wrapped under it. No content is re-encrypted. Save `store` afterwards; if this
+    /// fails part way, save it anyway and finish with [`SyncClient::resume_key_rotation`].
+    pub fn rotate_master_key(
+        &self,
+        store: &mut KeyStore,
+        current: &MasterKey,
+        passphrase: &str,
+    ) -> Result<(MasterKey, usize), SyncError> {
+        let next = store.begin_rotation(current, passphrase)?;
+        let rewrapped = self.resume_key_rotation(store, &next)?;
+        Ok((next, rewrapped))
+    }
+
+    /// Re-wraps any data keys still under the previous master key and completes the rotation.
+    pub fn resume_key_rotation(
+        &self,
+        store: &mut KeyStore,
+        current: &MasterKey,
+    ) -> Result<usize, SyncError> {
+        let Some(previous) = store.previous_key(current)? else {
+            return Ok(0);
+        };
+
+        let mut rewrapped = 0;
+        let mut cursor = None;
+        loop {
+            let page = self.list_wrapped_keys(cursor.as_deref())?;
+            let mut replacements = Vec::new();
+            for wrapped in page.keys {
+                if let Some(new_wrapped) = rewrap_data_key(&previous, current, &wrapped)? {
+                    replacements.push((wrapped, new_wrapped));
+                }
+            }
+            if !replacements.is_empty() {
+                self.rewrap_keys(&replacements)?;
+                rewrapped += replacements.len();
+            }
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        store.finish_rotation();
+        Ok(rewrapped)
+    }
+
+    /// Publishes the master key sealed to another device's public key.
+    pub fn share_master_key(
+        &self,
+        device_id: &str,
+        device_public_key: &[u8; KEY_LEN],
+        master: &MasterKey,
+    ) -> Result<(), SyncError> {
+        let envelope = seal_for_device(master, device_public_key)?;
+        let path = format!("/v1/devices/{}/key-envelope", percent_encode(device_id));
+        let response = self.send("PUT", &path, &to_hex(&envelope))?;
+        if response.status == 200 || response.status == 201 || response.status == 204 {
+            return Ok(());
+        }
+        Err(SyncError::Server(response.status, response.body))
+    }
+
+    /// Fetches and opens the master key another device sealed for this one.
+    pub fn receive_master_key(
+        &self,
+        device_id: &str,
+        device: &DeviceKeyPair,
+    ) -> Result<MasterKey, SyncError> {
+        let path = format!("/v1/devices/{}/key-envelope", percent_encode(device_id));
+        let response = self.send("GET", &path, "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+        let envelope = from_hex(response.body.trim())
+            .ok_or_else(|| SyncError::Protocol("key envelope is not hex".to_string()))?;
+        device.open_envelope(&envelope)
+    }
+}
+
+fn aad(slot: &str, generation: u32) -> Vec<u8> {
+    format!("rust-client keystore {slot} g{generation}").into_bytes()
+}
+
+fn open_master(key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> Result<MasterKey, SyncError> {
+    let mut plain = crypto::open(key, aad, sealed)?;
+    let master = open_master_bytes(&plain);
+    plain.zeroize();
+    master
+}
+
+fn open_master_bytes(plain: &[u8]) -> Result<MasterKey, SyncError> {
+    let mut bytes: [u8; KEY_LEN] = plain
+        .try_into()
+        .map_err(|_| SyncError::Crypto("sealed master key has the wrong length".to_string()))?;
+    let master = MasterKey::from_bytes(bytes);
+    bytes.zeroize();
+    Ok(master)
+}
+
+fn derive_key(input: &[u8], info: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0_u8; KEY_LEN];
+    Hkdf::<Sha256>::new(None, input)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn envelope_key(
+    shared: &[u8; KEY_LEN],
+    ephemeral: &[u8; KEY_LEN],
+    recipient: &[u8; KEY_LEN],
+) -> [u8; KEY_LEN] {
+    let mut info = ENVELOPE_INFO.to_vec();
+    info.extend_from_slice(ephemeral);
+    info.extend_from_slice(recipient);
+    derive_key(shared, &info)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::test_kdf_params;
+    use crate::test_support::{serve_scripted, temp_dir, MockResponse};
+    use crate::DataKey;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn recovery_key_prints_with_checksum_and_catches_typos() {
+        let recovery = RecoveryKey::generate().expect("recovery key should generate");
+        let printed = recovery.to_printable();
+        assert!(printed.split('-').all(|group| group.len() <= 4));
+
+        let reparsed = RecoveryKey::parse(&printed.to_uppercase().replace('-', " "))
+            .expect("printed key should parse regardless of case and separators");
+        assert_eq!(reparsed, recovery);
+
+        let mut typo = printed.into_bytes();
+        typo[0] = if typo[0] == b'a' { b'b' } else { b'a' };
+        assert!(RecoveryKey::parse(&String::from_utf8(typo).expect("ascii")).is_err());
+    }
+
+    #[test]
+    fn store_unlocks_with_passphrase_or_recovery_key_after_reload() {
+        let dir = temp_dir("keystore");
+        let (store, master, recovery) = KeyStore::create("hunter2 but longer", test_kdf_params())
+            .expect("store should be created");
+        store.save(dir.join("keystore")).expect("store should save");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join("keystore"))
+                .expect("store exists")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut loaded = KeyStore::load(dir.join("keystore")).expect("store should load");
+        assert_eq!(loaded, store);
+        assert_eq!(
+            loaded
+                .unlock("hunter2 but longer")
+                .expect("passphrase unlocks")
+                .as_bytes(),
+            master.as_bytes()
+        );
+        assert!(loaded.unlock("wrong").is_err());
+
+        let recovered = loaded
+            .unlock_with_recovery(&recovery)
+            .expect("recovery key unlocks");
+        loaded
+            .set_passphrase(&recovered, "new passphrase", test_kdf_params())
+            .expect("passphrase should be replaced");
+        assert_eq!(
+            loaded
+                .unlock("new passphrase")
+                .expect("new passphrase unlocks")
+                .as_bytes(),
+            master.as_bytes()
+        );
+    }
+
+    #[test]
+    fn rotation_rewraps_server_keys_without_touching_content() {
+        let (mut store, old_master, recovery) =
+            KeyStore::create("passphrase", test_kdf_params()).expect("store should be created");
+        let data_keys = (0..3)
+            .map(|_| DataKey::generate().expect("data key should generate"))
+            .collect::<Vec<_>>();
+        let server_keys = Arc::new(Mutex::new(
+            data_keys
+                .iter()
+                .map(|key| old_master.wrap_data_key(key).expect("wrap should succeed"))
+                .collect::<Vec<_>>(),
+        ));
+
+        let keys = Arc::clone(&server_keys);
+        let (base_url, handle) = serve_scripted(4, move |request| {
+            let mut keys = keys.lock().expect("server state lock");
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/keys/wrapped") => {
+                    MockResponse::text(200, &format!("key={}\nnext=page2\n", to_hex(&keys[0])))
+                }
+                ("GET", "/v1/keys/wrapped?cursor=page2") => MockResponse::text(
+                    200,
+                    &format!(
+                        "key={}\nkey={}\nnext=\n",
+                        to_hex(&keys[1]),
+                        to_hex(&keys[2])
+                    ),
+                ),
+                ("POST", "/v1/keys/rewrap") => {
+                    for line in request.body_text().lines() {
+                        let (old, new) = line
+                            .strip_prefix("rewrap=")
+                            .and_then(|pair| pair.split_once(':'))
+                            .expect("rewrap line");
+                        let old = from_hex(old).expect("hex");
+                        let slot = keys.iter().position(|key| *key == old).expect("known key");
+                        keys[slot] = from_hex(new).expect("hex");
+                    }
+                    MockResponse::text(204, "")
+                }
+                other => panic!("unexpected request {other:?}"),
+            }
+        });
+
+        let client = SyncClient::new(&base_url).expect("client should parse mock URL");
+        let (new_master, rewrapped) = client
+            .rotate_master_key(&mut store, &old_master, "passphrase")
+            .expect("rotation should succeed");
+        handle.join().expect("mock server thread should finish");
+
+        assert_eq!(rewrapped, 3);
+        assert!(!store.rotation_in_progress());
+        assert_eq!(store.generation(), 2);
+        assert_eq!(
+            store.unlock("passphrase").expect("unlock").as_bytes(),
+            new_master.as_bytes()
+        );
+        assert_eq!(
+            store
+                .unlock_with_recovery(&recovery)
+                .expect("recovery key survives rotation")
+                .as_bytes(),
+            new_master.as_bytes()
+        );
+        for wrapped in server_keys.lock().expect("server state lock").iter() {
+            assert!(new_master.unwrap_data_key(wrapped).is_ok());
+            assert!(old_master.unwrap_data_key(wrapped).is_err());
+        }
+
+        // Names and tags derive from the naming key, which outlives the rotation.
+        let path = temp_dir("rotated-store").join("keystore");
+        store.save(&path).expect("store should save");
+        let reloaded = KeyStore::load(&path).expect("store should load");
+        let unlocked = reloaded
+            .unlock_with_recovery(&recovery)
+            .expect("recovery unlocks");
+        assert_eq!(unlocked.naming_key(), old_master.naming_key());
+        assert_eq!(unlocked.content_tag("abc"), old_master.content_tag("abc"));
+        let (imported, _) =
+            KeyStore::import(&unlocked, "other", test_kdf_params()).expect("import should succeed");
+        assert_eq!(
+            imported
+                .unlock("other")
+                .expect("imported store unlocks")
+                .naming_key(),
+            old_master.naming_key()
+        );
+        let _ = fs::remove_dir_all(path.parent().expect("store has a parent"));
+    }
+
+    #[test]
+    fn master_key_sealed_to_a_device_opens_only_on_that_device() {
+        let master = MasterKey::generate()
+            .and_then(|key| key.rotated())
+            .expect("master key should generate");
+        let laptop = DeviceKeyPair::generate().expect("device key should generate");
+        let stranger = DeviceKeyPair::generate().expect("device key should generate");
+
+        let envelope = seal_for_device(&master, &laptop.public_key()).expect("seal should succeed");
+        let opened = laptop.open_envelope(&envelope).expect("laptop opens");
+        assert_eq!(opened.as_bytes(), master.as_bytes());
+        // A rotated key's naming key is not derivable from it, so it has to travel along.
+        assert_eq!(opened.naming_key(), master.naming_key());
+        assert!(stranger.open_envelope(&envelope).is_err());
+
+        let dir = temp_dir("device-key");
+        laptop
+            .save(dir.join("device.key"))
+            .expect("device key should save");
+        let reloaded = DeviceKeyPair::load(dir.join("device.key")).expect("device key should load");
+        assert_eq!(reloaded.public_key(), laptop.public_key());
+    }
+}
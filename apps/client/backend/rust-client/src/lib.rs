@@ -1,22 +1,205 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::fs::{self, File};
-use std::hash::Hasher;
-use std::io::{BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+use pipeline::{HashJob, HashedJob};
+use sha2::{Digest, Sha256};
+
+mod archive;
+mod bulk;
+mod changes;
+mod config;
+mod content;
+mod control;
+mod crypto;
+mod daemon;
+mod devices;
+mod hash_cache;
+mod ignore;
+mod json;
+mod keys;
+mod logging;
+mod metadata;
+mod path_cipher;
+mod pipeline;
+mod profiles;
+mod queue_store;
+mod reconcile;
+mod restore;
+mod selective;
+mod snapshot;
+mod sparse;
+mod stability;
+#[cfg(test)]
+mod test_support;
+mod throttle;
+mod verify;
+mod watcher;
+
+pub use archive::{archive_parts, ArchiveSource};
+pub use bulk::{ArchivePart, BulkRestore, JobState, PartDownload, RestoreJob, RestoreJobRequest};
+pub use changes::{ChangeFeed, ChangePage, ChangeStream, RemoteChange, RemoteChangeKind};
+pub use config::{
+    default_config_path, default_state_dir, Config, ConfigOverrides, ContentSettings,
+    EncryptionSettings, HashingSettings, RestoreSettings, RetryPolicy, PASSPHRASE_ENV,
+};
+pub use content::{
+    ChunkRecord, Codec, CompressionOptions, ContentDecoder, ContentManifest, ContentOptions,
+    EncryptionHeader, MAX_CHUNK_SIZE,
+};
+#[cfg(unix)]
+pub use control::ControlClient;
+pub use control::{ControlServer, ControlState, DaemonStatus, CONTROL_SOCKET, PROTOCOL_VERSION};
+pub use crypto::{ChunkPosition, DataKey, KdfParams, MasterKey};
+pub use daemon::{run_profiles, Backoff, Daemon, DaemonSignals, LockFile};
+pub use devices::{host_name, DeviceIdentity, DeviceRecord, CAPABILITIES};
+pub use hash_cache::{CachedHash, HashCache};
+pub use ignore::IgnoreRules;
+pub use json::Json;
+pub use keys::{
+    rewrap_data_key, seal_for_device, DeviceKeyPair, KeyStore, RecoveryKey, WrappedKeyPage,
+};
+pub use logging::{log_enabled, set_log_level, LogLevel};
+pub use metadata::{FileMetadata, MetadataReport, Xattr};
+pub use path_cipher::{PathCipher, PathIndex};
+pub use pipeline::HashBudget;
+pub use profiles::{list_profiles, load_isolated, profiles_in, Profile, DEFAULT_PROFILE};
+pub use queue_store::{load_dead_letters, load_queue};
+pub use reconcile::{
+    reconcile, Causality, FileVersion, ReconcileReport, Reconciler, Reconciliation, VersionVector,
+};
+pub use restore::{
+    CollisionPolicy, FileListPage, PlannedRestore, RestoreAction, RestoreReport, Restorer,
+};
+pub use selective::{restore_included, SelectiveCleanup, SelectiveSync};
+pub use snapshot::{
+    format_utc, parse_utc, ChangeKind, Snapshot, SnapshotChange, SnapshotPage, SnapshotSummary,
+    SyncedIndex,
+};
+pub use sparse::{sparse_layout, write_sparse, Extent, SparseLayout};
+pub use stability::{FileStamp, StabilityPolicy};
+pub use throttle::{
+    format_rate, parse_rate, BandwidthLimiter, BandwidthSchedule, ScheduleRule, ThrottledTransport,
+};
+pub use verify::{Verifier, VerifyEntry, VerifyReport, VerifyStatus};
+pub use watcher::{RootWatcher, WatchChanges};
 
 #[derive(Debug, Clone)]
 pub struct SyncClient {
     host: String,
     port: u16,
+    /// Sent as `X-Device-Id` with every request once set.
+    device: Option<String>,
+    /// Sent as `Authorization: Bearer` with every request once set.
+    token: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct SyncRequest {
     pub path: String,
     pub hash: String,
+    pub metadata: Option<FileMetadata>,
+    /// Metadata sealed by the root's [`PathCipher`], sent in place of `metadata` when paths
+    /// are encrypted.
+    pub sealed_metadata: Option<Vec<u8>>,
+    /// Set when this path is a hard link to a file already sent under `link_to`.
+    pub link_to: Option<String>,
+    /// Allocated ranges of a sparse file; the hash covers only these ranges.
+    pub sparse: Option<SparseLayout>,
+    /// Uploaded content, filled in at flush time when content upload is enabled.
+    pub chunks: Vec<ChunkRecord>,
+    pub encryption: Option<EncryptionHeader>,
+    /// Edits per device behind this content, sent when bidirectional sync is on.
+    pub version: Option<VersionVector>,
+}
+
+impl SyncRequest {
+    /// Renders the request in the `key=value` line format sent to `POST /v1/sync`.
+    pub fn encode_body(&self) -> String {
+        let mut body = format!("path={}\nhash={}\n", self.path, self.hash);
+        if let Some(metadata) = &self.metadata {
+            metadata.encode_lines(&mut body);
+        }
+        if let Some(sealed) = &self.sealed_metadata {
+            body.push_str(&format!("sealed_metadata={}\n", metadata::to_hex(sealed)));
+        }
+        if let Some(sparse) = &self.sparse {
+            sparse.encode_lines(&mut body);
+        }
+        if let Some(link_to) = &self.link_to {
+            body.push_str(&format!("link={link_to}\n"));
+        }
+        if let Some(encryption) = &self.encryption {
+            encryption.encode_line(&mut body);
+        }
+        for chunk in &self.chunks {
+            chunk.encode_line(&mut body);
+        }
+        if let Some(version) = &self.version {
+            body.push_str(&format!("version={version}\n"));
+        }
+        body
+    }
+
+    /// Parses a body produced by [`SyncRequest::encode_body`]. Unknown keys are ignored so
+    /// newer servers can add fields without breaking older clients.
+    pub fn decode_body(body: &str) -> Result<Self, SyncError> {
+        let mut path = None;
+        let mut hash = None;
+        let mut link_to = None;
+        let mut size = None;
+        let mut extents = None;
+        let mut chunks = Vec::new();
+        let mut encryption = None;
+        let mut version = None;
+        let mut sealed_metadata = None;
+        let mut metadata = FileMetadata::default();
+        let mut has_metadata = false;
+
+        for line in body.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| SyncError::Protocol(format!("malformed field: {line}")))?;
+            match key {
+                "path" => path = Some(value.to_string()),
+                "hash" => hash = Some(value.to_string()),
+                "link" => link_to = Some(value.to_string()),
+                "size" => size = Some(value),
+                "extents" => extents = Some(value),
+                "chunk" => chunks.push(ChunkRecord::decode(value)?),
+                "encryption" => encryption = Some(EncryptionHeader::decode(value)?),
+                "version" => version = Some(VersionVector::decode(value)?),
+                "sealed_metadata" => {
+                    sealed_metadata =
+                        Some(metadata::from_hex(value).ok_or_else(|| {
+                            SyncError::Protocol(format!("malformed field: {line}"))
+                        })?)
+                }
+                _ => has_metadata |= metadata.decode_line(key, value)?,
+            }
+        }
+
+        let sparse = match (size, extents) {
+            (Some(size), Some(extents)) => Some(SparseLayout::decode(size, extents)?),
+            _ => None,
+        };
+
+        Ok(Self {
+            path: path.ok_or_else(|| SyncError::Protocol("missing path field".to_string()))?,
+            hash: hash.ok_or_else(|| SyncError::Protocol("missing hash field".to_string()))?,
+            metadata: has_metadata.then_some(metadata),
+            sealed_metadata,
+            link_to,
+            sparse,
+            chunks,
+            encryption,
+            version,
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -27,6 +210,9 @@ pub enum SyncError {
     Protocol(String),
     Server(u16, String),
     InvalidPath(String),
+    FileUnstable(String),
+    Crypto(String),
+    InvalidConfig(String),
 }
 
 impl fmt::Display for SyncError {
@@ -38,6 +224,9 @@ impl fmt::Display for SyncError {
             Self::Protocol(message) => write!(f, "protocol error: {message}"),
             Self::Server(status, body) => write!(f, "server returned {status}: {body}"),
             Self::InvalidPath(path) => write!(f, "invalid path: {path}"),
+            Self::FileUnstable(path) => write!(f, "file is still changing: {path}"),
+            Self::Crypto(message) => write!(f, "encryption error: {message}"),
+            Self::InvalidConfig(message) => write!(f, "invalid configuration: {message}"),
         }
     }
 }
@@ -67,7 +256,24 @@ impl SyncClient {
             return Err(SyncError::InvalidBaseUrl(base_url.to_string()));
         }
 
-        Ok(Self { host, port })
+        Ok(Self {
+            host,
+            port,
+            device: None,
+            token: None,
+        })
+    }
+
+    /// Identifies every request as coming from device `id`, so the server can attribute it.
+    pub fn with_device(mut self, id: &str) -> Self {
+        self.device = Some(id.to_string());
+        self
+    }
+
+    /// Signs every request in with the account's bearer `token`.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
     }
 
     pub fn health_check(&self) -> Result<bool, SyncError> {
@@ -76,8 +282,7 @@ impl SyncClient {
     }
 
     pub fn sync_file(&self, req: &SyncRequest) -> Result<(), SyncError> {
-        let body = format!("path={}\nhash={}\n", req.path, req.hash);
-        let response = self.send("POST", "/v1/sync", &body)?;
+        let response = self.send("POST", "/v1/sync", &req.encode_body())?;
 
         if response.status == 200 || response.status == 202 {
             return Ok(());
@@ -86,7 +291,104 @@ impl SyncClient {
         Err(SyncError::Server(response.status, response.body))
     }
 
-    fn send(&self, method: &str, path: &str, body: &str) -> Result<HttpResponse, SyncError> {
+    /// Uploads one content chunk under its content address.
+    pub fn upload_chunk(&self, chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+        let path = format!("/v1/chunks/{chunk_id}");
+        let response = self.send_bytes("PUT", &path, "application/octet-stream", body)?;
+
+        if response.status == 200 || response.status == 201 || response.status == 204 {
+            return Ok(());
+        }
+
+        Err(SyncError::Server(response.status, response.body))
+    }
+
+    pub(crate) fn send(
+        &self,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<HttpResponse, SyncError> {
+        self.send_bytes(method, path, "text/plain", body.as_bytes())
+    }
+
+    pub(crate) fn send_bytes(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<HttpResponse, SyncError> {
+        let (status, body) = self.exchange(method, path, content_type, body)?;
+        Ok(HttpResponse {
+            status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    /// Sends one request and returns the status with the raw response body, so binary
+    /// downloads survive intact.
+    pub(crate) fn exchange(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>), SyncError> {
+        let mut stream = self.open(method, path, content_type, body)?;
+        let mut buffer = Vec::new();
+        stream
+            .read_to_end(&mut buffer)
+            .map_err(SyncError::Connection)?;
+
+        HttpResponse::parse(&buffer)
+    }
+
+    /// GETs `path` and copies a `200` body into `out` as it arrives, for downloads too
+    /// large to hold in memory. Returns the bytes written; a body cut short is an error.
+    pub(crate) fn download_to(&self, path: &str, out: &mut dyn Write) -> Result<u64, SyncError> {
+        let mut reader = BufReader::new(self.open("GET", path, "text/plain", b"")?);
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let read = reader
+                .read_until(b'\n', &mut head)
+                .map_err(SyncError::Connection)?;
+            if read == 0 {
+                return Err(SyncError::Protocol(
+                    "missing response separator".to_string(),
+                ));
+            }
+        }
+        let (status, _) = HttpResponse::parse(&head)?;
+        if status != 200 {
+            let mut body = String::new();
+            let _ = reader.read_to_string(&mut body);
+            return Err(SyncError::Server(status, body));
+        }
+
+        let expected = String::from_utf8_lossy(&head).lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("Content-Length")
+                .then(|| value.trim().parse::<u64>().ok())?
+        });
+        let written = io::copy(&mut reader, out).map_err(SyncError::Connection)?;
+        if expected.is_some_and(|expected| expected != written) {
+            return Err(SyncError::Connection(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("download of {path} ended after {written} bytes"),
+            )));
+        }
+        Ok(written)
+    }
+
+    /// Connects and sends one request, leaving the response to be read from the stream.
+    fn open(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<TcpStream, SyncError> {
         let address = format!("{}:{}", self.host, self.port);
         let mut stream = TcpStream::connect(address).map_err(SyncError::Connection)?;
         stream
@@ -96,38 +398,43 @@ impl SyncClient {
             .set_write_timeout(Some(Duration::from_secs(2)))
             .map_err(SyncError::Connection)?;
 
-        let request = format!(
-            "{method} {path} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        let mut identity = String::new();
+        if let Some(id) = &self.device {
+            identity.push_str(&format!("X-Device-Id: {id}\r\n"));
+        }
+        if let Some(token) = &self.token {
+            identity.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        let head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {}\r\n{identity}Content-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
             self.host,
             body.len(),
-            body
         );
 
         stream
-            .write_all(request.as_bytes())
+            .write_all(head.as_bytes())
             .map_err(SyncError::Connection)?;
+        stream.write_all(body).map_err(SyncError::Connection)?;
         stream.flush().map_err(SyncError::Connection)?;
-
-        let mut buffer = String::new();
-        stream
-            .read_to_string(&mut buffer)
-            .map_err(SyncError::Connection)?;
-
-        HttpResponse::parse(&buffer)
+        Ok(stream)
     }
 }
 
 #[derive(Debug)]
-struct HttpResponse {
-    status: u16,
-    body: String,
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) body: String,
 }
 
 impl HttpResponse {
-    fn parse(raw: &str) -> Result<Self, SyncError> {
-        let (head, body) = raw
-            .split_once("\r\n\r\n")
+    /// Splits a raw response into its status code and body bytes.
+    fn parse(raw: &[u8]) -> Result<(u16, Vec<u8>), SyncError> {
+        let marker = b"\r\n\r\n";
+        let header_end = raw
+            .windows(marker.len())
+            .position(|window| window == marker)
             .ok_or_else(|| SyncError::Protocol("missing response separator".to_string()))?;
+        let head = String::from_utf8_lossy(&raw[..header_end]);
 
         let status_line = head
             .lines()
@@ -141,16 +448,100 @@ impl HttpResponse {
             .parse::<u16>()
             .map_err(|_| SyncError::Protocol("invalid response status code".to_string()))?;
 
-        Ok(Self {
-            status,
-            body: body.to_string(),
-        })
+        Ok((status, raw[header_end + marker.len()..].to_vec()))
     }
 }
 
 pub trait SyncTransport {
     fn health_check(&mut self) -> Result<bool, SyncError>;
     fn sync_file(&mut self, req: &SyncRequest) -> Result<(), SyncError>;
+
+    /// Stores one content chunk. Only needed when the manager uploads content.
+    fn upload_chunk(&mut self, _chunk_id: &str, _body: &[u8]) -> Result<(), SyncError> {
+        Err(unsupported("chunk upload"))
+    }
+
+    /// Lists the server paths of every file at or below `prefix`. Only needed for restore.
+    fn list_files(&mut self, _prefix: &str) -> Result<Vec<String>, SyncError> {
+        Err(unsupported("file listing"))
+    }
+
+    /// Fetches the latest record the server holds for `path`.
+    fn fetch_file(&mut self, _path: &str) -> Result<SyncRequest, SyncError> {
+        Err(unsupported("file download"))
+    }
+
+    /// Downloads one content chunk by its content address.
+    fn download_chunk(&mut self, _chunk_id: &str) -> Result<Vec<u8>, SyncError> {
+        Err(unsupported("chunk download"))
+    }
+
+    /// Stores a snapshot manifest under its id.
+    fn put_snapshot(&mut self, _snapshot: &Snapshot) -> Result<(), SyncError> {
+        Err(unsupported("snapshot upload"))
+    }
+
+    /// Lists every snapshot the server holds, oldest first.
+    fn list_snapshots(&mut self) -> Result<Vec<SnapshotSummary>, SyncError> {
+        Err(unsupported("snapshot listing"))
+    }
+
+    fn fetch_snapshot(&mut self, _id: &str) -> Result<Snapshot, SyncError> {
+        Err(unsupported("snapshot download"))
+    }
+
+    /// One page of the server's change feed after `cursor`, or from its start.
+    fn changes(&mut self, _cursor: Option<&str>) -> Result<ChangePage, SyncError> {
+        Err(unsupported("change feed"))
+    }
+
+    /// Asks the server to build a restore archive and returns the job id.
+    fn create_restore_job(&mut self, _request: &RestoreJobRequest) -> Result<String, SyncError> {
+        Err(unsupported("restore jobs"))
+    }
+
+    fn restore_job(&mut self, _id: &str) -> Result<RestoreJob, SyncError> {
+        Err(unsupported("restore jobs"))
+    }
+
+    /// Streams one part of a ready job's archive into `out`, returning its length.
+    fn download_archive_part(
+        &mut self,
+        _id: &str,
+        _index: usize,
+        _out: &mut dyn Write,
+    ) -> Result<u64, SyncError> {
+        Err(unsupported("archive download"))
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, for path segments and query values.
+pub(crate) fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+const UNSUPPORTED: &str = "is not supported by this transport";
+
+fn unsupported(operation: &str) -> SyncError {
+    SyncError::Protocol(format!("{operation} {UNSUPPORTED}"))
+}
+
+/// Whether `err` says the server or transport does not offer an operation at all, as
+/// opposed to failing it this time.
+pub(crate) fn is_unsupported(err: &SyncError) -> bool {
+    match err {
+        SyncError::Server(404 | 501, _) => true,
+        SyncError::Protocol(message) => message.ends_with(UNSUPPORTED),
+        _ => false,
+    }
 }
 
 pub struct HttpTransport {
@@ -163,6 +554,18 @@ impl HttpTransport {
             client: SyncClient::new(base_url)?,
         })
     }
+
+    /// See [`SyncClient::with_device`].
+    pub fn with_device(mut self, id: &str) -> Self {
+        self.client = self.client.with_device(id);
+        self
+    }
+
+    /// See [`SyncClient::with_token`].
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.client = self.client.with_token(token);
+        self
+    }
 }
 
 impl SyncTransport for HttpTransport {
@@ -173,12 +576,88 @@ impl SyncTransport for HttpTransport {
     fn sync_file(&mut self, req: &SyncRequest) -> Result<(), SyncError> {
         self.client.sync_file(req)
     }
+
+    fn upload_chunk(&mut self, chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+        self.client.upload_chunk(chunk_id, body)
+    }
+
+    fn list_files(&mut self, prefix: &str) -> Result<Vec<String>, SyncError> {
+        let mut paths = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.client.list_files(prefix, cursor.as_deref())?;
+            paths.extend(page.paths);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(paths),
+            }
+        }
+    }
+
+    fn fetch_file(&mut self, path: &str) -> Result<SyncRequest, SyncError> {
+        self.client.fetch_file(path)
+    }
+
+    fn download_chunk(&mut self, chunk_id: &str) -> Result<Vec<u8>, SyncError> {
+        self.client.download_chunk(chunk_id)
+    }
+
+    fn put_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), SyncError> {
+        self.client.put_snapshot(snapshot)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<SnapshotSummary>, SyncError> {
+        let mut snapshots = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.client.list_snapshots(cursor.as_deref())?;
+            snapshots.extend(page.snapshots);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(snapshots),
+            }
+        }
+    }
+
+    fn fetch_snapshot(&mut self, id: &str) -> Result<Snapshot, SyncError> {
+        self.client.fetch_snapshot(id)
+    }
+
+    fn changes(&mut self, cursor: Option<&str>) -> Result<ChangePage, SyncError> {
+        self.client.changes(cursor)
+    }
+
+    fn create_restore_job(&mut self, request: &RestoreJobRequest) -> Result<String, SyncError> {
+        self.client.create_restore_job(request)
+    }
+
+    fn restore_job(&mut self, id: &str) -> Result<RestoreJob, SyncError> {
+        self.client.restore_job(id)
+    }
+
+    fn download_archive_part(
+        &mut self,
+        id: &str,
+        index: usize,
+        out: &mut dyn Write,
+    ) -> Result<u64, SyncError> {
+        self.client.download_archive_part(id, index, out)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct QueueEntry {
     request: SyncRequest,
     attempts: u32,
+    stamp: Option<FileStamp>,
+}
+
+/// A request that failed `max_attempts` times in a row and is no longer retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub request: SyncRequest,
+    pub attempts: u32,
+    pub error: String,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -186,11 +665,35 @@ pub struct FlushReport {
     pub succeeded: usize,
     pub failed: usize,
     pub remaining: usize,
+    pub deferred: usize,
+}
+
+impl fmt::Display for FlushReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} failed, {} remaining, {} deferred",
+            self.succeeded, self.failed, self.remaining, self.deferred
+        )
+    }
 }
 
 pub struct SyncManager<T: SyncTransport> {
     transport: T,
     queue: VecDeque<QueueEntry>,
+    stability: StabilityPolicy,
+    hash_budget: HashBudget,
+    hash_cache: Option<HashCache>,
+    content: Option<ContentOptions>,
+    /// One cipher per encrypted root; empty when paths are sent in the clear.
+    path_ciphers: Vec<PathCipher>,
+    path_index: PathIndex,
+    deferred: Vec<PathBuf>,
+    max_attempts: Option<u32>,
+    dead_letters: Vec<DeadLetter>,
+    synced: Option<SyncedIndex>,
+    device: Option<String>,
+    selective: SelectiveSync,
 }
 
 impl<T: SyncTransport> SyncManager<T> {
@@ -198,6 +701,18 @@ impl<T: SyncTransport> SyncManager<T> {
         Self {
             transport,
             queue: VecDeque::new(),
+            stability: StabilityPolicy::default(),
+            hash_budget: HashBudget::default(),
+            hash_cache: None,
+            content: None,
+            path_ciphers: Vec::new(),
+            path_index: PathIndex::in_memory(),
+            deferred: Vec::new(),
+            max_attempts: None,
+            dead_letters: Vec::new(),
+            synced: None,
+            device: None,
+            selective: SelectiveSync::default(),
         }
     }
 
@@ -207,40 +722,291 @@ impl<T: SyncTransport> SyncManager<T> {
             .map(|request| QueueEntry {
                 request,
                 attempts: 0,
+                stamp: None,
             })
             .collect::<VecDeque<_>>();
 
-        Self { transport, queue }
+        Self {
+            queue,
+            ..Self::new(transport)
+        }
+    }
+
+    pub fn with_stability_policy(mut self, policy: StabilityPolicy) -> Self {
+        self.stability = policy;
+        self
+    }
+
+    /// Moves a request to the dead letters after `attempts` failed sends instead of
+    /// retrying it forever. Without this, failed requests stay queued.
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts.max(1));
+        self
+    }
+
+    /// Restores dead letters saved by [`SyncManager::save_dead_letters`].
+    pub fn with_dead_letters(mut self, dead_letters: Vec<DeadLetter>) -> Self {
+        self.dead_letters = dead_letters;
+        self
     }
 
+    pub fn with_hash_budget(mut self, budget: HashBudget) -> Self {
+        self.hash_budget = budget;
+        self
+    }
+
+    /// Reuses digests for files whose stamp has not changed since they were last hashed.
+    pub fn with_hash_cache(mut self, cache: HashCache) -> Self {
+        self.hash_cache = Some(cache);
+        self
+    }
+
+    /// Uploads file content in chunks before each sync request, encrypting it on this
+    /// device when the options carry a master key.
+    pub fn with_content_upload(mut self, options: ContentOptions) -> Self {
+        self.content = Some(options);
+        self
+    }
+
+    /// Sends paths under the cipher's root encrypted, with their metadata sealed, and
+    /// records each encrypted path in `index` so it can be mapped back to the local name.
+    /// Call once per root; files outside every encrypted root are then refused rather than
+    /// sent in the clear.
+    pub fn with_path_encryption(mut self, cipher: PathCipher, index: PathIndex) -> Self {
+        self.path_ciphers.push(cipher);
+        self.path_index = index;
+        self
+    }
+
+    /// Records every request the server accepts in `index`, so
+    /// [`SyncManager::commit_snapshot`] can describe the whole synced tree.
+    pub fn with_snapshots(mut self, index: SyncedIndex) -> Self {
+        self.synced = Some(index);
+        self
+    }
+
+    /// Stamps every request with a version vector advanced for `device`, for bidirectional
+    /// sync. With [`SyncManager::with_snapshots`] the vector builds on the last synced one,
+    /// and content the server already holds for the path (such as a file this device just
+    /// downloaded) is not sent again.
+    pub fn with_versions(mut self, device: &str) -> Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Keeps paths the rules exclude off this device: they are not queued, and removing
+    /// them locally is not a deletion. See [`SyncManager::clean_excluded`].
+    pub fn with_selective_sync(mut self, rules: SelectiveSync) -> Self {
+        self.selective = rules;
+        self
+    }
+
+    pub fn selective_sync(&self) -> &SelectiveSync {
+        &self.selective
+    }
+
+    /// What the server last accepted per path, when snapshots are on.
+    pub fn synced_index(&self) -> Option<&SyncedIndex> {
+        self.synced.as_ref()
+    }
+
+    pub(crate) fn synced_index_mut(&mut self) -> Option<&mut SyncedIndex> {
+        self.synced.as_mut()
+    }
+
+    pub fn path_index(&self) -> &PathIndex {
+        &self.path_index
+    }
+
+    pub fn hash_cache_mut(&mut self) -> Option<&mut HashCache> {
+        self.hash_cache.as_mut()
+    }
+
+    /// Persists the hash cache. `queue_directory` does this after every scan; callers
+    /// queueing individual files decide when to pay for the write.
+    pub fn save_hash_cache(&mut self) -> Result<(), SyncError> {
+        match &mut self.hash_cache {
+            Some(cache) => cache.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Hashes and queues one file. A file that keeps changing while it is read is
+    /// parked in the deferred list and retried on the next flush.
     pub fn queue_file<P: AsRef<Path>>(&mut self, file_path: P) -> Result<SyncRequest, SyncError> {
-        let request = build_sync_request(file_path.as_ref())?;
-        self.queue.push_back(QueueEntry {
-            request: request.clone(),
-            attempts: 0,
-        });
-        Ok(request)
+        let file_path = file_path.as_ref();
+        if !self.selective.includes(file_path) {
+            return Err(SyncError::InvalidPath(format!(
+                "{} is excluded from sync on this device",
+                file_path.display()
+            )));
+        }
+        if let Some((request, stamp)) = self.cached_request(file_path)? {
+            self.push_entry(request.clone(), stamp);
+            return Ok(request);
+        }
+
+        let hashed_at = SystemTime::now();
+        match build_sync_request(file_path, &self.stability) {
+            Ok((request, stamp)) => {
+                self.remember_hash(&request, stamp, hashed_at);
+                self.push_entry(request.clone(), stamp);
+                Ok(request)
+            }
+            Err(err) => {
+                if matches!(err, SyncError::FileUnstable(_)) {
+                    self.defer(file_path.to_path_buf());
+                }
+                Err(err)
+            }
+        }
     }
 
-    pub fn queue_directory<P: AsRef<Path>>(&mut self, directory_path: P) -> Result<usize, SyncError> {
+    /// Queues every file under a directory, hashing on a worker pool sized by the
+    /// manager's [`HashBudget`]. Results enter the queue as they finish, so queue order
+    /// follows hashing speed rather than path order. Files still being written are
+    /// deferred rather than failing the whole directory, and are not counted as queued.
+    /// Hard links are hashed once; later links are queued as references to the first path.
+    /// Files excluded by selective sync are skipped.
+    pub fn queue_directory<P: AsRef<Path>>(
+        &mut self,
+        directory_path: P,
+    ) -> Result<usize, SyncError> {
         let mut files = Vec::new();
         collect_files(directory_path.as_ref(), &mut files)?;
+        files.retain(|file_path| self.selective.includes(file_path));
+        files.sort();
+
+        let scan_started = SystemTime::now();
+        let mut queued = 0;
+        let mut misses = Vec::new();
+        for job in group_hard_links(files) {
+            match self.cached_request(&job.path)? {
+                Some((request, stamp)) => {
+                    queued += self.push_with_links(request, stamp, &job.links)?
+                }
+                None => misses.push(job),
+            }
+        }
 
-        for file_path in &files {
-            let request = build_sync_request(file_path)?;
-            self.queue.push_back(QueueEntry {
-                request,
-                attempts: 0,
-            });
+        let budget = self.hash_budget.clone();
+        let policy = self.stability;
+        pipeline::hash_in_parallel(misses, &budget, policy, |hashed| {
+            let HashedJob { job, result } = hashed;
+            match result {
+                Ok((request, stamp)) => {
+                    self.remember_hash(&request, stamp, scan_started);
+                    queued += self.push_with_links(request, stamp, &job.links)?;
+                }
+                Err(SyncError::FileUnstable(_)) => {
+                    self.defer(job.path);
+                    for link_path in job.links {
+                        self.defer(link_path);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+            Ok(())
+        })?;
+
+        self.save_hash_cache()?;
+        Ok(queued)
+    }
+
+    /// Queues a hashed file followed by references for its other hard links and returns
+    /// how many entries were added.
+    fn push_with_links(
+        &mut self,
+        request: SyncRequest,
+        stamp: FileStamp,
+        link_paths: &[PathBuf],
+    ) -> Result<usize, SyncError> {
+        let links = link_paths
+            .iter()
+            .map(|link_path| {
+                let link = SyncRequest {
+                    path: link_path.to_string_lossy().to_string(),
+                    hash: request.hash.clone(),
+                    metadata: request.metadata.clone(),
+                    link_to: Some(request.path.clone()),
+                    ..SyncRequest::default()
+                };
+                Ok((link, FileStamp::read(link_path)?))
+            })
+            .collect::<Result<Vec<_>, SyncError>>()?;
+
+        self.push_entry(request, stamp);
+        for (link, link_stamp) in links {
+            self.push_entry(link, link_stamp);
         }
+        Ok(1 + link_paths.len())
+    }
+
+    /// Builds a request from the hash cache without reading file content, if the cache
+    /// holds a trustworthy digest for the file's current stamp.
+    fn cached_request(
+        &self,
+        file_path: &Path,
+    ) -> Result<Option<(SyncRequest, FileStamp)>, SyncError> {
+        let Some(cache) = &self.hash_cache else {
+            return Ok(None);
+        };
+        if !file_path.is_file() {
+            return Ok(None);
+        }
+        let stamp = FileStamp::read(file_path)?;
+        let path = file_path.to_string_lossy().to_string();
+        let Some(cached) = cache.lookup(&path, &stamp) else {
+            return Ok(None);
+        };
+        let request = SyncRequest {
+            path,
+            hash: cached.hash,
+            metadata: Some(FileMetadata::collect(file_path)?),
+            link_to: None,
+            sparse: cached.sparse,
+            ..SyncRequest::default()
+        };
+        Ok(Some((request, stamp)))
+    }
 
-        Ok(files.len())
+    fn remember_hash(&mut self, request: &SyncRequest, stamp: FileStamp, hashed_at: SystemTime) {
+        if let Some(cache) = &mut self.hash_cache {
+            cache.record(
+                &request.path,
+                stamp,
+                hashed_at,
+                &request.hash,
+                request.sparse.clone(),
+            );
+        }
+    }
+
+    /// Queues deferred files that have settled since the last attempt and returns how many were queued.
+    pub fn retry_deferred(&mut self) -> usize {
+        let mut queued = 0;
+        for file_path in std::mem::take(&mut self.deferred) {
+            match build_sync_request(&file_path, &self.stability) {
+                Ok((request, stamp)) => {
+                    self.push_entry(request, stamp);
+                    queued += 1;
+                }
+                Err(SyncError::FileUnstable(_)) => self.deferred.push(file_path),
+                // The file vanished or became unreadable; a later scan will pick it up again.
+                Err(_) => {}
+            }
+        }
+        queued
     }
 
     pub fn pending_count(&self) -> usize {
         self.queue.len()
     }
 
+    pub fn deferred_paths(&self) -> &[PathBuf] {
+        &self.deferred
+    }
+
     pub fn snapshot_queue(&self) -> Vec<SyncRequest> {
         self.queue
             .iter()
@@ -248,54 +1014,394 @@ impl<T: SyncTransport> SyncManager<T> {
             .collect::<Vec<_>>()
     }
 
+    /// Writes the queue to disk so [`load_queue`] can restore it after a restart.
+    pub fn save_queue<P: AsRef<Path>>(&self, path: P) -> Result<(), SyncError> {
+        queue_store::save_queue(path.as_ref(), &self.snapshot_queue())
+    }
+
+    pub fn dead_letters(&self) -> &[DeadLetter] {
+        &self.dead_letters
+    }
+
+    pub fn save_dead_letters<P: AsRef<Path>>(&self, path: P) -> Result<(), SyncError> {
+        queue_store::save_dead_letters(path.as_ref(), &self.dead_letters)
+    }
+
     pub fn health_check(&mut self) -> Result<bool, SyncError> {
         self.transport.health_check()
     }
 
     pub fn flush_once(&mut self) -> FlushReport {
+        self.flush_while(|_| true)
+    }
+
+    /// Like [`SyncManager::flush_once`], but passes each request to `keep_going` before
+    /// sending it and leaves the rest queued once it returns false, e.g. when a shutdown
+    /// was requested.
+    pub fn flush_while(&mut self, mut keep_going: impl FnMut(&SyncRequest) -> bool) -> FlushReport {
+        self.retry_deferred();
+
         let mut succeeded = 0;
         let mut failed = 0;
         let mut remaining = VecDeque::new();
 
         while let Some(mut entry) = self.queue.pop_front() {
-            match self.transport.sync_file(&entry.request) {
-                Ok(()) => {
+            if !keep_going(&entry.request) {
+                remaining.push_back(entry);
+                remaining.extend(self.queue.drain(..));
+                break;
+            }
+            match self.refresh_if_modified(&mut entry) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    failed += 1;
+                    self.record_failure(entry, err, &mut remaining);
+                    continue;
+                }
+            }
+
+            let versions = self
+                .device
+                .as_deref()
+                .map(|device| (device, self.synced.as_ref()));
+            match send_entry(
+                &mut self.transport,
+                self.content.as_ref(),
+                &self.path_ciphers,
+                versions,
+                &entry,
+            ) {
+                Ok(sent) => {
+                    if !self.path_ciphers.is_empty() {
+                        self.path_index
+                            .record(&sent.path, Path::new(&entry.request.path));
+                    }
+                    if let Some(synced) = &mut self.synced {
+                        synced.record(sent);
+                    }
+                    self.dead_letters
+                        .retain(|dead| dead.request.path != entry.request.path);
                     succeeded += 1;
                 }
-                Err(_) => {
+                // Still being written: wait for it to settle like any other busy file
+                // instead of spending an attempt.
+                Err(SyncError::FileUnstable(_)) => self.defer(PathBuf::from(&entry.request.path)),
+                Err(err) => {
                     failed += 1;
-                    entry.attempts += 1;
-                    remaining.push_back(entry);
+                    self.record_failure(entry, err, &mut remaining);
                 }
             }
         }
 
         self.queue = remaining;
+        // The index only speeds up lookups; encrypted paths can always be decrypted again.
+        let _ = self.path_index.save();
 
         FlushReport {
             succeeded,
             failed,
             remaining: self.queue.len(),
+            deferred: self.deferred.len(),
+        }
+    }
+
+    /// Flushes until nothing is queued or deferred, or `rounds` flushes have run, waiting
+    /// on `backoff` between rounds. `succeeded` counts every round; the other fields
+    /// describe the last one.
+    pub fn flush_with_retries(&mut self, rounds: usize, backoff: &mut Backoff) -> FlushReport {
+        let mut succeeded = 0;
+        let mut report = FlushReport {
+            succeeded: 0,
+            failed: 0,
+            remaining: self.queue.len(),
+            deferred: self.deferred.len(),
+        };
+        for round in 0..rounds {
+            if round > 0 {
+                std::thread::sleep(backoff.failure());
+            }
+            report = self.flush_once();
+            succeeded += report.succeeded;
+            if report.remaining == 0 && report.deferred == 0 {
+                break;
+            }
+        }
+        FlushReport {
+            succeeded,
+            ..report
+        }
+    }
+
+    /// Leaves a deleted local path out of later snapshots. Earlier snapshots keep it.
+    /// Paths excluded by selective sync are kept: they are gone locally, not deleted.
+    pub fn forget(&mut self, file_path: &Path) {
+        if !self.selective.includes(file_path) {
+            return;
+        }
+        let Ok(server_path) = self.server_path(file_path) else {
+            return;
+        };
+        if let Some(synced) = &mut self.synced {
+            synced.remove(&server_path);
+        }
+    }
+
+    /// The path the server knows a local file by.
+    pub(crate) fn server_path(&self, local_path: &Path) -> Result<String, SyncError> {
+        server_path_for(&self.path_ciphers, local_path)
+    }
+
+    pub(crate) fn local_path(&self, server_path: &str) -> Result<PathBuf, SyncError> {
+        if self.path_ciphers.is_empty() {
+            return Ok(PathBuf::from(server_path));
+        }
+        path_cipher::cipher_for_server(&self.path_ciphers, server_path)
+            .ok_or_else(|| SyncError::InvalidPath(server_path.to_string()))?
+            .decrypt_path(server_path)
+    }
+
+    /// The hash the server records for content with plaintext hash `hash`.
+    pub(crate) fn server_hash(&self, hash: &str) -> String {
+        match self
+            .content
+            .as_ref()
+            .and_then(|options| options.encryption.as_ref())
+        {
+            Some(master) => master.content_tag(hash),
+            None => hash.to_string(),
+        }
+    }
+
+    /// Uploads a manifest of everything synced so far, once nothing is left queued or
+    /// deferred. Returns `None` when snapshots are off, work is still pending, or the tree
+    /// has not changed since the last snapshot.
+    pub fn commit_snapshot(&mut self) -> Result<Option<SnapshotSummary>, SyncError> {
+        let Some(synced) = &mut self.synced else {
+            return Ok(None);
+        };
+        if !self.queue.is_empty() || !self.deferred.is_empty() || synced.is_empty() {
+            return Ok(None);
+        }
+        let Some(snapshot) = synced.pending_snapshot(SystemTime::now()) else {
+            return Ok(None);
+        };
+        self.transport.put_snapshot(&snapshot)?;
+        synced.mark_committed(&snapshot.id);
+        synced.save()?;
+        Ok(Some(snapshot.summary()))
+    }
+
+    pub fn save_synced_index(&self) -> Result<(), SyncError> {
+        match &self.synced {
+            Some(synced) => synced.save(),
+            None => Ok(()),
+        }
+    }
+
+    fn push_entry(&mut self, request: SyncRequest, stamp: FileStamp) {
+        self.queue.push_back(QueueEntry {
+            request,
+            attempts: 0,
+            stamp: Some(stamp),
+        });
+    }
+
+    /// Counts a failed send against the entry and keeps it queued in `remaining`, or moves
+    /// it to the dead letters once it has used up its attempts.
+    fn record_failure(
+        &mut self,
+        mut entry: QueueEntry,
+        err: SyncError,
+        remaining: &mut VecDeque<QueueEntry>,
+    ) {
+        entry.attempts += 1;
+        if self.max_attempts.is_some_and(|max| entry.attempts >= max) {
+            self.dead_letters.push(DeadLetter {
+                request: entry.request,
+                attempts: entry.attempts,
+                error: err.to_string(),
+            });
+        } else {
+            remaining.push_back(entry);
+        }
+    }
+
+    fn defer(&mut self, file_path: PathBuf) {
+        if !self.deferred.contains(&file_path) {
+            self.deferred.push(file_path);
         }
     }
+
+    /// Rehashes an entry whose file changed after it was queued so the server never
+    /// receives a hash for content that no longer exists. Returns false when the
+    /// entry was moved to the deferred list instead, and an error when the file can no
+    /// longer be read.
+    fn refresh_if_modified(&mut self, entry: &mut QueueEntry) -> Result<bool, SyncError> {
+        let Some(queued_stamp) = entry.stamp else {
+            return Ok(true);
+        };
+        let file_path = PathBuf::from(&entry.request.path);
+        if FileStamp::read(&file_path)? == queued_stamp {
+            return Ok(true);
+        }
+        match build_sync_request(&file_path, &self.stability) {
+            Ok((mut request, stamp)) => {
+                request.link_to = entry.request.link_to.take();
+                entry.request = request;
+                entry.stamp = Some(stamp);
+                Ok(true)
+            }
+            Err(_) => {
+                self.defer(file_path);
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Sends one queued entry and returns the request as the server saw it. Content is uploaded
+/// first when content upload is enabled; with encryption the plaintext hash is replaced by
+/// a keyed tag, and with path encryption the path and link target are encrypted and the
+/// metadata sealed. With
+/// versions, the request carries the synced version advanced for the device, and content
+/// identical to the synced record is not sent at all.
+fn send_entry<T: SyncTransport>(
+    transport: &mut T,
+    content: Option<&ContentOptions>,
+    path_ciphers: &[PathCipher],
+    versions: Option<(&str, Option<&SyncedIndex>)>,
+    entry: &QueueEntry,
+) -> Result<SyncRequest, SyncError> {
+    let mut outgoing = entry.request.clone();
+
+    if let Some((device, synced)) = versions {
+        let server_path = server_path_for(path_ciphers, Path::new(&outgoing.path))?;
+        let server_hash = match content.and_then(|options| options.encryption.as_ref()) {
+            Some(master) => master.content_tag(&outgoing.hash),
+            None => outgoing.hash.clone(),
+        };
+        let base = synced.and_then(|synced| synced.get(&server_path));
+        if let Some(base) = base.filter(|base| base.hash == server_hash) {
+            return Ok(base.clone());
+        }
+        let mut version = base
+            .and_then(|base| base.version.clone())
+            .unwrap_or_default();
+        version.increment(device);
+        outgoing.version = Some(version);
+    }
+
+    if let Some(options) = content {
+        if outgoing.link_to.is_none() {
+            let file_path = PathBuf::from(&outgoing.path);
+            let manifest =
+                content::upload_content(transport, &file_path, outgoing.sparse.as_ref(), options)?;
+            if let Some(stamp) = entry.stamp {
+                if FileStamp::read(&file_path)? != stamp {
+                    return Err(SyncError::FileUnstable(outgoing.path));
+                }
+            }
+            outgoing.chunks = manifest.chunks;
+            outgoing.encryption = manifest.encryption;
+        }
+        if let Some(master) = &options.encryption {
+            outgoing.hash = master.content_tag(&outgoing.hash);
+        }
+    }
+
+    if !path_ciphers.is_empty() {
+        let local_path = PathBuf::from(&outgoing.path);
+        outgoing.path = server_path_for(path_ciphers, &local_path)?;
+        if let Some(link_to) = &outgoing.link_to {
+            outgoing.link_to = Some(server_path_for(path_ciphers, Path::new(link_to))?);
+        }
+        if let Some(metadata) = outgoing.metadata.take() {
+            let cipher = path_cipher::cipher_for_local(path_ciphers, &local_path)
+                .expect("the path was just encrypted under one of the ciphers");
+            outgoing.sealed_metadata = Some(cipher.seal_metadata(&metadata, &outgoing.path)?);
+        }
+    }
+
+    transport.sync_file(&outgoing)?;
+    Ok(outgoing)
 }
 
-fn build_sync_request(file_path: &Path) -> Result<SyncRequest, SyncError> {
+/// `local_path` encrypted under its root's cipher, or as is when no root is encrypted.
+fn server_path_for(path_ciphers: &[PathCipher], local_path: &Path) -> Result<String, SyncError> {
+    if path_ciphers.is_empty() {
+        return Ok(local_path.to_string_lossy().to_string());
+    }
+    path_cipher::cipher_for_local(path_ciphers, local_path)
+        .ok_or_else(|| SyncError::InvalidPath(local_path.display().to_string()))?
+        .encrypt_path(local_path)
+}
+
+fn build_sync_request(
+    file_path: &Path,
+    stability: &StabilityPolicy,
+) -> Result<(SyncRequest, FileStamp), SyncError> {
     if !file_path.is_file() {
         return Err(SyncError::InvalidPath(file_path.display().to_string()));
     }
 
-    let hash = hash_file_streaming(file_path)?;
-    Ok(SyncRequest {
-        path: file_path.to_string_lossy().to_string(),
-        hash,
-    })
+    let hashed = stability::hash_file_stable(file_path, stability)?;
+    Ok((
+        SyncRequest {
+            path: file_path.to_string_lossy().to_string(),
+            hash: hashed.hash,
+            metadata: Some(FileMetadata::collect(file_path)?),
+            link_to: None,
+            sparse: hashed.sparse,
+            ..SyncRequest::default()
+        },
+        hashed.stamp,
+    ))
+}
+
+/// Turns a sorted file list into hash jobs, folding every extra hard link to an
+/// inode into the job of the first path that reaches it.
+fn group_hard_links(files: Vec<PathBuf>) -> Vec<HashJob> {
+    let mut jobs: Vec<HashJob> = Vec::with_capacity(files.len());
+    let mut first_link: HashMap<(u64, u64), usize> = HashMap::new();
+
+    for path in files {
+        if let Some(key) = hard_link_key(&path) {
+            if let Some(&index) = first_link.get(&key) {
+                jobs[index].links.push(path);
+                continue;
+            }
+            first_link.insert(key, jobs.len());
+        }
+        jobs.push(HashJob {
+            path,
+            links: Vec::new(),
+        });
+    }
+
+    jobs
+}
+
+/// Identifies files with more than one hard link by (device, inode).
+#[cfg(unix)]
+fn hard_link_key(file_path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(file_path).ok()?;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_key(_file_path: &Path) -> Option<(u64, u64)> {
+    None
 }
 
+/// SHA-256 of the file as lowercase hex. Digests are persisted in the hash cache and in
+/// remote records, so they must not depend on the toolchain that built the client.
 fn hash_file_streaming(file_path: &Path) -> Result<String, SyncError> {
     let file = File::open(file_path).map_err(SyncError::Io)?;
     let mut reader = BufReader::new(file);
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let mut hasher = Sha256::new();
     let mut buffer = [0_u8; 8192];
 
     loop {
@@ -303,10 +1409,10 @@ fn hash_file_streaming(file_path: &Path) -> Result<String, SyncError> {
         if bytes_read == 0 {
             break;
         }
-        hasher.write(&buffer[..bytes_read]);
+        hasher.update(&buffer[..bytes_read]);
     }
 
-    Ok(format!("{:016x}", hasher.finish()))
+    Ok(metadata::to_hex(&hasher.finalize()))
 }
 
 fn collect_files(directory_path: &Path, files: &mut Vec<PathBuf>) -> Result<(), SyncError> {
@@ -334,7 +1440,8 @@ mod tests {
     use std::net::TcpListener;
     use std::sync::{Arc, Mutex};
     use std::thread;
-    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::test_support::temp_dir;
 
     #[derive(Debug, Clone, Copy)]
     enum MockOutcome {
@@ -345,6 +1452,9 @@ mod tests {
     struct MockTransport {
         outcomes: VecDeque<MockOutcome>,
         requests: Vec<SyncRequest>,
+        chunks: HashMap<String, Vec<u8>>,
+        /// Appended to on every chunk upload, like a file another program is still writing.
+        growing: Option<PathBuf>,
     }
 
     impl MockTransport {
@@ -352,6 +1462,8 @@ mod tests {
             Self {
                 outcomes: outcomes.into(),
                 requests: Vec::new(),
+                chunks: HashMap::new(),
+                growing: None,
             }
         }
 
@@ -373,6 +1485,18 @@ mod tests {
                 MockOutcome::Fail => Err(SyncError::Server(503, "service unavailable".to_string())),
             }
         }
+
+        fn upload_chunk(&mut self, chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+            self.chunks.insert(chunk_id.to_string(), body.to_vec());
+            if let Some(path) = &self.growing {
+                let mut file = fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .map_err(SyncError::Io)?;
+                file.write_all(b"more\n").map_err(SyncError::Io)?;
+            }
+            Ok(())
+        }
     }
 
     #[test]
@@ -408,6 +1532,7 @@ mod tests {
             .sync_file(&SyncRequest {
                 path: "notes/todo.txt".to_string(),
                 hash: "abc123".to_string(),
+                ..SyncRequest::default()
             })
             .expect("202 response should be treated as successful enqueue");
 
@@ -418,6 +1543,26 @@ mod tests {
         assert!(request.contains("path=notes/todo.txt\nhash=abc123\n"));
     }
 
+    #[test]
+    fn requests_carry_the_profile_token_and_device_id() {
+        let captured_request = Arc::new(Mutex::new(String::new()));
+        let (base_url, handle) = start_mock_server(
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+            Arc::clone(&captured_request),
+        );
+
+        let client = SyncClient::new(&base_url)
+            .expect("client should parse mock URL")
+            .with_device("cafe0123")
+            .with_token("family-7f3a");
+        client.health_check().expect("health check should succeed");
+        handle.join().expect("mock server thread should finish");
+
+        let request = captured_request.lock().expect("capture lock should work");
+        assert!(request.contains("\r\nX-Device-Id: cafe0123\r\n"));
+        assert!(request.contains("\r\nAuthorization: Bearer family-7f3a\r\n"));
+    }
+
     #[test]
     fn sync_file_returns_error_when_server_rejects_payload() {
         let captured_request = Arc::new(Mutex::new(String::new()));
@@ -431,6 +1576,7 @@ mod tests {
             .sync_file(&SyncRequest {
                 path: "notes/todo.txt".to_string(),
                 hash: "abc123".to_string(),
+                ..SyncRequest::default()
             })
             .expect_err("500 response should be surfaced as a server error");
 
@@ -445,6 +1591,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn upload_chunk_puts_binary_body_under_its_content_address() {
+        let captured_request = Arc::new(Mutex::new(String::new()));
+        let (base_url, handle) = start_mock_server(
+            "HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n",
+            Arc::clone(&captured_request),
+        );
+
+        let client = SyncClient::new(&base_url).expect("client should parse mock URL");
+        client
+            .upload_chunk("c0ffee", &[0, 159, 146, 150])
+            .expect("201 response should count as stored");
+
+        handle.join().expect("mock server thread should finish");
+
+        let request = captured_request.lock().expect("capture lock should work");
+        assert!(request.starts_with("PUT /v1/chunks/c0ffee HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(request.contains("Content-Length: 4\r\n"));
+    }
+
     #[test]
     fn syncs_a_single_file_story_using_mock_transport() {
         let temp = temp_dir("single-file");
@@ -601,6 +1768,69 @@ mod tests {
         assert_eq!(manager.pending_count(), 0);
     }
 
+    #[test]
+    fn flush_with_retries_stops_once_the_queue_drains_or_rounds_run_out() {
+        let temp = temp_dir("retry-rounds");
+        let file_path = temp.join("flaky.txt");
+        fs::write(&file_path, "content").expect("test file should be written");
+        let outcomes = || vec![MockOutcome::Fail, MockOutcome::Fail, MockOutcome::Ok];
+        let mut backoff = Backoff::new(Duration::ZERO, Duration::ZERO);
+
+        let mut manager = SyncManager::new(MockTransport::with_outcomes(outcomes()));
+        manager
+            .queue_file(&file_path)
+            .expect("file should be queued");
+        let report = manager.flush_with_retries(5, &mut backoff);
+        assert_eq!(
+            (report.succeeded, report.failed, report.remaining),
+            (1, 0, 0)
+        );
+        assert_eq!(manager.transport.sent().len(), 3);
+        assert_eq!(
+            report.to_string(),
+            "1 sent, 0 failed, 0 remaining, 0 deferred"
+        );
+
+        let mut manager = SyncManager::new(MockTransport::with_outcomes(outcomes()));
+        manager
+            .queue_file(&file_path)
+            .expect("file should be queued");
+        let report = manager.flush_with_retries(2, &mut backoff);
+        assert_eq!(
+            (report.succeeded, report.failed, report.remaining),
+            (0, 1, 1)
+        );
+    }
+
+    #[test]
+    fn requests_that_keep_failing_become_dead_letters_until_sent() {
+        let temp = temp_dir("dead-letters");
+        let file_path = temp.join("doomed.txt");
+        fs::write(&file_path, "content").expect("test file should be written");
+        let transport = MockTransport::with_outcomes(vec![
+            MockOutcome::Fail,
+            MockOutcome::Fail,
+            MockOutcome::Ok,
+        ]);
+        let mut manager = SyncManager::new(transport).with_max_attempts(2);
+
+        manager
+            .queue_file(&file_path)
+            .expect("file should be queued");
+        assert_eq!(manager.flush_once().remaining, 1);
+        let report = manager.flush_once();
+        assert_eq!((report.failed, report.remaining), (1, 0));
+        assert_eq!(manager.dead_letters().len(), 1);
+        assert_eq!(manager.dead_letters()[0].attempts, 2);
+        assert!(manager.dead_letters()[0].error.contains("503"));
+
+        manager
+            .queue_file(&file_path)
+            .expect("file should be queued again");
+        assert_eq!(manager.flush_once().succeeded, 1);
+        assert!(manager.dead_letters().is_empty());
+    }
+
     #[test]
     fn directory_sync_continues_after_single_file_failure_and_recovers() {
         let temp = temp_dir("partial-directory");
@@ -667,6 +1897,326 @@ mod tests {
         assert_eq!(report.remaining, 0);
     }
 
+    #[test]
+    fn defers_file_still_being_written_until_it_settles() {
+        let temp = temp_dir("still-writing");
+        let file_path = temp.join("download.part");
+        fs::write(&file_path, "partial").expect("test file should be written");
+
+        let transport = MockTransport::with_outcomes(vec![MockOutcome::Ok]);
+        let mut manager = SyncManager::new(transport).with_stability_policy(StabilityPolicy {
+            quiet_period: Duration::from_secs(3600),
+            ..StabilityPolicy::default()
+        });
+
+        let error = manager
+            .queue_file(&file_path)
+            .expect_err("recently modified file should not be queued yet");
+        assert!(matches!(error, SyncError::FileUnstable(_)));
+        assert_eq!(manager.pending_count(), 0);
+        assert_eq!(manager.deferred_paths(), std::slice::from_ref(&file_path));
+
+        let still_writing = manager.flush_once();
+        assert_eq!(still_writing.succeeded, 0);
+        assert_eq!(still_writing.deferred, 1);
+
+        let mut manager = manager.with_stability_policy(StabilityPolicy::default());
+        let settled = manager.flush_once();
+        assert_eq!(settled.succeeded, 1);
+        assert_eq!(settled.deferred, 0);
+        assert!(manager.deferred_paths().is_empty());
+    }
+
+    #[test]
+    fn file_changing_during_upload_is_deferred_without_using_up_attempts() {
+        let temp = temp_dir("changing-during-upload");
+        let file_path = temp.join("recording.wav");
+        fs::write(&file_path, "take one\n").expect("test file should be written");
+
+        let mut transport = MockTransport::with_outcomes(Vec::new());
+        transport.growing = Some(file_path.clone());
+        let mut manager = SyncManager::new(transport)
+            .with_content_upload(ContentOptions::default())
+            .with_max_attempts(1);
+        manager
+            .queue_file(&file_path)
+            .expect("file should be queued");
+
+        for _ in 0..3 {
+            let report = manager.flush_once();
+            assert_eq!(
+                (report.succeeded, report.failed, report.deferred),
+                (0, 0, 1)
+            );
+        }
+        assert!(manager.dead_letters().is_empty());
+        assert!(manager.transport.sent().is_empty());
+        assert_eq!(manager.deferred_paths(), std::slice::from_ref(&file_path));
+    }
+
+    #[test]
+    fn unreadable_file_fails_instead_of_going_out_with_a_stale_hash() {
+        let temp = temp_dir("unreadable-before-upload");
+        let file_path = temp.join("gone.txt");
+        fs::write(&file_path, "soon removed").expect("test file should be written");
+
+        let mut manager = SyncManager::new(MockTransport::with_outcomes(Vec::new()));
+        manager
+            .queue_file(&file_path)
+            .expect("file should be queued");
+        fs::remove_file(&file_path).expect("file should be removed");
+
+        let report = manager.flush_once();
+        assert_eq!(
+            (report.succeeded, report.failed, report.remaining),
+            (0, 1, 1)
+        );
+        assert!(manager.transport.sent().is_empty());
+    }
+
+    #[test]
+    fn file_hashes_are_sha256_so_they_survive_toolchain_upgrades() {
+        let temp = temp_dir("sha256-hash");
+        let file_path = temp.join("abc.txt");
+        fs::write(&file_path, "abc").expect("test file should be written");
+
+        assert_eq!(
+            hash_file_streaming(&file_path).expect("file should hash"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn rehashes_file_modified_between_queueing_and_upload() {
+        let temp = temp_dir("modified-before-upload");
+        let file_path = temp.join("app.log");
+        fs::write(&file_path, "first line\n").expect("test file should be written");
+
+        let transport = MockTransport::with_outcomes(vec![MockOutcome::Ok]);
+        let mut manager = SyncManager::new(transport);
+        let queued = manager
+            .queue_file(&file_path)
+            .expect("log file should be queued");
+
+        fs::write(&file_path, "first line\nsecond line\n").expect("log file should be appended");
+
+        let report = manager.flush_once();
+        assert_eq!(report.succeeded, 1);
+
+        let sent = manager.transport.sent();
+        assert_eq!(sent.len(), 1);
+        assert_ne!(sent[0].hash, queued.hash);
+        assert_eq!(
+            sent[0].hash,
+            hash_file_streaming(&file_path).expect("current content should hash")
+        );
+    }
+
+    #[test]
+    fn queued_request_carries_file_metadata_to_the_server() {
+        let temp = temp_dir("metadata");
+        let file_path = temp.join("build.sh");
+        fs::write(&file_path, "#!/bin/sh\n").expect("script should be written");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755))
+                .expect("script should become executable");
+        }
+
+        let transport = MockTransport::with_outcomes(vec![MockOutcome::Ok]);
+        let mut manager = SyncManager::new(transport);
+        let request = manager
+            .queue_file(&file_path)
+            .expect("script should be queued");
+
+        let metadata = request
+            .metadata
+            .as_ref()
+            .expect("metadata should be captured");
+        #[cfg(unix)]
+        assert_eq!(metadata.mode, Some(0o755));
+        assert!(metadata.mtime_ns.is_some());
+
+        let body = request.encode_body();
+        assert_eq!(
+            SyncRequest::decode_body(&body).expect("encoded body should decode"),
+            request
+        );
+    }
+
+    #[test]
+    fn directory_sync_sends_hard_links_as_references_to_one_content_entry() {
+        let temp = temp_dir("hard-links");
+        fs::write(temp.join("a-original.txt"), "shared content")
+            .expect("original should be written");
+        fs::hard_link(temp.join("a-original.txt"), temp.join("b-link.txt"))
+            .expect("hard link should be created");
+        fs::write(temp.join("c-other.txt"), "other content").expect("other file should be written");
+
+        let transport = MockTransport::with_outcomes(vec![]);
+        let mut manager = SyncManager::new(transport);
+        let queued = manager
+            .queue_directory(&temp)
+            .expect("directory with hard links should queue");
+        assert_eq!(queued, 3);
+
+        let snapshot = manager.snapshot_queue();
+        let find = |name: &str| {
+            snapshot
+                .iter()
+                .find(|request| request.path.ends_with(name))
+                .expect("every file should be queued")
+        };
+        let original = find("a-original.txt");
+        let link = find("b-link.txt");
+        assert_eq!(original.link_to, None);
+        #[cfg(unix)]
+        {
+            assert_eq!(link.link_to.as_deref(), Some(original.path.as_str()));
+            assert_eq!(link.hash, original.hash);
+            assert!(link
+                .encode_body()
+                .contains(&format!("link={}\n", original.path)));
+        }
+        assert_eq!(find("c-other.txt").link_to, None);
+    }
+
+    #[test]
+    fn rescans_reuse_cached_hashes_until_paranoid_mode_forces_a_rehash() {
+        let temp = temp_dir("hash-cache");
+        let file_path = temp.join("movie.mkv");
+        fs::write(&file_path, "pretend this is 40 GB").expect("media file should be written");
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .and_then(|file| file.set_modified(SystemTime::now() - Duration::from_secs(3600)))
+            .expect("mtime should be moved out of the racy window");
+
+        let cache = HashCache::open(temp.join("state/hash-cache.tsv")).expect("cache should open");
+        let transport = MockTransport::with_outcomes(vec![]);
+        let mut manager = SyncManager::new(transport).with_hash_cache(cache);
+
+        manager
+            .queue_directory(&temp)
+            .expect("first scan should hash and cache the file");
+        let real_hash = manager.snapshot_queue()[0].hash.clone();
+        assert!(temp.join("state/hash-cache.tsv").is_file());
+
+        // Plant a sentinel digest so a cache hit is observable without timing the scan.
+        let path = file_path.to_string_lossy().to_string();
+        let stamp = FileStamp::read(&file_path).expect("stamp should be readable");
+        let cache = manager.hash_cache_mut().expect("cache should be attached");
+        cache.record(&path, stamp, SystemTime::now(), "cached-digest", None);
+
+        let cached = manager
+            .queue_file(&file_path)
+            .expect("rescan should queue the file");
+        assert_eq!(cached.hash, "cached-digest");
+
+        manager
+            .hash_cache_mut()
+            .expect("cache should be attached")
+            .set_paranoid(true);
+        let rehashed = manager
+            .queue_file(&file_path)
+            .expect("paranoid rescan should queue");
+        assert_eq!(rehashed.hash, real_hash);
+    }
+
+    #[test]
+    fn encrypted_upload_keeps_content_and_hash_unreadable_to_the_server() {
+        let temp = temp_dir("encrypted-upload");
+        let file_path = temp.join("minutes.txt");
+        let content = "board minutes: approve the new member dues".repeat(50);
+        fs::write(&file_path, &content).expect("test file should be written");
+
+        let master = MasterKey::derive_from_passphrase("members only", &crypto::test_kdf_params())
+            .expect("master key should derive");
+        let transport = MockTransport::with_outcomes(vec![MockOutcome::Ok]);
+        let mut manager = SyncManager::new(transport).with_content_upload(ContentOptions {
+            chunk_size: 256,
+            encryption: Some(master.clone()),
+            ..ContentOptions::default()
+        });
+        let queued = manager
+            .queue_file(&file_path)
+            .expect("file should be queued");
+
+        let report = manager.flush_once();
+        assert_eq!(report.succeeded, 1);
+
+        let sent = &manager.transport.sent()[0];
+        assert_ne!(sent.hash, queued.hash);
+        assert_eq!(sent.hash, master.content_tag(&queued.hash));
+        assert!(sent.encryption.is_some());
+        assert_eq!(sent.chunks.len(), content.len().div_ceil(256));
+
+        let manifest = ContentManifest {
+            chunks: sent.chunks.clone(),
+            encryption: sent.encryption.clone(),
+            ..ContentManifest::default()
+        };
+        let decoder = ContentDecoder::new(&manifest, Some(&master)).expect("decoder should build");
+        let mut restored = Vec::new();
+        for (index, chunk) in sent.chunks.iter().enumerate() {
+            let body = manager.transport.chunks[&chunk.id].clone();
+            assert!(!String::from_utf8_lossy(&body).contains("minutes"));
+            restored.extend(decoder.decode(index, body).expect("chunk should decrypt"));
+        }
+        assert_eq!(restored, content.as_bytes());
+
+        let decoded = SyncRequest::decode_body(&sent.encode_body()).expect("body should decode");
+        assert_eq!(&decoded, sent);
+    }
+
+    #[test]
+    fn path_encryption_hides_names_from_the_server_and_indexes_them_locally() {
+        let temp = temp_dir("encrypted-paths");
+        let private = temp.join("medical");
+        fs::create_dir_all(&private).expect("private dir should be created");
+        fs::write(private.join("diagnosis.pdf"), "pdf").expect("file should be written");
+
+        let master = MasterKey::generate().expect("master key should generate");
+        let cipher = PathCipher::for_root(&master, &temp, "root-1");
+        let transport = MockTransport::with_outcomes(vec![MockOutcome::Ok]);
+        let mut manager = SyncManager::new(transport)
+            .with_path_encryption(cipher.clone(), PathIndex::in_memory());
+
+        manager
+            .queue_directory(&temp)
+            .expect("directory should queue");
+        let report = manager.flush_once();
+        assert_eq!(report.succeeded, 1);
+
+        let sent = manager.transport.sent()[0].clone();
+        let sent_path = sent.path.clone();
+        assert!(sent_path.starts_with("root-1/"));
+        assert!(!sent_path.contains("medical") && !sent_path.contains("diagnosis"));
+        assert_eq!(sent.metadata, None, "plaintext metadata must not be sent");
+        let sealed = sent
+            .sealed_metadata
+            .as_deref()
+            .expect("metadata should be sent sealed");
+        assert_eq!(
+            cipher
+                .open_metadata(sealed, &sent_path)
+                .expect("sealed metadata should open"),
+            FileMetadata::collect(&private.join("diagnosis.pdf")).expect("metadata should collect")
+        );
+        assert_eq!(
+            manager.path_index().lookup(&sent_path),
+            Some(private.join("diagnosis.pdf").as_path())
+        );
+        assert_eq!(
+            cipher
+                .decrypt_path(&sent_path)
+                .expect("restore should decrypt the name"),
+            private.join("diagnosis.pdf")
+        );
+    }
+
+    #[rustfmt::skip]
     fn start_mock_server(
         response: &'static str,
         captured_request: Arc<Mutex<String>>,
@@ -710,6 +2260,7 @@ mod tests {
         String::from_utf8_lossy(&buffer).to_string()
     }
 
+    #[rustfmt::skip]
     fn request_is_complete(buffer: &[u8]) -> bool {
         let marker = b"\r\n\r\n";
         if let Some(header_end) = buffer.windows(marker.len()).position(|window| window == marker) {
@@ -731,15 +2282,4 @@ mod tests {
 
         false
     }
-
-    fn temp_dir(label: &str) -> PathBuf {
-        let nanos = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("clock should be after epoch")
-            .as_nanos();
-
-        let path = std::env::temp_dir().join(format!("rust-client-{label}-{nanos}"));
-        fs::create_dir_all(&path).expect("temp test dir should be created");
-        path
-    }
 }
@@ -0,0 +1,96 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::SyncError;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+thread_local! {
+    /// Profile name and log level of a thread running one of several profiles.
+    static PROFILE: RefCell<Option<(String, LogLevel)>> = const { RefCell::new(None) };
+}
+
+/// Verbosity of the messages written to stderr; each level includes the ones above it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    #[default]
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<Self, SyncError> {
+        match value.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" | "warning" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(SyncError::InvalidConfig(format!(
+                "log level `{value}` should be one of error, warn, info, debug"
+            ))),
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Error,
+            1 => Self::Warn,
+            2 => Self::Info,
+            _ => Self::Debug,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Sets the process-wide log level, or only this thread's while it runs a profile.
+pub fn set_log_level(level: LogLevel) {
+    let profile = PROFILE.with_borrow_mut(|profile| profile.as_mut().map(|(_, own)| *own = level));
+    if profile.is_none() {
+        LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    let threshold = PROFILE
+        .with_borrow(|profile| profile.as_ref().map(|(_, own)| *own as u8))
+        .unwrap_or_else(|| LEVEL.load(Ordering::Relaxed));
+    level as u8 <= threshold
+}
+
+/// Labels this thread's messages with profile `name` and gives it a log level of its own,
+/// so profiles run side by side stay apart. `None` returns to the process-wide settings.
+pub(crate) fn set_thread_profile(name: Option<&str>) {
+    let level = LogLevel::from_u8(LEVEL.load(Ordering::Relaxed));
+    PROFILE.set(name.map(|name| (name.to_string(), level)));
+}
+
+pub(crate) fn log(component: &str, level: LogLevel, message: &str) {
+    if !log_enabled(level) {
+        return;
+    }
+    let component = PROFILE.with_borrow(|profile| match profile {
+        Some((name, _)) => format!("{component} {name}"),
+        None => component.to_string(),
+    });
+    match level {
+        LogLevel::Info => eprintln!("[rust-client {component}] {message}"),
+        _ => eprintln!("[rust-client {component}] {level}: {message}"),
+    }
+}
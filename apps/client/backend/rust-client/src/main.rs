@@ -1,67 +1,1670 @@
-use rust_client::{SyncClient, SyncRequest};
+mod cli;
+
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cli::{Invocation, Parsed};
+#[cfg(unix)]
+use rust_client::ControlClient;
+use rust_client::{
+    archive_parts, default_config_path, default_state_dir, format_rate, format_utc, host_name,
+    list_profiles, load_dead_letters, load_queue, parse_rate, run_profiles, set_log_level,
+    ArchiveSource, Backoff, BandwidthLimiter, BandwidthSchedule, BulkRestore, ChangeFeed,
+    CollisionPolicy, Config, ConfigOverrides, Daemon, DaemonSignals, DeviceIdentity, DeviceKeyPair,
+    HashCache, HashingSettings, HttpTransport, Json, KdfParams, KeyStore, LockFile, LogLevel,
+    MasterKey, PathIndex, Profile, RecoveryKey, RestoreAction, RestoreJobRequest, RestoreReport,
+    Restorer, SelectiveSync, Snapshot, SnapshotSummary, SyncClient, SyncError, SyncManager,
+    SyncRequest, SyncTransport, SyncedIndex, ThrottledTransport, Verifier, VerifyReport,
+    VerifyStatus, CONTROL_SOCKET, DEFAULT_PROFILE, PASSPHRASE_ENV,
+};
 
 fn main() {
-    if let Err(message) = run() {
-        eprintln!("{message}");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        if cli::wants_json(&args) {
+            println!("{}", Json::object([("error", Json::from(message))]));
+        } else {
+            eprintln!("{message}");
+        }
         std::process::exit(1);
     }
 }
 
-fn run() -> Result<(), String> {
-    let args: Vec<String> = std::env::args().collect();
+fn run(args: &[String]) -> Result<(), String> {
+    let invocation = match cli::parse(args)? {
+        Parsed::Run(invocation) => invocation,
+        Parsed::Help(text) => {
+            print!("{text}");
+            return Ok(());
+        }
+        Parsed::Version => {
+            println!("{} {}", cli::BIN, env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    };
+    if invocation.switch("verbose") {
+        set_log_level(LogLevel::Debug);
+    }
+    let context = Context {
+        invocation: &invocation,
+    };
+
+    match invocation.name().as_str() {
+        "health" => health(&context),
+        "sync" => sync(&context),
+        "queue" => queue(&context),
+        "flush" => flush(&context),
+        "status" => status(&context),
+        "pause" | "resume" => {
+            let config = context.config()?;
+            let command = invocation.name();
+            let response = daemon_request(&config, &command)?.ok_or_else(|| {
+                format!("no daemon is running for {}", config.state_dir.display())
+            })?;
+            context.print(response, &format!("daemon {command}d"));
+            Ok(())
+        }
+        "dead-letters" => dead_letters(&context),
+        "restore" => restore(&context),
+        "verify" => verify(&context),
+        "changes" => changes(&context),
+        "snapshots list" => snapshots_list(&context),
+        "snapshots diff" => snapshots_diff(&context),
+        "config show" => config_show(&context),
+        "config check" => {
+            let path = context.config_path()?;
+            context.config()?;
+            context.print(
+                Json::object([("valid", Json::from(true))]),
+                &format!("{} is valid", path.display()),
+            );
+            Ok(())
+        }
+        "config path" => {
+            let path = context.config_path()?.display().to_string();
+            context.print(Json::object([("path", Json::from(path.as_str()))]), &path);
+            Ok(())
+        }
+        "daemon" => daemon(&context),
+        "completions" => {
+            let [shell] = invocation.positionals.as_slice() else {
+                return Err("completions takes one shell: bash, zsh or fish".to_string());
+            };
+            print!("{}", cli::completions(shell)?);
+            Ok(())
+        }
+        "profiles list" => profiles_list(&context),
+        "profiles add" => profiles_add(&context),
+        devices if devices.starts_with("devices ") => run_devices(&context),
+        keys if keys.starts_with("keys ") => run_keys(&context),
+        other => Err(format!("command `{other}` is not implemented")),
+    }
+}
+
+/// Flags that override config file settings; see [`ConfigOverrides::set`].
+const SETTING_FLAGS: [&str; 5] = [
+    "server",
+    "state-dir",
+    "root",
+    "log-level",
+    "bandwidth-limit",
+];
+
+/// Global options shared by every command.
+struct Context<'a> {
+    invocation: &'a Invocation,
+}
+
+impl Context<'_> {
+    fn json(&self) -> bool {
+        self.invocation.switch("json")
+    }
+
+    /// Prints `json` with `--json`, otherwise `text`.
+    fn print(&self, json: Json, text: &str) {
+        if self.json() {
+            println!("{json}");
+        } else {
+            println!("{text}");
+        }
+    }
+
+    /// `--profile` or `RUST_CLIENT_PROFILE`; an explicit `--config` wins over the variable.
+    fn profile(&self) -> Result<Option<Profile>, String> {
+        let name = match (
+            self.invocation.value("profile"),
+            self.invocation.value("config"),
+        ) {
+            (Some(_), Some(_)) => {
+                return Err("pass either --profile or --config, not both".to_string())
+            }
+            (Some(name), None) => name.to_string(),
+            (None, Some(_)) => return Ok(None),
+            (None, None) => match std::env::var("RUST_CLIENT_PROFILE") {
+                Ok(name) if !name.is_empty() => name,
+                _ => return Ok(None),
+            },
+        };
+        let profile = Profile::named(&name).map_err(to_message)?;
+        if name != DEFAULT_PROFILE && !profile.exists() {
+            return Err(format!(
+                "profile `{name}` does not exist; create it with `rust-client profiles add {name}`"
+            ));
+        }
+        Ok(Some(profile))
+    }
+
+    fn config_path(&self) -> Result<PathBuf, String> {
+        if let Some(profile) = self.profile()? {
+            return Ok(profile.config_path().to_path_buf());
+        }
+        match self.invocation.value("config") {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => default_config_path().map_err(to_message),
+        }
+    }
 
-    if args.len() < 4 {
-        print_usage();
-        return Err("missing required arguments".to_string());
+    /// Whether there is a config file to read; without one, commands rely on overrides.
+    fn has_config(&self) -> Result<bool, String> {
+        Ok(self.invocation.value("config").is_some() || self.config_path()?.exists())
+    }
+
+    /// Command-line overrides layered over the environment. `--verbose` implies debug logging.
+    fn overrides(&self) -> Result<ConfigOverrides, String> {
+        let mut flags = ConfigOverrides::default();
+        for (name, value) in &self.invocation.values {
+            if SETTING_FLAGS.contains(name) {
+                flags
+                    .set(name, value)
+                    .map_err(|err| format!("--{name}: {err}"))?;
+            }
+        }
+        if self.invocation.switch("verbose") && flags.log_level.is_none() {
+            flags.log_level = Some(LogLevel::Debug);
+        }
+        Ok(flags.layered_over(ConfigOverrides::from_env().map_err(to_message)?))
+    }
+
+    fn config(&self) -> Result<Config, String> {
+        match self.profile()? {
+            Some(profile) => profile.load(&self.overrides()?),
+            None => Config::load_with(self.config_path()?, &self.overrides()?),
+        }
+        .map_err(to_message)
+    }
+
+    /// `--server`, `RUST_CLIENT_SERVER` or the config file, in that order.
+    fn server(&self) -> Result<String, String> {
+        match self.overrides()?.server {
+            Some(server) => Ok(server),
+            None => self.config().map(|config| config.server),
+        }
+    }
+
+    /// `--state-dir`, `RUST_CLIENT_STATE_DIR`, the config file or the default, in that order.
+    fn state_dir(&self) -> Result<PathBuf, String> {
+        if let Some(state_dir) = self.overrides()?.state_dir {
+            return Ok(state_dir);
+        }
+        if self.has_config()? {
+            return self.config().map(|config| config.state_dir);
+        }
+        match self.profile()? {
+            Some(profile) => Ok(profile.state_dir().to_path_buf()),
+            None => default_state_dir().map_err(to_message),
+        }
+    }
+
+    /// The account token from the config file, if there is one.
+    fn token(&self) -> Result<Option<String>, String> {
+        if !self.has_config()? {
+            return Ok(None);
+        }
+        Ok(self.config()?.token)
+    }
+
+    /// This device's identity, once `devices register` or the daemon created one.
+    fn identity(&self) -> Result<Option<DeviceIdentity>, String> {
+        DeviceIdentity::load(self.state_dir()?).map_err(to_message)
+    }
+
+    /// A client for the server that signs in with the profile's token and identifies this
+    /// device, once it has an identity.
+    fn client(&self) -> Result<SyncClient, String> {
+        let mut client = SyncClient::new(&self.server()?).map_err(to_message)?;
+        if let Some(identity) = self.identity()? {
+            client = client.with_device(identity.id());
+        }
+        if let Some(token) = self.token()? {
+            client = client.with_token(&token);
+        }
+        Ok(client)
+    }
+
+    /// Like [`Context::client`], as a transport.
+    fn transport(&self) -> Result<HttpTransport, String> {
+        let mut transport = HttpTransport::new(&self.server()?).map_err(to_message)?;
+        if let Some(identity) = self.identity()? {
+            transport = transport.with_device(identity.id());
+        }
+        if let Some(token) = self.token()? {
+            transport = transport.with_token(&token);
+        }
+        Ok(transport)
+    }
+
+    /// A manager over `transport` that scans the way the daemon does: uploads are held to
+    /// the bandwidth limit, paths the selective sync rules exclude are skipped, and the
+    /// state directory's hash cache spares unchanged files a rehash unless `[hashing]
+    /// paranoid` or `--paranoid` asks for a full rehash. Directories are hashed within the
+    /// `[hashing]` budget, or `--hash-workers` and `--hash-rate`.
+    fn scan_manager<T: SyncTransport>(
+        &self,
+        transport: T,
+    ) -> Result<SyncManager<ThrottledTransport<T>>, String> {
+        let (bandwidth, selective, mut hashing) = if self.has_config()? {
+            let config = self.config()?;
+            (config.bandwidth, config.selective, config.hashing)
+        } else {
+            let limit = self.overrides()?.bandwidth_limit.flatten();
+            let schedule = BandwidthSchedule::fixed(limit);
+            (schedule, SelectiveSync::new(), HashingSettings::default())
+        };
+        let transport = ThrottledTransport::new(transport, BandwidthLimiter::new(bandwidth));
+        let mut hash_cache =
+            HashCache::open(self.state_dir()?.join("hash-cache")).map_err(to_message)?;
+        hash_cache.set_paranoid(hashing.paranoid || self.invocation.switch("paranoid"));
+        for (name, value) in &self.invocation.values {
+            if matches!(*name, "hash-workers" | "hash-rate") {
+                hashing
+                    .set(name, value)
+                    .map_err(|err| format!("--{name}: {err}"))?;
+            }
+        }
+        Ok(SyncManager::new(transport)
+            .with_selective_sync(selective)
+            .with_hash_cache(hash_cache)
+            .with_hash_budget(hashing.budget()))
+    }
+
+    /// Applies the config file's content upload and encryption settings; nothing is
+    /// changed when there is no file.
+    fn configure_manager<T: SyncTransport>(
+        &self,
+        mut manager: SyncManager<T>,
+    ) -> Result<SyncManager<T>, String> {
+        if !self.has_config()? {
+            return Ok(manager);
+        }
+        let config = self.config()?;
+        let master = config.master_key(prompt_passphrase).map_err(to_message)?;
+        let ciphers = config.path_ciphers(master.as_ref());
+        if !ciphers.is_empty() {
+            let index = PathIndex::open(config.state_dir.join("path-index")).map_err(to_message)?;
+            for cipher in ciphers {
+                manager = manager.with_path_encryption(cipher, index.clone());
+            }
+        }
+        if let Some(options) = config.content.options(master.as_ref()) {
+            manager = manager.with_content_upload(options);
+        }
+        Ok(manager)
     }
 
-    let command = args[1].as_str();
-    let server = if args[2] == "--server" {
-        args[3].as_str()
+    /// The master key from `--store`, or from the config's key store when it turns
+    /// encryption on.
+    fn master_key(&self) -> Result<Option<MasterKey>, String> {
+        if let Some(store_path) = self.invocation.value("store") {
+            let store = KeyStore::load(store_path).map_err(to_message)?;
+            return store
+                .unlock(&read_passphrase()?)
+                .map(Some)
+                .map_err(to_message);
+        }
+        if !self.has_config()? {
+            return Ok(None);
+        }
+        self.config()?
+            .master_key(prompt_passphrase)
+            .map_err(to_message)
+    }
+}
+
+fn health(context: &Context) -> Result<(), String> {
+    let healthy = context.client()?.health_check().map_err(to_message)?;
+    let text = if healthy {
+        "server is healthy"
     } else {
-        return Err("expected --server <URL>".to_string());
+        "server is reachable but unhealthy"
+    };
+    context.print(Json::object([("healthy", Json::from(healthy))]), text);
+    Ok(())
+}
+
+fn sync(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    if invocation.positionals.is_empty() {
+        return Err("sync needs at least one file or directory".to_string());
+    }
+    if invocation.switch("dry-run") {
+        let mut manager = context.scan_manager(DryRun)?;
+        queue_paths(&mut manager, &invocation.positionals)?;
+        print_plan(context, &manager);
+        return Ok(());
+    }
+
+    let retries = match invocation.value("retries") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| format!("--retries expects a number, got `{value}`"))?,
+        None => 3,
     };
+    // Like `flush`, never work on the queue and caches behind a running daemon's back.
+    let _lock = LockFile::acquire(context.state_dir()?.join("daemon.lock")).map_err(to_message)?;
+    let mut manager = context.configure_manager(context.scan_manager(context.transport()?)?)?;
+    queue_paths(&mut manager, &invocation.positionals)?;
+    manager.save_hash_cache().map_err(to_message)?;
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+    let report = manager.flush_with_retries(retries + 1, &mut backoff);
 
-    let client = SyncClient::new(server).map_err(|err| err.to_string())?;
+    context.print(
+        Json::object([
+            ("succeeded", Json::from(report.succeeded)),
+            ("failed", Json::from(report.failed)),
+            ("remaining", Json::from(report.remaining)),
+            ("deferred", Json::from(report.deferred)),
+        ]),
+        &report.to_string(),
+    );
+    if report.remaining > 0 || report.deferred > 0 {
+        return Err(format!(
+            "{} file(s) were not sent",
+            report.remaining + report.deferred
+        ));
+    }
+    Ok(())
+}
 
-    match command {
-        "health" => {
-            let ok = client.health_check().map_err(|err| err.to_string())?;
-            if ok {
-                println!("server is healthy");
-            } else {
-                println!("server is reachable but unhealthy");
+/// Hashes and queues each path; directories are walked recursively. Files still being
+/// written are deferred rather than failing the command.
+fn queue_paths<T: SyncTransport>(
+    manager: &mut SyncManager<T>,
+    paths: &[String],
+) -> Result<(), String> {
+    for path in paths.iter().map(Path::new) {
+        let queued = if path.is_dir() {
+            manager.queue_directory(path).map(drop)
+        } else {
+            manager.queue_file(path).map(drop)
+        };
+        match queued {
+            Ok(()) | Err(SyncError::FileUnstable(_)) => {}
+            Err(err) => return Err(format!("{}: {err}", path.display())),
+        }
+    }
+    Ok(())
+}
+
+fn print_plan<T: SyncTransport>(context: &Context, manager: &SyncManager<T>) {
+    let requests = manager.snapshot_queue();
+    let deferred = manager
+        .deferred_paths()
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    if context.json() {
+        let requests = requests
+            .iter()
+            .map(|request| {
+                Json::object([
+                    ("path", Json::from(request.path.as_str())),
+                    ("hash", Json::from(request.hash.as_str())),
+                    ("link_to", Json::from(request.link_to.clone())),
+                ])
+            })
+            .collect();
+        println!(
+            "{}",
+            Json::object([
+                ("requests", Json::Array(requests)),
+                ("deferred", Json::from(deferred))
+            ])
+        );
+        return;
+    }
+    println!("would send {} request(s):", requests.len());
+    for request in &requests {
+        match &request.link_to {
+            Some(target) => println!(
+                "  {}  {} (hard link to {target})",
+                request.hash, request.path
+            ),
+            None => println!("  {}  {}", request.hash, request.path),
+        }
+    }
+    for path in &deferred {
+        println!("  still being written: {path}");
+    }
+}
+
+/// Stands in for the server during `sync --dry-run`, which never flushes.
+struct DryRun;
+
+impl SyncTransport for DryRun {
+    fn health_check(&mut self) -> Result<bool, SyncError> {
+        Ok(true)
+    }
+
+    fn sync_file(&mut self, _req: &SyncRequest) -> Result<(), SyncError> {
+        Ok(())
+    }
+}
+
+fn queue(context: &Context) -> Result<(), String> {
+    let config = context.config()?;
+    let queue = load_queue(config.state_dir.join("queue")).map_err(to_message)?;
+    if context.json() {
+        let entries = queue
+            .iter()
+            .map(|request| {
+                Json::object([
+                    ("path", Json::from(request.path.as_str())),
+                    ("hash", Json::from(request.hash.as_str())),
+                ])
+            })
+            .collect();
+        println!("{}", Json::Array(entries));
+        return Ok(());
+    }
+    println!("{} request(s) queued", queue.len());
+    for request in &queue {
+        println!("  {}  {}", request.hash, request.path);
+    }
+    Ok(())
+}
+
+fn flush(context: &Context) -> Result<(), String> {
+    let config = context.config()?;
+    if let Some(response) = daemon_request(&config, "sync_now")? {
+        context.print(response, "asked the daemon to sync now");
+        return Ok(());
+    }
+    let _lock = LockFile::acquire(config.state_dir.join("daemon.lock")).map_err(to_message)?;
+
+    let transport = ThrottledTransport::new(
+        context.transport()?,
+        BandwidthLimiter::new(config.bandwidth.clone()),
+    );
+    let queue_path = config.state_dir.join("queue");
+    let queue = load_queue(&queue_path).map_err(to_message)?;
+    let hash_cache = HashCache::open(config.state_dir.join("hash-cache")).map_err(to_message)?;
+    let synced = SyncedIndex::open(config.state_dir.join("synced-index")).map_err(to_message)?;
+    let mut manager = SyncManager::from_snapshot(transport, queue)
+        .with_hash_cache(hash_cache)
+        .with_snapshots(synced);
+    let report = manager.flush_once();
+    manager.save_queue(&queue_path).map_err(to_message)?;
+    manager.save_hash_cache().map_err(to_message)?;
+    manager.save_synced_index().map_err(to_message)?;
+    // Like the daemon, record the tree once everything queued has reached the server.
+    if let Err(err) = manager.commit_snapshot() {
+        eprintln!("cannot commit snapshot: {err}");
+    }
+
+    context.print(
+        Json::object([
+            ("succeeded", Json::from(report.succeeded)),
+            ("failed", Json::from(report.failed)),
+            ("remaining", Json::from(report.remaining)),
+        ]),
+        &format!(
+            "flushed {} request(s), {} failed, {} remaining",
+            report.succeeded, report.failed, report.remaining
+        ),
+    );
+    if report.failed > 0 {
+        return Err(format!(
+            "{} request(s) failed and stay queued",
+            report.failed
+        ));
+    }
+    Ok(())
+}
+
+fn status(context: &Context) -> Result<(), String> {
+    let config_path = context.config_path()?;
+    let config = context.config()?;
+    let daemon_pid = LockFile::holder(config.state_dir.join("daemon.lock"));
+    let live = daemon_request(&config, "status")?;
+    let queued = match &live {
+        Some(live) => live.get("queued").and_then(Json::as_i64).unwrap_or(0) as usize,
+        None => load_queue(config.state_dir.join("queue"))
+            .map_err(to_message)?
+            .len(),
+    };
+    let roots = config
+        .roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect::<Vec<_>>();
+
+    if context.json() {
+        println!(
+            "{}",
+            Json::object([
+                ("config", Json::from(config_path.display().to_string())),
+                ("server", Json::from(config.server.as_str())),
+                ("roots", Json::from(roots)),
+                (
+                    "state_dir",
+                    Json::from(config.state_dir.display().to_string())
+                ),
+                (
+                    "daemon_pid",
+                    daemon_pid.map_or(Json::Null, |pid| Json::from(u64::from(pid)))
+                ),
+                ("queued", Json::from(queued)),
+                ("daemon", live.unwrap_or(Json::Null)),
+            ])
+        );
+        return Ok(());
+    }
+    println!("config:    {}", config_path.display());
+    println!("server:    {}", config.server);
+    println!("roots:     {}", roots.join(", "));
+    println!("state dir: {}", config.state_dir.display());
+    match daemon_pid {
+        Some(pid) => println!("daemon:    running (pid {pid})"),
+        None => println!("daemon:    not running"),
+    }
+    println!("queued:    {queued} request(s)");
+    if let Some(live) = live {
+        let field = |name: &str| live.get(name).cloned().unwrap_or(Json::Null);
+        if field("paused") == Json::Bool(true) {
+            println!("uploads:   paused");
+        }
+        if let Json::Array(paths) = field("in_flight") {
+            for path in paths.iter().filter_map(Json::as_str) {
+                println!("sending:   {path}");
             }
         }
-        "sync" => {
-            if args.len() != 8 {
-                print_usage();
-                return Err("sync requires --path and --hash".to_string());
+        match field("last_success").as_i64() {
+            Some(seconds) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs() as i64);
+                println!("last sync: {}s ago", (now - seconds).max(0));
             }
+            None => println!("last sync: none since the daemon started"),
+        }
+        println!(
+            "dead:      {} request(s)",
+            field("dead_letters").as_i64().unwrap_or(0)
+        );
+    }
+    Ok(())
+}
 
-            if args[4] != "--path" || args[6] != "--hash" {
-                return Err("expected sync --server <URL> --path <PATH> --hash <HASH>".to_string());
+fn dead_letters(context: &Context) -> Result<(), String> {
+    let config = context.config()?;
+    let entries = match daemon_request(&config, "dead_letters")? {
+        Some(response) => match response.get("dead_letters") {
+            Some(Json::Array(entries)) => entries.clone(),
+            _ => Vec::new(),
+        },
+        None => load_dead_letters(config.state_dir.join("dead-letters"))
+            .map_err(to_message)?
+            .into_iter()
+            .map(|dead| {
+                Json::object([
+                    ("path", Json::from(dead.request.path)),
+                    ("hash", Json::from(dead.request.hash)),
+                    ("attempts", Json::from(u64::from(dead.attempts))),
+                    ("error", Json::from(dead.error)),
+                ])
+            })
+            .collect(),
+    };
+    if context.json() {
+        println!("{}", Json::Array(entries));
+        return Ok(());
+    }
+    println!("{} dead letter(s)", entries.len());
+    for entry in &entries {
+        let text = |name: &str| {
+            entry
+                .get(name)
+                .and_then(Json::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let attempts = entry.get("attempts").and_then(Json::as_i64).unwrap_or(0);
+        println!(
+            "  {} ({attempts} attempts): {}",
+            text("path"),
+            text("error")
+        );
+    }
+    Ok(())
+}
+
+/// Sends `command` to the running daemon's control socket; `None` when no daemon is running.
+#[cfg(unix)]
+fn daemon_request(config: &Config, command: &str) -> Result<Option<Json>, String> {
+    if LockFile::holder(config.state_dir.join("daemon.lock")).is_none() {
+        return Ok(None);
+    }
+    let mut client =
+        ControlClient::connect(config.state_dir.join(CONTROL_SOCKET)).map_err(to_message)?;
+    client.request(command).map(Some).map_err(to_message)
+}
+
+#[cfg(not(unix))]
+fn daemon_request(_config: &Config, _command: &str) -> Result<Option<Json>, String> {
+    Ok(None)
+}
+
+fn restore(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    if invocation.positionals.is_empty() {
+        return Err("restore needs at least one file or directory".to_string());
+    }
+    if let Some(directory) = invocation.value("from-parts") {
+        let parts = archive_parts(directory).map_err(to_message)?;
+        if parts.is_empty() {
+            return Err(format!("{directory} holds no part-*.tar files"));
+        }
+        let mut restorer = configure_restorer(
+            context,
+            Restorer::new(ArchiveSource::open(&parts).map_err(to_message)?),
+        )?;
+        let mut total = RestoreReport::default();
+        for path in &invocation.positionals {
+            let mut report = restorer.restore(path).map_err(to_message)?;
+            // Chunks come off local disk; nothing is downloaded.
+            report.downloaded_bytes = 0;
+            add_report(&mut total, report);
+        }
+        return finish_restore(context, total);
+    }
+    if invocation.switch("bulk") {
+        return bulk_restore(context);
+    }
+
+    let transport = context.transport()?;
+    let mut restorer = configure_restorer(context, Restorer::new(transport))?;
+    let snapshot = match invocation.value("at") {
+        Some(spec) => Some(find_snapshot(context, spec)?),
+        None => None,
+    };
+
+    let mut total = RestoreReport::default();
+    for path in &invocation.positionals {
+        let report = match &snapshot {
+            Some(snapshot) => restorer.restore_snapshot(snapshot, path),
+            None => restorer.restore(path),
+        }
+        .map_err(to_message)?;
+        add_report(&mut total, report);
+    }
+    finish_restore(context, total)
+}
+
+/// Applies the restore options shared by live, bulk and archive restores.
+fn configure_restorer<T: SyncTransport>(
+    context: &Context,
+    mut restorer: Restorer<T>,
+) -> Result<Restorer<T>, String> {
+    let invocation = context.invocation;
+    if let Some(directory) = invocation.value("to") {
+        restorer = restorer.with_target(directory);
+    }
+    if let Some(policy) = invocation.value("on-conflict") {
+        let policy = CollisionPolicy::parse(policy).ok_or_else(|| {
+            format!("--on-conflict expects skip, overwrite, overwrite-if-older or keep-both, got `{policy}`")
+        })?;
+        restorer = restorer.with_collision_policy(policy);
+    }
+    if invocation.switch("dry-run") {
+        restorer = restorer.with_dry_run();
+    }
+    if let Some(master) = context.master_key()? {
+        restorer = restorer.with_master_key(master);
+    }
+    Ok(restorer)
+}
+
+/// `restore --bulk`: one server-built archive per path, downloaded in verified parts and
+/// extracted locally. Parts are removed afterwards unless kept or written to `--parts-dir`.
+fn bulk_restore(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    let part_size = match invocation.value("part-size") {
+        Some(value) => match parse_rate(value) {
+            Ok(Some(size)) if size >= 1 << 20 => size,
+            _ => {
+                return Err(format!(
+                    "--part-size expects a size of at least 1MiB, got `{value}`"
+                ))
             }
+        },
+        None => context.config()?.restore.part_size,
+    };
+    let snapshot = match invocation.value("at") {
+        Some(spec) => Some(find_snapshot_summary(context, spec)?.id),
+        None => None,
+    };
+    let transport = context.transport()?;
+    let mut bulk = BulkRestore::new(transport);
 
-            let request = SyncRequest {
-                path: args[5].clone(),
-                hash: args[7].clone(),
-            };
+    let mut total = RestoreReport::default();
+    for path in &invocation.positionals {
+        let request = RestoreJobRequest {
+            prefix: path.clone(),
+            snapshot: snapshot.clone(),
+            part_size,
+        };
+        let id = bulk.start(&request).map_err(to_message)?;
+        let mut last_state = "";
+        let job = bulk
+            .wait(&id, |job| {
+                if job.state.as_str() != last_state {
+                    last_state = job.state.as_str();
+                    eprintln!("restore job {id}: {last_state}");
+                }
+            })
+            .map_err(to_message)?;
+
+        let directory = match invocation.value("parts-dir") {
+            Some(directory) => PathBuf::from(directory),
+            None => context.config()?.state_dir.join("restore-jobs").join(&id),
+        };
+        let download = bulk.download_parts(&job, &directory).map_err(to_message)?;
+        eprintln!(
+            "downloaded {} of {} part(s) ({} bytes) to {}",
+            download.parts.len() - download.reused,
+            download.parts.len(),
+            download.downloaded_bytes,
+            directory.display()
+        );
+        total.downloaded_bytes += download.downloaded_bytes;
+
+        let archive = ArchiveSource::open(&download.parts).map_err(to_message)?;
+        let mut restorer = configure_restorer(context, Restorer::new(archive))?;
+        let mut report = restorer.restore(path).map_err(to_message)?;
+        // Only the parts came over the network; reading chunks back out of them is local.
+        report.downloaded_bytes = 0;
+        let keep = invocation.switch("keep-parts") || invocation.value("parts-dir").is_some();
+        if report.failed.is_empty() && !keep {
+            std::fs::remove_dir_all(&directory)
+                .map_err(|err| format!("{}: {err}", directory.display()))?;
+        }
+        add_report(&mut total, report);
+    }
+    finish_restore(context, total)
+}
+
+fn add_report(total: &mut RestoreReport, report: RestoreReport) {
+    total.restored += report.restored;
+    total.resumed += report.resumed;
+    total.unchanged += report.unchanged;
+    total.skipped += report.skipped;
+    total.downloaded_bytes += report.downloaded_bytes;
+    total.failed.extend(report.failed);
+    total.planned.extend(report.planned);
+}
+
+/// Prints the combined report, or the plan for a dry run, and fails if any path did.
+fn finish_restore(context: &Context, total: RestoreReport) -> Result<(), String> {
+    if context.invocation.switch("dry-run") {
+        print_restore_plan(context, &total);
+        if total.failed.is_empty() {
+            return Ok(());
+        }
+        return Err(format!(
+            "{} file(s) could not be planned",
+            total.failed.len()
+        ));
+    }
+
+    let failed = total
+        .failed
+        .iter()
+        .map(|(path, err)| {
+            Json::object([
+                ("path", Json::from(path.as_str())),
+                ("error", Json::from(err.to_string())),
+            ])
+        })
+        .collect::<Vec<_>>();
+    context.print(
+        Json::object([
+            ("restored", Json::from(total.restored)),
+            ("resumed", Json::from(total.resumed)),
+            ("unchanged", Json::from(total.unchanged)),
+            ("skipped", Json::from(total.skipped)),
+            ("downloaded_bytes", Json::from(total.downloaded_bytes)),
+            ("failed", Json::from(failed)),
+        ]),
+        &total.to_string(),
+    );
+    if total.failed.is_empty() {
+        return Ok(());
+    }
+    if !context.json() {
+        for (path, err) in &total.failed {
+            eprintln!("  {path}: {err}");
+        }
+    }
+    Err(format!(
+        "{} file(s) could not be restored",
+        total.failed.len()
+    ))
+}
+
+/// Prints what `restore --dry-run` would do to each destination, plus paths it could not plan.
+fn print_restore_plan(context: &Context, report: &RestoreReport) {
+    if context.json() {
+        let planned = report
+            .planned
+            .iter()
+            .map(|planned| {
+                let written = match &planned.action {
+                    RestoreAction::KeepBoth(alternate) => {
+                        Json::from(alternate.display().to_string())
+                    }
+                    _ => Json::Null,
+                };
+                Json::object([
+                    ("path", Json::from(planned.path.as_str())),
+                    ("dest", Json::from(planned.dest.display().to_string())),
+                    ("action", Json::from(planned.action.as_str())),
+                    ("restored_as", written),
+                ])
+            })
+            .collect();
+        let failed = report
+            .failed
+            .iter()
+            .map(|(path, err)| {
+                Json::object([
+                    ("path", Json::from(path.as_str())),
+                    ("error", Json::from(err.to_string())),
+                ])
+            })
+            .collect();
+        println!(
+            "{}",
+            Json::object([
+                ("planned", Json::Array(planned)),
+                ("failed", Json::Array(failed))
+            ])
+        );
+        return;
+    }
+    let changes = report.planned.iter().filter(|planned| {
+        !matches!(
+            planned.action,
+            RestoreAction::Skip | RestoreAction::Unchanged
+        )
+    });
+    println!("would change {} file(s):", changes.clone().count());
+    for planned in changes {
+        match &planned.action {
+            RestoreAction::KeepBoth(alternate) => {
+                println!("  keep-both  {} (local file kept)", alternate.display())
+            }
+            action => println!("  {:<9}  {}", action.as_str(), planned.dest.display()),
+        }
+    }
+    for planned in report
+        .planned
+        .iter()
+        .filter(|planned| planned.action == RestoreAction::Skip)
+    {
+        println!(
+            "  skip       {} (local file differs)",
+            planned.dest.display()
+        );
+    }
+    println!("{} unchanged", report.unchanged);
+    for (path, err) in &report.failed {
+        eprintln!("  {path}: {err}");
+    }
+}
+
+/// Audits each root against the server and fails unless everything is in sync, so the
+/// exit status can drive alerts.
+fn verify(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    let sample = match invocation.value("sample") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| format!("--sample expects a number, got `{value}`"))?,
+        None => 0,
+    };
+    let has_config = invocation.value("config").is_some() || context.config_path()?.exists();
+    let config = if has_config {
+        Some(context.config()?)
+    } else {
+        None
+    };
+    let roots = if invocation.positionals.is_empty() {
+        let roots = config
+            .as_ref()
+            .map(|config| config.roots.clone())
+            .unwrap_or_default();
+        if roots.is_empty() {
+            return Err(
+                "verify needs a root, either as an argument or in the config file".to_string(),
+            );
+        }
+        roots
+    } else {
+        invocation.positionals.iter().map(PathBuf::from).collect()
+    };
+
+    let transport = context.transport()?;
+    let mut verifier = Verifier::new(transport).with_sample(sample);
+    if let Some(config) = &config {
+        verifier = verifier
+            .with_ignore(config.ignore.clone())
+            .with_scratch_dir(config.state_dir.join("verify-scratch"));
+    }
+    if let Some(master) = context.master_key()? {
+        for cipher in config
+            .as_ref()
+            .map(|config| config.path_ciphers(Some(&master)))
+            .unwrap_or_default()
+        {
+            verifier = verifier.with_path_encryption(cipher);
+        }
+        verifier = verifier.with_master_key(master);
+    }
+    let mut report = VerifyReport::default();
+    for root in &roots {
+        report.merge(verifier.verify(root).map_err(to_message)?);
+    }
+
+    if context.json() {
+        let failures = |failures: &[(String, SyncError)]| {
+            failures
+                .iter()
+                .map(|(path, err)| {
+                    Json::object([
+                        ("path", Json::from(path.as_str())),
+                        ("error", Json::from(err.to_string())),
+                    ])
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut fields = vec![("complete".to_string(), Json::from(report.is_complete()))];
+        for status in VerifyStatus::ALL {
+            fields.push((
+                status.as_str().replace('-', "_"),
+                Json::from(report.count(status)),
+            ));
+        }
+        let paths = report
+            .entries
+            .iter()
+            .map(|entry| {
+                Json::object([
+                    ("path", Json::from(entry.path.as_str())),
+                    ("status", Json::from(entry.status.as_str())),
+                ])
+            })
+            .collect::<Vec<_>>();
+        fields.push(("paths".to_string(), Json::from(paths)));
+        fields.push(("sampled".to_string(), Json::from(report.sampled)));
+        fields.push((
+            "sample_failures".to_string(),
+            Json::from(failures(&report.sample_failures)),
+        ));
+        fields.push(("errors".to_string(), Json::from(failures(&report.errors))));
+        println!("{}", Json::object(fields));
+    } else {
+        println!("{report}");
+        for entry in report
+            .entries
+            .iter()
+            .filter(|entry| entry.status != VerifyStatus::InSync)
+        {
+            println!("  {:<16}  {}", entry.status.as_str(), entry.path);
+        }
+        for (path, err) in &report.sample_failures {
+            println!("  {:<16}  {path}: {err}", "sample-failed");
+        }
+        for (path, err) in &report.errors {
+            eprintln!("  {path}: {err}");
+        }
+    }
+    if report.is_complete() {
+        Ok(())
+    } else {
+        Err("the server does not hold a complete, intact copy".to_string())
+    }
+}
+
+/// Prints the server's change feed. The cursor is shown, not stored, so the daemon's own
+/// position in the feed is unaffected.
+fn changes(context: &Context) -> Result<(), String> {
+    let transport = context.transport()?;
+    let mut feed = ChangeFeed::new(transport);
+    if let Some(cursor) = context.invocation.value("since") {
+        feed = feed.with_cursor(cursor);
+    }
+    let changes = feed
+        .events()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(to_message)?;
+    let cursor = feed.cursor().unwrap_or_default().to_string();
+    if context.json() {
+        let entries = changes
+            .iter()
+            .map(|change| {
+                let optional =
+                    |value: &Option<String>| value.as_deref().map_or(Json::Null, Json::from);
+                Json::object([
+                    ("change", Json::from(change.kind.as_str())),
+                    ("path", Json::from(change.path.as_str())),
+                    ("hash", optional(&change.hash)),
+                    ("device", optional(&change.device)),
+                    ("time", Json::from(format_utc(change.time))),
+                ])
+            })
+            .collect();
+        let output = Json::object([
+            ("changes", Json::Array(entries)),
+            ("cursor", Json::from(cursor)),
+        ]);
+        println!("{output}");
+        return Ok(());
+    }
+    println!("{} change(s)", changes.len());
+    for change in &changes {
+        println!("  {}  {change}", format_utc(change.time));
+    }
+    println!("cursor: {cursor}");
+    Ok(())
+}
+
+fn snapshots_list(context: &Context) -> Result<(), String> {
+    let mut transport = context.transport()?;
+    let snapshots = transport.list_snapshots().map_err(to_message)?;
+    if context.json() {
+        let entries = snapshots
+            .iter()
+            .map(|snapshot| {
+                Json::object([
+                    ("id", Json::from(snapshot.id.as_str())),
+                    ("created", Json::from(format_utc(snapshot.created))),
+                    ("files", Json::from(snapshot.files)),
+                ])
+            })
+            .collect();
+        println!("{}", Json::Array(entries));
+        return Ok(());
+    }
+    println!("{} snapshot(s)", snapshots.len());
+    for snapshot in &snapshots {
+        println!("  {snapshot}");
+    }
+    Ok(())
+}
+
+fn snapshots_diff(context: &Context) -> Result<(), String> {
+    let [from, to] = context.invocation.positionals.as_slice() else {
+        return Err("snapshots diff takes two snapshots: <FROM> <TO>".to_string());
+    };
+    let older = find_snapshot(context, from)?;
+    let newer = find_snapshot(context, to)?;
+    let changes = older.diff(&newer);
+    if context.json() {
+        let entries = changes
+            .iter()
+            .map(|change| {
+                Json::object([
+                    ("path", Json::from(change.path.as_str())),
+                    ("change", Json::from(change.kind.as_str())),
+                ])
+            })
+            .collect();
+        println!("{}", Json::Array(entries));
+        return Ok(());
+    }
+    println!(
+        "{} change(s) from {} to {}",
+        changes.len(),
+        older.id,
+        newer.id
+    );
+    for change in &changes {
+        println!("  {:<8}  {}", change.kind.as_str(), change.path);
+    }
+    Ok(())
+}
+
+/// Resolves a snapshot id, id prefix or time against the server's listing and fetches it.
+fn find_snapshot(context: &Context, spec: &str) -> Result<Snapshot, String> {
+    let summary = find_snapshot_summary(context, spec)?;
+    let mut transport = context.transport()?;
+    transport.fetch_snapshot(&summary.id).map_err(to_message)
+}
+
+fn find_snapshot_summary(context: &Context, spec: &str) -> Result<SnapshotSummary, String> {
+    let mut transport = context.transport()?;
+    let snapshots = transport.list_snapshots().map_err(to_message)?;
+    Snapshot::select(&snapshots, spec)
+        .cloned()
+        .ok_or_else(|| format!("no snapshot matches `{spec}`"))
+}
+
+fn config_show(context: &Context) -> Result<(), String> {
+    let config = context.config()?;
+    let strings = |values: Vec<String>| Json::from(values);
+    let roots = strings(
+        config
+            .roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect(),
+    );
+    let ignore = strings(config.ignore.patterns().map(str::to_string).collect());
+    let schedule = strings(
+        config
+            .bandwidth
+            .rules
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    );
+    let paths = |paths: Vec<&Path>| {
+        strings(
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+        )
+    };
+    let exclude = paths(config.selective.excluded_paths().collect());
+    let include = paths(config.selective.included_paths().collect());
+    let state_dir = Json::from(config.state_dir.display().to_string());
+    let limit = Json::from(format_rate(config.bandwidth.default_limit));
+    let secs = |duration: std::time::Duration| Json::from(duration.as_secs());
+    let key_store = Json::from(config.key_store_path().display().to_string());
+    let passphrase_file = config
+        .encryption
+        .passphrase_file
+        .as_ref()
+        .map(|path| Json::from(path.display().to_string()));
+
+    if context.json() {
+        println!(
+            "{}",
+            Json::object([
+                ("server", Json::from(config.server.as_str())),
+                ("token_set", Json::from(config.token.is_some())),
+                ("roots", roots),
+                ("ignore", ignore),
+                ("state_dir", state_dir),
+                ("log_level", Json::from(config.log_level.as_str())),
+                ("flush_interval_secs", secs(config.flush_interval)),
+                ("scan_interval_secs", secs(config.scan_interval)),
+                ("bidirectional", Json::from(config.bidirectional)),
+                ("selective_exclude", exclude),
+                ("selective_include", include),
+                ("initial_backoff_secs", secs(config.retry.initial_backoff)),
+                ("max_backoff_secs", secs(config.retry.max_backoff)),
+                (
+                    "max_attempts",
+                    Json::from(u64::from(config.retry.max_attempts))
+                ),
+                ("bandwidth_limit", limit),
+                ("bandwidth_schedule", schedule),
+                ("content_upload", Json::from(config.content.upload)),
+                ("content_chunk_size", Json::from(config.content.chunk_size)),
+                ("content_compress", Json::from(config.content.compress)),
+                ("encrypt_content", Json::from(config.encryption.content)),
+                ("encrypt_paths", Json::from(config.encryption.paths)),
+                ("key_store", key_store),
+                (
+                    "passphrase_file",
+                    passphrase_file.clone().unwrap_or(Json::Null)
+                ),
+                ("hashing_paranoid", Json::from(config.hashing.paranoid)),
+                (
+                    "hashing_workers",
+                    Json::from(config.hashing.budget().workers)
+                ),
+                (
+                    "hashing_read_rate",
+                    Json::from(format_rate(config.hashing.read_rate))
+                ),
+                ("restore_part_size", Json::from(config.restore.part_size)),
+            ])
+        );
+        return Ok(());
+    }
+    // JSON strings and arrays are valid TOML, so the output can be saved as a config file.
+    println!("server = {}", Json::from(config.server.as_str()));
+    if config.token.is_some() {
+        println!("# token is set and not shown");
+    }
+    println!("roots = {roots}");
+    println!("ignore = {ignore}");
+    println!("state_dir = {state_dir}");
+    println!("log_level = \"{}\"", config.log_level);
+    println!("\n[sync]");
+    println!("flush_interval_secs = {}", config.flush_interval.as_secs());
+    println!("scan_interval_secs = {}", config.scan_interval.as_secs());
+    println!("bidirectional = {}", config.bidirectional);
+    println!("\n[selective_sync]");
+    println!("exclude = {exclude}");
+    println!("include = {include}");
+    println!("\n[retry]");
+    println!(
+        "initial_backoff_secs = {}",
+        config.retry.initial_backoff.as_secs()
+    );
+    println!("max_backoff_secs = {}", config.retry.max_backoff.as_secs());
+    println!("max_attempts = {}", config.retry.max_attempts);
+    println!("\n[bandwidth]");
+    println!("limit = {limit}");
+    println!("schedule = {schedule}");
+    println!("\n[content]");
+    println!("upload = {}", config.content.upload);
+    println!("chunk_size = {}", config.content.chunk_size);
+    println!("compress = {}", config.content.compress);
+    println!("\n[encryption]");
+    println!("content = {}", config.encryption.content);
+    println!("paths = {}", config.encryption.paths);
+    println!("key_store = {key_store}");
+    if let Some(passphrase_file) = passphrase_file {
+        println!("passphrase_file = {passphrase_file}");
+    }
+    println!("\n[hashing]");
+    println!("paranoid = {}", config.hashing.paranoid);
+    println!("workers = {}", config.hashing.budget().workers);
+    println!(
+        "read_rate = {}",
+        Json::from(format_rate(config.hashing.read_rate))
+    );
+    println!("\n[restore]");
+    println!("part_size = {}", config.restore.part_size);
+    Ok(())
+}
+
+fn daemon(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    let signals = DaemonSignals::install().map_err(to_message)?;
+    if invocation.switch("all-profiles") {
+        if invocation.value("config").is_some() || invocation.value("profile").is_some() {
+            return Err(
+                "--all-profiles runs every profile; drop --config and --profile".to_string(),
+            );
+        }
+        let profiles = list_profiles().map_err(to_message)?;
+        if profiles.is_empty() {
+            return Err(
+                "no profiles to run; create one with `rust-client profiles add`".to_string(),
+            );
+        }
+        return run_profiles(&profiles, context.overrides()?, &signals).map_err(to_message);
+    }
+    let mut daemon = match context.profile()? {
+        Some(profile) => Daemon::start_profile(&profile, context.overrides()?),
+        None => Daemon::start(context.config_path()?, context.overrides()?),
+    }
+    .map_err(to_message)?;
+    daemon.run(&signals).map_err(to_message)
+}
+
+fn profiles_list(context: &Context) -> Result<(), String> {
+    let profiles = list_profiles().map_err(to_message)?;
+    let current = match context.profile()? {
+        Some(profile) => profile.name().to_string(),
+        None => DEFAULT_PROFILE.to_string(),
+    };
+    let mut entries = Vec::new();
+    if !context.json() {
+        println!("{} profile(s)", profiles.len());
+    }
+    for profile in &profiles {
+        // Overrides are left out: they would make every profile look alike.
+        let (server, state_dir, error) = match profile.load(&ConfigOverrides::default()) {
+            Ok(config) => (Some(config.server), config.state_dir, None),
+            Err(err) => (
+                None,
+                profile.state_dir().to_path_buf(),
+                Some(err.to_string()),
+            ),
+        };
+        let daemon_pid = LockFile::holder(state_dir.join("daemon.lock"));
+        let in_use = profile.name() == current;
+        if context.json() {
+            entries.push(Json::object([
+                ("name", Json::from(profile.name())),
+                (
+                    "config",
+                    Json::from(profile.config_path().display().to_string()),
+                ),
+                ("server", server.map_or(Json::Null, Json::from)),
+                ("state_dir", Json::from(state_dir.display().to_string())),
+                (
+                    "daemon_pid",
+                    daemon_pid.map_or(Json::Null, |pid| Json::from(u64::from(pid))),
+                ),
+                ("in_use", Json::from(in_use)),
+                ("error", error.map_or(Json::Null, Json::from)),
+            ]));
+            continue;
+        }
+        let marker = if in_use { '*' } else { ' ' };
+        let daemon = match daemon_pid {
+            Some(pid) => format!("daemon running (pid {pid})"),
+            None => "daemon not running".to_string(),
+        };
+        match (server, error) {
+            (Some(server), _) => println!(
+                "{marker} {}  {server}  {}  {daemon}",
+                profile.name(),
+                state_dir.display()
+            ),
+            (None, error) => println!("{marker} {}  {}", profile.name(), error.unwrap_or_default()),
+        }
+    }
+    if context.json() {
+        println!("{}", Json::Array(entries));
+    }
+    Ok(())
+}
 
-            client.sync_file(&request).map_err(|err| err.to_string())?;
-            println!("sync request queued");
+fn profiles_add(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    let [name] = invocation.positionals.as_slice() else {
+        return Err("profiles add takes one profile name".to_string());
+    };
+    if name == DEFAULT_PROFILE {
+        return Err(format!(
+            "the `{DEFAULT_PROFILE}` profile is the plain config file; edit it with your editor"
+        ));
+    }
+    let server = invocation
+        .value("server")
+        .ok_or_else(|| "profiles add needs --server".to_string())?;
+    let roots = invocation
+        .values
+        .iter()
+        .filter(|(option, _)| *option == "root")
+        .map(|(_, root)| std::path::absolute(root).map_err(|err| format!("--root {root}: {err}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let profile = Profile::named(name).map_err(to_message)?;
+    profile.create(server, &roots).map_err(to_message)?;
+    context.print(
+        Json::object([
+            ("name", Json::from(name.as_str())),
+            (
+                "config",
+                Json::from(profile.config_path().display().to_string()),
+            ),
+            (
+                "state_dir",
+                Json::from(profile.state_dir().display().to_string()),
+            ),
+        ]),
+        &format!(
+            "created profile `{name}` in {}; its state lives in {}",
+            profile.config_path().display(),
+            profile.state_dir().display()
+        ),
+    );
+    Ok(())
+}
+
+fn run_keys(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    let flag = |name: &str| invocation.required(name).map(str::to_string);
+
+    match invocation.command[1] {
+        "init" => {
+            let store_path = flag("store")?;
+            let passphrase = read_passphrase()?;
+            let kdf = KdfParams::generate().map_err(to_message)?;
+            let (store, _, recovery) = KeyStore::create(&passphrase, kdf).map_err(to_message)?;
+            store.save(&store_path).map_err(to_message)?;
+            println!("key store written to {store_path}");
+            print_recovery_key(&recovery);
+        }
+        "recover" => {
+            let store_path = flag("store")?;
+            let recovery = RecoveryKey::parse(&flag("recovery-key")?).map_err(to_message)?;
+            let mut store = KeyStore::load(&store_path).map_err(to_message)?;
+            let master = store.unlock_with_recovery(&recovery).map_err(to_message)?;
+            let passphrase = read_passphrase()?;
+            let kdf = KdfParams::generate().map_err(to_message)?;
+            store
+                .set_passphrase(&master, &passphrase, kdf)
+                .map_err(to_message)?;
+            store.save(&store_path).map_err(to_message)?;
+            println!("passphrase replaced; the recovery key is unchanged");
+        }
+        "rotate" => {
+            let client = context.client()?;
+            let store_path = flag("store")?;
+            let passphrase = read_passphrase()?;
+            let mut store = KeyStore::load(&store_path).map_err(to_message)?;
+            let mut master = store.unlock(&passphrase).map_err(to_message)?;
+            if store.rotation_in_progress() {
+                println!("resuming key rotation to generation {}", store.generation());
+            } else {
+                master = store
+                    .begin_rotation(&master, &passphrase)
+                    .map_err(to_message)?;
+                store.save(&store_path).map_err(to_message)?;
+            }
+            let rewrapped = client
+                .resume_key_rotation(&mut store, &master)
+                .map_err(to_message)?;
+            store.save(&store_path).map_err(to_message)?;
+            println!(
+                "rotated to key generation {}; re-wrapped {rewrapped} data keys",
+                store.generation()
+            );
         }
-        _ => {
-            print_usage();
-            return Err(format!("unknown command: {command}"));
+        "device-keygen" => {
+            let device = DeviceKeyPair::generate().map_err(to_message)?;
+            device.save(flag("device-key")?).map_err(to_message)?;
+            println!("{}", device.public_key_hex());
         }
+        "share" => {
+            let client = context.client()?;
+            let store = KeyStore::load(flag("store")?).map_err(to_message)?;
+            let master = store.unlock(&read_passphrase()?).map_err(to_message)?;
+            let device_id = flag("device")?;
+            let public_key = match invocation.value("public-key") {
+                Some(public_key) => public_key.to_string(),
+                None => {
+                    let devices = client.list_devices().map_err(to_message)?;
+                    let device = devices
+                        .into_iter()
+                        .find(|device| device.id == device_id)
+                        .ok_or_else(|| {
+                            format!("device {device_id} is not registered; pass --public-key")
+                        })?;
+                    if device.revoked {
+                        return Err(format!("device {device_id} is revoked"));
+                    }
+                    device.public_key
+                }
+            };
+            let public_key = DeviceKeyPair::parse_public_key(&public_key).map_err(to_message)?;
+            client
+                .share_master_key(&device_id, &public_key, &master)
+                .map_err(to_message)?;
+            println!("master key shared with device {device_id}");
+        }
+        "accept" => {
+            let client = context.client()?;
+            let (device_id, device) = match invocation.value("device-key") {
+                Some(key_path) => {
+                    let device = DeviceKeyPair::load(key_path).map_err(to_message)?;
+                    (flag("device")?, device)
+                }
+                None => {
+                    let identity = context.identity()?.ok_or_else(|| {
+                        "this device has no identity; run `rust-client devices register` \
+                         or pass --device and --device-key"
+                            .to_string()
+                    })?;
+                    let device_id = invocation
+                        .value("device")
+                        .unwrap_or(identity.id())
+                        .to_string();
+                    (device_id, identity.into_key())
+                }
+            };
+            let master = client
+                .receive_master_key(&device_id, &device)
+                .map_err(to_message)?;
+            let store_path = flag("store")?;
+            let kdf = KdfParams::generate().map_err(to_message)?;
+            let (store, recovery) =
+                KeyStore::import(&master, &read_passphrase()?, kdf).map_err(to_message)?;
+            store.save(&store_path).map_err(to_message)?;
+            println!("key store written to {store_path}");
+            print_recovery_key(&recovery);
+        }
+        other => return Err(format!("unknown keys command: {other}")),
     }
 
     Ok(())
 }
 
-fn print_usage() {
-    eprintln!("Usage:");
-    eprintln!("  rust-client health --server <http://host:port>");
-    eprintln!("  rust-client sync --server <http://host:port> --path <relative/path> --hash <sha256>");
+fn run_devices(context: &Context) -> Result<(), String> {
+    let invocation = context.invocation;
+    match invocation.command[1] {
+        "register" => {
+            let identity =
+                DeviceIdentity::load_or_create(context.state_dir()?).map_err(to_message)?;
+            let name = invocation
+                .value("name")
+                .map_or_else(host_name, str::to_string);
+            let record = identity.record(&name);
+            context
+                .client()?
+                .register_device(&record)
+                .map_err(to_message)?;
+            context.print(
+                Json::object([
+                    ("id", Json::from(record.id.as_str())),
+                    ("name", Json::from(record.name.as_str())),
+                    ("platform", Json::from(record.platform.as_str())),
+                ]),
+                &format!("registered this device as {} ({name})", record.id),
+            );
+        }
+        "list" => {
+            let devices = context.client()?.list_devices().map_err(to_message)?;
+            let this = context
+                .identity()?
+                .map(|identity| identity.id().to_string());
+            if context.json() {
+                let entries = devices
+                    .iter()
+                    .map(|device| {
+                        Json::object([
+                            ("id", Json::from(device.id.as_str())),
+                            ("name", Json::from(device.name.as_str())),
+                            ("platform", Json::from(device.platform.as_str())),
+                            ("capabilities", Json::from(device.capabilities.clone())),
+                            ("public_key", Json::from(device.public_key.as_str())),
+                            (
+                                "last_seen",
+                                device
+                                    .last_seen
+                                    .map_or(Json::Null, |time| Json::from(format_utc(time))),
+                            ),
+                            ("revoked", Json::from(device.revoked)),
+                            (
+                                "this_device",
+                                Json::from(this.as_deref() == Some(device.id.as_str())),
+                            ),
+                        ])
+                    })
+                    .collect();
+                println!("{}", Json::Array(entries));
+                return Ok(());
+            }
+            println!("{} device(s)", devices.len());
+            for device in &devices {
+                let marker = if this.as_deref() == Some(device.id.as_str()) {
+                    '*'
+                } else {
+                    ' '
+                };
+                let last_seen = device
+                    .last_seen
+                    .map_or_else(|| "never".to_string(), format_utc);
+                println!("{marker} {device}  last seen {last_seen}");
+            }
+        }
+        "rename" => {
+            let [id, name] = invocation.positionals.as_slice() else {
+                return Err("devices rename takes a device id and a new name".to_string());
+            };
+            context
+                .client()?
+                .rename_device(id, name)
+                .map_err(to_message)?;
+            context.print(
+                Json::object([("renamed", Json::from(id.as_str()))]),
+                &format!("renamed device {id} to {name}"),
+            );
+        }
+        "revoke" => {
+            let [id] = invocation.positionals.as_slice() else {
+                return Err("devices revoke takes one device id".to_string());
+            };
+            context.client()?.revoke_device(id).map_err(to_message)?;
+            context.print(
+                Json::object([("revoked", Json::from(id.as_str()))]),
+                &format!("revoked device {id}; the server refuses its requests from now on"),
+            );
+        }
+        other => return Err(format!("unknown devices command: {other}")),
+    }
+    Ok(())
+}
+
+fn to_message(err: SyncError) -> String {
+    err.to_string()
+}
+
+/// Reads the passphrase from `RUST_CLIENT_PASSPHRASE`, or the first line of stdin.
+fn read_passphrase() -> Result<String, String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    eprintln!("passphrase:");
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|err| err.to_string())?;
+    let passphrase = line.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        return Err("passphrase must not be empty".to_string());
+    }
+    Ok(passphrase)
+}
+
+/// [`read_passphrase`] for [`Config::master_key`].
+fn prompt_passphrase() -> Result<String, SyncError> {
+    read_passphrase().map_err(SyncError::InvalidConfig)
+}
+
+fn print_recovery_key(recovery: &RecoveryKey) {
+    println!("recovery key (write it down; it is not stored anywhere):");
+    println!("  {}", recovery.to_printable());
 }
@@ -0,0 +1,438 @@
+use std::fs::{self, File, FileTimes};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::SyncError;
+
+/// File attributes captured alongside the content hash so a restore can bring them back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime_ns: Option<i64>,
+    pub atime_ns: Option<i64>,
+    pub xattrs: Vec<Xattr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Outcome of reapplying metadata. Fields the process may not set (for example
+/// ownership when not running as root) are reported instead of failing the restore.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MetadataReport {
+    pub applied: Vec<String>,
+    pub skipped: Vec<(String, String)>,
+}
+
+impl FileMetadata {
+    pub fn collect(file_path: &Path) -> Result<Self, SyncError> {
+        let metadata = fs::metadata(file_path).map_err(SyncError::Io)?;
+        Ok(Self {
+            xattrs: xattr::list(file_path).map_err(SyncError::Io)?,
+            ..Self::from_fs_metadata(&metadata)
+        })
+    }
+
+    #[cfg(unix)]
+    fn from_fs_metadata(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            mtime_ns: Some(metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec()),
+            atime_ns: Some(metadata.atime() * 1_000_000_000 + metadata.atime_nsec()),
+            xattrs: Vec::new(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_fs_metadata(metadata: &fs::Metadata) -> Self {
+        use crate::stability::system_time_ns;
+
+        Self {
+            mtime_ns: metadata.modified().ok().map(system_time_ns),
+            atime_ns: metadata.accessed().ok().map(system_time_ns),
+            ..Self::default()
+        }
+    }
+
+    /// Appends the metadata as `key=value` lines in the sync payload format.
+    pub(crate) fn encode_lines(&self, out: &mut String) {
+        if let Some(mode) = self.mode {
+            out.push_str(&format!("mode={mode:o}\n"));
+        }
+        if let Some(uid) = self.uid {
+            out.push_str(&format!("uid={uid}\n"));
+        }
+        if let Some(gid) = self.gid {
+            out.push_str(&format!("gid={gid}\n"));
+        }
+        if let Some(mtime_ns) = self.mtime_ns {
+            out.push_str(&format!("mtime={mtime_ns}\n"));
+        }
+        if let Some(atime_ns) = self.atime_ns {
+            out.push_str(&format!("atime={atime_ns}\n"));
+        }
+        for xattr in &self.xattrs {
+            out.push_str(&format!("xattr={}:{}\n", xattr.name, to_hex(&xattr.value)));
+        }
+    }
+
+    /// Reads one `key=value` line produced by [`FileMetadata::encode_lines`].
+    /// Returns `Ok(false)` for keys that are not metadata.
+    pub(crate) fn decode_line(&mut self, key: &str, value: &str) -> Result<bool, SyncError> {
+        let invalid = || SyncError::Protocol(format!("invalid metadata field {key}={value}"));
+        match key {
+            "mode" => self.mode = Some(u32::from_str_radix(value, 8).map_err(|_| invalid())?),
+            "uid" => self.uid = Some(value.parse().map_err(|_| invalid())?),
+            "gid" => self.gid = Some(value.parse().map_err(|_| invalid())?),
+            "mtime" => self.mtime_ns = Some(value.parse().map_err(|_| invalid())?),
+            "atime" => self.atime_ns = Some(value.parse().map_err(|_| invalid())?),
+            "xattr" => {
+                // Names may contain `:`; the hex value never does.
+                let (name, hex) = value.rsplit_once(':').ok_or_else(invalid)?;
+                self.xattrs.push(Xattr {
+                    name: name.to_string(),
+                    value: from_hex(hex).ok_or_else(invalid)?,
+                });
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Reapplies every recorded attribute the current process is permitted to set.
+    /// Timestamps go last so no other step can disturb them.
+    pub fn apply(&self, file_path: &Path) -> Result<MetadataReport, SyncError> {
+        let mut report = MetadataReport::default();
+
+        for attr in &self.xattrs {
+            record(
+                &mut report,
+                format!("xattr {}", attr.name),
+                xattr::set(file_path, attr),
+            );
+        }
+
+        #[cfg(unix)]
+        if self.uid.is_some() || self.gid.is_some() {
+            record(
+                &mut report,
+                "ownership".to_string(),
+                std::os::unix::fs::chown(file_path, self.uid, self.gid),
+            );
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            record(
+                &mut report,
+                "mode".to_string(),
+                fs::set_permissions(file_path, fs::Permissions::from_mode(mode)),
+            );
+        }
+
+        if self.mtime_ns.is_some() || self.atime_ns.is_some() {
+            let mut times = FileTimes::new();
+            if let Some(mtime_ns) = self.mtime_ns {
+                times = times.set_modified(ns_to_system_time(mtime_ns));
+            }
+            if let Some(atime_ns) = self.atime_ns {
+                times = times.set_accessed(ns_to_system_time(atime_ns));
+            }
+            let result = File::options()
+                .write(true)
+                .open(file_path)
+                .or_else(|_| File::open(file_path))
+                .and_then(|file| file.set_times(times));
+            record(&mut report, "timestamps".to_string(), result);
+        }
+
+        Ok(report)
+    }
+}
+
+fn record(report: &mut MetadataReport, field: String, result: std::io::Result<()>) {
+    match result {
+        Ok(()) => report.applied.push(field),
+        Err(err) => report.skipped.push((field, err.to_string())),
+    }
+}
+
+fn ns_to_system_time(ns: i64) -> SystemTime {
+    if ns >= 0 {
+        UNIX_EPOCH + Duration::from_nanos(ns as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_nanos(ns.unsigned_abs())
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod xattr {
+    use std::ffi::{CString, OsStr};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use super::Xattr;
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))
+    }
+
+    pub(super) fn list(path: &Path) -> io::Result<Vec<Xattr>> {
+        let c_path = c_path(path)?;
+        let names = match read_buffer(|buf, len| unsafe { list_raw(&c_path, buf, len) }) {
+            Ok(names) => names,
+            Err(err) if unsupported(&err) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut xattrs = Vec::new();
+        for raw_name in names
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+        {
+            let name = OsStr::from_bytes(raw_name).to_string_lossy().to_string();
+            let c_name = CString::new(raw_name).expect("xattr names never contain NUL");
+            match read_buffer(|buf, len| unsafe { get_raw(&c_path, &c_name, buf, len) }) {
+                Ok(value) => xattrs.push(Xattr { name, value }),
+                // Security namespaces can be listed but not read by unprivileged users.
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+                Err(err) => return Err(err),
+            }
+        }
+        xattrs.sort_by(|left, right| left.name.cmp(&right.name));
+        Ok(xattrs)
+    }
+
+    pub(super) fn set(path: &Path, attr: &Xattr) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        let c_name = CString::new(attr.name.as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "xattr name contains NUL"))?;
+        let result = unsafe { set_raw(&c_path, &c_name, &attr.value) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn unsupported(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(libc::ENOTSUP)
+    }
+
+    /// Runs a size-probe-then-fill libc call, retrying if the value grew in between.
+    fn read_buffer(call: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = call(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buffer = vec![0_u8; size as usize];
+            let written = call(buffer.as_mut_ptr().cast(), buffer.len());
+            if written >= 0 {
+                buffer.truncate(written as usize);
+                return Ok(buffer);
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) {
+                return Err(err);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn list_raw(path: &CString, buf: *mut libc::c_void, len: usize) -> isize {
+        libc::listxattr(path.as_ptr(), buf.cast(), len)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn get_raw(path: &CString, name: &CString, buf: *mut libc::c_void, len: usize) -> isize {
+        libc::getxattr(path.as_ptr(), name.as_ptr(), buf, len)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn set_raw(path: &CString, name: &CString, value: &[u8]) -> libc::c_int {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn list_raw(path: &CString, buf: *mut libc::c_void, len: usize) -> isize {
+        libc::listxattr(path.as_ptr(), buf.cast(), len, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn get_raw(path: &CString, name: &CString, buf: *mut libc::c_void, len: usize) -> isize {
+        libc::getxattr(path.as_ptr(), name.as_ptr(), buf, len, 0, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn set_raw(path: &CString, name: &CString, value: &[u8]) -> libc::c_int {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+            0,
+        )
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod xattr {
+    use std::io;
+    use std::path::Path;
+
+    use super::Xattr;
+
+    pub(super) fn list(_path: &Path) -> io::Result<Vec<Xattr>> {
+        Ok(Vec::new())
+    }
+
+    pub(super) fn set(_path: &Path, _attr: &Xattr) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes are not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn metadata_round_trips_through_payload_lines() {
+        let metadata = FileMetadata {
+            mode: Some(0o755),
+            uid: Some(1000),
+            gid: Some(100),
+            mtime_ns: Some(1_700_000_000_123_456_789),
+            atime_ns: Some(-5),
+            xattrs: vec![Xattr {
+                name: "user.tag".to_string(),
+                value: b"blue\0".to_vec(),
+            }],
+        };
+
+        let mut lines = String::new();
+        metadata.encode_lines(&mut lines);
+        assert!(lines.contains("mode=755\n"));
+        assert!(lines.contains("xattr=user.tag:626c756500\n"));
+
+        let mut decoded = FileMetadata::default();
+        for line in lines.lines() {
+            let (key, value) = line
+                .split_once('=')
+                .expect("every line should be key=value");
+            assert!(decoded
+                .decode_line(key, value)
+                .expect("encoded line should decode"));
+        }
+        assert_eq!(decoded, metadata);
+    }
+
+    #[test]
+    fn xattr_names_containing_colons_round_trip() {
+        let metadata = FileMetadata {
+            xattrs: vec![Xattr {
+                name: "user.com.example:origin:url".to_string(),
+                value: b"https://example.com".to_vec(),
+            }],
+            ..FileMetadata::default()
+        };
+
+        let mut lines = String::new();
+        metadata.encode_lines(&mut lines);
+        let mut decoded = FileMetadata::default();
+        for line in lines.lines() {
+            let (key, value) = line
+                .split_once('=')
+                .expect("every line should be key=value");
+            assert!(decoded
+                .decode_line(key, value)
+                .expect("encoded line should decode"));
+        }
+        assert_eq!(decoded, metadata);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restores_executable_bit_and_mtime_onto_a_fresh_copy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = temp_dir("metadata");
+
+        let original = temp.join("deploy.sh");
+        fs::write(&original, "#!/bin/sh\necho deploy\n").expect("script should be written");
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o750))
+            .expect("script should become executable");
+        File::options()
+            .write(true)
+            .open(&original)
+            .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000)))
+            .expect("mtime should be set");
+        let tagged = xattr::set(
+            &original,
+            &Xattr {
+                name: "user.backup-tag".to_string(),
+                value: b"scripts".to_vec(),
+            },
+        )
+        .is_ok();
+
+        let captured = FileMetadata::collect(&original).expect("metadata should be collected");
+        assert_eq!(captured.mode, Some(0o750));
+
+        let restored = temp.join("deploy-restored.sh");
+        fs::write(&restored, "#!/bin/sh\necho deploy\n").expect("restored copy should be written");
+        let report = captured
+            .apply(&restored)
+            .expect("metadata should be applied");
+        assert!(report.applied.contains(&"mode".to_string()));
+
+        let restored_meta = fs::metadata(&restored).expect("restored file should exist");
+        assert_eq!(restored_meta.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(
+            restored_meta.modified().expect("mtime should be readable"),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+        );
+        if tagged {
+            let restored_xattrs = xattr::list(&restored).expect("xattrs should be listed");
+            assert!(restored_xattrs
+                .iter()
+                .any(|attr| attr.name == "user.backup-tag" && attr.value == b"scripts"));
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::hash_cache::{escape, unescape};
+use crate::metadata::to_hex;
+use crate::{crypto, FileMetadata, MasterKey, SyncError};
+
+const SIV_LEN: usize = 24;
+const METADATA_AAD: &[u8] = b"rust-client metadata v1\0";
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Deterministically encrypts the paths under one sync root.
+///
+/// Each path segment is encrypted separately with a synthetic nonce (an HMAC of the
+/// segment), so the same name always produces the same ciphertext within a root. That lets
+/// the server dedupe and index entries and list subtrees, while names stay unreadable and
+/// the same name under two roots looks unrelated. The server can still see how deep the
+/// tree is and which entries share a parent.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct PathCipher {
+    #[zeroize(skip)]
+    root: PathBuf,
+    #[zeroize(skip)]
+    root_id: String,
+    siv_key: [u8; 32],
+    enc_key: [u8; 32],
+    meta_key: [u8; 32],
+}
+
+impl std::fmt::Debug for PathCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PathCipher")
+            .field("root", &self.root)
+            .field("root_id", &self.root_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PathCipher {
+    /// `root_id` names the sync root on the server and is sent in the clear as the first
+    /// path segment; pick an opaque value if the root's name is itself sensitive.
+    pub fn for_root<P: AsRef<Path>>(master: &MasterKey, root: P, root_id: &str) -> Self {
+        let root_key = hmac(
+            master.naming_key(),
+            &[b"rust-client path root v1\0", root_id.as_bytes()],
+        );
+        Self {
+            root: root.as_ref().to_path_buf(),
+            root_id: root_id.to_string(),
+            siv_key: hmac(&root_key, &[b"siv"]),
+            enc_key: hmac(&root_key, &[b"enc"]),
+            meta_key: hmac(&root_key, &[b"meta"]),
+        }
+    }
+
+    /// An opaque, stable `root_id` for `root`: the same for every device holding the master
+    /// key, and across key rotations, without revealing the root's name.
+    pub fn root_id_for<P: AsRef<Path>>(master: &MasterKey, root: P) -> String {
+        let digest = hmac(
+            master.naming_key(),
+            &[
+                b"rust-client path root id v1\0",
+                root.as_ref().to_string_lossy().as_bytes(),
+            ],
+        );
+        format!("r{}", &to_hex(&digest)[..16])
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn root_id(&self) -> &str {
+        &self.root_id
+    }
+
+    /// Maps a local path under the root to `<root_id>/<segment>/<segment>...`.
+    pub fn encrypt_path(&self, local_path: &Path) -> Result<String, SyncError> {
+        let relative = local_path
+            .strip_prefix(&self.root)
+            .map_err(|_| SyncError::InvalidPath(local_path.display().to_string()))?;
+
+        let mut encrypted = self.root_id.clone();
+        for component in relative.components() {
+            let Component::Normal(segment) = component else {
+                return Err(SyncError::InvalidPath(local_path.display().to_string()));
+            };
+            encrypted.push('/');
+            encrypted.push_str(&self.encrypt_segment(&segment.to_string_lossy())?);
+        }
+        Ok(encrypted)
+    }
+
+    /// Reverses [`PathCipher::encrypt_path`], returning the local path under the root.
+    pub fn decrypt_path(&self, encrypted: &str) -> Result<PathBuf, SyncError> {
+        let mut segments = encrypted.split('/');
+        if segments.next() != Some(self.root_id.as_str()) {
+            return Err(SyncError::InvalidPath(encrypted.to_string()));
+        }
+        let mut local_path = self.root.clone();
+        for segment in segments {
+            local_path.push(self.decrypt_segment(segment)?);
+        }
+        Ok(local_path)
+    }
+
+    /// Encrypts a file's metadata, xattrs included, for the record at `encrypted_path`.
+    /// Sealed metadata opens only under the same path, so it cannot be moved to another file.
+    pub fn seal_metadata(
+        &self,
+        metadata: &FileMetadata,
+        encrypted_path: &str,
+    ) -> Result<Vec<u8>, SyncError> {
+        let mut lines = String::new();
+        metadata.encode_lines(&mut lines);
+        crypto::seal(
+            &self.meta_key,
+            &metadata_aad(encrypted_path),
+            lines.as_bytes(),
+        )
+    }
+
+    /// Reverses [`PathCipher::seal_metadata`].
+    pub fn open_metadata(
+        &self,
+        sealed: &[u8],
+        encrypted_path: &str,
+    ) -> Result<FileMetadata, SyncError> {
+        let invalid = || SyncError::Crypto(format!("cannot decrypt metadata of {encrypted_path}"));
+        let plain = crypto::open(&self.meta_key, &metadata_aad(encrypted_path), sealed)
+            .map_err(|_| invalid())?;
+        let lines = String::from_utf8(plain).map_err(|_| invalid())?;
+        let mut metadata = FileMetadata::default();
+        for line in lines.lines() {
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            if !metadata.decode_line(key, value)? {
+                return Err(invalid());
+            }
+        }
+        Ok(metadata)
+    }
+
+    fn encrypt_segment(&self, segment: &str) -> Result<String, SyncError> {
+        let siv: [u8; SIV_LEN] = hmac(&self.siv_key, &[segment.as_bytes()])[..SIV_LEN]
+            .try_into()
+            .expect("HMAC-SHA256 output is longer than the nonce");
+        let cipher = XChaCha20Poly1305::new((&self.enc_key).into());
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&siv),
+                Payload {
+                    msg: segment.as_bytes(),
+                    aad: self.root_id.as_bytes(),
+                },
+            )
+            .map_err(|_| SyncError::Crypto("path encryption failed".to_string()))?;
+        let mut sealed = siv.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(base32_encode(&sealed))
+    }
+
+    fn decrypt_segment(&self, segment: &str) -> Result<String, SyncError> {
+        let invalid = || SyncError::Crypto(format!("cannot decrypt path segment {segment}"));
+        let sealed = base32_decode(segment).ok_or_else(invalid)?;
+        if sealed.len() < SIV_LEN {
+            return Err(invalid());
+        }
+        let (siv, ciphertext) = sealed.split_at(SIV_LEN);
+        let cipher = XChaCha20Poly1305::new((&self.enc_key).into());
+        let plain = cipher
+            .decrypt(
+                XNonce::from_slice(siv),
+                Payload {
+                    msg: ciphertext,
+                    aad: self.root_id.as_bytes(),
+                },
+            )
+            .map_err(|_| invalid())?;
+        // The nonce must be the one this name would have produced, or the segment was forged.
+        if hmac(&self.siv_key, &[&plain])[..SIV_LEN] != *siv {
+            return Err(invalid());
+        }
+        String::from_utf8(plain).map_err(|_| invalid())
+    }
+}
+
+/// Local lookup table from encrypted server paths back to plaintext local paths, so
+/// listings and restores can show real names without decrypting every entry.
+#[derive(Debug, Clone, Default)]
+pub struct PathIndex {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, PathBuf>,
+    dirty: bool,
+}
+
+impl PathIndex {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let path = path.as_ref().to_path_buf();
+        let mut index = Self {
+            path: Some(path.clone()),
+            ..Self::default()
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(err) => return Err(SyncError::Io(err)),
+        };
+        for (number, line) in contents.lines().enumerate() {
+            let corrupt = || {
+                SyncError::Protocol(format!(
+                    "corrupt path index {} at line {}",
+                    path.display(),
+                    number + 1
+                ))
+            };
+            let (encrypted, plain) = line.split_once('\t').ok_or_else(corrupt)?;
+            index.entries.insert(
+                encrypted.to_string(),
+                PathBuf::from(unescape(plain).ok_or_else(corrupt)?),
+            );
+        }
+        Ok(index)
+    }
+
+    pub fn record(&mut self, encrypted: &str, local_path: &Path) {
+        let previous = self
+            .entries
+            .insert(encrypted.to_string(), local_path.to_path_buf());
+        self.dirty |= previous.as_deref() != Some(local_path);
+    }
+
+    pub fn lookup(&self, encrypted: &str) -> Option<&Path> {
+        self.entries.get(encrypted).map(PathBuf::as_path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn save(&mut self) -> Result<(), SyncError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut contents = String::new();
+        for (encrypted, local_path) in &self.entries {
+            contents.push_str(&format!(
+                "{encrypted}\t{}\n",
+                escape(&local_path.to_string_lossy())
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(SyncError::Io)?;
+        }
+        let temp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&temp_path).map_err(SyncError::Io)?;
+        file.write_all(contents.as_bytes()).map_err(SyncError::Io)?;
+        file.sync_all().map_err(SyncError::Io)?;
+        fs::rename(&temp_path, path).map_err(SyncError::Io)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// The cipher for `local_path` among per-root ciphers: the one with the deepest root above it.
+pub(crate) fn cipher_for_local<'a>(
+    ciphers: &'a [PathCipher],
+    local_path: &Path,
+) -> Option<&'a PathCipher> {
+    ciphers
+        .iter()
+        .filter(|cipher| local_path.starts_with(&cipher.root))
+        .max_by_key(|cipher| cipher.root.components().count())
+}
+
+/// The cipher whose root id is the first segment of `server_path`.
+pub(crate) fn cipher_for_server<'a>(
+    ciphers: &'a [PathCipher],
+    server_path: &str,
+) -> Option<&'a PathCipher> {
+    let root_id = server_path.split('/').next()?;
+    ciphers.iter().find(|cipher| cipher.root_id == root_id)
+}
+
+fn metadata_aad(encrypted_path: &str) -> Vec<u8> {
+    let mut aad = METADATA_AAD.to_vec();
+    aad.extend_from_slice(encrypted_path.as_bytes());
+    aad
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Lowercase RFC 4648 base32 without padding: safe on case-insensitive filesystems and URLs.
+pub(crate) fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+pub(crate) fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+    for ch in text.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&symbol| symbol == ch)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KEY_LEN;
+    use crate::test_support::temp_dir;
+
+    fn cipher(root_id: &str) -> PathCipher {
+        PathCipher::for_root(
+            &MasterKey::from_bytes([7; KEY_LEN]),
+            "/home/member/Documents",
+            root_id,
+        )
+    }
+
+    #[test]
+    fn encrypts_each_segment_deterministically_and_decrypts_back() {
+        let cipher = cipher("docs");
+        let local = Path::new("/home/member/Documents/taxes/2025/return.pdf");
+
+        let encrypted = cipher
+            .encrypt_path(local)
+            .expect("path under root should encrypt");
+        assert_eq!(
+            encrypted,
+            cipher
+                .encrypt_path(local)
+                .expect("encryption is deterministic")
+        );
+        assert!(encrypted.starts_with("docs/"));
+        assert_eq!(encrypted.split('/').count(), 4);
+        assert!(!encrypted.contains("taxes") && !encrypted.contains("return"));
+        assert_eq!(
+            cipher
+                .decrypt_path(&encrypted)
+                .expect("path should decrypt"),
+            local
+        );
+
+        let sibling = cipher
+            .encrypt_path(Path::new("/home/member/Documents/taxes/2024"))
+            .expect("sibling should encrypt");
+        assert_eq!(
+            sibling.split('/').nth(1),
+            encrypted.split('/').nth(1),
+            "a shared parent directory encrypts to the same segment"
+        );
+    }
+
+    #[test]
+    fn different_roots_produce_unrelated_ciphertext() {
+        let local = Path::new("/home/member/Documents/notes.txt");
+        let first = cipher("docs")
+            .encrypt_path(local)
+            .expect("path should encrypt");
+        let second = cipher("laptop-docs")
+            .encrypt_path(local)
+            .expect("path should encrypt");
+        assert_ne!(first.split('/').nth(1), second.split('/').nth(1));
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_root_and_forged_segments() {
+        let cipher = cipher("docs");
+        assert!(matches!(
+            cipher.encrypt_path(Path::new("/etc/passwd")),
+            Err(SyncError::InvalidPath(_))
+        ));
+
+        let encrypted = cipher
+            .encrypt_path(Path::new("/home/member/Documents/a.txt"))
+            .expect("path should encrypt");
+        let mut forged = encrypted.into_bytes();
+        let last = forged.len() - 1;
+        forged[last] = if forged[last] == b'a' { b'b' } else { b'a' };
+        assert!(cipher
+            .decrypt_path(&String::from_utf8(forged).expect("still ascii"))
+            .is_err());
+    }
+
+    #[test]
+    fn metadata_is_sealed_per_path_and_root() {
+        let docs = cipher("docs");
+        let metadata = FileMetadata {
+            mode: Some(0o640),
+            mtime_ns: Some(1_700_000_000_000_000_000),
+            xattrs: vec![crate::Xattr {
+                name: "user.tag".to_string(),
+                value: b"tax receipts".to_vec(),
+            }],
+            ..FileMetadata::default()
+        };
+        let sealed = docs
+            .seal_metadata(&metadata, "docs/abc")
+            .expect("metadata should seal");
+        assert!(!sealed.windows(8).any(|window| window == b"user.tag"));
+        assert_eq!(
+            docs.open_metadata(&sealed, "docs/abc")
+                .expect("metadata should open"),
+            metadata
+        );
+        assert!(docs.open_metadata(&sealed, "docs/xyz").is_err());
+        assert!(cipher("other").open_metadata(&sealed, "docs/abc").is_err());
+    }
+
+    #[test]
+    fn picks_the_cipher_for_the_deepest_root_and_by_root_id() {
+        let master = MasterKey::from_bytes([7; KEY_LEN]);
+        let outer_id = PathCipher::root_id_for(&master, "/home/member");
+        assert_eq!(outer_id, PathCipher::root_id_for(&master, "/home/member"));
+        assert!(!outer_id.contains("member"));
+        let ciphers = [
+            PathCipher::for_root(&master, "/home/member", &outer_id),
+            PathCipher::for_root(&master, "/home/member/Documents", "docs"),
+        ];
+
+        let chosen = |path: &str| cipher_for_local(&ciphers, Path::new(path)).map(|c| c.root_id());
+        assert_eq!(chosen("/home/member/Documents/a.txt"), Some("docs"));
+        assert_eq!(chosen("/home/member/b.txt"), Some(outer_id.as_str()));
+        assert_eq!(chosen("/etc/passwd"), None);
+        assert_eq!(
+            cipher_for_server(&ciphers, "docs/abc").map(PathCipher::root_id),
+            Some("docs")
+        );
+        assert!(cipher_for_server(&ciphers, "elsewhere/abc").is_none());
+    }
+
+    #[test]
+    fn base32_round_trips_every_length() {
+        for length in 0..20 {
+            let bytes = (0..length).map(|n| (n * 37) as u8).collect::<Vec<_>>();
+            assert_eq!(
+                base32_decode(&base32_encode(&bytes)).as_deref(),
+                Some(bytes.as_slice())
+            );
+        }
+    }
+
+    #[test]
+    fn index_persists_encrypted_to_plaintext_mapping() {
+        let dir = temp_dir("path-index");
+        let index_path = dir.join("path-index.tsv");
+        let mut index = PathIndex::open(&index_path).expect("missing index should open empty");
+        index.record(
+            "docs/abc",
+            Path::new("/home/member/Documents/tab\there.txt"),
+        );
+        index.save().expect("index should save");
+
+        let reopened = PathIndex::open(&index_path).expect("saved index should load");
+        assert_eq!(
+            reopened.lookup("docs/abc"),
+            Some(Path::new("/home/member/Documents/tab\there.txt"))
+        );
+    }
+}
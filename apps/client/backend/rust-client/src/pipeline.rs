@@ -0,0 +1,234 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{build_sync_request, FileStamp, StabilityPolicy, SyncError, SyncRequest};
+
+/// Limits how hard directory onboarding may push the machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashBudget {
+    /// Hashing threads. Defaults to half the available cores so the desktop stays responsive.
+    pub workers: usize,
+    /// Capacity of the job and result channels between traversal, workers and the queue.
+    pub channel_capacity: usize,
+    /// Combined read rate across all workers; `None` reads as fast as the disk allows.
+    pub max_read_bytes_per_sec: Option<u64>,
+}
+
+impl Default for HashBudget {
+    fn default() -> Self {
+        let cores = thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1);
+        let workers = (cores / 2).max(1);
+        Self {
+            workers,
+            channel_capacity: workers * 4,
+            max_read_bytes_per_sec: None,
+        }
+    }
+}
+
+/// One file to hash, plus any other hard links to the same inode that reuse its result.
+#[derive(Debug)]
+pub(crate) struct HashJob {
+    pub path: PathBuf,
+    pub links: Vec<PathBuf>,
+}
+
+pub(crate) struct HashedJob {
+    pub job: HashJob,
+    pub result: Result<(SyncRequest, FileStamp), SyncError>,
+}
+
+/// Hashes `jobs` on a worker pool and hands each result to `on_result` on the calling
+/// thread as soon as it finishes. Returning an error from `on_result` stops the pipeline;
+/// workers notice the closed result channel and exit after their current file.
+pub(crate) fn hash_in_parallel(
+    jobs: Vec<HashJob>,
+    budget: &HashBudget,
+    policy: StabilityPolicy,
+    mut on_result: impl FnMut(HashedJob) -> Result<(), SyncError>,
+) -> Result<(), SyncError> {
+    let capacity = budget.channel_capacity.max(1);
+    let pacer = budget.max_read_bytes_per_sec.map(ReadPacer::new);
+    let (job_tx, job_rx) = sync_channel::<HashJob>(capacity);
+    let (result_tx, result_rx) = sync_channel::<HashedJob>(capacity);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            for job in jobs {
+                if job_tx.send(job).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for _ in 0..budget.workers.max(1) {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            let pacer = pacer.as_ref();
+            scope.spawn(move || loop {
+                let next = job_rx
+                    .lock()
+                    .expect("job queue lock should not be poisoned")
+                    .recv();
+                let Ok(job) = next else {
+                    break;
+                };
+                if let Some(pacer) = pacer {
+                    pacer.wait_for(fs::metadata(&job.path).map(|meta| meta.len()).unwrap_or(0));
+                }
+                let result = build_sync_request(&job.path, &policy);
+                if result_tx.send(HashedJob { job, result }).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(job_rx);
+        drop(result_tx);
+
+        for hashed in result_rx {
+            on_result(hashed)?;
+        }
+        Ok(())
+    })
+}
+
+/// Spaces out reads so the workers together stay under a byte rate.
+struct ReadPacer {
+    bytes_per_sec: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl ReadPacer {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn wait_for(&self, bytes: u64) {
+        let start = {
+            let mut next_free = self
+                .next_free
+                .lock()
+                .expect("pacer lock should not be poisoned");
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        let now = Instant::now();
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_file_streaming;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn hashes_every_job_across_workers_with_tiny_channels() {
+        let temp = temp_dir("pipeline");
+        let jobs = (0..40)
+            .map(|index| {
+                let path = temp.join(format!("file-{index}.txt"));
+                fs::write(&path, format!("content {index}")).expect("test file should be written");
+                HashJob {
+                    path,
+                    links: Vec::new(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let budget = HashBudget {
+            workers: 4,
+            channel_capacity: 1,
+            max_read_bytes_per_sec: None,
+        };
+        let mut results = Vec::new();
+        hash_in_parallel(jobs, &budget, StabilityPolicy::default(), |hashed| {
+            let (request, _) = hashed.result?;
+            results.push(request);
+            Ok(())
+        })
+        .expect("pipeline should hash every file");
+
+        assert_eq!(results.len(), 40);
+        for request in &results {
+            assert_eq!(
+                request.hash,
+                hash_file_streaming(std::path::Path::new(&request.path))
+                    .expect("sequential hash should succeed")
+            );
+        }
+    }
+
+    #[test]
+    fn stops_early_when_the_consumer_returns_an_error() {
+        let temp = temp_dir("pipeline-stop");
+        let jobs = (0..20)
+            .map(|index| {
+                let path = temp.join(format!("file-{index}.txt"));
+                fs::write(&path, "x").expect("test file should be written");
+                HashJob {
+                    path,
+                    links: Vec::new(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut seen = 0;
+        let error = hash_in_parallel(
+            jobs,
+            &HashBudget::default(),
+            StabilityPolicy::default(),
+            |_| {
+                seen += 1;
+                Err(SyncError::Protocol("consumer gave up".to_string()))
+            },
+        )
+        .expect_err("consumer error should stop the pipeline");
+
+        assert!(matches!(error, SyncError::Protocol(_)));
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn read_budget_spaces_out_hashing() {
+        let temp = temp_dir("pipeline-budget");
+        let jobs = (0..3)
+            .map(|index| {
+                let path = temp.join(format!("file-{index}.bin"));
+                fs::write(&path, vec![0_u8; 10_000]).expect("test file should be written");
+                HashJob {
+                    path,
+                    links: Vec::new(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let budget = HashBudget {
+            workers: 3,
+            channel_capacity: 3,
+            max_read_bytes_per_sec: Some(100_000),
+        };
+        let started = Instant::now();
+        hash_in_parallel(jobs, &budget, StabilityPolicy::default(), |hashed| {
+            hashed.result.map(|_| ())
+        })
+        .expect("throttled pipeline should finish");
+
+        // 30 KB at 100 KB/s: the third file may not start before ~200 ms.
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+}
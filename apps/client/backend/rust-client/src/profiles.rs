@@ -0,0 +1,294 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{default_config_path, default_state_dir, Config, ConfigOverrides, Json, SyncError};
+
+/// The profile backed by the plain config file and state directory.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Named profiles live in this directory beside the config file (`<name>.toml`) and inside
+/// the state directory (`<name>/`).
+const PROFILES_DIR: &str = "profiles";
+
+/// One account on this machine: its own config file with server, token and roots, and its
+/// own state directory for the queue, index, lock and device identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    name: String,
+    config_path: PathBuf,
+    state_dir: PathBuf,
+}
+
+impl Profile {
+    /// Profile `name` in the default locations; see [`Profile::in_dirs`].
+    pub fn named(name: &str) -> Result<Self, SyncError> {
+        let config_path = default_config_path()?;
+        let config_dir = config_path.parent().unwrap_or(Path::new("."));
+        Self::in_dirs(name, config_dir, &default_state_dir()?)
+    }
+
+    /// `default` is `config_dir/config.toml` with `state_dir` itself. Any other name is
+    /// `config_dir/profiles/<name>.toml` with `state_dir/profiles/<name>`.
+    pub fn in_dirs(name: &str, config_dir: &Path, state_dir: &Path) -> Result<Self, SyncError> {
+        validate_name(name)?;
+        let (config_path, state_dir) = if name == DEFAULT_PROFILE {
+            (config_dir.join("config.toml"), state_dir.to_path_buf())
+        } else {
+            (
+                config_dir.join(PROFILES_DIR).join(format!("{name}.toml")),
+                state_dir.join(PROFILES_DIR).join(name),
+            )
+        };
+        Ok(Self {
+            name: name.to_string(),
+            config_path,
+            state_dir,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    /// The state directory used when the config file does not name one.
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
+    pub fn exists(&self) -> bool {
+        self.config_path.exists()
+    }
+
+    /// Reads the profile's config file with `overrides` on top.
+    pub fn load(&self, overrides: &ConfigOverrides) -> Result<Config, SyncError> {
+        Config::load_in(&self.config_path, overrides, Some(&self.state_dir))
+    }
+
+    /// Writes the config file of a new profile. An existing profile is never replaced.
+    pub fn create(&self, server: &str, roots: &[PathBuf]) -> Result<(), SyncError> {
+        let roots = roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<_>>();
+        let text = format!(
+            "server = {}\nroots = {}\n",
+            Json::from(server),
+            Json::from(roots)
+        );
+        Config::parse_in(&text, &ConfigOverrides::default(), Some(&self.state_dir))?;
+        if let Some(parent) = self.config_path.parent() {
+            fs::create_dir_all(parent).map_err(SyncError::Io)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.config_path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::AlreadyExists => SyncError::InvalidConfig(format!(
+                    "profile `{}` already exists at {}",
+                    self.name,
+                    self.config_path.display()
+                )),
+                _ => SyncError::Io(err),
+            })?;
+        file.write_all(text.as_bytes()).map_err(SyncError::Io)
+    }
+}
+
+/// Profile names become file names, so they are kept to letters, digits, `-` and `_`.
+fn validate_name(name: &str) -> Result<(), SyncError> {
+    let valid = (1..=64).contains(&name.len())
+        && !name.starts_with('-')
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if !valid {
+        return Err(SyncError::InvalidConfig(format!(
+            "profile name `{}` may only use letters, digits, `-` and `_`",
+            name.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+/// Every profile with a config file in the default locations.
+pub fn list_profiles() -> Result<Vec<Profile>, SyncError> {
+    let config_path = default_config_path()?;
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    profiles_in(config_dir, &default_state_dir()?)
+}
+
+/// Every profile with a config file under `config_dir`: `default` first, then the named
+/// profiles by name. Files that are not `<name>.toml` are skipped.
+pub fn profiles_in(config_dir: &Path, state_dir: &Path) -> Result<Vec<Profile>, SyncError> {
+    let mut names = Vec::new();
+    match fs::read_dir(config_dir.join(PROFILES_DIR)) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry.map_err(SyncError::Io)?.path();
+                let name = path.file_stem().and_then(|stem| stem.to_str());
+                if let (Some(name), true) =
+                    (name, path.extension().is_some_and(|ext| ext == "toml"))
+                {
+                    if name != DEFAULT_PROFILE && validate_name(name).is_ok() {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(SyncError::Io(err)),
+    }
+    names.sort();
+
+    let mut profiles = vec![Profile::in_dirs(DEFAULT_PROFILE, config_dir, state_dir)?];
+    profiles.retain(Profile::exists);
+    for name in names {
+        profiles.push(Profile::in_dirs(&name, config_dir, state_dir)?);
+    }
+    Ok(profiles)
+}
+
+/// Loads several profiles to run side by side. Server, state directory and root overrides
+/// only make sense for one account, and no two profiles may share a state directory, since
+/// that would mean sharing a queue, an index and a device identity.
+pub fn load_isolated(
+    profiles: &[Profile],
+    overrides: &ConfigOverrides,
+) -> Result<Vec<Config>, SyncError> {
+    if profiles.len() > 1
+        && (overrides.server.is_some()
+            || overrides.state_dir.is_some()
+            || overrides.roots.is_some())
+    {
+        return Err(SyncError::InvalidConfig(
+            "server, state directory and root overrides apply to a single profile".to_string(),
+        ));
+    }
+    let mut configs: Vec<Config> = Vec::new();
+    for (index, profile) in profiles.iter().enumerate() {
+        let config = profile.load(overrides)?;
+        if let Some(other) = configs
+            .iter()
+            .position(|other| other.state_dir == config.state_dir)
+        {
+            return Err(SyncError::InvalidConfig(format!(
+                "profiles `{}` and `{}` share the state directory {}",
+                profiles[other].name,
+                profiles[index].name,
+                config.state_dir.display()
+            )));
+        }
+        configs.push(config);
+    }
+    Ok(configs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn profiles_get_their_own_config_file_and_state_directory() {
+        let dir = temp_dir("profiles-layout");
+        let (config_dir, state_dir) = (dir.join("config"), dir.join("state"));
+
+        let default = Profile::in_dirs(DEFAULT_PROFILE, &config_dir, &state_dir).expect("default");
+        assert_eq!(default.config_path(), config_dir.join("config.toml"));
+        assert_eq!(default.state_dir(), state_dir);
+        for name in ["", "-x", "a/b", "..", "with space"] {
+            assert!(
+                Profile::in_dirs(name, &config_dir, &state_dir).is_err(),
+                "{name:?}"
+            );
+        }
+
+        let work = Profile::in_dirs("work", &config_dir, &state_dir).expect("work");
+        work.create("http://127.0.0.1:8080", &[PathBuf::from("/home/me/Work")])
+            .expect("profile should be created");
+        assert!(
+            work.create("http://127.0.0.1:8080", &[]).is_err(),
+            "never replaced"
+        );
+        assert!(Profile::in_dirs("bad", &config_dir, &state_dir)
+            .expect("bad")
+            .create("http://127.0.0.1:8080", &[PathBuf::from("relative")])
+            .is_err());
+        let config = work
+            .load(&ConfigOverrides::default())
+            .expect("profile should load");
+        assert_eq!(config.roots, vec![PathBuf::from("/home/me/Work")]);
+        assert_eq!(config.state_dir, state_dir.join("profiles").join("work"));
+
+        fs::write(config_dir.join("profiles").join("notes.txt"), "").expect("write");
+        Profile::in_dirs("family", &config_dir, &state_dir)
+            .expect("family")
+            .create("http://127.0.0.1:9090", &[])
+            .expect("profile should be created");
+        let names = |profiles: Vec<Profile>| {
+            profiles
+                .iter()
+                .map(|profile| profile.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(profiles_in(&config_dir, &state_dir).expect("list")),
+            ["family", "work"]
+        );
+        fs::write(
+            config_dir.join("config.toml"),
+            "server = \"http://127.0.0.1:8080\"\n",
+        )
+        .expect("write");
+        assert_eq!(
+            names(profiles_in(&config_dir, &state_dir).expect("list")),
+            ["default", "family", "work"]
+        );
+    }
+
+    #[test]
+    fn profiles_run_together_only_with_separate_state_and_no_account_overrides() {
+        let dir = temp_dir("profiles-isolated");
+        let (config_dir, state_dir) = (dir.join("config"), dir.join("state"));
+        let profile =
+            |name: &str| Profile::in_dirs(name, &config_dir, &state_dir).expect("profile");
+        profile("a")
+            .create("http://127.0.0.1:8080", &[])
+            .expect("a");
+        profile("b")
+            .create("http://127.0.0.1:9090", &[])
+            .expect("b");
+        let both = [profile("a"), profile("b")];
+
+        let configs = load_isolated(&both, &ConfigOverrides::default()).expect("isolated");
+        assert_eq!(configs[1].server, "http://127.0.0.1:9090");
+        assert_ne!(configs[0].state_dir, configs[1].state_dir);
+
+        let mut overrides = ConfigOverrides::default();
+        overrides.set("state-dir", "/tmp/shared").expect("override");
+        assert!(load_isolated(&both, &overrides).is_err());
+        assert!(
+            load_isolated(&both[..1], &overrides).is_ok(),
+            "fine for one profile"
+        );
+
+        let shared = state_dir.join("shared").display().to_string();
+        for name in ["a", "b"] {
+            let text = format!(
+                "server = \"http://127.0.0.1:8080\"\nstate_dir = {}\n",
+                Json::from(shared.as_str())
+            );
+            fs::write(profile(name).config_path(), text).expect("write");
+        }
+        let error = load_isolated(&both, &ConfigOverrides::default()).expect_err("shared state");
+        assert!(error
+            .to_string()
+            .contains("profiles `a` and `b` share the state directory"));
+    }
+}
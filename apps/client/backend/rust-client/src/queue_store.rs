@@ -0,0 +1,247 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::hash_cache::{escape, unescape};
+use crate::{DeadLetter, SyncError, SyncRequest};
+
+/// v2 escapes paths so a newline in a file name cannot split a request in two.
+const QUEUE_HEADER: &str = "# rust-client queue v2";
+const DEAD_LETTER_HEADER: &str = "# rust-client dead letters v2";
+const LEGACY_QUEUE_HEADER: &str = "# rust-client queue v1";
+const LEGACY_DEAD_LETTER_HEADER: &str = "# rust-client dead letters v1";
+
+/// Writes each request as its `POST /v1/sync` body, with paths escaped, separated by
+/// blank lines.
+pub(crate) fn save_queue(path: &Path, requests: &[SyncRequest]) -> Result<(), SyncError> {
+    let mut contents = format!("{QUEUE_HEADER}\n");
+    for request in requests {
+        contents.push('\n');
+        contents.push_str(&encode_stored(request));
+    }
+    write_atomically(path, &contents)
+}
+
+/// Like the queue file, with `attempts=` and `error=` lines ahead of each body.
+pub(crate) fn save_dead_letters(path: &Path, dead_letters: &[DeadLetter]) -> Result<(), SyncError> {
+    let mut contents = format!("{DEAD_LETTER_HEADER}\n");
+    for dead in dead_letters {
+        contents.push_str(&format!(
+            "\nattempts={}\nerror={}\n{}",
+            dead.attempts,
+            escape(&dead.error),
+            encode_stored(&dead.request)
+        ));
+    }
+    write_atomically(path, &contents)
+}
+
+pub(crate) fn write_atomically(path: &Path, contents: &str) -> Result<(), SyncError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(SyncError::Io)?;
+    }
+    let temp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp_path).map_err(SyncError::Io)?;
+    file.write_all(contents.as_bytes()).map_err(SyncError::Io)?;
+    file.sync_all().map_err(SyncError::Io)?;
+    fs::rename(&temp_path, path).map_err(SyncError::Io)
+}
+
+/// Reads a queue written by [`crate::SyncManager::save_queue`]. A missing file is an empty queue.
+pub fn load_queue<P: AsRef<Path>>(path: P) -> Result<Vec<SyncRequest>, SyncError> {
+    let (blocks, escaped) =
+        versioned_blocks(path.as_ref(), QUEUE_HEADER, LEGACY_QUEUE_HEADER, "queue")?;
+    blocks
+        .iter()
+        .map(|block| decode_stored(block, escaped))
+        .collect()
+}
+
+/// Reads dead letters written by [`crate::SyncManager::save_dead_letters`]. A missing file
+/// means there are none.
+pub fn load_dead_letters<P: AsRef<Path>>(path: P) -> Result<Vec<DeadLetter>, SyncError> {
+    let path = path.as_ref();
+    let invalid = || SyncError::Protocol(format!("{} has a malformed dead letter", path.display()));
+    let (blocks, escaped) = versioned_blocks(
+        path,
+        DEAD_LETTER_HEADER,
+        LEGACY_DEAD_LETTER_HEADER,
+        "dead letter",
+    )?;
+    blocks
+        .iter()
+        .map(|block| {
+            let mut lines = block.splitn(3, '\n');
+            let attempts = lines.next().and_then(|line| line.strip_prefix("attempts="));
+            let error = lines.next().and_then(|line| line.strip_prefix("error="));
+            let (Some(attempts), Some(error), Some(body)) = (attempts, error, lines.next()) else {
+                return Err(invalid());
+            };
+            Ok(DeadLetter {
+                request: decode_stored(body, escaped)?,
+                attempts: attempts.parse().map_err(|_| invalid())?,
+                error: unescape(error).ok_or_else(invalid)?,
+            })
+        })
+        .collect()
+}
+
+fn encode_stored(request: &SyncRequest) -> String {
+    SyncRequest {
+        path: escape(&request.path),
+        link_to: request.link_to.as_deref().map(escape),
+        ..request.clone()
+    }
+    .encode_body()
+}
+
+fn decode_stored(block: &str, escaped: bool) -> Result<SyncRequest, SyncError> {
+    let mut request = SyncRequest::decode_body(block)?;
+    if escaped {
+        let unescape_path = |value: &str| {
+            unescape(value)
+                .ok_or_else(|| SyncError::Protocol(format!("malformed stored path: {value}")))
+        };
+        request.path = unescape_path(&request.path)?;
+        if let Some(link_to) = &request.link_to {
+            request.link_to = Some(unescape_path(link_to)?);
+        }
+    }
+    Ok(request)
+}
+
+/// Splits a file into its blank-line separated blocks after checking its header.
+pub(crate) fn blocks(path: &Path, header: &str, kind: &str) -> Result<Vec<String>, SyncError> {
+    let Some(contents) = read(path)? else {
+        return Ok(Vec::new());
+    };
+    let body = contents
+        .strip_prefix(header)
+        .ok_or_else(|| SyncError::Protocol(format!("{} is not a {kind} file", path.display())))?;
+    Ok(split_blocks(body))
+}
+
+/// Like [`blocks`], also accepting files written under the `legacy` header, and telling
+/// whether the file is the current, path-escaping version.
+fn versioned_blocks(
+    path: &Path,
+    header: &str,
+    legacy: &str,
+    kind: &str,
+) -> Result<(Vec<String>, bool), SyncError> {
+    let Some(contents) = read(path)? else {
+        return Ok((Vec::new(), true));
+    };
+    if let Some(body) = contents.strip_prefix(header) {
+        return Ok((split_blocks(body), true));
+    }
+    let body = contents
+        .strip_prefix(legacy)
+        .ok_or_else(|| SyncError::Protocol(format!("{} is not a {kind} file", path.display())))?;
+    Ok((split_blocks(body), false))
+}
+
+fn read(path: &Path) -> Result<Option<String>, SyncError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(SyncError::Io(err)),
+    }
+}
+
+fn split_blocks(body: &str) -> Vec<String> {
+    body.split("\n\n")
+        .map(str::trim)
+        .filter(|block| !block.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn queue_round_trips_through_disk() {
+        let path = temp_dir("queue-store").join("queue");
+        let requests = vec![
+            SyncRequest {
+                path: "a.txt".to_string(),
+                hash: "aa".to_string(),
+                ..SyncRequest::default()
+            },
+            SyncRequest {
+                path: "b.txt".to_string(),
+                hash: "bb".to_string(),
+                link_to: Some("a.txt".to_string()),
+                ..SyncRequest::default()
+            },
+        ];
+
+        assert!(load_queue(&path)
+            .expect("missing queue is empty")
+            .is_empty());
+        save_queue(&path, &requests).expect("queue should save");
+        assert_eq!(load_queue(&path).expect("queue should load"), requests);
+
+        let dead_path = path.with_file_name("dead-letters");
+        let dead_letters = vec![DeadLetter {
+            request: requests[0].clone(),
+            attempts: 10,
+            error: "server error 500:\nboom\tagain".to_string(),
+        }];
+        assert!(load_dead_letters(&dead_path)
+            .expect("missing file has none")
+            .is_empty());
+        save_dead_letters(&dead_path, &dead_letters).expect("dead letters should save");
+        assert_eq!(
+            load_dead_letters(&dead_path).expect("dead letters should load"),
+            dead_letters
+        );
+        assert!(load_queue(&dead_path).is_err());
+    }
+
+    #[test]
+    fn paths_with_newlines_and_escapes_round_trip() {
+        let path = temp_dir("queue-store-escapes").join("queue");
+        let requests = vec![
+            SyncRequest {
+                path: "/root/two\n\nlines%0A.txt".to_string(),
+                hash: "aa".to_string(),
+                link_to: Some("/root/tab\there.txt".to_string()),
+                ..SyncRequest::default()
+            },
+            SyncRequest {
+                path: "/root/plain.txt".to_string(),
+                hash: "bb".to_string(),
+                ..SyncRequest::default()
+            },
+        ];
+        save_queue(&path, &requests).expect("queue should save");
+        assert_eq!(load_queue(&path).expect("queue should load"), requests);
+
+        let dead_path = path.with_file_name("dead-letters");
+        let dead_letters = vec![DeadLetter {
+            request: requests[0].clone(),
+            attempts: 3,
+            error: "boom".to_string(),
+        }];
+        save_dead_letters(&dead_path, &dead_letters).expect("dead letters should save");
+        assert_eq!(
+            load_dead_letters(&dead_path).expect("dead letters should load"),
+            dead_letters
+        );
+
+        // Queues written before paths were escaped still load as they were.
+        fs::write(
+            &path,
+            format!("{LEGACY_QUEUE_HEADER}\n\npath=/root/100%25.txt\nhash=cc\n"),
+        )
+        .expect("legacy queue should be written");
+        assert_eq!(
+            load_queue(&path).expect("legacy queue should load")[0].path,
+            "/root/100%25.txt"
+        );
+    }
+}
@@ -0,0 +1,840 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::path_cipher;
+use crate::stability::{self, StabilityPolicy};
+use crate::{
+    MasterKey, PathCipher, Restorer, SyncError, SyncManager, SyncRequest, SyncTransport,
+    SyncedIndex,
+};
+
+/// Edit counters per device for one file. Every upload from a device bumps that device's
+/// counter on top of the version it last saw, so comparing two vectors tells whether one
+/// edit was made with knowledge of the other or both happened independently.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+/// How two version vectors relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// Every edit in this vector is also in the other one, which has more.
+    Before,
+    After,
+    /// Each side has edits the other has not seen: a divergent edit.
+    Concurrent,
+}
+
+impl VersionVector {
+    pub fn get(&self, device: &str) -> u64 {
+        self.0.get(device).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, device: &str) {
+        *self.0.entry(device.to_string()).or_insert(0) += 1;
+    }
+
+    /// Takes the larger counter for every device.
+    pub fn merge(&mut self, other: &Self) {
+        for (device, &count) in &other.0 {
+            let entry = self.0.entry(device.clone()).or_insert(0);
+            *entry = (*entry).max(count);
+        }
+    }
+
+    pub fn compare(&self, other: &Self) -> Causality {
+        let devices = self.0.keys().chain(other.0.keys());
+        let (mut behind, mut ahead) = (false, false);
+        for device in devices {
+            let (mine, theirs) = (self.get(device), other.get(device));
+            behind |= mine < theirs;
+            ahead |= mine > theirs;
+        }
+        match (behind, ahead) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+
+    /// Parses `<device>:<count>,...` as written by `Display`.
+    pub(crate) fn decode(value: &str) -> Result<Self, SyncError> {
+        let invalid = || SyncError::Protocol(format!("invalid version vector: {value}"));
+        let mut vector = BTreeMap::new();
+        for entry in value.split(',').filter(|entry| !entry.is_empty()) {
+            let (device, count) = entry.rsplit_once(':').ok_or_else(invalid)?;
+            if device.is_empty() {
+                return Err(invalid());
+            }
+            vector.insert(device.to_string(), count.parse().map_err(|_| invalid())?);
+        }
+        Ok(Self(vector))
+    }
+}
+
+impl fmt::Display for VersionVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (device, count)) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{device}:{count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A file's content hash, as the server records it, and the version that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileVersion {
+    pub hash: String,
+    pub version: VersionVector,
+}
+
+impl FileVersion {
+    fn of(record: &SyncRequest) -> Self {
+        Self {
+            hash: record.hash.clone(),
+            version: record.version.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// What to do with one path so this device and the server agree again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconciliation {
+    UpToDate,
+    /// Only the local file changed: send it.
+    Upload,
+    /// Only the server changed: bring its version here.
+    Download,
+    /// The server deleted a file this device has not touched.
+    DeleteLocal,
+    /// Both sides ended up the same; only the synced state needs to catch up.
+    Adopt,
+    /// Divergent edits: keep the local file as a conflict copy and download the server's.
+    Conflict,
+}
+
+/// Decides how to reconcile one path from the version both sides last agreed on (`base`),
+/// the local file's hash in server form (`None` when it does not exist) and the server's
+/// record (`None` when it has none).
+///
+/// A local edit is never overwritten: when the server moved on too, the result is a
+/// conflict unless both sides hold the same content. A server version that does not
+/// descend from `base` (a write that raced ours and replaced it) is a conflict as well.
+/// Local deletions are not propagated, and an edit on either side wins over a deletion.
+pub fn reconcile(
+    base: Option<&FileVersion>,
+    local: Option<&str>,
+    remote: Option<&FileVersion>,
+) -> Reconciliation {
+    let local_changed = local != base.map(|base| base.hash.as_str());
+    let remote_changed = match (base, remote) {
+        (Some(base), Some(remote)) => {
+            remote.hash != base.hash
+                || matches!(
+                    remote.version.compare(&base.version),
+                    Causality::After | Causality::Concurrent
+                )
+        }
+        (None, None) => false,
+        _ => true,
+    };
+
+    match (local_changed, remote_changed) {
+        (false, false) => Reconciliation::UpToDate,
+        (true, false) if local.is_some() => Reconciliation::Upload,
+        (true, false) => Reconciliation::UpToDate,
+        (false, true) => match (base, remote) {
+            (_, None) if local.is_some() => Reconciliation::DeleteLocal,
+            (_, None) => Reconciliation::Adopt,
+            (None, Some(_)) => Reconciliation::Download,
+            (Some(base), Some(remote)) => match remote.version.compare(&base.version) {
+                Causality::After | Causality::Equal => Reconciliation::Download,
+                Causality::Before | Causality::Concurrent if remote.hash == base.hash => {
+                    Reconciliation::Adopt
+                }
+                Causality::Before | Causality::Concurrent => Reconciliation::Conflict,
+            },
+        },
+        (true, true) => match (local, remote) {
+            (Some(local), Some(remote)) if local == remote.hash => Reconciliation::Adopt,
+            (Some(_), Some(_)) => Reconciliation::Conflict,
+            (Some(_), None) => Reconciliation::Upload,
+            (None, Some(_)) => Reconciliation::Download,
+            (None, None) => Reconciliation::Adopt,
+        },
+    }
+}
+
+/// What one [`Reconciler::reconcile`] pass changed on disk.
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    pub downloaded: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    /// Each conflicted path with the copy its local version was moved to.
+    pub conflicts: Vec<(PathBuf, PathBuf)>,
+    pub failed: Vec<(String, SyncError)>,
+}
+
+/// Applies remote changes to local roots for bidirectional sync.
+///
+/// The manager's synced index holds the version each path was last agreed at; the manager
+/// must stamp its uploads with [`SyncManager::with_versions`] for the same device. Remote
+/// edits to untouched files are downloaded and remote deletions applied; on a divergent
+/// edit the local file is renamed to `<name> (conflict <device>)<.ext>` (numbered when
+/// taken), queued for upload, and the server's version takes its place.
+pub struct Reconciler<T: SyncTransport> {
+    restorer: Restorer<T>,
+    device: String,
+    roots: Vec<PathBuf>,
+    master: Option<MasterKey>,
+    path_ciphers: Vec<PathCipher>,
+}
+
+impl<T: SyncTransport> Reconciler<T> {
+    /// Reconciles only paths under `roots`; anything else in the change feed is left alone.
+    pub fn new(transport: T, device: &str, roots: &[PathBuf]) -> Self {
+        Self {
+            restorer: Restorer::new(transport),
+            device: device.to_string(),
+            roots: roots.to_vec(),
+            master: None,
+            path_ciphers: Vec::new(),
+        }
+    }
+
+    /// Needed when content is encrypted, to compare keyed tags and decrypt downloads.
+    pub fn with_master_key(mut self, master: MasterKey) -> Self {
+        self.restorer = self.restorer.with_master_key(master.clone());
+        self.master = Some(master);
+        self
+    }
+
+    /// Maps encrypted server paths back to local paths under the cipher's root and opens
+    /// their sealed metadata. Call once per encrypted root.
+    pub fn with_path_encryption(mut self, cipher: PathCipher) -> Self {
+        self.path_ciphers.push(cipher);
+        self
+    }
+
+    /// Reconciles each server path, typically the paths of a change feed page. Failures are
+    /// reported per path and leave its synced state untouched, so it can be retried.
+    /// Paths the manager's selective sync rules exclude are skipped.
+    pub fn reconcile<'a, U: SyncTransport>(
+        &mut self,
+        manager: &mut SyncManager<U>,
+        server_paths: impl IntoIterator<Item = &'a str>,
+    ) -> ReconcileReport {
+        let mut report = ReconcileReport::default();
+        for server_path in server_paths {
+            if let Err(err) = self.reconcile_path(manager, server_path, &mut report) {
+                report.failed.push((server_path.to_string(), err));
+            }
+        }
+        report
+    }
+
+    fn reconcile_path<U: SyncTransport>(
+        &mut self,
+        manager: &mut SyncManager<U>,
+        server_path: &str,
+        report: &mut ReconcileReport,
+    ) -> Result<(), SyncError> {
+        let Some(local_path) = self.local_path(server_path)? else {
+            return Ok(());
+        };
+        if !manager.selective_sync().includes(&local_path) {
+            return Ok(());
+        }
+        let remote = match self.restorer.transport_mut().fetch_file(server_path) {
+            Ok(record) => Some(record),
+            Err(SyncError::Server(404, _)) => None,
+            Err(err) => return Err(err),
+        };
+        let base = synced_index(manager)?.get(server_path).map(FileVersion::of);
+        let local = self.local_hash(&local_path)?;
+        let decision = reconcile(
+            base.as_ref(),
+            local.as_deref(),
+            remote.as_ref().map(FileVersion::of).as_ref(),
+        );
+
+        match (decision, remote) {
+            (Reconciliation::UpToDate, _) => {}
+            (Reconciliation::Upload, _) => {
+                manager.queue_file(&local_path)?;
+            }
+            (Reconciliation::Download, Some(remote)) => {
+                self.download(&remote, &local_path)?;
+                synced_index(manager)?.record(remote);
+                report.downloaded.push(local_path);
+            }
+            (Reconciliation::DeleteLocal, _) => {
+                fs::remove_file(&local_path).map_err(SyncError::Io)?;
+                synced_index(manager)?.remove(server_path);
+                report.deleted.push(local_path);
+            }
+            (Reconciliation::Adopt, Some(mut remote)) => {
+                if let (Some(base), Some(version)) = (&base, &mut remote.version) {
+                    version.merge(&base.version);
+                }
+                synced_index(manager)?.record(remote);
+            }
+            (Reconciliation::Conflict, Some(remote)) => {
+                let copy = conflict_path(&local_path, &self.device);
+                fs::rename(&local_path, &copy).map_err(SyncError::Io)?;
+                self.download(&remote, &local_path)?;
+                synced_index(manager)?.record(remote);
+                manager.queue_file(&copy)?;
+                report.conflicts.push((local_path, copy));
+            }
+            (Reconciliation::Adopt, None) => {
+                synced_index(manager)?.remove(server_path);
+            }
+            (Reconciliation::Download | Reconciliation::Conflict, None) => {
+                unreachable!("downloads and conflicts always have a server record")
+            }
+        }
+        Ok(())
+    }
+
+    /// The local path for a server path, or `None` when it lies outside the roots. A path
+    /// that could climb out of its root through `..` is refused rather than acted on.
+    fn local_path(&self, server_path: &str) -> Result<Option<PathBuf>, SyncError> {
+        let local_path = if self.path_ciphers.is_empty() {
+            PathBuf::from(server_path)
+        } else {
+            match path_cipher::cipher_for_server(&self.path_ciphers, server_path) {
+                Some(cipher) => cipher.decrypt_path(server_path)?,
+                None => return Ok(None),
+            }
+        };
+        let Some(relative) = self
+            .roots
+            .iter()
+            .find_map(|root| local_path.strip_prefix(root).ok())
+        else {
+            return Ok(None);
+        };
+        if relative
+            .components()
+            .any(|part| !matches!(part, Component::Normal(_)))
+        {
+            return Err(SyncError::InvalidPath(server_path.to_string()));
+        }
+        Ok(Some(local_path))
+    }
+
+    /// The local file's hash the way the manager would send it, if the file exists.
+    fn local_hash(&self, local_path: &Path) -> Result<Option<String>, SyncError> {
+        if !local_path.is_file() {
+            return Ok(None);
+        }
+        let hash = stability::hash_file_stable(local_path, &StabilityPolicy::default())?.hash;
+        Ok(Some(match &self.master {
+            Some(master) => master.content_tag(&hash),
+            None => hash,
+        }))
+    }
+
+    fn download(&mut self, record: &SyncRequest, local_path: &Path) -> Result<(), SyncError> {
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).map_err(SyncError::Io)?;
+        }
+        let sealed = record.sealed_metadata.as_deref().and_then(|sealed| {
+            path_cipher::cipher_for_server(&self.path_ciphers, &record.path)
+                .map(|cipher| cipher.open_metadata(sealed, &record.path))
+        });
+        match sealed {
+            Some(metadata) => {
+                let mut record = record.clone();
+                record.metadata = Some(metadata?);
+                self.restorer.restore_file(&record, local_path)
+            }
+            None => self.restorer.restore_file(record, local_path),
+        }
+        .map(|_| ())
+    }
+}
+
+fn synced_index<U: SyncTransport>(
+    manager: &mut SyncManager<U>,
+) -> Result<&mut SyncedIndex, SyncError> {
+    manager.synced_index_mut().ok_or_else(|| {
+        SyncError::InvalidConfig("bidirectional sync needs the synced index".to_string())
+    })
+}
+
+/// The first free `<stem> (conflict <device>[ N])<.ext>` beside `local_path`. The name only
+/// depends on the path and the device whose edit lost, so every device agrees on it.
+fn conflict_path(local_path: &Path, device: &str) -> PathBuf {
+    let stem = local_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = local_path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|copy| {
+            let suffix = match copy {
+                1 => format!(" (conflict {device})"),
+                copy => format!(" (conflict {device} {copy})"),
+            };
+            local_path.with_file_name(format!("{stem}{suffix}{extension}"))
+        })
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .expect("an unbounded range always yields a free name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{foreign_edit, temp_dir, MemoryRemote};
+    use crate::ContentOptions;
+
+    /// Deterministic xorshift generator so failures reproduce.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+
+        fn chance(&mut self, percent: u64) -> bool {
+            self.below(100) < percent
+        }
+    }
+
+    fn vector(text: &str) -> VersionVector {
+        VersionVector::decode(text).expect("vector should parse")
+    }
+
+    fn random_version(rng: &mut Rng) -> FileVersion {
+        let mut version = VersionVector::default();
+        for device in ["a", "b", "c"] {
+            for _ in 0..rng.below(3) {
+                version.increment(device);
+            }
+        }
+        FileVersion {
+            hash: format!("h{}", rng.below(3)),
+            version,
+        }
+    }
+
+    /// The state `decision` leaves behind for device `me`: (base, local, remote).
+    fn apply(
+        decision: Reconciliation,
+        me: &str,
+        base: Option<FileVersion>,
+        local: Option<String>,
+        remote: Option<FileVersion>,
+    ) -> (Option<FileVersion>, Option<String>, Option<FileVersion>) {
+        match decision {
+            Reconciliation::UpToDate => (base, local, remote),
+            Reconciliation::Upload => {
+                let mut version = base.map(|base| base.version).unwrap_or_default();
+                version.increment(me);
+                let sent = FileVersion {
+                    hash: local.clone().expect("uploads have a local file"),
+                    version,
+                };
+                (Some(sent.clone()), local, Some(sent))
+            }
+            Reconciliation::Download | Reconciliation::Conflict => {
+                let remote = remote.expect("downloads have a server record");
+                (
+                    Some(remote.clone()),
+                    Some(remote.hash.clone()),
+                    Some(remote),
+                )
+            }
+            Reconciliation::DeleteLocal => (None, None, remote),
+            Reconciliation::Adopt => {
+                let adopted = remote.clone().map(|mut adopted| {
+                    if let Some(base) = &base {
+                        adopted.version.merge(&base.version);
+                    }
+                    adopted
+                });
+                (adopted, local, remote)
+            }
+        }
+    }
+
+    #[test]
+    fn version_vectors_order_edits_and_round_trip() {
+        assert_eq!(vector("a:1").compare(&vector("a:1")), Causality::Equal);
+        assert_eq!(vector("a:1").compare(&vector("a:1,b:1")), Causality::Before);
+        assert_eq!(vector("a:2,b:1").compare(&vector("a:1")), Causality::After);
+        assert_eq!(
+            vector("a:2").compare(&vector("a:1,b:1")),
+            Causality::Concurrent
+        );
+        assert_eq!(
+            VersionVector::default().compare(&vector("a:1")),
+            Causality::Before
+        );
+
+        let mut merged = vector("a:2,b:1");
+        merged.merge(&vector("b:3,c:1"));
+        assert_eq!(merged.to_string(), "a:2,b:3,c:1");
+        assert_eq!(vector(&merged.to_string()), merged);
+        assert!(VersionVector::decode("a:x").is_err());
+        assert!(VersionVector::decode(":1").is_err());
+
+        let request = SyncRequest {
+            path: "/r/a.txt".to_string(),
+            hash: "abc".to_string(),
+            version: Some(merged),
+            ..SyncRequest::default()
+        };
+        let body = request.encode_body();
+        assert!(body.contains("version=a:2,b:3,c:1\n"), "{body}");
+        assert_eq!(
+            SyncRequest::decode_body(&body).expect("body should parse"),
+            request
+        );
+    }
+
+    #[test]
+    fn random_states_never_lose_local_edits_and_settle_after_one_step() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        for _ in 0..20_000 {
+            let base = rng.chance(80).then(|| random_version(&mut rng));
+            let local = rng.chance(85).then(|| format!("h{}", rng.below(4)));
+            let remote = rng.chance(85).then(|| random_version(&mut rng));
+            let decision = reconcile(base.as_ref(), local.as_deref(), remote.as_ref());
+            let case = format!("{base:?} / {local:?} / {remote:?} -> {decision:?}");
+
+            let edited =
+                local.is_some() && local.as_deref() != base.as_ref().map(|b| b.hash.as_str());
+            if edited {
+                match decision {
+                    Reconciliation::Upload | Reconciliation::Conflict => {}
+                    Reconciliation::Adopt => {
+                        assert_eq!(
+                            local.as_deref(),
+                            remote.as_ref().map(|r| r.hash.as_str()),
+                            "{case}"
+                        )
+                    }
+                    _ => panic!("a local edit was dropped: {case}"),
+                }
+            }
+            if decision == Reconciliation::Upload {
+                // The new version must descend from whatever the server holds.
+                if let Some(remote) = &remote {
+                    let base = base.as_ref().expect("an upload over a record has a base");
+                    assert_eq!(remote.hash, base.hash, "{case}");
+                    assert!(
+                        matches!(
+                            remote.version.compare(&base.version),
+                            Causality::Equal | Causality::Before
+                        ),
+                        "{case}"
+                    );
+                }
+            }
+            if local.is_none() && base.is_some() && remote == base {
+                assert_eq!(
+                    decision,
+                    Reconciliation::UpToDate,
+                    "local deletions stay local: {case}"
+                );
+            }
+
+            let (base, local, remote) = apply(decision, "a", base, local, remote);
+            assert_eq!(
+                reconcile(base.as_ref(), local.as_deref(), remote.as_ref()),
+                Reconciliation::UpToDate,
+                "not settled after {case}"
+            );
+        }
+    }
+
+    #[test]
+    fn random_edit_histories_converge_on_every_device() {
+        let devices = ["a", "b", "c"];
+        let mut rng = Rng(0x0dd_ba11_cafe_f00d);
+        for _ in 0..2_000 {
+            let mut states: Vec<(Option<FileVersion>, Option<String>)> = vec![(None, None); 3];
+            let mut server: Option<FileVersion> = None;
+            let mut next_content = 0;
+
+            for _ in 0..rng.below(12) {
+                let device = rng.below(3) as usize;
+                if rng.chance(10) {
+                    server = None;
+                } else if rng.chance(45) {
+                    next_content += 1;
+                    states[device].1 = Some(format!("c{next_content}"));
+                } else {
+                    let (base, local) = states[device].clone();
+                    let decision = reconcile(base.as_ref(), local.as_deref(), server.as_ref());
+                    let previous = server.clone();
+                    let (base, local, remote) =
+                        apply(decision, devices[device], base, local, server);
+                    if decision == Reconciliation::Upload {
+                        if let (Some(previous), Some(sent)) = (&previous, &remote) {
+                            assert_eq!(sent.version.compare(&previous.version), Causality::After);
+                        }
+                    }
+                    states[device] = (base, local);
+                    server = remote;
+                }
+            }
+
+            for _round in 0..3 {
+                for (device, state) in states.iter_mut().enumerate() {
+                    let (base, local) = state.clone();
+                    let decision = reconcile(base.as_ref(), local.as_deref(), server.as_ref());
+                    let (base, local, remote) =
+                        apply(decision, devices[device], base, local, server);
+                    *state = (base, local);
+                    server = remote;
+                }
+            }
+            for (base, local) in &states {
+                assert_eq!(local.as_deref(), server.as_ref().map(|s| s.hash.as_str()));
+                assert_eq!(
+                    reconcile(base.as_ref(), local.as_deref(), server.as_ref()),
+                    Reconciliation::UpToDate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn downloads_remote_edits_and_keeps_both_sides_of_a_conflict() {
+        let root = temp_dir("reconcile");
+        fs::write(root.join("a.txt"), "one").expect("write a");
+        fs::write(root.join("b.txt"), "base").expect("write b");
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions::default())
+            .with_snapshots(SyncedIndex::in_memory())
+            .with_versions("laptop");
+        manager.queue_directory(&root).expect("root should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        let server_version = |path: &Path| {
+            remote.state().files[path.to_string_lossy().as_ref()]
+                .version
+                .as_ref()
+                .map(ToString::to_string)
+        };
+        assert_eq!(
+            server_version(&root.join("a.txt")).as_deref(),
+            Some("laptop:1")
+        );
+
+        // The desk saw the laptop's a.txt before editing it, but edited b.txt independently
+        // while the laptop changed it too.
+        foreign_edit(&remote, &root.join("a.txt"), "desk a", "desk:1,laptop:1");
+        foreign_edit(&remote, &root.join("b.txt"), "desk b", "desk:1");
+        foreign_edit(&remote, &root.join("c/new.txt"), "desk new", "desk:1");
+        foreign_edit(&remote, Path::new("/elsewhere/x.txt"), "not ours", "desk:1");
+        fs::write(root.join("b.txt"), "laptop b").expect("edit b");
+
+        let paths = ["a.txt", "b.txt", "c/new.txt"]
+            .map(|name| root.join(name).to_string_lossy().to_string());
+        let mut reconciler = Reconciler::new(remote.clone(), "laptop", std::slice::from_ref(&root));
+        let report = reconciler.reconcile(
+            &mut manager,
+            paths.iter().map(String::as_str).chain(["/elsewhere/x.txt"]),
+        );
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(
+            report.downloaded,
+            [root.join("a.txt"), root.join("c/new.txt")]
+        );
+        let copy = root.join("b (conflict laptop).txt");
+        assert_eq!(report.conflicts, [(root.join("b.txt"), copy.clone())]);
+        let read = |path: PathBuf| fs::read_to_string(path).expect("file should read");
+        assert_eq!(read(root.join("a.txt")), "desk a");
+        assert_eq!(read(root.join("b.txt")), "desk b");
+        assert_eq!(read(copy.clone()), "laptop b");
+        assert_eq!(read(root.join("c/new.txt")), "desk new");
+        assert!(!Path::new("/elsewhere/x.txt").exists());
+
+        // Only the conflict copy goes up; the downloaded b.txt is already on the server.
+        manager
+            .queue_file(root.join("b.txt"))
+            .expect("b should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        assert_eq!(
+            server_version(&root.join("b.txt")).as_deref(),
+            Some("desk:1")
+        );
+        assert_eq!(server_version(&copy).as_deref(), Some("laptop:1"));
+
+        let again = reconciler.reconcile(&mut manager, paths.iter().map(String::as_str));
+        assert!(
+            again.downloaded.is_empty() && again.conflicts.is_empty() && again.failed.is_empty()
+        );
+
+        // A second conflict on the same file gets the next number.
+        foreign_edit(&remote, &root.join("b.txt"), "desk b2", "desk:2");
+        fs::write(root.join("b.txt"), "laptop b2").expect("edit b again");
+        let report = reconciler.reconcile(&mut manager, [paths[1].as_str()]);
+        assert_eq!(
+            report.conflicts[0].1,
+            root.join("b (conflict laptop 2).txt")
+        );
+
+        // A later local edit descends from the desk's version of a.txt.
+        fs::write(root.join("a.txt"), "laptop a").expect("edit a");
+        manager
+            .queue_file(root.join("a.txt"))
+            .expect("a should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        assert_eq!(
+            server_version(&root.join("a.txt")).as_deref(),
+            Some("desk:1,laptop:2")
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn applies_remote_deletions_only_to_untouched_files() {
+        let root = temp_dir("reconcile-delete");
+        fs::write(root.join("kept.txt"), "kept").expect("write kept");
+        fs::write(root.join("gone.txt"), "gone").expect("write gone");
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions::default())
+            .with_snapshots(SyncedIndex::in_memory())
+            .with_versions("laptop");
+        manager.queue_directory(&root).expect("root should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        let paths =
+            ["kept.txt", "gone.txt"].map(|name| root.join(name).to_string_lossy().to_string());
+        for path in &paths {
+            remote.state().files.remove(path);
+        }
+        fs::write(root.join("kept.txt"), "edited after the deletion").expect("edit kept");
+
+        let mut reconciler = Reconciler::new(remote.clone(), "laptop", std::slice::from_ref(&root));
+        let report = reconciler.reconcile(&mut manager, paths.iter().map(String::as_str));
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(report.deleted, [root.join("gone.txt")]);
+        assert!(root.join("kept.txt").exists());
+        assert_eq!(
+            manager.pending_count(),
+            1,
+            "the edit wins over the deletion and is sent again"
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn refuses_server_paths_that_climb_out_of_the_root() {
+        let outer = temp_dir("reconcile-escape");
+        let root = outer.join("root");
+        fs::create_dir_all(&root).expect("create root");
+        fs::write(outer.join("victim.txt"), "keep me").expect("write victim");
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions::default())
+            .with_snapshots(SyncedIndex::in_memory())
+            .with_versions("laptop");
+        let escaping = root.join("../escaped.txt");
+        let victim = root.join("../victim.txt");
+        foreign_edit(&remote, &escaping, "planted", "desk:1");
+        foreign_edit(&remote, &victim, "overwritten", "desk:1");
+
+        let paths = [&escaping, &victim].map(|path| path.to_string_lossy().to_string());
+        let mut reconciler = Reconciler::new(remote.clone(), "laptop", std::slice::from_ref(&root));
+        let report = reconciler.reconcile(&mut manager, paths.iter().map(String::as_str));
+        assert_eq!(report.failed.len(), 2);
+        assert!(report
+            .failed
+            .iter()
+            .all(|(_, err)| matches!(err, SyncError::InvalidPath(_))));
+        assert!(report.downloaded.is_empty() && report.conflicts.is_empty());
+        assert!(!outer.join("escaped.txt").exists());
+        assert_eq!(
+            fs::read_to_string(outer.join("victim.txt")).expect("victim should read"),
+            "keep me"
+        );
+
+        // A remote deletion of a path outside the root leaves the local file alone too.
+        remote.state().files.remove(&paths[1]);
+        let report = reconciler.reconcile(&mut manager, [paths[1].as_str()]);
+        assert!(matches!(
+            report.failed[..],
+            [(_, SyncError::InvalidPath(_))]
+        ));
+        assert!(outer.join("victim.txt").exists());
+        assert_eq!(manager.pending_count(), 0);
+        let _ = fs::remove_dir_all(outer);
+    }
+
+    #[test]
+    fn downloads_encrypted_paths_and_opens_their_sealed_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let laptop = temp_dir("reconcile-encrypted-laptop");
+        let desk = temp_dir("reconcile-encrypted-desk");
+        fs::create_dir_all(laptop.join("medical")).expect("dir should be created");
+        let file = laptop.join("medical/scan.pdf");
+        fs::write(&file, "scan").expect("write scan");
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).expect("chmod");
+
+        let master = MasterKey::generate().expect("master key should generate");
+        let remote = MemoryRemote::default();
+        let mut uploader = SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions::default())
+            .with_path_encryption(
+                PathCipher::for_root(&master, &laptop, "docs"),
+                crate::PathIndex::in_memory(),
+            )
+            .with_versions("laptop");
+        uploader
+            .queue_directory(&laptop)
+            .expect("root should queue");
+        assert_eq!(uploader.flush_once().failed, 0);
+        let server_path = remote
+            .state()
+            .files
+            .keys()
+            .next()
+            .expect("one file should be on the server")
+            .clone();
+        assert!(remote.state().files[&server_path].metadata.is_none());
+
+        let mut manager = SyncManager::new(MemoryRemote::default())
+            .with_snapshots(SyncedIndex::in_memory())
+            .with_versions("desk");
+        let mut reconciler = Reconciler::new(remote.clone(), "desk", std::slice::from_ref(&desk))
+            .with_path_encryption(PathCipher::for_root(&master, &desk, "docs"));
+        let report = reconciler.reconcile(&mut manager, [server_path.as_str()]);
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        let restored = desk.join("medical/scan.pdf");
+        assert_eq!(report.downloaded, std::slice::from_ref(&restored));
+        assert_eq!(
+            fs::read_to_string(&restored).expect("scan should read"),
+            "scan"
+        );
+        assert_eq!(
+            fs::metadata(&restored)
+                .expect("metadata")
+                .permissions()
+                .mode()
+                & 0o777,
+            0o640
+        );
+        let _ = fs::remove_dir_all(laptop);
+        let _ = fs::remove_dir_all(desk);
+    }
+}
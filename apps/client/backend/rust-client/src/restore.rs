@@ -0,0 +1,1019 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+
+use crate::logging::{self, LogLevel};
+use crate::metadata::to_hex;
+use crate::{
+    hard_link_key, hash_file_streaming, percent_encode, sparse, ContentDecoder, ContentManifest,
+    MasterKey, Snapshot, SyncClient, SyncError, SyncRequest, SyncTransport,
+};
+
+/// One page of the paths the server holds, as returned by `GET /v1/files`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileListPage {
+    pub paths: Vec<String>,
+    pub next_cursor: Option<String>,
+}
+
+impl SyncClient {
+    /// Lists the paths at or below `prefix`, one page at a time.
+    pub fn list_files(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+    ) -> Result<FileListPage, SyncError> {
+        let mut path = format!("/v1/files?prefix={}", percent_encode(prefix));
+        if let Some(cursor) = cursor {
+            path.push_str(&format!("&cursor={}", percent_encode(cursor)));
+        }
+        let response = self.send("GET", &path, "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+
+        let mut page = FileListPage::default();
+        for line in response.body.lines().filter(|line| !line.is_empty()) {
+            match line.split_once('=') {
+                Some(("path", path)) => page.paths.push(path.to_string()),
+                Some(("next", cursor)) if !cursor.is_empty() => {
+                    page.next_cursor = Some(cursor.to_string())
+                }
+                Some(("next", _)) => {}
+                _ => {
+                    return Err(SyncError::Protocol(format!(
+                        "unexpected file listing line: {line}"
+                    )))
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    /// Fetches the latest record for `path`, in the same format `POST /v1/sync` accepts.
+    pub fn fetch_file(&self, path: &str) -> Result<SyncRequest, SyncError> {
+        let response = self.send("GET", &format!("/v1/files/{}", percent_encode(path)), "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+        SyncRequest::decode_body(&response.body)
+    }
+
+    /// Downloads one chunk exactly as it was uploaded.
+    pub fn download_chunk(&self, chunk_id: &str) -> Result<Vec<u8>, SyncError> {
+        let path = format!("/v1/chunks/{chunk_id}");
+        let (status, body) = self.exchange("GET", &path, "text/plain", b"")?;
+        if status != 200 {
+            return Err(SyncError::Server(
+                status,
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+        }
+        Ok(body)
+    }
+}
+
+/// True when `path` is `prefix` itself or lies below it.
+pub(crate) fn under_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path == prefix || path.starts_with(&format!("{prefix}/"))
+}
+
+/// What to do when a restored file would replace a different file already on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Leave the local file alone.
+    Skip,
+    /// Replace the local file.
+    #[default]
+    Overwrite,
+    /// Replace the local file only if it was last modified before the restored version.
+    OverwriteIfOlder,
+    /// Restore next to the local file as `<name> (restored)<.ext>`, numbering further copies.
+    KeepBoth,
+}
+
+impl CollisionPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(CollisionPolicy::Skip),
+            "overwrite" => Some(CollisionPolicy::Overwrite),
+            "overwrite-if-older" => Some(CollisionPolicy::OverwriteIfOlder),
+            "keep-both" => Some(CollisionPolicy::KeepBoth),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollisionPolicy::Skip => "skip",
+            CollisionPolicy::Overwrite => "overwrite",
+            CollisionPolicy::OverwriteIfOlder => "overwrite-if-older",
+            CollisionPolicy::KeepBoth => "keep-both",
+        }
+    }
+}
+
+/// What a restore does, or with a dry run would do, to one destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreAction {
+    Create,
+    Overwrite,
+    /// The local file stays and the restored copy goes to this path instead.
+    KeepBoth(PathBuf),
+    Skip,
+    Unchanged,
+}
+
+impl RestoreAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestoreAction::Create => "create",
+            RestoreAction::Overwrite => "overwrite",
+            RestoreAction::KeepBoth(_) => "keep-both",
+            RestoreAction::Skip => "skip",
+            RestoreAction::Unchanged => "unchanged",
+        }
+    }
+}
+
+/// One file of a dry run: the server path, where it would go and what would happen there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedRestore {
+    pub path: String,
+    pub dest: PathBuf,
+    pub action: RestoreAction,
+}
+
+/// What a restore did. `resumed` counts files that continued an interrupted download,
+/// `unchanged` files that were already on disk with the recorded content, `skipped` files
+/// the collision policy left alone. A dry run fills `planned` and changes nothing.
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    pub resumed: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub downloaded_bytes: u64,
+    pub failed: Vec<(String, SyncError)>,
+    pub planned: Vec<PlannedRestore>,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} restored ({} resumed), {} unchanged, {} skipped, {} failed, {} bytes downloaded",
+            self.restored,
+            self.resumed,
+            self.unchanged,
+            self.skipped,
+            self.failed.len(),
+            self.downloaded_bytes
+        )
+    }
+}
+
+/// Downloads files the server holds back onto disk.
+///
+/// Every chunk is checked against its content address as it arrives and the finished file
+/// against its recorded hash before it replaces anything. Content is written to a hidden
+/// `.<name>.restore-partial` file next to the destination and renamed into place, so a
+/// reader never sees a half-restored file. A progress file beside it records how many
+/// chunks are safely on disk; an interrupted restore picks up from there.
+pub struct Restorer<T: SyncTransport> {
+    transport: T,
+    master: Option<MasterKey>,
+    target: Option<PathBuf>,
+    policy: CollisionPolicy,
+    dry_run: bool,
+}
+
+/// How one file was restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Downloaded { bytes: u64, resumed: bool },
+    Linked,
+    Unchanged,
+}
+
+/// Where a record's content comes from: a file restored earlier in the same run that it
+/// is a hard link to, or its own chunks.
+enum Source {
+    Link(PathBuf),
+    Content(Box<SyncRequest>),
+}
+
+impl<T: SyncTransport> Restorer<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            master: None,
+            target: None,
+            policy: CollisionPolicy::default(),
+            dry_run: false,
+        }
+    }
+
+    /// Needed to decrypt content that was uploaded with encryption.
+    pub fn with_master_key(mut self, master: MasterKey) -> Self {
+        self.master = Some(master);
+        self
+    }
+
+    /// Restores into `directory` instead of the original location: restoring `/a/b` puts
+    /// `/a/b/c.txt` at `<directory>/b/c.txt`.
+    pub fn with_target<P: AsRef<Path>>(mut self, directory: P) -> Self {
+        self.target = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reports what would change in [`RestoreReport::planned`] without downloading or
+    /// writing anything.
+    pub fn with_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    /// Restores every file at or below `prefix` to the path it was synced from. A file that
+    /// fails is reported and the rest carry on.
+    pub fn restore(&mut self, prefix: &str) -> Result<RestoreReport, SyncError> {
+        let paths = self.transport.list_files(prefix)?;
+        if paths.is_empty() {
+            return Err(SyncError::InvalidPath(format!(
+                "{prefix} is not on the server"
+            )));
+        }
+
+        // Never write outside the requested subtree, whatever the server sends back.
+        let mut records = Vec::with_capacity(paths.len());
+        for path in paths {
+            let record = self.transport.fetch_file(&path)?;
+            if !under_prefix(&path, prefix) || record.path != path {
+                return Err(SyncError::Protocol(format!(
+                    "server returned {} for a restore of {prefix}",
+                    record.path
+                )));
+            }
+            records.push(record);
+        }
+        Ok(self.restore_records(records, prefix, None))
+    }
+
+    /// Restores every file at or below `prefix` as it was when `snapshot` was taken,
+    /// including files deleted or changed since.
+    pub fn restore_snapshot(
+        &mut self,
+        snapshot: &Snapshot,
+        prefix: &str,
+    ) -> Result<RestoreReport, SyncError> {
+        let records = snapshot
+            .files
+            .values()
+            .filter(|record| under_prefix(&record.path, prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        if records.is_empty() {
+            return Err(SyncError::InvalidPath(format!(
+                "{prefix} is not in snapshot {}",
+                snapshot.id
+            )));
+        }
+        Ok(self.restore_records(records, prefix, Some(snapshot)))
+    }
+
+    /// Link targets missing from `records` are looked up in `snapshot` when given, or
+    /// fetched from the server's latest state otherwise.
+    fn restore_records(
+        &mut self,
+        mut records: Vec<SyncRequest>,
+        prefix: &str,
+        snapshot: Option<&Snapshot>,
+    ) -> RestoreReport {
+        // Link targets first, so later links can point at a restored file.
+        records.sort_by_key(|record| record.link_to.is_some());
+
+        let mut report = RestoreReport::default();
+        let mut restored: HashMap<String, PathBuf> = HashMap::new();
+        for record in records {
+            let planned = self.destination(&record.path, prefix).and_then(|dest| {
+                let source = self.source(&record, &restored, snapshot)?;
+                let action = self.resolve(&record, &source, &dest)?;
+                Ok((dest, source, action))
+            });
+            let (dest, source, action) = match planned {
+                Ok(planned) => planned,
+                Err(err) => {
+                    report.failed.push((record.path, err));
+                    continue;
+                }
+            };
+            let written = match &action {
+                RestoreAction::Skip => None,
+                RestoreAction::KeepBoth(alternate) => Some(alternate.clone()),
+                _ => Some(dest.clone()),
+            };
+            if self.dry_run {
+                match action {
+                    RestoreAction::Skip => report.skipped += 1,
+                    RestoreAction::Unchanged => report.unchanged += 1,
+                    _ => report.restored += 1,
+                }
+                if let Some(written) = written {
+                    restored.insert(record.path.clone(), written);
+                }
+                report.planned.push(PlannedRestore {
+                    path: record.path,
+                    dest,
+                    action,
+                });
+                continue;
+            }
+
+            let Some(written) = written else {
+                report.skipped += 1;
+                continue;
+            };
+            let outcome = match action {
+                RestoreAction::Unchanged => Ok(Outcome::Unchanged),
+                _ => self.write(&source, &written),
+            };
+            match outcome {
+                Ok(outcome) => {
+                    match outcome {
+                        Outcome::Downloaded { bytes, resumed } => {
+                            report.restored += 1;
+                            report.resumed += usize::from(resumed);
+                            report.downloaded_bytes += bytes;
+                        }
+                        Outcome::Linked => report.restored += 1,
+                        Outcome::Unchanged => report.unchanged += 1,
+                    }
+                    restored.insert(record.path, written);
+                }
+                Err(err) => report.failed.push((record.path, err)),
+            }
+        }
+        report
+    }
+
+    /// Restores one server record to `dest`, replacing whatever is there.
+    pub fn restore_file(&mut self, record: &SyncRequest, dest: &Path) -> Result<u64, SyncError> {
+        let source = self.source(record, &HashMap::new(), None)?;
+        if self.is_current(&source, dest)? {
+            return Ok(0);
+        }
+        match self.write(&source, dest)? {
+            Outcome::Downloaded { bytes, .. } => Ok(bytes),
+            Outcome::Linked | Outcome::Unchanged => Ok(0),
+        }
+    }
+
+    /// Where a server path under `prefix` lands: the path itself, or the same path relative
+    /// to the prefix's parent inside the target directory. Paths with `..` are refused.
+    fn destination(&self, path: &str, prefix: &str) -> Result<PathBuf, SyncError> {
+        if Path::new(path)
+            .components()
+            .any(|part| part == Component::ParentDir)
+        {
+            return Err(SyncError::InvalidPath(path.to_string()));
+        }
+        let Some(target) = &self.target else {
+            return Ok(PathBuf::from(path));
+        };
+        let prefix = prefix.trim_end_matches('/');
+        let parent = prefix.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let relative = path
+            .strip_prefix(parent)
+            .ok_or_else(|| SyncError::InvalidPath(path.to_string()))?;
+        Ok(target.join(relative.trim_start_matches('/')))
+    }
+
+    fn source(
+        &mut self,
+        record: &SyncRequest,
+        restored: &HashMap<String, PathBuf>,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<Source, SyncError> {
+        let Some(target) = &record.link_to else {
+            return Ok(Source::Content(Box::new(record.clone())));
+        };
+        if let Some(target_dest) = restored.get(target) {
+            return Ok(Source::Link(target_dest.clone()));
+        }
+        // The link target is not part of this restore, so this path gets its own copy.
+        let target = match snapshot {
+            Some(snapshot) => snapshot.files.get(target).cloned().ok_or_else(|| {
+                SyncError::Protocol(format!(
+                    "snapshot {} links {} to missing {target}",
+                    snapshot.id, record.path
+                ))
+            })?,
+            None => self.transport.fetch_file(target)?,
+        };
+        Ok(Source::Content(Box::new(SyncRequest {
+            path: record.path.clone(),
+            metadata: record.metadata.clone().or(target.metadata.clone()),
+            link_to: None,
+            ..target
+        })))
+    }
+
+    /// Decides what happens at `dest`, applying the collision policy when a different
+    /// file is already there.
+    fn resolve(
+        &self,
+        record: &SyncRequest,
+        source: &Source,
+        dest: &Path,
+    ) -> Result<RestoreAction, SyncError> {
+        let local = match fs::symlink_metadata(dest) {
+            Ok(local) => local,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(RestoreAction::Create),
+            Err(err) => return Err(SyncError::Io(err)),
+        };
+        if self.is_current(source, dest)? {
+            return Ok(RestoreAction::Unchanged);
+        }
+        Ok(match self.policy {
+            CollisionPolicy::Skip => RestoreAction::Skip,
+            CollisionPolicy::Overwrite => RestoreAction::Overwrite,
+            CollisionPolicy::OverwriteIfOlder => {
+                let local_ns = local
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|elapsed| elapsed.as_nanos() as i64);
+                let restored_ns = record
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.mtime_ns);
+                match (local_ns, restored_ns) {
+                    (Some(local_ns), Some(restored_ns)) if local_ns < restored_ns => {
+                        RestoreAction::Overwrite
+                    }
+                    // Keep the local file when either age is unknown.
+                    _ => RestoreAction::Skip,
+                }
+            }
+            CollisionPolicy::KeepBoth => self.keep_both_path(source, dest)?,
+        })
+    }
+
+    /// The first free `<stem> (restored[ N])<.ext>` beside `dest`. A copy restored by an
+    /// earlier run that still matches counts as unchanged, so reruns add nothing.
+    fn keep_both_path(&self, source: &Source, dest: &Path) -> Result<RestoreAction, SyncError> {
+        let stem = dest
+            .file_stem()
+            .ok_or_else(|| SyncError::InvalidPath(dest.display().to_string()))?
+            .to_string_lossy();
+        let extension = dest
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+        for copy in 1.. {
+            let suffix = match copy {
+                1 => " (restored)".to_string(),
+                copy => format!(" (restored {copy})"),
+            };
+            let candidate = dest.with_file_name(format!("{stem}{suffix}{extension}"));
+            if fs::symlink_metadata(&candidate).is_err() {
+                return Ok(RestoreAction::KeepBoth(candidate));
+            }
+            if self.is_current(source, &candidate)? {
+                return Ok(RestoreAction::Unchanged);
+            }
+        }
+        unreachable!("an unbounded range always yields a free name")
+    }
+
+    /// True when `dest` already holds what `source` would write there.
+    fn is_current(&self, source: &Source, dest: &Path) -> Result<bool, SyncError> {
+        if !dest.is_file() {
+            return Ok(false);
+        }
+        match source {
+            Source::Link(target) => {
+                Ok(hard_link_key(target).is_some() && hard_link_key(target) == hard_link_key(dest))
+            }
+            Source::Content(record) if record.chunks.is_empty() => Ok(false),
+            Source::Content(record) => self.matches_record(dest, record),
+        }
+    }
+
+    fn write(&mut self, source: &Source, dest: &Path) -> Result<Outcome, SyncError> {
+        let target = match source {
+            Source::Content(record) => return self.download(record, dest),
+            Source::Link(target) => target,
+        };
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(SyncError::Io)?;
+        }
+        let partial = sibling(dest, "restore-partial")?;
+        let _ = fs::remove_file(&partial);
+        fs::hard_link(target, &partial).map_err(SyncError::Io)?;
+        fs::rename(&partial, dest).map_err(SyncError::Io)?;
+        Ok(Outcome::Linked)
+    }
+
+    fn download(&mut self, record: &SyncRequest, dest: &Path) -> Result<Outcome, SyncError> {
+        if record.chunks.is_empty() {
+            return Err(SyncError::Protocol(format!(
+                "the server holds no content for {}; it was synced without content upload",
+                record.path
+            )));
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(SyncError::Io)?;
+        }
+
+        let decoder = ContentDecoder::new(
+            &ContentManifest {
+                chunks: record.chunks.clone(),
+                encryption: record.encryption.clone(),
+                uploaded_bytes: 0,
+            },
+            self.master.as_ref(),
+        )?;
+        let partial = sibling(dest, "restore-partial")?;
+        let progress = sibling(dest, "restore-progress")?;
+        let done = read_progress(&progress, &record.hash).min(record.chunks.len());
+
+        let mut file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&partial)
+            .map_err(SyncError::Io)?;
+        if done == 0 {
+            file.set_len(0).map_err(SyncError::Io)?;
+        }
+
+        let mut bytes = 0;
+        for (index, chunk) in record.chunks.iter().enumerate().skip(done) {
+            let body = self.transport.download_chunk(&chunk.id)?;
+            if to_hex(&Sha256::digest(&body)) != chunk.id {
+                return Err(SyncError::Protocol(format!(
+                    "chunk {} of {} does not match its content address",
+                    chunk.id, record.path
+                )));
+            }
+            bytes += body.len() as u64;
+            let plaintext = decoder.decode(index, body)?;
+            if plaintext.len() as u64 != chunk.length {
+                return Err(SyncError::Protocol(format!(
+                    "chunk {index} of {} has {} bytes, expected {}",
+                    record.path,
+                    plaintext.len(),
+                    chunk.length
+                )));
+            }
+            file.seek(SeekFrom::Start(chunk.offset))
+                .map_err(SyncError::Io)?;
+            file.write_all(&plaintext).map_err(SyncError::Io)?;
+            file.sync_data().map_err(SyncError::Io)?;
+            fs::write(
+                &progress,
+                format!("hash={}\nchunks={}\n", record.hash, index + 1),
+            )
+            .map_err(SyncError::Io)?;
+        }
+
+        let size = match &record.sparse {
+            Some(layout) => layout.size,
+            None => record
+                .chunks
+                .iter()
+                .map(|chunk| chunk.offset + chunk.length)
+                .max()
+                .unwrap_or(0),
+        };
+        file.set_len(size).map_err(SyncError::Io)?;
+        file.sync_all().map_err(SyncError::Io)?;
+        drop(file);
+
+        if !self.matches_record(&partial, record)? {
+            let _ = fs::remove_file(&partial);
+            let _ = fs::remove_file(&progress);
+            return Err(SyncError::Protocol(format!(
+                "restored content of {} does not match its recorded hash",
+                record.path
+            )));
+        }
+        if let Some(metadata) = &record.metadata {
+            for (field, reason) in metadata.apply(&partial)?.skipped {
+                logging::log(
+                    "restore",
+                    LogLevel::Debug,
+                    &format!("{}: could not restore {field}: {reason}", dest.display()),
+                );
+            }
+        }
+        fs::rename(&partial, dest).map_err(SyncError::Io)?;
+        let _ = fs::remove_file(&progress);
+        Ok(Outcome::Downloaded {
+            bytes,
+            resumed: done > 0,
+        })
+    }
+
+    pub(crate) fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Hashes `file_path` the way the record's hash was computed and compares.
+    fn matches_record(&self, file_path: &Path, record: &SyncRequest) -> Result<bool, SyncError> {
+        let hash = plaintext_hash(file_path, record)?;
+        let hash = match (&record.encryption, &self.master) {
+            (Some(_), Some(master)) => master.content_tag(&hash),
+            _ => hash,
+        };
+        Ok(hash == record.hash)
+    }
+}
+
+/// The plain hash of `file_path`, over the record's data extents when it was sparse.
+pub(crate) fn plaintext_hash(file_path: &Path, record: &SyncRequest) -> Result<String, SyncError> {
+    match &record.sparse {
+        Some(layout) => sparse::hash_extents(file_path, layout),
+        None => hash_file_streaming(file_path),
+    }
+}
+
+/// `.<name>.<suffix>` in the destination's directory.
+fn sibling(dest: &Path, suffix: &str) -> Result<PathBuf, SyncError> {
+    let name = dest
+        .file_name()
+        .ok_or_else(|| SyncError::InvalidPath(dest.display().to_string()))?;
+    Ok(dest.with_file_name(format!(".{}.{suffix}", name.to_string_lossy())))
+}
+
+/// Chunks already written for the record with `hash`; zero when there is no progress file
+/// or it belongs to another version of the file.
+fn read_progress(progress: &Path, hash: &str) -> usize {
+    let Ok(text) = fs::read_to_string(progress) else {
+        return 0;
+    };
+    let mut lines = text.lines();
+    if lines.next() != Some(&format!("hash={hash}")) {
+        return 0;
+    }
+    lines
+        .next()
+        .and_then(|line| line.strip_prefix("chunks="))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve_scripted, temp_dir, MemoryRemote, MockResponse};
+    use crate::{ContentOptions, SyncManager};
+
+    fn upload(remote: &MemoryRemote, root: &Path, options: ContentOptions) {
+        let mut manager = SyncManager::new(remote.clone()).with_content_upload(options);
+        manager
+            .queue_directory(root)
+            .expect("directory should queue");
+        let report = manager.flush_once();
+        assert_eq!(report.failed, 0, "upload should succeed");
+    }
+
+    #[test]
+    fn restores_a_subtree_with_content_metadata_and_hard_links() {
+        let root = temp_dir("restore-tree");
+        fs::create_dir_all(root.join("docs/deep")).expect("dirs should be created");
+        fs::write(root.join("docs/a.txt"), "alpha").expect("write a");
+        fs::write(root.join("docs/deep/b.csv"), b"member,hours\n".repeat(500)).expect("write b");
+        fs::write(root.join("other.txt"), "not restored").expect("write other");
+        fs::hard_link(root.join("docs/a.txt"), root.join("docs/a-link.txt")).expect("link");
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(root.join("docs/a.txt"))
+            .and_then(|file| file.set_modified(old))
+            .expect("mtime should be set");
+
+        let master = MasterKey::generate().expect("master key should generate");
+        let remote = MemoryRemote::default();
+        upload(
+            &remote,
+            &root,
+            ContentOptions {
+                chunk_size: 1000,
+                encryption: Some(master.clone()),
+                compression: Some(Default::default()),
+            },
+        );
+        fs::remove_dir_all(root.join("docs")).expect("docs should be removed");
+        fs::remove_file(root.join("other.txt")).expect("other should be removed");
+
+        let prefix = root.join("docs").display().to_string();
+        let mut restorer = Restorer::new(remote.clone()).with_master_key(master);
+        let report = restorer.restore(&prefix).expect("restore should run");
+
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(
+            (report.restored, report.resumed, report.unchanged),
+            (3, 0, 0)
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("docs/a.txt")).expect("a"),
+            "alpha"
+        );
+        assert_eq!(
+            fs::read(root.join("docs/deep/b.csv")).expect("b"),
+            b"member,hours\n".repeat(500)
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("docs/a-link.txt")).expect("link"),
+            "alpha"
+        );
+        assert_eq!(
+            fs::metadata(root.join("docs/a.txt"))
+                .and_then(|m| m.modified())
+                .expect("mtime"),
+            old
+        );
+        assert!(!root.join("other.txt").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let ino = |name: &str| fs::metadata(root.join(name)).expect("metadata").ino();
+            assert_eq!(ino("docs/a.txt"), ino("docs/a-link.txt"));
+        }
+        let leftovers = fs::read_dir(root.join("docs"))
+            .expect("docs should list")
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // Running it again downloads nothing, and the hard link is already in place.
+        let again = restorer
+            .restore(&prefix)
+            .expect("second restore should run");
+        assert_eq!(
+            (again.restored, again.unchanged, again.downloaded_bytes),
+            (0, 3, 0)
+        );
+    }
+
+    #[test]
+    fn restores_into_another_directory_under_each_collision_policy() {
+        let root = temp_dir("restore-target");
+        let at =
+            |seconds| std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+        let write = |path: PathBuf, text: &str, seconds| {
+            fs::create_dir_all(path.parent().expect("parent")).expect("dirs should be created");
+            fs::write(&path, text).expect("file should be written");
+            File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(at(seconds)))
+                .expect("mtime should be set");
+        };
+        for name in ["a.txt", "b.txt", "notes.md"] {
+            write(
+                root.join("src/docs").join(name),
+                &format!("server {name}"),
+                1_600_000_000,
+            );
+        }
+        let remote = MemoryRemote::default();
+        upload(&remote, &root.join("src"), ContentOptions::default());
+        let prefix = root.join("src/docs").display().to_string();
+        let out = root.join("out/docs");
+        write(out.join("a.txt"), "newer local", 1_700_000_000);
+        write(out.join("b.txt"), "older local", 1_500_000_000);
+        let restorer = |policy| {
+            Restorer::new(remote.clone())
+                .with_target(root.join("out"))
+                .with_collision_policy(policy)
+        };
+        let read = |name: &str| fs::read_to_string(out.join(name)).expect("file should read");
+
+        let plan = restorer(CollisionPolicy::OverwriteIfOlder)
+            .with_dry_run()
+            .restore(&prefix)
+            .expect("dry run should run");
+        let actions = plan
+            .planned
+            .iter()
+            .map(|planned| (planned.dest.clone(), planned.action.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                (out.join("a.txt"), RestoreAction::Skip),
+                (out.join("b.txt"), RestoreAction::Overwrite),
+                (out.join("notes.md"), RestoreAction::Create),
+            ]
+        );
+        assert_eq!(
+            (plan.restored, plan.skipped, remote.state().downloads),
+            (2, 1, 0)
+        );
+        assert!(!out.join("notes.md").exists());
+
+        let report = restorer(CollisionPolicy::OverwriteIfOlder)
+            .restore(&prefix)
+            .expect("restore");
+        assert_eq!((report.restored, report.skipped), (2, 1));
+        assert_eq!(
+            (read("a.txt"), read("b.txt")),
+            ("newer local".to_string(), "server b.txt".to_string())
+        );
+        assert_eq!(read("notes.md"), "server notes.md");
+        assert_eq!(
+            fs::read_to_string(root.join("src/docs/a.txt")).expect("source"),
+            "server a.txt"
+        );
+
+        let report = restorer(CollisionPolicy::KeepBoth)
+            .restore(&prefix)
+            .expect("restore");
+        assert_eq!((report.restored, report.unchanged), (1, 2));
+        assert_eq!(
+            (read("a.txt"), read("a (restored).txt")),
+            ("newer local".to_string(), "server a.txt".to_string())
+        );
+        let again = restorer(CollisionPolicy::KeepBoth)
+            .restore(&prefix)
+            .expect("restore");
+        assert_eq!(
+            (again.restored, again.unchanged),
+            (0, 3),
+            "a matching copy is not made twice"
+        );
+        fs::write(out.join("a (restored).txt"), "edited copy").expect("edit copy");
+        restorer(CollisionPolicy::KeepBoth)
+            .restore(&prefix)
+            .expect("restore");
+        assert_eq!(read("a (restored 2).txt"), "server a.txt");
+
+        fs::write(out.join("b.txt"), "edited again").expect("edit b");
+        let skipped = restorer(CollisionPolicy::Skip)
+            .restore(&prefix)
+            .expect("restore");
+        assert_eq!(
+            (skipped.skipped, read("b.txt")),
+            (2, "edited again".to_string())
+        );
+        restorer(CollisionPolicy::Overwrite)
+            .restore(&prefix)
+            .expect("restore");
+        assert_eq!(
+            (read("a.txt"), read("b.txt")),
+            ("server a.txt".to_string(), "server b.txt".to_string())
+        );
+
+        let escape = format!("{prefix}/../../escaped.txt");
+        let record = SyncRequest {
+            path: escape.clone(),
+            ..remote
+                .state()
+                .files
+                .values()
+                .next()
+                .cloned()
+                .expect("record")
+        };
+        remote.state().files.insert(escape.clone(), record);
+        let report = restorer(CollisionPolicy::Overwrite)
+            .restore(&prefix)
+            .expect("restore");
+        assert!(
+            matches!(report.failed.as_slice(), [(path, SyncError::InvalidPath(_))] if *path == escape)
+        );
+        assert!(!root.join("escaped.txt").exists() && !root.join("out/escaped.txt").exists());
+        assert_eq!(
+            CollisionPolicy::parse("overwrite-if-older"),
+            Some(CollisionPolicy::OverwriteIfOlder)
+        );
+        assert_eq!(CollisionPolicy::parse("newest"), None);
+    }
+
+    #[test]
+    fn interrupted_restore_resumes_from_the_last_chunk_written() {
+        let root = temp_dir("restore-resume");
+        let content = (0..20_000_u32)
+            .flat_map(|n| n.to_le_bytes())
+            .collect::<Vec<_>>();
+        fs::write(root.join("big.bin"), &content).expect("write big");
+        let remote = MemoryRemote::default();
+        upload(
+            &remote,
+            &root,
+            ContentOptions {
+                chunk_size: 8000,
+                ..ContentOptions::default()
+            },
+        );
+        fs::write(root.join("big.bin"), "stale local copy").expect("overwrite big");
+        let prefix = root.join("big.bin").display().to_string();
+
+        remote.state().fail_downloads_after = Some(4);
+        let report = Restorer::new(remote.clone())
+            .restore(&prefix)
+            .expect("restore should run");
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(
+            fs::read(root.join("big.bin")).expect("big"),
+            b"stale local copy"
+        );
+        assert!(root.join(".big.bin.restore-partial").exists());
+
+        remote.state().fail_downloads_after = None;
+        let before = remote.state().downloads;
+        let report = Restorer::new(remote.clone())
+            .restore(&prefix)
+            .expect("restore should resume");
+        assert_eq!((report.restored, report.resumed), (1, 1));
+        assert_eq!(remote.state().downloads - before, 10 - 4);
+        assert_eq!(fs::read(root.join("big.bin")).expect("big"), content);
+        assert!(!root.join(".big.bin.restore-partial").exists());
+        assert!(!root.join(".big.bin.restore-progress").exists());
+    }
+
+    #[test]
+    fn corrupted_content_is_rejected_without_touching_the_destination() {
+        let root = temp_dir("restore-corrupt");
+        fs::write(root.join("a.txt"), "original").expect("write a");
+        let remote = MemoryRemote::default();
+        upload(&remote, &root, ContentOptions::default());
+        let path = root.join("a.txt").display().to_string();
+        fs::write(root.join("a.txt"), "local edit").expect("edit a");
+
+        let id = remote.state().files[&path].chunks[0].id.clone();
+        remote.state().chunks.insert(id, b"tampered".to_vec());
+        let report = Restorer::new(remote.clone())
+            .restore(&path)
+            .expect("restore should run");
+        assert!(
+            matches!(report.failed.as_slice(), [(_, SyncError::Protocol(message))] if message.contains("content address"))
+        );
+
+        // A record whose hash disagrees with its chunks is caught after download.
+        remote.state().chunks.clear();
+        remote.state().files.clear();
+        fs::write(root.join("a.txt"), "original").expect("rewrite a");
+        upload(&remote, &root, ContentOptions::default());
+        remote.state().files.get_mut(&path).expect("record").hash = "0000000000000000".to_string();
+        let report = Restorer::new(remote.clone())
+            .restore(&path)
+            .expect("restore should run");
+        assert!(
+            matches!(report.failed.as_slice(), [(_, SyncError::Protocol(message))] if message.contains("recorded hash"))
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("a.txt")).expect("a"),
+            "original"
+        );
+        assert!(!root.join(".a.txt.restore-partial").exists());
+
+        let missing = Restorer::new(remote).restore("/nowhere");
+        assert!(matches!(missing, Err(SyncError::InvalidPath(_))));
+    }
+
+    #[test]
+    fn http_client_lists_fetches_records_and_downloads_binary_chunks() {
+        let chunk = vec![0_u8, 255, 13, 10, 13, 10, 7];
+        let record = "path=/r/a b.txt\nhash=abc\nchunk=ff:0:7\n";
+        let (url, handle) = serve_scripted(4, move |request| match request.path.as_str() {
+            "/v1/files?prefix=%2Fr" => MockResponse::text(200, "path=/r/a b.txt\nnext=c1\n"),
+            "/v1/files?prefix=%2Fr&cursor=c1" => MockResponse::text(200, "next=\n"),
+            "/v1/files/%2Fr%2Fa%20b.txt" => MockResponse::text(200, record),
+            _ => MockResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: vec![0, 255, 13, 10, 13, 10, 7],
+            },
+        });
+        let mut transport = crate::HttpTransport::new(&url).expect("url should parse");
+
+        assert_eq!(
+            transport.list_files("/r").expect("listing"),
+            vec!["/r/a b.txt"]
+        );
+        let fetched = transport.fetch_file("/r/a b.txt").expect("record");
+        assert_eq!(fetched.chunks[0].length, 7);
+        assert_eq!(transport.download_chunk("ff").expect("chunk"), chunk);
+        let seen = handle.join().expect("mock server should finish");
+        assert_eq!(seen[3].path, "/v1/chunks/ff");
+        assert!(
+            under_prefix("/r/a", "/r/") && under_prefix("/r", "/r") && !under_prefix("/rx", "/r")
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::queue_store::{blocks, write_atomically};
+use crate::stability::{self, FileStamp};
+use crate::{collect_files, RestoreReport, Restorer, SyncError, SyncManager, SyncTransport};
+
+const RULES_HEADER: &str = "# rust-client selective sync v1";
+
+/// Which synced subtrees this device keeps locally. Rules name absolute local paths; the
+/// deepest rule covering a path decides, and paths no rule covers are included, so an
+/// `include` only matters inside an excluded subtree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelectiveSync {
+    /// Rule path to whether it is included.
+    rules: BTreeMap<PathBuf, bool>,
+}
+
+/// What [`SyncManager::clean_excluded`] did with the files under excluded subtrees.
+#[derive(Debug, Default)]
+pub struct SelectiveCleanup {
+    pub removed: Vec<PathBuf>,
+    /// Files left in place because the server does not hold their current content yet.
+    pub kept: Vec<PathBuf>,
+}
+
+impl SelectiveSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps `path` on this device. Replaces an earlier rule for the same path.
+    pub fn include<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.rules.insert(path.as_ref().to_path_buf(), true);
+        self
+    }
+
+    /// Keeps `path` off this device. Replaces an earlier rule for the same path.
+    pub fn exclude<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.rules.insert(path.as_ref().to_path_buf(), false);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn includes(&self, path: &Path) -> bool {
+        path.ancestors()
+            .find_map(|ancestor| self.rules.get(ancestor).copied())
+            .unwrap_or(true)
+    }
+
+    pub fn included_paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths(true)
+    }
+
+    pub fn excluded_paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths(false)
+    }
+
+    fn paths(&self, included: bool) -> impl Iterator<Item = &Path> {
+        self.rules
+            .iter()
+            .filter(move |(_, &rule)| rule == included)
+            .map(|(path, _)| path.as_path())
+    }
+
+    /// Loads the rules saved at `path`, or no rules when the file does not exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let mut rules = Self::new();
+        for block in blocks(path.as_ref(), RULES_HEADER, "selective sync")? {
+            for line in block.lines() {
+                rules = match line.split_once('=') {
+                    Some(("include", rule)) => rules.include(rule),
+                    Some(("exclude", rule)) => rules.exclude(rule),
+                    _ => {
+                        return Err(SyncError::Protocol(format!(
+                            "unexpected selective sync line: {line}"
+                        )))
+                    }
+                };
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SyncError> {
+        let mut contents = format!("{RULES_HEADER}\n\n");
+        for (rule, &included) in &self.rules {
+            let kind = if included { "include" } else { "exclude" };
+            contents.push_str(&format!("{kind}={}\n", rule.display()));
+        }
+        write_atomically(path.as_ref(), &contents)
+    }
+}
+
+impl<T: SyncTransport> SyncManager<T> {
+    /// Removes local files under excluded subtrees, then the directories left empty. A file
+    /// is only removed when the synced index holds its current content, so nothing that
+    /// exists only here is lost; the rest are reported as kept. Without content upload the
+    /// server cannot give files back, so nothing is removed. Removed files stay in the
+    /// synced index and in later snapshots: they were excluded, not deleted.
+    pub fn clean_excluded(&mut self) -> Result<SelectiveCleanup, SyncError> {
+        let mut cleanup = SelectiveCleanup::default();
+        let excluded = self
+            .selective
+            .excluded_paths()
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        for subtree in excluded {
+            let mut files = Vec::new();
+            if subtree.is_dir() {
+                collect_files(&subtree, &mut files)?;
+            } else if subtree.is_file() {
+                files.push(subtree.clone());
+            }
+            files.sort();
+            for file_path in files {
+                if self.selective.includes(&file_path) {
+                    continue;
+                }
+                let removed = match self.held_stamp(&file_path)? {
+                    Some(stamp) => remove_if_unchanged(&file_path, stamp)?,
+                    None => false,
+                };
+                if removed {
+                    cleanup.removed.push(file_path);
+                } else {
+                    cleanup.kept.push(file_path);
+                }
+            }
+            remove_empty_directories(&subtree, &self.selective);
+        }
+        Ok(cleanup)
+    }
+
+    /// The stamp `file_path` had when it was hashed, if the server holds that content and
+    /// nothing for the file is pending.
+    fn held_stamp(&self, file_path: &Path) -> Result<Option<FileStamp>, SyncError> {
+        let (Some(synced), Some(_)) = (&self.synced, &self.content) else {
+            return Ok(None);
+        };
+        let local = file_path.to_string_lossy();
+        let pending = self.queue.iter().any(|entry| entry.request.path == local)
+            || self.deferred.iter().any(|deferred| deferred == file_path);
+        if pending {
+            return Ok(None);
+        }
+        let Some(record) = synced.get(&self.server_path(file_path)?) else {
+            return Ok(None);
+        };
+        if record.chunks.is_empty() && record.link_to.is_none() {
+            return Ok(None);
+        }
+        let stable = stability::hash_file_stable(file_path, &self.stability)?;
+        Ok((record.hash == self.server_hash(&stable.hash)).then_some(stable.stamp))
+    }
+}
+
+/// Downloads files that `previous` kept off this device and the manager's rules now
+/// include, recording each in the synced index. Files already present locally are left
+/// alone. Listing failures abort; per-file failures are reported.
+pub fn restore_included<T: SyncTransport, U: SyncTransport>(
+    restorer: &mut Restorer<T>,
+    manager: &mut SyncManager<U>,
+    previous: &SelectiveSync,
+) -> Result<RestoreReport, SyncError> {
+    let mut server_paths = BTreeSet::new();
+    for subtree in previous.excluded_paths() {
+        if !manager.selective.includes(subtree)
+            && manager
+                .selective
+                .included_paths()
+                .all(|included| !included.starts_with(subtree))
+        {
+            continue;
+        }
+        let prefix = manager.server_path(subtree)?;
+        server_paths.extend(restorer.transport_mut().list_files(&prefix)?);
+    }
+
+    let mut report = RestoreReport::default();
+    for server_path in server_paths {
+        let local_path = match manager.local_path(&server_path) {
+            Ok(local_path) => local_path,
+            Err(err) => {
+                report.failed.push((server_path, err));
+                continue;
+            }
+        };
+        if previous.includes(&local_path) || !manager.selective.includes(&local_path) {
+            continue;
+        }
+        if fs::symlink_metadata(&local_path).is_ok() {
+            report.skipped += 1;
+            continue;
+        }
+        let restored = restorer
+            .transport_mut()
+            .fetch_file(&server_path)
+            .and_then(|record| {
+                if let Some(parent) = local_path.parent() {
+                    fs::create_dir_all(parent).map_err(SyncError::Io)?;
+                }
+                let bytes = restorer.restore_file(&record, &local_path)?;
+                Ok((record, bytes))
+            });
+        match restored {
+            Ok((record, bytes)) => {
+                if let Some(synced) = &mut manager.synced {
+                    synced.record(record);
+                }
+                report.restored += 1;
+                report.downloaded_bytes += bytes;
+            }
+            Err(err) => report.failed.push((server_path, err)),
+        }
+    }
+    Ok(report)
+}
+
+/// Removes `file_path` only if its stamp still matches the one taken when it was hashed,
+/// so a write that lands after the check is not lost.
+fn remove_if_unchanged(file_path: &Path, stamp: FileStamp) -> Result<bool, SyncError> {
+    if FileStamp::read(file_path)? != stamp {
+        return Ok(false);
+    }
+    fs::remove_file(file_path).map_err(SyncError::Io)?;
+    Ok(true)
+}
+
+/// Removes excluded directories under `subtree` that are empty, deepest first.
+fn remove_empty_directories(subtree: &Path, rules: &SelectiveSync) {
+    let Ok(entries) = fs::read_dir(subtree) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            remove_empty_directories(&path, rules);
+        }
+    }
+    if !rules.includes(subtree) {
+        // Fails, as intended, while anything is left inside.
+        let _ = fs::remove_dir(subtree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{temp_dir, MemoryRemote};
+    use crate::{ContentOptions, SyncedIndex};
+
+    fn manager(remote: &MemoryRemote, rules: SelectiveSync) -> SyncManager<MemoryRemote> {
+        SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions::default())
+            .with_snapshots(SyncedIndex::in_memory())
+            .with_selective_sync(rules)
+    }
+
+    #[test]
+    fn deepest_rule_decides_and_rules_round_trip() {
+        let dir = temp_dir("selective-rules");
+        let rules = SelectiveSync::new()
+            .exclude("/r/media")
+            .include("/r/media/keep")
+            .exclude("/r/media/keep/raw")
+            .include("/r/other")
+            .exclude("/r/other");
+
+        assert!(rules.includes(Path::new("/r/notes.txt")));
+        assert!(!rules.includes(Path::new("/r/media")));
+        assert!(!rules.includes(Path::new("/r/media/a.jpg")));
+        assert!(rules.includes(Path::new("/r/media/keep/b.jpg")));
+        assert!(!rules.includes(Path::new("/r/media/keep/raw/c.raw")));
+        assert!(
+            !rules.includes(Path::new("/r/other/d")),
+            "the later rule wins"
+        );
+        assert!(
+            rules.includes(Path::new("/r/media-old/e")),
+            "rules match whole components"
+        );
+
+        let path = dir.join("selective-sync");
+        assert_eq!(
+            SelectiveSync::load(&path).expect("missing file is fine"),
+            SelectiveSync::new()
+        );
+        rules.save(&path).expect("rules should save");
+        assert_eq!(
+            SelectiveSync::load(&path).expect("rules should load"),
+            rules
+        );
+        fs::write(&path, format!("{RULES_HEADER}\n\nmaybe=/r\n")).expect("write rules");
+        assert!(SelectiveSync::load(&path).is_err());
+    }
+
+    #[test]
+    fn excluded_files_are_not_queued_and_cleanup_keeps_unsynced_ones() {
+        let root = temp_dir("selective-clean");
+        let media = root.join("media");
+        fs::create_dir_all(media.join("2023")).expect("create media");
+        fs::write(root.join("notes.txt"), "notes").expect("write notes");
+        fs::write(media.join("2023/a.jpg"), "a").expect("write a");
+        fs::write(media.join("b.jpg"), "b").expect("write b");
+        let remote = MemoryRemote::default();
+
+        let mut manager = manager(&remote, SelectiveSync::new());
+        manager.queue_directory(&root).expect("queue root");
+        assert_eq!(manager.flush_once().succeeded, 3);
+
+        let mut manager = manager.with_selective_sync(SelectiveSync::new().exclude(&media));
+        fs::write(media.join("b.jpg"), "edited here").expect("edit b");
+        fs::write(media.join("new.jpg"), "new").expect("write new");
+        assert_eq!(manager.queue_directory(&root).expect("queue root"), 1);
+        assert!(matches!(
+            manager.queue_file(media.join("new.jpg")),
+            Err(SyncError::InvalidPath(message)) if message.contains("excluded")
+        ));
+
+        let cleanup = manager.clean_excluded().expect("cleanup");
+        assert_eq!(cleanup.removed, vec![media.join("2023/a.jpg")]);
+        assert_eq!(
+            cleanup.kept,
+            vec![media.join("b.jpg"), media.join("new.jpg")]
+        );
+        assert!(
+            !media.join("2023").exists(),
+            "empty directories are removed"
+        );
+        assert!(root.join("notes.txt").exists());
+
+        manager.forget(&media.join("2023/a.jpg"));
+        let synced = manager.synced_index().expect("snapshots are on");
+        assert!(
+            synced
+                .get(&media.join("2023/a.jpg").to_string_lossy())
+                .is_some(),
+            "cleanup is not a deletion"
+        );
+    }
+
+    #[test]
+    fn files_written_after_their_check_are_not_removed() {
+        let dir = temp_dir("selective-race");
+        let file_path = dir.join("a.jpg");
+        fs::write(&file_path, "synced").expect("write a");
+        let stamp = FileStamp::read(&file_path).expect("stamp");
+
+        fs::write(&file_path, "written after the check").expect("rewrite a");
+        assert!(!remove_if_unchanged(&file_path, stamp).expect("check should run"));
+        assert_eq!(
+            fs::read_to_string(&file_path).expect("a should remain"),
+            "written after the check"
+        );
+
+        let stamp = FileStamp::read(&file_path).expect("stamp");
+        assert!(remove_if_unchanged(&file_path, stamp).expect("removal should run"));
+        assert!(!file_path.exists());
+    }
+
+    #[test]
+    fn reincluded_subtrees_are_downloaded_again() {
+        let root = temp_dir("selective-restore");
+        let media = root.join("media");
+        fs::create_dir_all(media.join("raw")).expect("create media");
+        fs::write(media.join("a.jpg"), "a").expect("write a");
+        fs::write(media.join("raw/b.raw"), "b").expect("write b");
+        fs::write(root.join("notes.txt"), "notes").expect("write notes");
+        let remote = MemoryRemote::default();
+        let mut manager = manager(&remote, SelectiveSync::new());
+        manager.queue_directory(&root).expect("queue root");
+        manager.flush_once();
+
+        let excluded = SelectiveSync::new().exclude(&media);
+        manager = manager.with_selective_sync(excluded.clone());
+        assert_eq!(manager.clean_excluded().expect("cleanup").removed.len(), 2);
+        assert!(!media.exists());
+
+        let partly = SelectiveSync::new()
+            .exclude(&media)
+            .include(media.join("raw"));
+        manager = manager.with_selective_sync(partly.clone());
+        let mut restorer = Restorer::new(remote.clone());
+        let report = restore_included(&mut restorer, &mut manager, &excluded).expect("restore");
+        assert_eq!((report.restored, report.failed.len()), (1, 0));
+        assert_eq!(
+            fs::read_to_string(media.join("raw/b.raw")).expect("read b"),
+            "b"
+        );
+        assert!(!media.join("a.jpg").exists());
+
+        manager = manager.with_selective_sync(SelectiveSync::new());
+        let report = restore_included(&mut restorer, &mut manager, &partly).expect("restore");
+        assert_eq!(report.restored, 1);
+        assert_eq!(
+            fs::read_to_string(media.join("a.jpg")).expect("read a"),
+            "a"
+        );
+        let report =
+            restore_included(&mut restorer, &mut manager, &SelectiveSync::new()).expect("restore");
+        assert_eq!(report.restored, 0, "unchanged rules download nothing");
+    }
+}
@@ -0,0 +1,662 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::control::unix_seconds;
+use crate::metadata::to_hex;
+use crate::queue_store::{blocks, write_atomically};
+use crate::{percent_encode, SyncClient, SyncError, SyncRequest};
+
+const SNAPSHOT_HEADER: &str = "# rust-client snapshot v1";
+const INDEX_HEADER: &str = "# rust-client synced index v1";
+
+/// One line of `GET /v1/snapshots`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotSummary {
+    pub id: String,
+    pub created: SystemTime,
+    pub files: usize,
+}
+
+/// Every synced file as the server recorded it at one moment, keyed by server path. Each
+/// entry is the full sync request, so a snapshot names the exact chunks to restore.
+///
+/// The id is derived from the file entries alone: two snapshots of an unchanged tree share
+/// it, and a manifest fetched from the server can be checked against the id it was asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub id: String,
+    pub created: SystemTime,
+    pub files: BTreeMap<String, SyncRequest>,
+}
+
+/// How a path differs between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+    /// Same content, different mode, ownership, timestamps or xattrs.
+    Metadata,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Metadata => "metadata",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotChange {
+    pub path: String,
+    pub kind: ChangeKind,
+}
+
+impl Snapshot {
+    pub fn new(created: SystemTime, files: BTreeMap<String, SyncRequest>) -> Self {
+        Self {
+            id: manifest_id(&files),
+            created,
+            files,
+        }
+    }
+
+    pub fn summary(&self) -> SnapshotSummary {
+        SnapshotSummary {
+            id: self.id.clone(),
+            created: self.created,
+            files: self.files.len(),
+        }
+    }
+
+    /// The manifest body sent to `PUT /v1/snapshots/<id>`: a header, `created=<unix secs>`,
+    /// then each file's sync request body, separated by blank lines.
+    pub fn encode(&self) -> String {
+        format!(
+            "{SNAPSHOT_HEADER}\ncreated={}\n{}",
+            unix_seconds(self.created),
+            encode_files(&self.files)
+        )
+    }
+
+    pub fn decode(text: &str) -> Result<Self, SyncError> {
+        let invalid =
+            |what: &str| SyncError::Protocol(format!("invalid snapshot manifest: {what}"));
+        let body = text
+            .strip_prefix(SNAPSHOT_HEADER)
+            .and_then(|rest| rest.strip_prefix("\ncreated="))
+            .ok_or_else(|| invalid("missing header"))?;
+        let (created, files) = body.split_once('\n').unwrap_or((body, ""));
+        let created = created
+            .parse::<u64>()
+            .map_err(|_| invalid("bad created time"))?;
+
+        let mut entries = BTreeMap::new();
+        for block in files
+            .split("\n\n")
+            .map(str::trim)
+            .filter(|block| !block.is_empty())
+        {
+            let request = SyncRequest::decode_body(block)?;
+            entries.insert(request.path.clone(), request);
+        }
+        Ok(Self::new(
+            UNIX_EPOCH + Duration::from_secs(created),
+            entries,
+        ))
+    }
+
+    /// Paths that were added, removed or changed going from `self` to `newer`, sorted by path.
+    pub fn diff(&self, newer: &Snapshot) -> Vec<SnapshotChange> {
+        let mut changes = Vec::new();
+        for (path, old) in &self.files {
+            let kind = match newer.files.get(path) {
+                None => Some(ChangeKind::Removed),
+                Some(new) if new.hash != old.hash || new.link_to != old.link_to => {
+                    Some(ChangeKind::Modified)
+                }
+                Some(new) if new.metadata != old.metadata => Some(ChangeKind::Metadata),
+                Some(_) => None,
+            };
+            if let Some(kind) = kind {
+                changes.push(SnapshotChange {
+                    path: path.clone(),
+                    kind,
+                });
+            }
+        }
+        for path in newer
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+        {
+            changes.push(SnapshotChange {
+                path: path.clone(),
+                kind: ChangeKind::Added,
+            });
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        changes
+    }
+
+    /// Picks a snapshot by id, unique id prefix (at least four characters), or time: a
+    /// UTC date or date-time (see [`parse_utc`]) or unix seconds selects the latest
+    /// snapshot taken at or before it.
+    pub fn select<'a>(summaries: &'a [SnapshotSummary], spec: &str) -> Option<&'a SnapshotSummary> {
+        if let Some(exact) = summaries.iter().find(|summary| summary.id == spec) {
+            return Some(exact);
+        }
+        if spec.len() >= 4 {
+            let mut matches = summaries
+                .iter()
+                .filter(|summary| summary.id.starts_with(spec));
+            if let (Some(only), None) = (matches.next(), matches.next()) {
+                return Some(only);
+            }
+        }
+        let at = match spec.parse::<u64>() {
+            Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+            Err(_) => parse_utc(spec)?,
+        };
+        summaries
+            .iter()
+            .filter(|summary| summary.created <= at)
+            .max_by_key(|summary| summary.created)
+    }
+}
+
+fn encode_files(files: &BTreeMap<String, SyncRequest>) -> String {
+    files
+        .values()
+        .map(|request| format!("\n{}", request.encode_body()))
+        .collect()
+}
+
+fn manifest_id(files: &BTreeMap<String, SyncRequest>) -> String {
+    to_hex(&Sha256::digest(encode_files(files).as_bytes())[..8])
+}
+
+/// The latest request the server accepted for each path, persisted so every snapshot covers
+/// the whole tree rather than only what the last flush sent. It also remembers the last
+/// committed snapshot, so an unchanged tree is not committed again.
+#[derive(Debug, Clone, Default)]
+pub struct SyncedIndex {
+    path: Option<PathBuf>,
+    files: BTreeMap<String, SyncRequest>,
+    committed: Option<String>,
+    /// Set when the tree may differ from the committed snapshot, so an idle daemon does not
+    /// rehash the whole index on every flush.
+    changed: bool,
+}
+
+impl SyncedIndex {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Loads the index at `path`, or starts empty when the file does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SyncError> {
+        let path = path.as_ref();
+        let mut index = Self {
+            path: Some(path.to_path_buf()),
+            changed: true,
+            ..Self::default()
+        };
+        for block in blocks(path, INDEX_HEADER, "synced index")? {
+            if let Some(id) = block.strip_prefix("committed=") {
+                index.committed = Some(id.to_string());
+                continue;
+            }
+            let request = SyncRequest::decode_body(&block)?;
+            index.files.insert(request.path.clone(), request);
+        }
+        Ok(index)
+    }
+
+    pub fn record(&mut self, request: SyncRequest) {
+        if self.files.get(&request.path) != Some(&request) {
+            self.files.insert(request.path.clone(), request);
+            self.changed = true;
+        }
+    }
+
+    /// The record last synced for a server path.
+    pub fn get(&self, server_path: &str) -> Option<&SyncRequest> {
+        self.files.get(server_path)
+    }
+
+    /// Drops a path from later snapshots. Returns whether it was known.
+    pub fn remove(&mut self, server_path: &str) -> bool {
+        let known = self.files.remove(server_path).is_some();
+        self.changed |= known;
+        known
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// A snapshot of the current tree, or `None` when it matches the last one committed.
+    pub fn pending_snapshot(&self, created: SystemTime) -> Option<Snapshot> {
+        if !self.changed {
+            return None;
+        }
+        let snapshot = Snapshot::new(created, self.files.clone());
+        (self.committed.as_deref() != Some(snapshot.id.as_str())).then_some(snapshot)
+    }
+
+    pub fn mark_committed(&mut self, id: &str) {
+        self.committed = Some(id.to_string());
+        self.changed = false;
+    }
+
+    pub fn save(&self) -> Result<(), SyncError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut contents = format!("{INDEX_HEADER}\n");
+        if let Some(id) = &self.committed {
+            contents.push_str(&format!("\ncommitted={id}\n"));
+        }
+        contents.push_str(&encode_files(&self.files));
+        write_atomically(path, &contents)
+    }
+}
+
+/// One page of `GET /v1/snapshots`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotPage {
+    pub snapshots: Vec<SnapshotSummary>,
+    pub next_cursor: Option<String>,
+}
+
+impl SyncClient {
+    pub fn put_snapshot(&self, snapshot: &Snapshot) -> Result<(), SyncError> {
+        let path = format!("/v1/snapshots/{}", snapshot.id);
+        let response = self.send("PUT", &path, &snapshot.encode())?;
+        if response.status == 200 || response.status == 201 || response.status == 204 {
+            return Ok(());
+        }
+        Err(SyncError::Server(response.status, response.body))
+    }
+
+    pub fn list_snapshots(&self, cursor: Option<&str>) -> Result<SnapshotPage, SyncError> {
+        let path = match cursor {
+            Some(cursor) => format!("/v1/snapshots?cursor={}", percent_encode(cursor)),
+            None => "/v1/snapshots".to_string(),
+        };
+        let response = self.send("GET", &path, "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+
+        let mut page = SnapshotPage::default();
+        for line in response.body.lines().filter(|line| !line.is_empty()) {
+            match line.split_once('=') {
+                Some(("snapshot", value)) => page.snapshots.push(decode_summary(value)?),
+                Some(("next", cursor)) if !cursor.is_empty() => {
+                    page.next_cursor = Some(cursor.to_string())
+                }
+                Some(("next", _)) => {}
+                _ => {
+                    return Err(SyncError::Protocol(format!(
+                        "unexpected snapshot listing line: {line}"
+                    )))
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    /// Fetches a manifest and checks that its entries hash to the requested id.
+    pub fn fetch_snapshot(&self, id: &str) -> Result<Snapshot, SyncError> {
+        let response = self.send("GET", &format!("/v1/snapshots/{}", percent_encode(id)), "")?;
+        if response.status != 200 {
+            return Err(SyncError::Server(response.status, response.body));
+        }
+        let snapshot = Snapshot::decode(&response.body)?;
+        if snapshot.id != id {
+            return Err(SyncError::Protocol(format!(
+                "snapshot {id} does not match its manifest"
+            )));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// `<id>:<created unix secs>:<file count>`
+fn decode_summary(value: &str) -> Result<SnapshotSummary, SyncError> {
+    let invalid = || SyncError::Protocol(format!("invalid snapshot summary: {value}"));
+    let mut parts = value.split(':');
+    let (Some(id), Some(created), Some(files), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    Ok(SnapshotSummary {
+        id: id.to_string(),
+        created: UNIX_EPOCH + Duration::from_secs(created.parse().map_err(|_| invalid())?),
+        files: files.parse().map_err(|_| invalid())?,
+    })
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` in UTC.
+pub fn format_utc(time: SystemTime) -> String {
+    let seconds = unix_seconds(time);
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let of_day = seconds % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60
+    )
+}
+
+/// Parses `YYYY-MM-DD`, optionally followed by `T` or a space and `HH:MM[:SS]`, and an
+/// optional trailing `Z`. Times are UTC.
+pub fn parse_utc(text: &str) -> Option<SystemTime> {
+    let text = text.strip_suffix('Z').unwrap_or(text);
+    let (date, time) = match text.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (text, None),
+    };
+    let number = |part: &str, digits: usize| {
+        (part.len() == digits && part.bytes().all(|byte| byte.is_ascii_digit()))
+            .then(|| part.parse::<i64>().ok())
+            .flatten()
+    };
+
+    let mut date = date.split('-');
+    let (year, month, day) = match (date.next(), date.next(), date.next(), date.next()) {
+        (Some(year), Some(month), Some(day), None) => {
+            (number(year, 4)?, number(month, 2)?, number(day, 2)?)
+        }
+        _ => return None,
+    };
+    let mut seconds_of_day = 0;
+    if let Some(time) = time {
+        let mut time = time.split(':');
+        let (hour, minute, second) = match (time.next(), time.next(), time.next(), time.next()) {
+            (Some(hour), Some(minute), None, None) => (number(hour, 2)?, number(minute, 2)?, 0),
+            (Some(hour), Some(minute), Some(second), None) => {
+                (number(hour, 2)?, number(minute, 2)?, number(second, 2)?)
+            }
+            _ => return None,
+        };
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        seconds_of_day = hour * 3600 + minute * 60 + second;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if !(1..=12).contains(&month) || civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    let seconds = u64::try_from(days * 86_400 + seconds_of_day).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+impl fmt::Display for SnapshotSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {}  {} files",
+            self.id,
+            format_utc(self.created),
+            self.files
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve_scripted, temp_dir, MemoryRemote, MockResponse};
+    use crate::{ContentOptions, Restorer, SyncManager, SyncTransport};
+    use std::fs;
+
+    #[test]
+    fn commits_snapshots_after_full_flushes_and_restores_an_older_tree() {
+        let root = temp_dir("snapshot-tree");
+        fs::write(root.join("notes.txt"), "version one").expect("write notes");
+        fs::write(root.join("gone.txt"), "deleted later").expect("write gone");
+        let remote = MemoryRemote::default();
+        let index_path = root.join("state/synced-index");
+        let mut manager = SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions::default())
+            .with_snapshots(SyncedIndex::open(&index_path).expect("index should open"));
+
+        manager.queue_directory(&root).expect("root should queue");
+        assert_eq!(manager.commit_snapshot().expect("nothing sent yet"), None);
+        manager.flush_once();
+        let first = manager
+            .commit_snapshot()
+            .expect("commit")
+            .expect("first snapshot");
+        assert_eq!(first.files, 2);
+        assert_eq!(
+            manager.commit_snapshot().expect("commit"),
+            None,
+            "unchanged tree"
+        );
+
+        fs::write(root.join("notes.txt"), "version two").expect("rewrite notes");
+        fs::write(root.join("new.txt"), "added").expect("write new");
+        fs::remove_file(root.join("gone.txt")).expect("remove gone");
+        manager.forget(&root.join("gone.txt"));
+        manager
+            .queue_file(root.join("notes.txt"))
+            .expect("notes should queue");
+        manager
+            .queue_file(root.join("new.txt"))
+            .expect("new should queue");
+        manager.flush_once();
+        let second = manager
+            .commit_snapshot()
+            .expect("commit")
+            .expect("second snapshot");
+        manager.save_synced_index().expect("index should save");
+
+        let mut transport = remote.clone();
+        let listed = transport.list_snapshots().expect("snapshots should list");
+        assert_eq!(listed, vec![first.clone(), second.clone()]);
+        let older = transport.fetch_snapshot(&first.id).expect("first manifest");
+        let newer = transport
+            .fetch_snapshot(&second.id)
+            .expect("second manifest");
+        let path = |name: &str| root.join(name).display().to_string();
+        assert_eq!(
+            older.diff(&newer),
+            vec![
+                SnapshotChange {
+                    path: path("gone.txt"),
+                    kind: ChangeKind::Removed
+                },
+                SnapshotChange {
+                    path: path("new.txt"),
+                    kind: ChangeKind::Added
+                },
+                SnapshotChange {
+                    path: path("notes.txt"),
+                    kind: ChangeKind::Modified
+                },
+            ]
+        );
+
+        let report = Restorer::new(remote.clone())
+            .restore_snapshot(&older, &root.display().to_string())
+            .expect("restore should run");
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(
+            fs::read_to_string(root.join("notes.txt")).expect("notes"),
+            "version one"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("gone.txt")).expect("gone"),
+            "deleted later"
+        );
+
+        // A restarted manager remembers what was committed.
+        let reopened = SyncedIndex::open(&index_path).expect("index should reopen");
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.pending_snapshot(SystemTime::now()), None);
+    }
+
+    #[test]
+    fn manifests_round_trip_and_their_ids_follow_the_file_entries() {
+        let mut files = BTreeMap::new();
+        for (path, hash) in [("/r/a", "01"), ("/r/b c", "02")] {
+            files.insert(
+                path.to_string(),
+                SyncRequest {
+                    path: path.to_string(),
+                    hash: hash.to_string(),
+                    ..SyncRequest::default()
+                },
+            );
+        }
+        let snapshot = Snapshot::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000), files);
+        let decoded = Snapshot::decode(&snapshot.encode()).expect("manifest should decode");
+        assert_eq!(decoded, snapshot);
+        assert_eq!(
+            Snapshot::new(SystemTime::now(), snapshot.files.clone()).id,
+            snapshot.id
+        );
+
+        let tampered = snapshot.encode().replace("hash=02", "hash=03");
+        assert_ne!(
+            Snapshot::decode(&tampered).expect("still decodes").id,
+            snapshot.id
+        );
+        assert!(Snapshot::decode("path=/r/a\nhash=01\n").is_err());
+    }
+
+    #[test]
+    fn http_client_pages_snapshot_listings_and_verifies_fetched_manifests() {
+        let snapshot = Snapshot::new(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            BTreeMap::from([(
+                "/r/a".to_string(),
+                SyncRequest {
+                    path: "/r/a".to_string(),
+                    hash: "01".to_string(),
+                    ..SyncRequest::default()
+                },
+            )]),
+        );
+        let manifest = snapshot.encode();
+        let id = snapshot.id.clone();
+        let (base_url, handle) = serve_scripted(5, move |request| match request.path.as_str() {
+            "/v1/snapshots" => {
+                MockResponse::text(200, &format!("snapshot={id}:1700000000:1\nnext=p2\n"))
+            }
+            "/v1/snapshots?cursor=p2" => {
+                MockResponse::text(200, "snapshot=ffff0000:1700000100:0\nnext=\n")
+            }
+            path if path.starts_with("/v1/snapshots/") && request.method == "PUT" => {
+                MockResponse::text(201, "")
+            }
+            _ => MockResponse::text(200, &manifest),
+        });
+
+        let mut transport = crate::HttpTransport::new(&base_url).expect("client should build");
+        transport
+            .put_snapshot(&snapshot)
+            .expect("manifest should upload");
+        let listed = transport.list_snapshots().expect("listing should page");
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0], snapshot.summary());
+        assert_eq!(
+            transport.fetch_snapshot(&snapshot.id).expect("manifest"),
+            snapshot
+        );
+        assert!(matches!(
+            transport.fetch_snapshot("ffff0000"),
+            Err(SyncError::Protocol(_))
+        ));
+
+        let requests = handle.join().expect("mock server should finish");
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(requests[0].path, format!("/v1/snapshots/{}", snapshot.id));
+        assert_eq!(requests[0].body_text(), snapshot.encode());
+    }
+
+    #[test]
+    fn selects_snapshots_by_id_prefix_or_time() {
+        let at = |seconds: u64| UNIX_EPOCH + Duration::from_secs(seconds);
+        let day = 86_400;
+        let summaries = vec![
+            SnapshotSummary {
+                id: "aaaa1111".to_string(),
+                created: at(19_000 * day),
+                files: 1,
+            },
+            SnapshotSummary {
+                id: "aaaa2222".to_string(),
+                created: at(19_000 * day + 3600),
+                files: 2,
+            },
+            SnapshotSummary {
+                id: "bbbb3333".to_string(),
+                created: at(19_001 * day),
+                files: 3,
+            },
+        ];
+        let pick =
+            |spec: &str| Snapshot::select(&summaries, spec).map(|summary| summary.id.as_str());
+
+        assert_eq!(pick("bbbb3333"), Some("bbbb3333"));
+        assert_eq!(pick("bbbb"), Some("bbbb3333"));
+        assert_eq!(pick("aaaa"), None, "ambiguous prefix, and not a date");
+        assert_eq!(format_utc(at(19_000 * day + 3600)), "2022-01-08T01:00:00Z");
+        assert_eq!(pick("2022-01-08T00:30"), Some("aaaa1111"));
+        assert_eq!(pick("2022-01-08 23:59:59Z"), Some("aaaa2222"));
+        assert_eq!(pick("2022-01-07"), None);
+        assert_eq!(pick(&(19_001 * day).to_string()), Some("bbbb3333"));
+        for bad in ["2022-02-30", "2022-13-01", "2022-1-08", "2022-01-08T24:00"] {
+            assert_eq!(parse_utc(bad), None, "{bad} should be rejected");
+        }
+        assert_eq!(parse_utc("2024-02-29T12:00:00Z"), Some(at(1_709_208_000)));
+    }
+}
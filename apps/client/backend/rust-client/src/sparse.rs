@@ -0,0 +1,274 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::{hash_file_streaming, metadata, SyncError};
+
+/// A run of allocated bytes inside a sparse file. Everything outside the extents reads as zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Logical size plus the allocated ranges of a sparse file. Only the extents are hashed
+/// and transferred; a restore recreates the holes with [`write_sparse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseLayout {
+    pub size: u64,
+    pub extents: Vec<Extent>,
+}
+
+impl SparseLayout {
+    /// Sum of the extent lengths, i.e. the bytes that actually need to move.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.length).sum()
+    }
+
+    pub(crate) fn encode_lines(&self, out: &mut String) {
+        let extents = self
+            .extents
+            .iter()
+            .map(|extent| format!("{}+{}", extent.offset, extent.length))
+            .collect::<Vec<_>>()
+            .join(",");
+        out.push_str(&format!("size={}\nextents={extents}\n", self.size));
+    }
+
+    pub(crate) fn decode(size: &str, extents: &str) -> Result<Self, SyncError> {
+        let invalid = || SyncError::Protocol(format!("invalid sparse layout: {extents}"));
+        let size = size
+            .parse::<u64>()
+            .map_err(|_| SyncError::Protocol(format!("invalid sparse size: {size}")))?;
+        let extents = extents
+            .split(',')
+            .filter(|extent| !extent.is_empty())
+            .map(|extent| {
+                let (offset, length) = extent.split_once('+').ok_or_else(invalid)?;
+                Ok(Extent {
+                    offset: offset.parse().map_err(|_| invalid())?,
+                    length: length.parse().map_err(|_| invalid())?,
+                })
+            })
+            .collect::<Result<Vec<_>, SyncError>>()?;
+        Ok(Self { size, extents })
+    }
+}
+
+/// Hashes a file, reading only its allocated ranges when it is sparse. Dense files hash
+/// exactly as [`hash_file_streaming`] does, so their digests stay comparable.
+pub(crate) fn hash_allocated(
+    file_path: &Path,
+) -> Result<(String, Option<SparseLayout>), SyncError> {
+    let Some(layout) = sparse_layout(file_path).map_err(SyncError::Io)? else {
+        return Ok((hash_file_streaming(file_path)?, None));
+    };
+
+    Ok((hash_extents(file_path, &layout)?, Some(layout)))
+}
+
+/// Hashes the ranges `layout` names in `file_path`, as recorded for sparse files.
+pub(crate) fn hash_extents(file_path: &Path, layout: &SparseLayout) -> Result<String, SyncError> {
+    let mut file = File::open(file_path).map_err(SyncError::Io)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0_u8; 8192];
+
+    hasher.update(layout.size.to_le_bytes());
+    for extent in &layout.extents {
+        hasher.update(extent.offset.to_le_bytes());
+        hasher.update(extent.length.to_le_bytes());
+        file.seek(SeekFrom::Start(extent.offset))
+            .map_err(SyncError::Io)?;
+        let mut reader = (&mut file).take(extent.length);
+        loop {
+            let bytes_read = reader.read(&mut buffer).map_err(SyncError::Io)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+    }
+
+    Ok(metadata::to_hex(&hasher.finalize()))
+}
+
+/// Returns the data extents of a file whose allocated blocks cover less than its size,
+/// or `None` for dense files and platforms without hole detection.
+pub fn sparse_layout(file_path: &Path) -> io::Result<Option<SparseLayout>> {
+    let metadata = fs::metadata(file_path)?;
+    if !looks_sparse(&metadata) {
+        return Ok(None);
+    }
+    let file = File::open(file_path)?;
+    Ok(
+        data_extents(&file, metadata.len())?.map(|extents| SparseLayout {
+            size: metadata.len(),
+            extents,
+        }),
+    )
+}
+
+/// Writes `data` (the concatenated extent contents) into `dest`, seeking over the holes
+/// so the filesystem leaves them unallocated.
+pub fn write_sparse(dest: &Path, layout: &SparseLayout, data: &mut impl Read) -> io::Result<()> {
+    let mut file = File::create(dest)?;
+    for extent in &layout.extents {
+        file.seek(SeekFrom::Start(extent.offset))?;
+        let copied = io::copy(&mut data.take(extent.length), &mut file)?;
+        if copied != extent.length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sparse data ended before the last extent",
+            ));
+        }
+    }
+    file.set_len(layout.size)?;
+    file.flush()
+}
+
+#[cfg(unix)]
+fn looks_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.is_file() && metadata.blocks() * 512 < metadata.size()
+}
+
+#[cfg(not(unix))]
+fn looks_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+))]
+fn data_extents(file: &File, size: u64) -> io::Result<Option<Vec<Extent>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut position = 0_i64;
+
+    while (position as u64) < size {
+        let data = unsafe { libc::lseek(fd, position as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data past `position`: the rest of the file is a hole.
+                Some(libc::ENXIO) => Ok(Some(extents)),
+                // The filesystem cannot report holes; fall back to a dense read.
+                Some(libc::EINVAL) | Some(libc::ENOTSUP) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        extents.push(Extent {
+            offset: data as u64,
+            length: (hole - data) as u64,
+        });
+        position = hole as i64;
+    }
+
+    Ok(Some(extents))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+)))]
+fn data_extents(_file: &File, _size: u64) -> io::Result<Option<Vec<Extent>>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn layout_round_trips_through_payload_lines() {
+        let layout = SparseLayout {
+            size: 10 << 20,
+            extents: vec![
+                Extent {
+                    offset: 0,
+                    length: 4096,
+                },
+                Extent {
+                    offset: 4 << 20,
+                    length: 8192,
+                },
+            ],
+        };
+
+        let mut lines = String::new();
+        layout.encode_lines(&mut lines);
+        assert_eq!(lines, "size=10485760\nextents=0+4096,4194304+8192\n");
+        assert_eq!(
+            SparseLayout::decode("10485760", "0+4096,4194304+8192").expect("layout should decode"),
+            layout
+        );
+        assert_eq!(layout.allocated_bytes(), 12288);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hashes_only_data_extents_and_restores_holes() {
+        let temp = temp_dir("sparse");
+
+        let disk_image = temp.join("vm.img");
+        {
+            let mut file = File::create(&disk_image).expect("disk image should be created");
+            file.set_len(64 << 20)
+                .expect("disk image should be extended");
+            file.seek(SeekFrom::Start(16 << 20))
+                .expect("seek should work");
+            file.write_all(&[7_u8; 4096])
+                .expect("data block should be written");
+        }
+
+        let Some(layout) = sparse_layout(&disk_image).expect("layout should be readable") else {
+            // The temp filesystem does not keep holes (e.g. some overlay setups).
+            return;
+        };
+        assert_eq!(layout.size, 64 << 20);
+        assert!(layout.allocated_bytes() < 1 << 20);
+
+        let (hash, hashed_layout) = hash_allocated(&disk_image).expect("sparse file should hash");
+        assert_eq!(hashed_layout.as_ref(), Some(&layout));
+        assert!(!hash.is_empty());
+
+        let mut source = File::open(&disk_image).expect("disk image should open");
+        let mut extent_data = Vec::new();
+        for extent in &layout.extents {
+            source
+                .seek(SeekFrom::Start(extent.offset))
+                .expect("seek should work");
+            (&mut source)
+                .take(extent.length)
+                .read_to_end(&mut extent_data)
+                .expect("extent should be readable");
+        }
+
+        let restored = temp.join("vm-restored.img");
+        write_sparse(&restored, &layout, &mut extent_data.as_slice())
+            .expect("sparse restore should succeed");
+
+        assert_eq!(
+            fs::read(&restored).expect("restored image should be readable"),
+            fs::read(&disk_image).expect("original image should be readable")
+        );
+        let restored_layout = sparse_layout(&restored)
+            .expect("restored layout should be readable")
+            .expect("restored image should still be sparse");
+        assert_eq!(restored_layout.allocated_bytes(), layout.allocated_bytes());
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sparse::{self, SparseLayout};
+use crate::SyncError;
+
+/// Point-in-time view of the file attributes that change whenever content is rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub ctime_ns: i64,
+    pub inode: u64,
+}
+
+impl FileStamp {
+    pub fn read(file_path: &Path) -> Result<Self, SyncError> {
+        let metadata = fs::metadata(file_path).map_err(SyncError::Io)?;
+        Ok(Self::from_metadata(&metadata))
+    }
+
+    #[cfg(unix)]
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            size: metadata.size(),
+            mtime_ns: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            ctime_ns: metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec(),
+            inode: metadata.ino(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        let mtime_ns = metadata.modified().map(system_time_ns).unwrap_or(0);
+        Self {
+            size: metadata.len(),
+            mtime_ns,
+            ctime_ns: metadata.created().map(system_time_ns).unwrap_or(mtime_ns),
+            inode: 0,
+        }
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        let now_ns = system_time_ns(now);
+        let newest_ns = self.mtime_ns.max(self.ctime_ns);
+        Duration::from_nanos(now_ns.saturating_sub(newest_ns).max(0) as u64)
+    }
+}
+
+/// Controls how long the client waits for a file to stop changing before hashing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StabilityPolicy {
+    /// Hash attempts made before a file is reported as unstable.
+    pub max_attempts: u32,
+    /// Pause between attempts when the file changed during a read.
+    pub retry_delay: Duration,
+    /// Files modified more recently than this are treated as still being written.
+    pub quiet_period: Duration,
+}
+
+impl Default for StabilityPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            retry_delay: Duration::from_millis(100),
+            quiet_period: Duration::ZERO,
+        }
+    }
+}
+
+pub(crate) struct StableHash {
+    pub hash: String,
+    pub stamp: FileStamp,
+    pub sparse: Option<SparseLayout>,
+}
+
+/// Hashes a file and proves nothing changed it mid-read by comparing stamps taken before and after.
+pub(crate) fn hash_file_stable(
+    file_path: &Path,
+    policy: &StabilityPolicy,
+) -> Result<StableHash, SyncError> {
+    let unstable = || SyncError::FileUnstable(file_path.display().to_string());
+
+    for attempt in 0..policy.max_attempts.max(1) {
+        if attempt > 0 {
+            thread::sleep(policy.retry_delay);
+        }
+
+        let before = FileStamp::read(file_path)?;
+        if before.age(SystemTime::now()) < policy.quiet_period {
+            return Err(unstable());
+        }
+
+        let (hash, sparse) = sparse::hash_allocated(file_path)?;
+        let after = FileStamp::read(file_path)?;
+        if before == after {
+            return Ok(StableHash {
+                hash,
+                stamp: after,
+                sparse,
+            });
+        }
+    }
+
+    Err(unstable())
+}
+
+pub(crate) fn system_time_ns(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_nanos() as i64,
+        Err(err) => -(err.duration().as_nanos() as i64),
+    }
+}
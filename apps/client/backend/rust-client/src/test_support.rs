@@ -0,0 +1,485 @@
+//! Helpers shared by module tests that need a temp directory, a multi-request mock server
+//! or an in-memory sync server.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::archive::{CHUNK_PREFIX, MANIFEST_ENTRY, MANIFEST_HEADER};
+use crate::metadata::to_hex;
+use crate::restore::under_prefix;
+use crate::{
+    ArchivePart, ChangePage, ContentOptions, JobState, RemoteChange, RemoteChangeKind, RestoreJob,
+    RestoreJobRequest, Snapshot, SnapshotSummary, SyncError, SyncManager, SyncRequest,
+    SyncTransport, VersionVector,
+};
+
+pub(crate) fn temp_dir(label: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock should be after epoch")
+        .as_nanos();
+
+    let path = std::env::temp_dir().join(format!("rust-client-{label}-{nanos}"));
+    fs::create_dir_all(&path).expect("temp test dir should be created");
+    path
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CapturedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl CapturedRequest {
+    pub fn body_text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn text(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Serves `connections` requests, answering each through `respond`, and returns every
+/// request it saw once the last one is handled.
+pub(crate) fn serve_scripted(
+    connections: usize,
+    mut respond: impl FnMut(&CapturedRequest) -> MockResponse + Send + 'static,
+) -> (String, thread::JoinHandle<Vec<CapturedRequest>>) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("mock server should bind on a random port");
+    let address = listener
+        .local_addr()
+        .expect("local addr should be available");
+
+    let handle = thread::spawn(move || {
+        let mut seen = Vec::new();
+        for _ in 0..connections {
+            let (mut stream, _) = listener.accept().expect("mock server should accept");
+            let request = read_request(&mut stream);
+            let response = respond(&request);
+            let mut head = format!(
+                "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n",
+                response.status,
+                response.body.len()
+            );
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+            head.push_str("\r\n");
+            stream
+                .write_all(head.as_bytes())
+                .and_then(|_| stream.write_all(&response.body))
+                .expect("mock server should write response");
+            seen.push(request);
+        }
+        seen
+    });
+
+    (format!("http://{address}"), handle)
+}
+
+fn read_request(stream: &mut TcpStream) -> CapturedRequest {
+    let mut buffer = Vec::new();
+    let mut chunk = [0_u8; 4096];
+    let marker = b"\r\n\r\n";
+
+    let header_end = loop {
+        let read = stream
+            .read(&mut chunk)
+            .expect("mock server should read request");
+        assert!(
+            read > 0,
+            "client closed the connection before sending headers"
+        );
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(position) = buffer
+            .windows(marker.len())
+            .position(|window| window == marker)
+        {
+            break position;
+        }
+    };
+
+    let headers = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let content_length = headers
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("Content-Length")
+                .then(|| value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or(0);
+
+    let body_start = header_end + marker.len();
+    while buffer.len() < body_start + content_length {
+        let read = stream
+            .read(&mut chunk)
+            .expect("mock server should read body");
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let request_line = headers.lines().next().unwrap_or_default().to_string();
+    let mut parts = request_line.split_whitespace();
+    CapturedRequest {
+        method: parts.next().unwrap_or_default().to_string(),
+        path: parts.next().unwrap_or_default().to_string(),
+        headers: headers
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+            .collect(),
+        body: buffer[body_start..].to_vec(),
+    }
+}
+
+/// Stores `content` on `remote` under `path` as if another device had sent it with
+/// `version` (`<device>:<count>,...`).
+pub(crate) fn foreign_edit(remote: &MemoryRemote, path: &Path, content: &str, version: &str) {
+    let scratch = temp_dir("foreign-edit");
+    let file = scratch.join("file");
+    fs::write(&file, content).expect("scratch file should be written");
+    let mut manager =
+        SyncManager::new(remote.clone()).with_content_upload(ContentOptions::default());
+    manager
+        .queue_file(&file)
+        .expect("scratch file should queue");
+    assert_eq!(manager.flush_once().failed, 0);
+    let mut record = remote
+        .state()
+        .files
+        .remove(file.to_string_lossy().as_ref())
+        .expect("scratch record should exist");
+    record.path = path.to_string_lossy().to_string();
+    record.version = Some(VersionVector::decode(version).expect("version should parse"));
+    remote
+        .clone()
+        .sync_file(&record)
+        .expect("record should sync");
+    let _ = fs::remove_dir_all(scratch);
+}
+
+/// In-memory sync server that keeps the latest record per path and every chunk, so tests
+/// can upload with one handle and restore with a clone of it.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryRemote {
+    state: Arc<Mutex<RemoteState>>,
+}
+
+#[derive(Default)]
+pub(crate) struct RemoteState {
+    pub files: BTreeMap<String, SyncRequest>,
+    pub chunks: HashMap<String, Vec<u8>>,
+    /// Snapshot manifests in the order they were committed.
+    pub snapshots: Vec<Snapshot>,
+    pub downloads: usize,
+    /// Total chunk downloads allowed before every further one fails.
+    pub fail_downloads_after: Option<usize>,
+    /// Restore jobs by id, each holding its finished archive parts.
+    pub jobs: Vec<MemoryJob>,
+    /// How many status polls a new job answers `building` before it is ready.
+    pub job_polls: usize,
+    /// Archive parts served so far.
+    pub part_downloads: usize,
+    /// A part index served with one byte flipped, to exercise hash checks.
+    pub corrupt_part: Option<usize>,
+    /// The change feed; `sync_file` appends to it and the cursor is an index into it.
+    pub changes: Vec<RemoteChange>,
+    /// Changes per feed page; zero serves everything at once.
+    pub change_page: usize,
+}
+
+pub(crate) struct MemoryJob {
+    pub id: String,
+    pub polls_left: usize,
+    pub parts: Vec<Vec<u8>>,
+}
+
+impl MemoryRemote {
+    pub fn state(&self) -> MutexGuard<'_, RemoteState> {
+        self.state
+            .lock()
+            .expect("remote state lock should not be poisoned")
+    }
+}
+
+impl SyncTransport for MemoryRemote {
+    fn health_check(&mut self) -> Result<bool, SyncError> {
+        Ok(true)
+    }
+
+    fn sync_file(&mut self, req: &SyncRequest) -> Result<(), SyncError> {
+        let mut state = self.state();
+        state.files.insert(req.path.clone(), req.clone());
+        state.changes.push(RemoteChange {
+            kind: RemoteChangeKind::Updated,
+            path: req.path.clone(),
+            hash: Some(req.hash.clone()),
+            device: None,
+            time: UNIX_EPOCH,
+        });
+        Ok(())
+    }
+
+    fn upload_chunk(&mut self, chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+        self.state()
+            .chunks
+            .insert(chunk_id.to_string(), body.to_vec());
+        Ok(())
+    }
+
+    fn list_files(&mut self, prefix: &str) -> Result<Vec<String>, SyncError> {
+        Ok(self
+            .state()
+            .files
+            .keys()
+            .filter(|path| under_prefix(path, prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn fetch_file(&mut self, path: &str) -> Result<SyncRequest, SyncError> {
+        self.state()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| SyncError::Server(404, "not found".to_string()))
+    }
+
+    fn download_chunk(&mut self, chunk_id: &str) -> Result<Vec<u8>, SyncError> {
+        let mut state = self.state();
+        if state
+            .fail_downloads_after
+            .is_some_and(|limit| state.downloads >= limit)
+        {
+            return Err(SyncError::Connection(std::io::Error::other(
+                "connection reset",
+            )));
+        }
+        state.downloads += 1;
+        state
+            .chunks
+            .get(chunk_id)
+            .cloned()
+            .ok_or_else(|| SyncError::Server(404, "not found".to_string()))
+    }
+
+    fn put_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), SyncError> {
+        let mut state = self.state();
+        state
+            .snapshots
+            .retain(|existing| existing.id != snapshot.id);
+        state.snapshots.push(snapshot.clone());
+        Ok(())
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<SnapshotSummary>, SyncError> {
+        Ok(self
+            .state()
+            .snapshots
+            .iter()
+            .map(Snapshot::summary)
+            .collect())
+    }
+
+    fn fetch_snapshot(&mut self, id: &str) -> Result<Snapshot, SyncError> {
+        self.state()
+            .snapshots
+            .iter()
+            .find(|snapshot| snapshot.id == id)
+            .cloned()
+            .ok_or_else(|| SyncError::Server(404, "not found".to_string()))
+    }
+
+    /// Builds the archive straight away: the manifest, then every chunk it names once, cut
+    /// into `part_size` pieces.
+    fn changes(&mut self, cursor: Option<&str>) -> Result<ChangePage, SyncError> {
+        let state = self.state();
+        let start = match cursor {
+            Some(cursor) => cursor
+                .parse::<usize>()
+                .map_err(|_| SyncError::Server(400, "bad cursor".to_string()))?,
+            None => 0,
+        };
+        let start = start.min(state.changes.len());
+        let end = match state.change_page {
+            0 => state.changes.len(),
+            size => (start + size).min(state.changes.len()),
+        };
+        Ok(ChangePage {
+            changes: state.changes[start..end].to_vec(),
+            cursor: end.to_string(),
+            more: end < state.changes.len(),
+        })
+    }
+
+    fn create_restore_job(&mut self, request: &RestoreJobRequest) -> Result<String, SyncError> {
+        let mut state = self.state();
+        let files = match &request.snapshot {
+            Some(id) => {
+                let snapshot = state.snapshots.iter().find(|snapshot| &snapshot.id == id);
+                snapshot
+                    .ok_or_else(|| SyncError::Server(404, "not found".to_string()))?
+                    .files
+                    .clone()
+            }
+            None => state.files.clone(),
+        };
+        let records = files
+            .into_values()
+            .filter(|record| under_prefix(&record.path, &request.prefix))
+            .collect::<Vec<_>>();
+
+        let manifest = encode_manifest(&records);
+        let mut entries = vec![(MANIFEST_ENTRY.to_string(), manifest.into_bytes())];
+        for chunk in records.iter().flat_map(|record| &record.chunks) {
+            let name = format!("{CHUNK_PREFIX}{}", chunk.id);
+            if entries.iter().all(|(existing, _)| *existing != name) {
+                let body = state.chunks.get(&chunk.id).cloned().unwrap_or_default();
+                entries.push((name, body));
+            }
+        }
+        let stream = tar_archive(&entries);
+        let id = format!("job-{}", state.jobs.len() + 1);
+        let polls_left = state.job_polls;
+        state.jobs.push(MemoryJob {
+            id: id.clone(),
+            polls_left,
+            parts: stream
+                .chunks(request.part_size as usize)
+                .map(<[u8]>::to_vec)
+                .collect(),
+        });
+        Ok(id)
+    }
+
+    fn restore_job(&mut self, id: &str) -> Result<RestoreJob, SyncError> {
+        let mut state = self.state();
+        let job = state
+            .jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or_else(|| SyncError::Server(404, "not found".to_string()))?;
+        if job.polls_left > 0 {
+            job.polls_left -= 1;
+            return Ok(RestoreJob {
+                id: id.to_string(),
+                state: JobState::Building,
+                parts: Vec::new(),
+            });
+        }
+        let parts = job
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(index, bytes)| ArchivePart {
+                index,
+                size: bytes.len() as u64,
+                sha256: to_hex(&Sha256::digest(bytes)),
+            })
+            .collect();
+        Ok(RestoreJob {
+            id: id.to_string(),
+            state: JobState::Ready,
+            parts,
+        })
+    }
+
+    fn download_archive_part(
+        &mut self,
+        id: &str,
+        index: usize,
+        out: &mut dyn Write,
+    ) -> Result<u64, SyncError> {
+        let mut state = self.state();
+        state.part_downloads += 1;
+        let corrupt = state.corrupt_part == Some(index);
+        let mut bytes = state
+            .jobs
+            .iter()
+            .find(|job| job.id == id)
+            .and_then(|job| job.parts.get(index))
+            .cloned()
+            .ok_or_else(|| SyncError::Server(404, "not found".to_string()))?;
+        if corrupt {
+            bytes[0] ^= 0xff;
+        }
+        out.write_all(&bytes).map_err(SyncError::Io)?;
+        Ok(bytes.len() as u64)
+    }
+}
+
+/// An archive manifest as the sync server writes it.
+pub(crate) fn encode_manifest<'a>(records: impl IntoIterator<Item = &'a SyncRequest>) -> String {
+    let mut text = format!("{MANIFEST_HEADER}\n");
+    for record in records {
+        text.push('\n');
+        text.push_str(&record.encode_body());
+    }
+    text
+}
+
+/// A ustar stream of regular files, ending with the two zero blocks.
+pub(crate) fn tar_archive(entries: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for (name, body) in entries {
+        tar_entry(&mut stream, name, b'0', body);
+    }
+    stream.resize(stream.len() + 1024, 0);
+    stream
+}
+
+/// Appends one ustar entry of type `kind`, padded to a block boundary. Names longer than
+/// the 100-byte header field are cut off, as they are behind a PAX or GNU long name.
+pub(crate) fn tar_entry(stream: &mut Vec<u8>, name: &str, kind: u8, body: &[u8]) {
+    let mut header = [0_u8; 512];
+    let name = &name.as_bytes()[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    header[100..107].copy_from_slice(b"0000644");
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", body.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+    stream.extend_from_slice(&header);
+    stream.extend_from_slice(body);
+    stream.resize(stream.len().next_multiple_of(512), 0);
+}
@@ -0,0 +1,447 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    ChangePage, RestoreJob, RestoreJobRequest, Snapshot, SnapshotSummary, SyncError, SyncRequest,
+    SyncTransport,
+};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// One time-of-day window with its own limit. `end` before `start` wraps past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleRule {
+    pub start_minute: u32,
+    pub end_minute: u32,
+    /// Bytes per second, or `None` for unlimited.
+    pub limit: Option<u64>,
+}
+
+impl ScheduleRule {
+    /// Parses `HH:MM-HH:MM <rate>`, e.g. `01:00-06:00 unlimited` or `09:00-17:00 512KiB/s`.
+    pub fn parse(text: &str) -> Result<Self, SyncError> {
+        let invalid = || {
+            SyncError::InvalidConfig(format!(
+                "schedule rule `{text}` should look like `01:00-06:00 1MB/s`"
+            ))
+        };
+        let (window, rate) = text
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            start_minute: parse_time_of_day(start).ok_or_else(invalid)?,
+            end_minute: parse_time_of_day(end).ok_or_else(invalid)?,
+            limit: parse_rate(rate)?,
+        })
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        if self.start_minute <= self.end_minute {
+            (self.start_minute..self.end_minute).contains(&minute)
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+impl fmt::Display for ScheduleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02} {}",
+            self.start_minute / 60,
+            self.start_minute % 60,
+            self.end_minute / 60,
+            self.end_minute % 60,
+            format_rate(self.limit)
+        )
+    }
+}
+
+/// Upload limit by time of day: the first rule covering the current local time wins,
+/// otherwise `default_limit` applies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    pub default_limit: Option<u64>,
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl BandwidthSchedule {
+    pub fn fixed(limit: Option<u64>) -> Self {
+        Self {
+            default_limit: limit,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn with_rule(mut self, rule: ScheduleRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn limit_at(&self, minute_of_day: u32) -> Option<u64> {
+        self.rules
+            .iter()
+            .find(|rule| rule.contains(minute_of_day % MINUTES_PER_DAY))
+            .map_or(self.default_limit, |rule| rule.limit)
+    }
+}
+
+/// Parses a rate such as `1MB/s`, `512KiB/s`, `250000` (bytes/sec) or `unlimited`.
+pub fn parse_rate(text: &str) -> Result<Option<u64>, SyncError> {
+    let trimmed = text.trim();
+    if trimmed.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    let invalid = || SyncError::InvalidConfig(format!("invalid rate `{trimmed}`"));
+    let without_suffix = trimmed.strip_suffix("/s").unwrap_or(trimmed);
+    let split = without_suffix
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(without_suffix.len());
+    let (number, unit) = without_suffix.split_at(split);
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "kib" => 1 << 10,
+        "mb" => 1_000_000,
+        "mib" => 1 << 20,
+        "gb" => 1_000_000_000,
+        "gib" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    let rate = number.parse::<u64>().map_err(|_| invalid())?;
+    if rate == 0 {
+        return Err(SyncError::InvalidConfig(
+            "a rate of 0 would stall uploads; pause syncing instead".to_string(),
+        ));
+    }
+    rate.checked_mul(multiplier).map(Some).ok_or_else(invalid)
+}
+
+/// Renders a rate so that [`parse_rate`] reads it back: `unlimited` or bytes per second.
+pub fn format_rate(limit: Option<u64>) -> String {
+    limit.map_or_else(|| "unlimited".to_string(), |rate| format!("{rate}B/s"))
+}
+
+fn parse_time_of_day(text: &str) -> Option<u32> {
+    let (hours, minutes) = text.split_once(':')?;
+    let hours = hours.parse::<u32>().ok()?;
+    let minutes = minutes.parse::<u32>().ok()?;
+    // 24:00 is allowed as the end of a window.
+    (hours < 24 && minutes < 60 || hours == 24 && minutes == 0).then_some(hours * 60 + minutes)
+}
+
+#[derive(Debug)]
+struct BucketState {
+    schedule: BandwidthSchedule,
+    rate: Option<u64>,
+    /// May go negative: a large chunk is let through and later sends wait off the debt.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token-bucket upload limiter. Clones share one bucket, so a handle kept by the daemon or
+/// control socket can change the limit while a flush is running.
+#[derive(Debug, Clone)]
+pub struct BandwidthLimiter {
+    state: Arc<Mutex<BucketState>>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BucketState {
+                schedule,
+                rate: None,
+                tokens: 0.0,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(BandwidthSchedule::default())
+    }
+
+    /// Replaces the schedule; the next send picks up the new limit.
+    pub fn set_schedule(&self, schedule: BandwidthSchedule) {
+        self.lock().schedule = schedule;
+    }
+
+    /// Shorthand for a schedule with a single limit at all hours.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.set_schedule(BandwidthSchedule::fixed(limit));
+    }
+
+    pub fn schedule(&self) -> BandwidthSchedule {
+        self.lock().schedule.clone()
+    }
+
+    /// Limit in effect right now, in bytes per second.
+    pub fn current_limit(&self) -> Option<u64> {
+        self.lock()
+            .schedule
+            .limit_at(local_minute_of_day(SystemTime::now()))
+    }
+
+    /// Blocks until `bytes` may be sent under the current limit.
+    pub fn acquire(&self, bytes: u64) {
+        let wait = self.reserve(
+            bytes,
+            Instant::now(),
+            local_minute_of_day(SystemTime::now()),
+        );
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Takes `bytes` from the bucket and returns how long the caller must wait first.
+    fn reserve(&self, bytes: u64, now: Instant, minute_of_day: u32) -> Duration {
+        let mut state = self.lock();
+        let rate = state.schedule.limit_at(minute_of_day);
+        if rate != state.rate {
+            // A new limit starts with a full one-second burst rather than old debt.
+            state.rate = rate;
+            state.tokens = rate.unwrap_or(0) as f64;
+            state.refilled_at = now;
+        }
+        let Some(rate) = rate else {
+            return Duration::ZERO;
+        };
+
+        let capacity = rate as f64;
+        let elapsed = now
+            .saturating_duration_since(state.refilled_at)
+            .as_secs_f64();
+        state.tokens = (state.tokens + elapsed * capacity).min(capacity);
+        state.refilled_at = now;
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / capacity)
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BucketState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Transport wrapper that paces every request body and chunk through a [`BandwidthLimiter`].
+pub struct ThrottledTransport<T: SyncTransport> {
+    inner: T,
+    limiter: BandwidthLimiter,
+}
+
+impl<T: SyncTransport> ThrottledTransport<T> {
+    pub fn new(inner: T, limiter: BandwidthLimiter) -> Self {
+        Self { inner, limiter }
+    }
+
+    pub fn limiter(&self) -> &BandwidthLimiter {
+        &self.limiter
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: SyncTransport> SyncTransport for ThrottledTransport<T> {
+    fn health_check(&mut self) -> Result<bool, SyncError> {
+        self.inner.health_check()
+    }
+
+    fn sync_file(&mut self, req: &SyncRequest) -> Result<(), SyncError> {
+        self.limiter.acquire(req.encode_body().len() as u64);
+        self.inner.sync_file(req)
+    }
+
+    fn upload_chunk(&mut self, chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+        self.limiter.acquire(body.len() as u64);
+        self.inner.upload_chunk(chunk_id, body)
+    }
+
+    // Downloads are not throttled; the limiter only paces uploads.
+    fn list_files(&mut self, prefix: &str) -> Result<Vec<String>, SyncError> {
+        self.inner.list_files(prefix)
+    }
+
+    fn fetch_file(&mut self, path: &str) -> Result<SyncRequest, SyncError> {
+        self.inner.fetch_file(path)
+    }
+
+    fn download_chunk(&mut self, chunk_id: &str) -> Result<Vec<u8>, SyncError> {
+        self.inner.download_chunk(chunk_id)
+    }
+
+    fn put_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), SyncError> {
+        self.limiter.acquire(snapshot.encode().len() as u64);
+        self.inner.put_snapshot(snapshot)
+    }
+
+    fn list_snapshots(&mut self) -> Result<Vec<SnapshotSummary>, SyncError> {
+        self.inner.list_snapshots()
+    }
+
+    fn fetch_snapshot(&mut self, id: &str) -> Result<Snapshot, SyncError> {
+        self.inner.fetch_snapshot(id)
+    }
+
+    fn changes(&mut self, cursor: Option<&str>) -> Result<ChangePage, SyncError> {
+        self.inner.changes(cursor)
+    }
+
+    fn create_restore_job(&mut self, request: &RestoreJobRequest) -> Result<String, SyncError> {
+        self.inner.create_restore_job(request)
+    }
+
+    fn restore_job(&mut self, id: &str) -> Result<RestoreJob, SyncError> {
+        self.inner.restore_job(id)
+    }
+
+    fn download_archive_part(
+        &mut self,
+        id: &str,
+        index: usize,
+        out: &mut dyn Write,
+    ) -> Result<u64, SyncError> {
+        self.inner.download_archive_part(id, index, out)
+    }
+}
+
+/// Minutes since local midnight. Falls back to UTC where the local zone is unavailable.
+fn local_minute_of_day(now: SystemTime) -> u32 {
+    let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    #[cfg(unix)]
+    {
+        let time = seconds as libc::time_t;
+        // SAFETY: `tm` is plain data that `localtime_r` fills in; both pointers are valid.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if !unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return tm.tm_hour as u32 * 60 + tm.tm_min as u32;
+        }
+    }
+
+    ((seconds / 60) % u64::from(MINUTES_PER_DAY)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates_and_schedule_rules() {
+        assert_eq!(parse_rate("1MB/s").expect("rate"), Some(1_000_000));
+        assert_eq!(parse_rate("512KiB/s").expect("rate"), Some(512 * 1024));
+        assert_eq!(parse_rate("250000").expect("rate"), Some(250_000));
+        assert_eq!(parse_rate("unlimited").expect("rate"), None);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+
+        let rule = ScheduleRule::parse("01:00-06:00 unlimited").expect("rule should parse");
+        assert_eq!(
+            (rule.start_minute, rule.end_minute, rule.limit),
+            (60, 360, None)
+        );
+        assert!(ScheduleRule::parse("25:00-06:00 1MB/s").is_err());
+        assert!(ScheduleRule::parse("01:00 1MB/s").is_err());
+
+        let rule = ScheduleRule::parse("22:30-02:00 512KiB/s").expect("rule should parse");
+        assert_eq!(rule.to_string(), "22:30-02:00 524288B/s");
+        assert_eq!(
+            ScheduleRule::parse(&rule.to_string()).expect("rule should reparse"),
+            rule
+        );
+    }
+
+    #[test]
+    fn first_matching_window_wins_including_overnight_windows() {
+        let schedule = BandwidthSchedule::fixed(Some(1_000_000))
+            .with_rule(ScheduleRule::parse("01:00-06:00 unlimited").expect("rule"))
+            .with_rule(ScheduleRule::parse("22:00-02:00 4MB/s").expect("rule"));
+
+        assert_eq!(schedule.limit_at(12 * 60), Some(1_000_000));
+        assert_eq!(schedule.limit_at(3 * 60), None);
+        assert_eq!(schedule.limit_at(23 * 60), Some(4_000_000));
+        assert_eq!(schedule.limit_at(60 + 30), None);
+        assert_eq!(schedule.limit_at(30), Some(4_000_000));
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_paces_to_the_rate() {
+        let limiter = BandwidthLimiter::new(BandwidthSchedule::fixed(Some(1000)));
+        let start = Instant::now();
+
+        assert_eq!(limiter.reserve(1000, start, 0), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, start, 0), Duration::from_millis(500));
+        // Half a second later the debt is paid; the next 250 bytes take another quarter.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.reserve(250, later, 0), Duration::from_millis(250));
+    }
+
+    #[derive(Default)]
+    struct CountingTransport {
+        chunk_bytes: usize,
+    }
+
+    impl SyncTransport for CountingTransport {
+        fn health_check(&mut self) -> Result<bool, SyncError> {
+            Ok(true)
+        }
+
+        fn sync_file(&mut self, _req: &SyncRequest) -> Result<(), SyncError> {
+            Ok(())
+        }
+
+        fn upload_chunk(&mut self, _chunk_id: &str, body: &[u8]) -> Result<(), SyncError> {
+            self.chunk_bytes += body.len();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn throttled_transport_waits_once_the_burst_is_spent() {
+        let limiter = BandwidthLimiter::new(BandwidthSchedule::fixed(Some(20_000)));
+        let mut transport = ThrottledTransport::new(CountingTransport::default(), limiter);
+        let chunk = vec![0_u8; 10_000];
+
+        let started = Instant::now();
+        for index in 0..3 {
+            transport
+                .upload_chunk(&index.to_string(), &chunk)
+                .expect("upload should pass through");
+        }
+
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert_eq!(transport.into_inner().chunk_bytes, 30_000);
+    }
+
+    #[test]
+    fn limit_changes_take_effect_on_the_next_send() {
+        let limiter = BandwidthLimiter::new(BandwidthSchedule::fixed(Some(100)));
+        let shared = limiter.clone();
+        let now = Instant::now();
+        assert!(limiter.reserve(10_000, now, 0) > Duration::from_secs(90));
+
+        shared.set_limit(None);
+        assert_eq!(limiter.reserve(10_000, now, 0), Duration::ZERO);
+
+        shared.set_schedule(
+            BandwidthSchedule::fixed(None)
+                .with_rule(ScheduleRule::parse("09:00-17:00 1KB/s").expect("rule")),
+        );
+        assert_eq!(limiter.reserve(10_000, now, 8 * 60), Duration::ZERO);
+        assert_eq!(limiter.reserve(2000, now, 10 * 60), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::path_cipher;
+use crate::restore::plaintext_hash;
+use crate::watcher::walk;
+use crate::{IgnoreRules, MasterKey, PathCipher, Restorer, SyncError, SyncRequest, SyncTransport};
+
+/// How a path on one side compares with the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyStatus {
+    /// The server holds the file's current content.
+    InSync,
+    /// The file exists locally and the server has no record of it.
+    MissingRemotely,
+    /// The server's record is for different content than the file now holds.
+    StaleRemotely,
+    /// The server holds a record for a path that no longer exists locally.
+    RemoteOnly,
+}
+
+impl VerifyStatus {
+    pub const ALL: [VerifyStatus; 4] = [
+        VerifyStatus::InSync,
+        VerifyStatus::MissingRemotely,
+        VerifyStatus::StaleRemotely,
+        VerifyStatus::RemoteOnly,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerifyStatus::InSync => "in-sync",
+            VerifyStatus::MissingRemotely => "missing-remotely",
+            VerifyStatus::StaleRemotely => "stale-remotely",
+            VerifyStatus::RemoteOnly => "remote-only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyEntry {
+    /// The local path, or for remote-only entries the path the server record names.
+    pub path: String,
+    pub status: VerifyStatus,
+}
+
+/// The outcome of [`Verifier::verify`]. `entries` is sorted by path; `errors` holds files
+/// that could not be read or compared, and `sample_failures` sampled files whose download
+/// did not reproduce the recorded content.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub entries: Vec<VerifyEntry>,
+    pub sampled: usize,
+    pub sample_failures: Vec<(String, SyncError)>,
+    pub errors: Vec<(String, SyncError)>,
+}
+
+impl VerifyReport {
+    pub fn count(&self, status: VerifyStatus) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.status == status)
+            .count()
+    }
+
+    /// True when every file is in sync and everything checked came back intact.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+            && self.sample_failures.is_empty()
+            && self
+                .entries
+                .iter()
+                .all(|entry| entry.status == VerifyStatus::InSync)
+    }
+
+    /// Folds another root's report into this one.
+    pub fn merge(&mut self, other: VerifyReport) {
+        self.entries.extend(other.entries);
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.sampled += other.sampled;
+        self.sample_failures.extend(other.sample_failures);
+        self.errors.extend(other.errors);
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in sync, {} missing remotely, {} stale remotely, {} remote-only, {} sampled ({} failed), {} errors",
+            self.count(VerifyStatus::InSync),
+            self.count(VerifyStatus::MissingRemotely),
+            self.count(VerifyStatus::StaleRemotely),
+            self.count(VerifyStatus::RemoteOnly),
+            self.sampled,
+            self.sample_failures.len(),
+            self.errors.len()
+        )
+    }
+}
+
+/// Audits a local root against the records the server holds for it.
+///
+/// Every file is hashed the way it was when synced and compared with the server's record;
+/// records for paths that no longer exist are reported as remote-only. With a sample size
+/// set, that many in-sync files with uploaded content are also downloaded into a scratch
+/// directory and checked chunk by chunk and against their recorded hash, the same way a
+/// restore would, so a backup is proven readable and not only listed.
+pub struct Verifier<T: SyncTransport> {
+    restorer: Restorer<T>,
+    master: Option<MasterKey>,
+    path_ciphers: Vec<PathCipher>,
+    ignore: IgnoreRules,
+    sample: usize,
+    scratch: PathBuf,
+}
+
+impl<T: SyncTransport> Verifier<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            restorer: Restorer::new(transport),
+            master: None,
+            path_ciphers: Vec::new(),
+            ignore: IgnoreRules::default(),
+            sample: 0,
+            scratch: std::env::temp_dir(),
+        }
+    }
+
+    /// Needed to compare keyed content tags and to decrypt sampled content.
+    pub fn with_master_key(mut self, master: MasterKey) -> Self {
+        self.restorer = self.restorer.with_master_key(master.clone());
+        self.master = Some(master);
+        self
+    }
+
+    /// For roots synced with path encryption. Call once per encrypted root.
+    pub fn with_path_encryption(mut self, cipher: PathCipher) -> Self {
+        self.path_ciphers.push(cipher);
+        self
+    }
+
+    /// Leaves out the same files a sync of the root would.
+    pub fn with_ignore(mut self, ignore: IgnoreRules) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
+    /// Downloads `count` randomly chosen in-sync files to confirm their content.
+    pub fn with_sample(mut self, count: usize) -> Self {
+        self.sample = count;
+        self
+    }
+
+    /// Where sampled files are downloaded: each run makes its own private directory under
+    /// `directory` and removes only that afterwards.
+    pub fn with_scratch_dir<P: AsRef<Path>>(mut self, directory: P) -> Self {
+        self.scratch = directory.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn verify<P: AsRef<Path>>(&mut self, root: P) -> Result<VerifyReport, SyncError> {
+        let root = root.as_ref();
+        if !root.is_dir() {
+            return Err(SyncError::InvalidPath(root.display().to_string()));
+        }
+        let mut local = HashMap::new();
+        walk(root, root, &self.ignore, &mut local)?;
+
+        let prefix = self.server_path(root)?;
+        let mut remote = self
+            .restorer
+            .transport_mut()
+            .list_files(&prefix)?
+            .into_iter()
+            .map(|path| (path, ()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut report = VerifyReport::default();
+        let mut sampleable = Vec::new();
+        let mut files = local.into_keys().collect::<Vec<_>>();
+        files.sort();
+        for file in files {
+            let display = file.to_string_lossy().to_string();
+            let server_path = match self.server_path(&file) {
+                Ok(server_path) => server_path,
+                Err(err) => {
+                    report.errors.push((display, err));
+                    continue;
+                }
+            };
+            if remote.remove(&server_path).is_none() {
+                report.entries.push(VerifyEntry {
+                    path: display,
+                    status: VerifyStatus::MissingRemotely,
+                });
+                continue;
+            }
+            let compared = self
+                .restorer
+                .transport_mut()
+                .fetch_file(&server_path)
+                .and_then(|record| Ok((self.matches(&file, &record)?, record)));
+            match compared {
+                Ok((true, record)) => {
+                    if !record.chunks.is_empty() || record.link_to.is_some() {
+                        sampleable.push((display.clone(), record));
+                    }
+                    report.entries.push(VerifyEntry {
+                        path: display,
+                        status: VerifyStatus::InSync,
+                    });
+                }
+                Ok((false, _)) => report.entries.push(VerifyEntry {
+                    path: display,
+                    status: VerifyStatus::StaleRemotely,
+                }),
+                Err(err) => report.errors.push((display, err)),
+            }
+        }
+
+        // Whatever the server listed that no local file claimed.
+        for server_path in remote.into_keys() {
+            let cipher = path_cipher::cipher_for_server(&self.path_ciphers, &server_path);
+            let path = match cipher {
+                Some(cipher) => match cipher.decrypt_path(&server_path) {
+                    Ok(path) => path.to_string_lossy().to_string(),
+                    Err(err) => {
+                        report.errors.push((server_path, err));
+                        continue;
+                    }
+                },
+                None => server_path,
+            };
+            report.entries.push(VerifyEntry {
+                path,
+                status: VerifyStatus::RemoteOnly,
+            });
+        }
+        report.entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        if self.sample > 0 && !sampleable.is_empty() {
+            let scratch = create_scratch(&self.scratch).map_err(SyncError::Io)?;
+            for index in sample_indices(sampleable.len(), self.sample)? {
+                let (path, record) = &sampleable[index];
+                let dest = scratch.join(format!("sample-{index}"));
+                report.sampled += 1;
+                if let Err(err) = self.restorer.restore_file(record, &dest) {
+                    report.sample_failures.push((path.clone(), err));
+                }
+                let _ = fs::remove_file(&dest);
+            }
+            let _ = fs::remove_dir_all(&scratch);
+        }
+        Ok(report)
+    }
+
+    fn server_path(&self, local_path: &Path) -> Result<String, SyncError> {
+        match path_cipher::cipher_for_local(&self.path_ciphers, local_path) {
+            Some(cipher) => cipher.encrypt_path(local_path),
+            None => Ok(local_path.to_string_lossy().to_string()),
+        }
+    }
+
+    /// Compares the file with a record whose hash may be a keyed tag. Link records carry a
+    /// tag without an encryption header, so both forms are tried when a key is set.
+    fn matches(&self, file_path: &Path, record: &SyncRequest) -> Result<bool, SyncError> {
+        let hash = plaintext_hash(file_path, record)?;
+        match &self.master {
+            Some(master) => Ok(hash == record.hash || master.content_tag(&hash) == record.hash),
+            None if record.encryption.is_some() => Err(SyncError::Crypto(
+                "the record is encrypted; the master key is needed to compare it".to_string(),
+            )),
+            None => Ok(hash == record.hash),
+        }
+    }
+}
+
+/// `count` distinct random indices below `len`, or all of them when `count` covers `len`.
+fn sample_indices(len: usize, count: usize) -> Result<Vec<usize>, SyncError> {
+    let mut indices = (0..len).collect::<Vec<_>>();
+    let count = count.min(len);
+    // A partial Fisher-Yates shuffle: the first `count` slots end up a uniform sample.
+    for slot in 0..count {
+        let mut bytes = [0_u8; 8];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|err| SyncError::Crypto(format!("random source failed: {err}")))?;
+        let pick = slot + (u64::from_le_bytes(bytes) % (len - slot) as u64) as usize;
+        indices.swap(slot, pick);
+    }
+    indices.truncate(count);
+    indices.sort_unstable();
+    Ok(indices)
+}
+
+/// A fresh directory under `parent` that only the current user can read, since samples hold
+/// decrypted content.
+fn create_scratch(parent: &Path) -> std::io::Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    fs::create_dir_all(parent)?;
+    let scratch = parent.join(format!(
+        "rust-client-verify-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&scratch)?;
+    Ok(scratch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{temp_dir, MemoryRemote};
+    use crate::{ContentOptions, PathIndex, SyncManager};
+
+    fn statuses(report: &VerifyReport, root: &Path) -> Vec<(String, &'static str)> {
+        report
+            .entries
+            .iter()
+            .map(|entry| {
+                let relative = Path::new(&entry.path)
+                    .strip_prefix(root)
+                    .expect("entry under root");
+                (relative.display().to_string(), entry.status.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classifies_every_path_and_samples_downloads() {
+        let root = temp_dir("verify");
+        fs::create_dir_all(root.join("docs")).expect("dirs should be created");
+        for name in [
+            "docs/kept.txt",
+            "docs/changed.txt",
+            "docs/deleted.txt",
+            "big.csv",
+        ] {
+            fs::write(root.join(name), format!("{name} v1\n").repeat(400))
+                .expect("file should be written");
+        }
+        let master = MasterKey::generate().expect("master key should generate");
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone()).with_content_upload(ContentOptions {
+            chunk_size: 4096,
+            encryption: Some(master.clone()),
+            compression: None,
+        });
+        manager
+            .queue_directory(&root)
+            .expect("directory should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+
+        fs::write(root.join("docs/changed.txt"), "edited").expect("edit");
+        fs::remove_file(root.join("docs/deleted.txt")).expect("delete");
+        fs::write(root.join("docs/new.txt"), "never synced").expect("new file");
+        fs::write(root.join("scratch.tmp"), "ignored").expect("ignored file");
+        let scratch = temp_dir("verify-scratch");
+        fs::write(scratch.join("unrelated.txt"), "not the verifier's").expect("unrelated file");
+
+        let mut verifier = Verifier::new(remote.clone())
+            .with_master_key(master.clone())
+            .with_ignore(IgnoreRules::new(&["*.tmp"]).expect("rules should parse"))
+            .with_sample(10)
+            .with_scratch_dir(&scratch);
+        let report = verifier.verify(&root).expect("verify should run");
+        assert_eq!(
+            statuses(&report, &root),
+            [
+                ("big.csv".to_string(), "in-sync"),
+                ("docs/changed.txt".to_string(), "stale-remotely"),
+                ("docs/deleted.txt".to_string(), "remote-only"),
+                ("docs/kept.txt".to_string(), "in-sync"),
+                ("docs/new.txt".to_string(), "missing-remotely"),
+            ]
+        );
+        assert_eq!((report.sampled, report.sample_failures.len()), (2, 0));
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(!report.is_complete());
+        let left = fs::read_dir(&scratch)
+            .expect("scratch parent should remain")
+            .map(|entry| entry.expect("entry should read").file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            left,
+            ["unrelated.txt"],
+            "only the verifier's own directory is removed"
+        );
+
+        // A damaged chunk on the server is caught by the sample, not by the hash comparison.
+        let kept = root.join("docs/kept.txt").display().to_string();
+        let chunk_id = remote.state().files[&kept].chunks[0].id.clone();
+        remote.state().chunks.insert(chunk_id, b"bit rot".to_vec());
+        let report = verifier.verify(&root).expect("verify should run");
+        assert_eq!(report.count(VerifyStatus::InSync), 2);
+        let failed = report
+            .sample_failures
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, [kept.as_str()]);
+
+        // Without the key, keyed tags cannot be compared.
+        let report = Verifier::new(remote)
+            .verify(&root)
+            .expect("verify should run");
+        assert_eq!(report.errors.len(), 3);
+        let _ = fs::remove_dir_all(scratch);
+    }
+
+    #[test]
+    fn decrypts_remote_only_paths_of_an_encrypted_root() {
+        let root = temp_dir("verify-paths");
+        fs::create_dir_all(root.join("medical")).expect("dirs should be created");
+        fs::write(root.join("medical/scan.pdf"), "pdf").expect("write scan");
+        fs::write(root.join("medical/notes.txt"), "notes").expect("write notes");
+        let master = MasterKey::generate().expect("master key should generate");
+        let cipher = PathCipher::for_root(&master, &root, "root-1");
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone())
+            .with_path_encryption(cipher.clone(), PathIndex::in_memory());
+        manager
+            .queue_directory(&root)
+            .expect("directory should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        fs::remove_file(root.join("medical/notes.txt")).expect("delete notes");
+
+        let report = Verifier::new(remote)
+            .with_path_encryption(cipher)
+            .with_sample(5)
+            .verify(&root)
+            .expect("verify should run");
+        assert_eq!(
+            statuses(&report, &root),
+            [
+                ("medical/notes.txt".to_string(), "remote-only"),
+                ("medical/scan.pdf".to_string(), "in-sync"),
+            ]
+        );
+        // Hash-only records have no content to sample.
+        assert_eq!(report.sampled, 0);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+    }
+
+    #[test]
+    fn lists_and_restores_an_encrypted_root_after_key_rotation() {
+        use crate::crypto::test_kdf_params;
+        use crate::{rewrap_data_key, KeyStore};
+
+        let root = temp_dir("verify-rotation");
+        fs::create_dir_all(root.join("medical")).expect("dirs should be created");
+        fs::write(root.join("medical/scan.pdf"), "pdf").expect("write scan");
+        fs::write(root.join("medical/notes.txt"), "notes").expect("write notes");
+        let (mut store, old_master, _) =
+            KeyStore::create("passphrase", test_kdf_params()).expect("store should be created");
+        let cipher_for = |master: &MasterKey| {
+            PathCipher::for_root(master, &root, &PathCipher::root_id_for(master, &root))
+        };
+        let remote = MemoryRemote::default();
+        let mut manager = SyncManager::new(remote.clone())
+            .with_content_upload(ContentOptions {
+                encryption: Some(old_master.clone()),
+                ..ContentOptions::default()
+            })
+            .with_path_encryption(cipher_for(&old_master), PathIndex::in_memory());
+        manager
+            .queue_directory(&root)
+            .expect("directory should queue");
+        assert_eq!(manager.flush_once().failed, 0);
+        fs::remove_file(root.join("medical/notes.txt")).expect("delete notes");
+
+        let new_master = store
+            .begin_rotation(&old_master, "passphrase")
+            .expect("rotation should begin");
+        for record in remote.state().files.values_mut() {
+            let header = record.encryption.as_mut().expect("content is encrypted");
+            header.wrapped_key = rewrap_data_key(&old_master, &new_master, &header.wrapped_key)
+                .expect("data key should rewrap")
+                .expect("data key was under the old master key");
+        }
+        store.finish_rotation();
+        let keys = temp_dir("verify-rotation-keys");
+        let path = keys.join("keystore");
+        store.save(&path).expect("store should save");
+        let unlocked = KeyStore::load(&path)
+            .and_then(|store| store.unlock("passphrase"))
+            .expect("rotated store should unlock");
+
+        let report = Verifier::new(remote)
+            .with_path_encryption(cipher_for(&unlocked))
+            .with_master_key(unlocked)
+            .with_sample(5)
+            .verify(&root)
+            .expect("verify should run");
+        assert_eq!(
+            statuses(&report, &root),
+            [
+                ("medical/notes.txt".to_string(), "remote-only"),
+                ("medical/scan.pdf".to_string(), "in-sync"),
+            ]
+        );
+        assert_eq!(report.sampled, 1);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let _ = fs::remove_dir_all(root);
+        let _ = fs::remove_dir_all(keys);
+    }
+
+    #[test]
+    fn samples_distinct_indices() {
+        let picked = sample_indices(10, 4).expect("sample should be drawn");
+        assert_eq!(picked.len(), 4);
+        assert!(picked.windows(2).all(|pair| pair[0] < pair[1]) && picked[3] < 10);
+        assert_eq!(
+            sample_indices(3, 10).expect("sample should be drawn"),
+            [0, 1, 2]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::hash_cache::{escape, unescape};
+use crate::{FileStamp, IgnoreRules, SyncError};
+
+const STATE_HEADER: &str = "# rust-client watcher v1";
+
+/// Polling watcher for one sync root. Each scan compares file stamps with the previous
+/// scan, which works on every platform and filesystem (including network mounts where
+/// change notifications are unreliable). The last seen stamps can be persisted so a
+/// restarted daemon only reports what changed while it was down.
+#[derive(Debug)]
+pub struct RootWatcher {
+    root: PathBuf,
+    ignore: IgnoreRules,
+    known: HashMap<PathBuf, FileStamp>,
+    state_path: Option<PathBuf>,
+}
+
+/// Result of one scan. Removed files are reported but not synced yet.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WatchChanges {
+    pub changed: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl RootWatcher {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            ignore: IgnoreRules::default(),
+            known: HashMap::new(),
+            state_path: None,
+        }
+    }
+
+    /// Skips matching files and directories. Files that become ignored are reported as removed.
+    pub fn with_ignore(mut self, ignore: IgnoreRules) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
+    /// Loads the stamps saved by a previous run; a missing file means a first scan.
+    pub fn with_state_file<P: AsRef<Path>>(mut self, state_path: P) -> Result<Self, SyncError> {
+        let state_path = state_path.as_ref().to_path_buf();
+        match fs::read_to_string(&state_path) {
+            Ok(contents) => {
+                for line in contents.lines().filter(|line| !line.starts_with('#')) {
+                    if let Some((path, stamp)) = parse_line(line) {
+                        self.known.insert(path, stamp);
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SyncError::Io(err)),
+        }
+        self.state_path = Some(state_path);
+        Ok(self)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Walks the root and reports files that are new or whose stamp changed, in path order.
+    pub fn scan(&mut self) -> Result<WatchChanges, SyncError> {
+        let mut current = HashMap::new();
+        walk(&self.root, &self.root, &self.ignore, &mut current)?;
+
+        let mut changes = WatchChanges::default();
+        for (path, stamp) in &current {
+            if self.known.get(path) != Some(stamp) {
+                changes.changed.push(path.clone());
+            }
+        }
+        for path in self.known.keys() {
+            if !current.contains_key(path) {
+                changes.removed.push(path.clone());
+            }
+        }
+        changes.changed.sort();
+        changes.removed.sort();
+        self.known = current;
+        Ok(changes)
+    }
+
+    /// Forgets a file so the next scan reports it again, e.g. after it failed to queue.
+    pub fn forget(&mut self, file_path: &Path) {
+        self.known.remove(file_path);
+    }
+
+    pub fn save(&self) -> Result<(), SyncError> {
+        self.save_except(&[])
+    }
+
+    /// Saves every known stamp except those of `unsettled` files, e.g. ones the manager has
+    /// deferred, so a restart reports them again instead of treating them as synced.
+    pub fn save_except(&self, unsettled: &[PathBuf]) -> Result<(), SyncError> {
+        let Some(state_path) = &self.state_path else {
+            return Ok(());
+        };
+        let mut entries = self
+            .known
+            .iter()
+            .filter(|(path, _)| !unsettled.contains(path))
+            .collect::<Vec<_>>();
+        entries.sort_by(|left, right| left.0.cmp(right.0));
+
+        let mut contents = format!("{STATE_HEADER}\n");
+        for (path, stamp) in entries {
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                stamp.size,
+                stamp.mtime_ns,
+                stamp.ctime_ns,
+                stamp.inode,
+                escape(&path.to_string_lossy())
+            ));
+        }
+
+        let temp_path = state_path.with_extension("tmp");
+        let mut file = fs::File::create(&temp_path).map_err(SyncError::Io)?;
+        file.write_all(contents.as_bytes()).map_err(SyncError::Io)?;
+        file.sync_all().map_err(SyncError::Io)?;
+        fs::rename(&temp_path, state_path).map_err(SyncError::Io)
+    }
+}
+
+/// Stamps every file below `directory` that `ignore` lets through, relative to `root`.
+pub(crate) fn walk(
+    root: &Path,
+    directory: &Path,
+    ignore: &IgnoreRules,
+    found: &mut HashMap<PathBuf, FileStamp>,
+) -> Result<(), SyncError> {
+    for entry in fs::read_dir(directory).map_err(SyncError::Io)? {
+        let entry = entry.map_err(SyncError::Io)?;
+        let file_type = entry.file_type().map_err(SyncError::Io)?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if ignore.is_ignored(relative, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk(root, &path, ignore, found)?;
+        } else if file_type.is_file() {
+            // Files removed between listing and stat are simply absent from this scan.
+            if let Ok(metadata) = entry.metadata() {
+                found.insert(path, FileStamp::from_metadata(&metadata));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, FileStamp)> {
+    let mut fields = line.splitn(5, '\t');
+    let stamp = FileStamp {
+        size: fields.next()?.parse().ok()?,
+        mtime_ns: fields.next()?.parse().ok()?,
+        ctime_ns: fields.next()?.parse().ok()?,
+        inode: fields.next()?.parse().ok()?,
+    };
+    Some((PathBuf::from(unescape(fields.next()?)?), stamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn reports_new_modified_and_removed_files() {
+        let root = temp_dir("watcher");
+        fs::create_dir_all(root.join("nested")).expect("nested dir should be created");
+        fs::write(root.join("a.txt"), "a").expect("file should be written");
+        fs::write(root.join("nested/b.txt"), "b").expect("file should be written");
+
+        let mut watcher = RootWatcher::new(&root);
+        let first = watcher.scan().expect("scan should succeed");
+        assert_eq!(
+            first.changed,
+            vec![root.join("a.txt"), root.join("nested/b.txt")]
+        );
+        assert!(watcher
+            .scan()
+            .expect("scan should succeed")
+            .changed
+            .is_empty());
+
+        fs::write(root.join("a.txt"), "a changed").expect("file should be rewritten");
+        fs::remove_file(root.join("nested/b.txt")).expect("file should be removed");
+        let second = watcher.scan().expect("scan should succeed");
+        assert_eq!(second.changed, vec![root.join("a.txt")]);
+        assert_eq!(second.removed, vec![root.join("nested/b.txt")]);
+    }
+
+    #[test]
+    fn skips_ignored_files_and_directories() {
+        let root = temp_dir("watcher-ignore");
+        fs::create_dir_all(root.join("node_modules/pkg")).expect("dir should be created");
+        fs::write(root.join("node_modules/pkg/index.js"), "x").expect("file should be written");
+        fs::write(root.join("draft.tmp"), "x").expect("file should be written");
+        fs::write(root.join("notes.md"), "x").expect("file should be written");
+
+        let ignore = IgnoreRules::new(&["node_modules/", "*.tmp"]).expect("patterns should parse");
+        let mut watcher = RootWatcher::new(&root).with_ignore(ignore);
+        assert_eq!(
+            watcher.scan().expect("scan should succeed").changed,
+            vec![root.join("notes.md")]
+        );
+    }
+
+    #[test]
+    fn persisted_state_only_reports_changes_made_while_stopped() {
+        let root = temp_dir("watcher-state");
+        let state = root.join("watch.state");
+        let data = root.join("data");
+        fs::create_dir_all(&data).expect("data dir should be created");
+        fs::write(data.join("kept.txt"), "same").expect("file should be written");
+        fs::write(data.join("edited.txt"), "old").expect("file should be written");
+
+        let mut watcher = RootWatcher::new(&data)
+            .with_state_file(&state)
+            .expect("state should load");
+        watcher.scan().expect("scan should succeed");
+        watcher.save().expect("state should save");
+
+        let edited = fs::File::options()
+            .write(true)
+            .open(data.join("edited.txt"))
+            .expect("file should open");
+        edited
+            .set_modified(SystemTime::now() + Duration::from_secs(5))
+            .expect("mtime should be set");
+
+        let mut restarted = RootWatcher::new(&data)
+            .with_state_file(&state)
+            .expect("state should load");
+        assert_eq!(
+            restarted.scan().expect("scan should succeed").changed,
+            vec![data.join("edited.txt")]
+        );
+    }
+}
@@ -2,7 +2,15 @@
 """Minimal mock sync server for local client development."""
 
 from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import parse_qs, unquote, urlsplit
 import argparse
+import hashlib
+import io
+import tarfile
+import time
+
+ARCHIVE_MANIFEST_HEADER = "# rust-client archive manifest v1\n"
+CHANGE_PAGE_SIZE = 100
 
 
 class SyncHandler(BaseHTTPRequestHandler):
@@ -11,9 +19,116 @@ class SyncHandler(BaseHTTPRequestHandler):
             self._send(200, b"ok")
             return
 
+        if not self._admit_device():
+            return
+
+        url = urlsplit(self.path)
+        if url.path == "/v1/devices":
+            blocks = [_device_block(device) for device in self.server.devices.values()]
+            self._send(200, "\n".join(blocks).encode())
+            return
+
+        if url.path == "/v1/files":
+            # Single page; a real server would page with next=<cursor>.
+            prefix = parse_qs(url.query).get("prefix", [""])[0].rstrip("/")
+            paths = [
+                path for path in sorted(self.server.files)
+                if path == prefix or path.startswith(prefix + "/")
+            ]
+            lines = [f"path={path}" for path in paths]
+            self._send(200, ("\n".join(lines + ["next="]) + "\n").encode())
+            return
+
+        if url.path.startswith("/v1/files/"):
+            record = self.server.files.get(unquote(url.path[len("/v1/files/"):]))
+            if record is None:
+                self._send(404, b"not found")
+            else:
+                self._send(200, record.encode())
+            return
+
+        if url.path.startswith("/v1/chunks/"):
+            chunk = self.server.chunks.get(url.path[len("/v1/chunks/"):])
+            if chunk is None:
+                self._send(404, b"not found")
+            else:
+                self._send(200, chunk, "application/octet-stream")
+            return
+
+        if url.path == "/v1/snapshots":
+            # Single page, oldest first; a real server would page with next=<cursor>.
+            lines = [
+                f"snapshot={snapshot_id}:{created}:{files}"
+                for snapshot_id, (created, files, _) in self.server.snapshots.items()
+            ]
+            self._send(200, ("\n".join(lines + ["next="]) + "\n").encode())
+            return
+
+        if url.path.startswith("/v1/snapshots/"):
+            snapshot = self.server.snapshots.get(url.path[len("/v1/snapshots/"):])
+            if snapshot is None:
+                self._send(404, b"not found")
+            else:
+                self._send(200, snapshot[2])
+            return
+
+        if url.path == "/v1/changes":
+            # The cursor is simply an index into the change log.
+            start = int(parse_qs(url.query).get("cursor", ["0"])[0] or 0)
+            end = min(start + CHANGE_PAGE_SIZE, len(self.server.changes))
+            lines = [
+                f"change={kind}:{applied}:{device}:{hash_}:{path}"
+                for kind, applied, device, hash_, path in self.server.changes[start:end]
+            ]
+            more = "true" if end < len(self.server.changes) else "false"
+            self._send(200, ("\n".join(lines + [f"cursor={end}", f"more={more}"]) + "\n").encode())
+            return
+
+        if url.path.startswith("/v1/restore-jobs/"):
+            self._restore_job(unquote(url.path[len("/v1/restore-jobs/"):]))
+            return
+
+        if url.path == "/v1/keys/wrapped":
+            # Single page; a real server would page with next=<cursor>.
+            lines = [f"key={wrapped}" for wrapped in self.server.wrapped_keys]
+            self._send(200, ("\n".join(lines + ["next="]) + "\n").encode())
+            return
+
+        envelope = self.server.envelopes.get(self._device_id())
+        if envelope is not None:
+            self._send(200, envelope)
+            return
+
         self._send(404, b"not found")
 
     def do_POST(self) -> None:
+        if not self._admit_device():
+            return
+
+        device_id = self._device_path("/name")
+        if device_id is not None:
+            device = self.server.devices.get(device_id)
+            if device is None:
+                self._send(404, b"no such device")
+                return
+            content_length = int(self.headers.get("Content-Length", "0"))
+            body = self.rfile.read(content_length).decode("utf-8", errors="replace")
+            device["name"] = next(
+                (line[len("name="):] for line in body.splitlines() if line.startswith("name=")),
+                device["name"],
+            )
+            print(f"[mock-sync-server] renamed device {device_id} to {device['name']}")
+            self._send(204, b"")
+            return
+
+        if self.path == "/v1/keys/rewrap":
+            self._rewrap()
+            return
+
+        if self.path == "/v1/restore-jobs":
+            self._create_restore_job()
+            return
+
         if self.path != "/v1/sync":
             self._send(404, b"not found")
             return
@@ -29,20 +144,224 @@ class SyncHandler(BaseHTTPRequestHandler):
             self._send(500, b"sync failed")
             return
 
+        fields = dict(line.split("=", 1) for line in body.splitlines() if "=" in line)
+        if "path" in fields:
+            self.server.changes.append((
+                "updated",
+                int(time.time()),
+                self.headers.get("X-Device-Id") or fields.get("device", ""),
+                fields.get("hash", ""),
+                fields["path"],
+            ))
+        for line in body.splitlines():
+            if line.startswith("path="):
+                self.server.files[line[len("path="):]] = body
+            if line.startswith("encryption="):
+                wrapped = line.split(":")[1]
+                if wrapped not in self.server.wrapped_keys:
+                    self.server.wrapped_keys.append(wrapped)
+
         self._send(202, b"accepted")
 
+    def _rewrap(self) -> None:
+        content_length = int(self.headers.get("Content-Length", "0"))
+        body = self.rfile.read(content_length).decode("utf-8", errors="replace")
+        keys = self.server.wrapped_keys
+        for line in body.splitlines():
+            old, new = line[len("rewrap="):].split(":")
+            if old in keys:
+                keys[keys.index(old)] = new
+        print(f"[mock-sync-server] re-wrapped {len(body.splitlines())} data keys")
+        self._send(204, b"")
+
+    def _create_restore_job(self) -> None:
+        content_length = int(self.headers.get("Content-Length", "0"))
+        body = self.rfile.read(content_length).decode("utf-8", errors="replace")
+        fields = dict(line.split("=", 1) for line in body.splitlines() if "=" in line)
+        prefix = fields.get("prefix", "").rstrip("/")
+        part_size = int(fields.get("part_size", "0"))
+        if part_size <= 0:
+            self._send(400, b"part_size must be positive")
+            return
+
+        if "snapshot" in fields:
+            snapshot = self.server.snapshots.get(fields["snapshot"])
+            if snapshot is None:
+                self._send(404, b"no such snapshot")
+                return
+            blocks = snapshot[2].decode("utf-8", errors="replace").split("\n\n")
+            records = {}
+            for block in blocks:
+                path = next((line[len("path="):] for line in block.splitlines() if line.startswith("path=")), None)
+                if path is not None:
+                    records[path] = block.strip("\n") + "\n"
+        else:
+            records = {path: record if record.endswith("\n") else record + "\n"
+                       for path, record in self.server.files.items()}
+        records = {
+            path: record for path, record in sorted(records.items())
+            if path == prefix or path.startswith(prefix + "/")
+        }
+
+        # Same layout the client extracts: a manifest of records, then each chunk once.
+        buffer = io.BytesIO()
+        with tarfile.open(fileobj=buffer, mode="w", format=tarfile.USTAR_FORMAT) as archive:
+            def add(name: str, data: bytes) -> None:
+                info = tarfile.TarInfo(name)
+                info.size = len(data)
+                archive.addfile(info, io.BytesIO(data))
+
+            add("manifest", (ARCHIVE_MANIFEST_HEADER + "".join("\n" + record for record in records.values())).encode())
+            added = set()
+            for record in records.values():
+                for line in record.splitlines():
+                    if line.startswith("chunk="):
+                        chunk_id = line[len("chunk="):].split(":")[0]
+                        if chunk_id not in added and chunk_id in self.server.chunks:
+                            added.add(chunk_id)
+                            add("chunks/" + chunk_id, self.server.chunks[chunk_id])
+        stream = buffer.getvalue()
+        parts = [stream[offset:offset + part_size] for offset in range(0, len(stream), part_size)]
+
+        job_id = f"job-{len(self.server.jobs) + 1}"
+        # The first status poll reports the job as building, like a real server would.
+        self.server.jobs[job_id] = {"polls": 1, "parts": parts}
+        print(f"[mock-sync-server] restore job {job_id}: {len(records)} files in {len(parts)} part(s)")
+        self._send(202, f"job={job_id}\n".encode())
+
+    def _restore_job(self, rest: str) -> None:
+        job_id, _, part = rest.partition("/parts/")
+        job = self.server.jobs.get(job_id)
+        if job is None:
+            self._send(404, b"not found")
+            return
+        if part:
+            index = int(part) if part.isdigit() else -1
+            if not 0 <= index < len(job["parts"]) or job["polls"] > 0:
+                self._send(404, b"not found")
+            else:
+                self._send(200, job["parts"][index], "application/x-tar")
+            return
+        if job["polls"] > 0:
+            job["polls"] -= 1
+            self._send(200, b"state=building\n")
+            return
+        lines = ["state=ready"] + [
+            f"part={index}:{len(data)}:{hashlib.sha256(data).hexdigest()}"
+            for index, data in enumerate(job["parts"])
+        ]
+        self._send(200, ("\n".join(lines) + "\n").encode())
+
+    def _device_id(self):
+        return self._device_path("/key-envelope")
+
+    def _device_path(self, suffix: str):
+        prefix = "/v1/devices/"
+        if self.path.startswith(prefix) and self.path.endswith(suffix):
+            device_id = unquote(self.path[len(prefix):len(self.path) - len(suffix)])
+            if device_id and "/" not in device_id:
+                return device_id
+        return None
+
+    def _admit_device(self) -> bool:
+        """Records when a stamped device was last seen; refuses revoked devices."""
+        device = self.server.devices.get(self.headers.get("X-Device-Id", ""))
+        if device is None:
+            return True
+        if device["revoked"]:
+            self._send(403, b"device revoked")
+            return False
+        device["last_seen"] = int(time.time())
+        return True
+
+    def do_DELETE(self) -> None:
+        if not self._admit_device():
+            return
+
+        device = self.server.devices.get(self._device_path(""))
+        if device is None:
+            self._send(404, b"no such device")
+            return
+        device["revoked"] = True
+        print(f"[mock-sync-server] revoked device {device['id']}")
+        self._send(204, b"")
+
+    def do_PUT(self) -> None:
+        if not self._admit_device():
+            return
+
+        device_id = self._device_path("")
+        if device_id is not None:
+            content_length = int(self.headers.get("Content-Length", "0"))
+            body = self.rfile.read(content_length).decode("utf-8", errors="replace")
+            known = self.server.devices.get(device_id)
+            if known is not None and known["revoked"]:
+                self._send(403, b"device revoked")
+                return
+            device = {"id": device_id, "capabilities": [], "last_seen": int(time.time()), "revoked": False}
+            for line in body.splitlines():
+                key, _, value = line.partition("=")
+                if key == "capability":
+                    device["capabilities"].append(value)
+                elif key in ("name", "platform", "public_key"):
+                    device[key] = value
+            self.server.devices[device_id] = device
+            print(f"[mock-sync-server] registered device {device_id} ({device.get('name', '')})")
+            self._send(201 if known is None else 200, b"")
+            return
+
+        device_id = self._device_id()
+        if device_id is not None:
+            content_length = int(self.headers.get("Content-Length", "0"))
+            self.server.envelopes[device_id] = self.rfile.read(content_length)
+            print(f"[mock-sync-server] stored key envelope for device {device_id}")
+            self._send(204, b"")
+            return
+
+        if self.path.startswith("/v1/snapshots/"):
+            snapshot_id = self.path[len("/v1/snapshots/"):]
+            content_length = int(self.headers.get("Content-Length", "0"))
+            manifest = self.rfile.read(content_length)
+            lines = manifest.decode("utf-8", errors="replace").splitlines()
+            created = next((line[len("created="):] for line in lines if line.startswith("created=")), "0")
+            files = sum(1 for line in lines if line.startswith("path="))
+            self.server.snapshots.pop(snapshot_id, None)
+            self.server.snapshots[snapshot_id] = (created, files, manifest)
+            print(f"[mock-sync-server] stored snapshot {snapshot_id} ({files} files)")
+            self._send(201, b"")
+            return
+
+        if not self.path.startswith("/v1/chunks/"):
+            self._send(404, b"not found")
+            return
+
+        chunk_id = self.path[len("/v1/chunks/"):]
+        content_length = int(self.headers.get("Content-Length", "0"))
+        self.server.chunks[chunk_id] = self.rfile.read(content_length)
+        print(f"[mock-sync-server] stored chunk {chunk_id} ({content_length} bytes)")
+        self._send(201, b"")
+
     def log_message(self, format: str, *args) -> None:
         # Keep output focused on sync payloads.
         return
 
-    def _send(self, status: int, body: bytes) -> None:
+    def _send(self, status: int, body: bytes, content_type: str = "text/plain") -> None:
         self.send_response(status)
-        self.send_header("Content-Type", "text/plain")
+        self.send_header("Content-Type", content_type)
         self.send_header("Content-Length", str(len(body)))
         self.end_headers()
         self.wfile.write(body)
 
 
+def _device_block(device: dict) -> str:
+    lines = [f"id={device['id']}", f"name={device.get('name', '')}", f"platform={device.get('platform', '')}"]
+    lines += [f"capability={capability}" for capability in device["capabilities"]]
+    lines += [f"public_key={device.get('public_key', '')}", f"last_seen={device['last_seen']}"]
+    if device["revoked"]:
+        lines.append("revoked=true")
+    return "\n".join(lines) + "\n"
+
+
 def main() -> None:
     parser = argparse.ArgumentParser(description="Run a local mock sync server.")
     parser.add_argument("--host", default="127.0.0.1", help="Bind host")
@@ -56,10 +375,19 @@ def main() -> None:
 
     server = ThreadingHTTPServer((args.host, args.port), SyncHandler)
     server.fail_sync = args.fail_sync
+    server.chunks = {}
+    server.files = {}
+    server.snapshots = {}
+    server.jobs = {}
+    server.changes = []
+    server.wrapped_keys = []
+    server.envelopes = {}
+    server.devices = {}
 
     mode = "fail" if args.fail_sync else "normal"
     print(f"[mock-sync-server] listening on http://{args.host}:{args.port} ({mode} mode)")
-    print("[mock-sync-server] endpoints: GET /v1/health, POST /v1/sync")
+    print("[mock-sync-server] endpoints: GET /v1/health, POST /v1/sync, PUT/GET /v1/chunks/<id>, "
+          "GET /v1/files?prefix=<path>, GET /v1/files/<path>, PUT/GET /v1/snapshots[/<id>], GET /v1/changes?cursor=<c>, POST /v1/restore-jobs, GET /v1/restore-jobs/<id>[/parts/<n>], GET /v1/keys/wrapped, POST /v1/keys/rewrap, GET /v1/devices, PUT/DELETE /v1/devices/<id>, POST /v1/devices/<id>/name, PUT/GET /v1/devices/<id>/key-envelope")
 
     try:
         server.serve_forever()